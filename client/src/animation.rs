@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+
+use bevy::{prelude::*, window::RequestRedraw};
+use shared::{Graph, GraphChange, Node, NodeDiff, NodeId};
+
+/// Animation is purely local: keyframes are blended into a copy of the graph that's only
+/// used for rendering, so playback never sends anything to the server.
+pub struct AnimationPlugin;
+impl Plugin for AnimationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<AnimationTracks>()
+            .init_resource::<Playhead>()
+            .init_resource::<AnimatedGraph>()
+            .add_system(advance_playhead)
+            .add_system(apply_animation.after(advance_playhead));
+    }
+}
+
+pub struct Keyframe {
+    pub time: f32,
+    pub diff: NodeDiff,
+}
+
+/// The keyframes for each animated node, sorted by time.
+#[derive(Default)]
+pub struct AnimationTracks(pub HashMap<NodeId, Vec<Keyframe>>);
+impl AnimationTracks {
+    /// Records the node's current values at `time`, replacing any keyframe already there.
+    pub fn add_keyframe(&mut self, node: &Node, time: f32) {
+        let diff = NodeDiff {
+            rgb: Some(node.rgb),
+            material: Some(Box::new(node.material.into())),
+            transform: Some(node.transform.into()),
+            data: Some(node.data.clone().into()),
+            ..Default::default()
+        };
+        let keyframes = self.0.entry(node.id).or_default();
+        keyframes.retain(|keyframe| keyframe.time != time);
+        let index = keyframes.partition_point(|keyframe| keyframe.time < time);
+        keyframes.insert(index, Keyframe { time, diff });
+    }
+
+    pub fn keyframe_count(&self) -> usize {
+        self.0.values().map(Vec::len).sum()
+    }
+
+    /// Blends the keyframes either side of `time`. Before the first keyframe and after
+    /// the last, that keyframe is held.
+    fn sample(keyframes: &[Keyframe], time: f32) -> Option<NodeDiff> {
+        let index = keyframes.partition_point(|keyframe| keyframe.time <= time);
+        let previous = index.checked_sub(1).map(|i| &keyframes[i]);
+        match (previous, keyframes.get(index)) {
+            (Some(previous), Some(next)) => {
+                let t = (time - previous.time) / (next.time - previous.time);
+                Some(previous.diff.lerp(&next.diff, t))
+            }
+            (Some(keyframe), None) | (None, Some(keyframe)) => Some(keyframe.diff.clone()),
+            (None, None) => None,
+        }
+    }
+}
+
+pub struct Playhead {
+    /// In seconds.
+    pub time: f32,
+    /// Playback loops back to the start after this many seconds.
+    pub duration: f32,
+    pub playing: bool,
+}
+impl Default for Playhead {
+    fn default() -> Self {
+        Self {
+            time: 0.0,
+            duration: 5.0,
+            playing: false,
+        }
+    }
+}
+
+/// The graph with the animation applied, if there's anything to animate. This should be
+/// rendered instead of the real graph when present.
+#[derive(Default)]
+pub struct AnimatedGraph(pub Option<Graph>);
+
+fn advance_playhead(
+    time: Res<Time>,
+    mut playhead: ResMut<Playhead>,
+    mut redraw: EventWriter<RequestRedraw>,
+) {
+    if !playhead.playing {
+        return;
+    }
+    let duration = playhead.duration.max(f32::EPSILON);
+    playhead.time = (playhead.time + time.delta_seconds()) % duration;
+    // The app only updates in response to input, so ask for the next frame explicitly
+    redraw.send(RequestRedraw);
+}
+
+fn apply_animation(
+    graph: Res<Graph>,
+    tracks: Res<AnimationTracks>,
+    playhead: Res<Playhead>,
+    mut animated_graph: ResMut<AnimatedGraph>,
+) {
+    if !(graph.is_changed() || tracks.is_changed() || playhead.is_changed()) {
+        return;
+    }
+    if tracks.0.is_empty() {
+        if animated_graph.0.is_some() {
+            animated_graph.0 = None;
+        }
+        return;
+    }
+
+    let mut changes = vec![GraphChange::Initialize(graph.to_components())];
+    changes.extend(
+        tracks
+            .0
+            .iter()
+            .filter(|(node_id, _)| graph.get(**node_id).is_some())
+            .filter_map(|(node_id, keyframes)| {
+                let diff = AnimationTracks::sample(keyframes, playhead.time)?;
+                Some(GraphChange::ApplyDiff(*node_id, diff))
+            }),
+    );
+    let mut animated = Graph::new_client();
+    animated.apply_changes(&changes);
+    animated_graph.0 = Some(animated);
+}
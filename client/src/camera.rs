@@ -1,7 +1,9 @@
-use bevy::{input::mouse::MouseMotion, prelude::*, render::camera::Projection};
+use bevy::{
+    input::mouse::MouseMotion, prelude::*, render::camera::Projection, window::RequestRedraw,
+};
 use bevy_egui::EguiContext;
 
-use super::resources::OccupiedScreenSpace;
+use super::resources::{CameraBindings, GizmoState, OccupiedScreenSpace};
 
 /// Tags an entity as capable of panning and orbiting.
 #[derive(Component)]
@@ -10,6 +12,14 @@ pub struct PanOrbitCamera {
     pub focus: Vec3,
     pub radius: f32,
     pub upside_down: bool,
+    /// The focus that `focus` eases towards. Moving the camera directly snaps this to the
+    /// current focus, so that the two don't fight.
+    pub target_focus: Vec3,
+    /// The radius that `radius` eases towards, like `target_focus`.
+    pub target_radius: f32,
+    /// How quickly the camera eases towards its target. The remaining distance shrinks by
+    /// a factor of e every `1 / smoothing` seconds.
+    pub smoothing: f32,
 }
 
 impl Default for PanOrbitCamera {
@@ -18,13 +28,46 @@ impl Default for PanOrbitCamera {
             focus: Vec3::ZERO,
             radius: 5.0,
             upside_down: false,
+            target_focus: Vec3::ZERO,
+            target_radius: 5.0,
+            smoothing: 10.0,
         }
     }
 }
 
+impl PanOrbitCamera {
+    /// Eases towards the centre of the box between `min` and `max`, and far enough away
+    /// that the whole box fits in a view with the given vertical field of view.
+    pub fn fit(&mut self, min: Vec3, max: Vec3, fov: f32) {
+        let bounding_radius = (max - min).length() * 0.5;
+        self.target_focus = (min + max) * 0.5;
+        self.target_radius = (bounding_radius / (fov * 0.5).sin()).max(0.05);
+    }
+
+    /// Moves `focus` and `radius` part of the way to their targets, returning whether
+    /// they're still moving.
+    fn approach_target(&mut self, delta_seconds: f32) -> bool {
+        let remaining =
+            (self.target_focus - self.focus).length() + (self.target_radius - self.radius).abs();
+        if remaining < 1e-4 * self.radius {
+            self.focus = self.target_focus;
+            self.radius = self.target_radius;
+            return false;
+        }
+        let t = 1.0 - (-self.smoothing * delta_seconds).exp();
+        self.focus = self.focus.lerp(self.target_focus, t);
+        self.radius += (self.target_radius - self.radius) * t;
+        true
+    }
+}
+
 pub(crate) fn pan_orbit_camera(
     occupied_screen_space: Res<OccupiedScreenSpace>,
+    gizmo_state: Res<GizmoState>,
+    bindings: Res<CameraBindings>,
     windows: Res<Windows>,
+    time: Res<Time>,
+    mut redraw: EventWriter<RequestRedraw>,
     mut ev_motion: EventReader<MouseMotion>,
     input_mouse: Res<Input<MouseButton>>,
     mut egui_context: ResMut<EguiContext>,
@@ -35,9 +78,9 @@ pub(crate) fn pan_orbit_camera(
         With<Camera3d>,
     )>,
 ) {
-    let orbit_button = MouseButton::Left;
-    let pan_button = MouseButton::Middle;
-    let zoom_button = MouseButton::Right;
+    let orbit_button = bindings.orbit;
+    let pan_button = bindings.pan;
+    let zoom_button = bindings.zoom;
 
     let mut pan = Vec2::ZERO;
     let mut rotation_move = Vec2::ZERO;
@@ -48,7 +91,7 @@ pub(crate) fn pan_orbit_camera(
         let ctx = egui_context.ctx_mut();
         ctx.wants_keyboard_input() || ctx.wants_pointer_input()
     };
-    if !egui_wants_input {
+    if !egui_wants_input && !gizmo_state.dragging {
         if input_mouse.pressed(orbit_button) {
             for ev in ev_motion.iter() {
                 rotation_move += ev.delta;
@@ -120,6 +163,16 @@ pub(crate) fn pan_orbit_camera(
             pan_orbit.radius = f32::max(pan_orbit.radius, 0.05);
         }
 
+        let moved_directly =
+            rotation_move.length_squared() > 0.0 || pan.length_squared() > 0.0 || zoom != 0.0;
+        if moved_directly {
+            pan_orbit.target_focus = pan_orbit.focus;
+            pan_orbit.target_radius = pan_orbit.radius;
+        } else if pan_orbit.approach_target(time.delta_seconds()) {
+            // Frames are only drawn on input, so keep them coming until the camera settles
+            redraw.send(RequestRedraw);
+        }
+
         // emulating parent/child to make the yaw/y-axis rotation behave like a turntable
         // parent = x and y rotation
         // child = z-offset
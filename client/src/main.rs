@@ -1,20 +1,14 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Mutex,
-};
-
 use bevy::prelude::*;
 use bevy_egui::EguiPlugin;
 use clap::Parser;
 
-use shared::{
-    protocol::{PeerIncomingMessage, PeerOutgoingMessage},
-    Graph, GraphChange,
-};
-use tokio::net::TcpStream;
+use shared::{protocol::PeerIncomingMessage, Graph, GraphChange};
 
+mod animation;
 mod camera;
 mod mesh_generation;
+mod network;
+mod raymarch;
 mod resources;
 mod ui;
 
@@ -30,27 +24,31 @@ pub async fn main() -> anyhow::Result<()> {
     #[clap(author, version, about, long_about = None)]
     struct Args {
         #[clap(short, long)]
-        host: String,
+        host: Option<String>,
         #[clap(short, long)]
         port: Option<u16>,
+        /// The room to join on startup. Without one, the client starts disconnected, and
+        /// a room can be joined from the connection window.
         #[clap(short, long)]
-        room: String,
+        room: Option<String>,
+        /// Join without being able to edit the graph
+        #[clap(long)]
+        spectator: bool,
     }
 
     let args = Args::parse();
-    let port = args.port.unwrap_or(shared::DEFAULT_PORT);
-
-    let shutdown = Arc::new(AtomicBool::new(false));
-
-    let (rx, tx) = (Arc::new(Mutex::new(vec![])), Arc::new(Mutex::new(vec![])));
-    tx.lock().unwrap().push(
-        shared::protocol::RequestJoin {
-            room: args.room.clone(),
-        }
-        .into(),
-    );
-    let _network_tasks =
-        create_network_tasks(&args.host, port, rx.clone(), tx.clone(), shutdown.clone()).await?;
+    let mut network_state =
+        resources::NetworkState::new(tokio::runtime::Handle::current(), args.spectator);
+    if let Some(host) = args.host {
+        network_state.host = host;
+    }
+    if let Some(port) = args.port {
+        network_state.port = port;
+    }
+    if let Some(room) = args.room {
+        let (host, port) = (network_state.host.clone(), network_state.port);
+        network_state.connect(host, port, room, args.spectator);
+    }
 
     let mut app = App::new();
     #[cfg(target_arch = "wasm32")]
@@ -82,16 +80,31 @@ pub async fn main() -> anyhow::Result<()> {
             ..Default::default()
         })
         .insert_resource(resources::RenderParameters {
-            wireframe: false,
+            wireframe: resources::WireframeMode::Solid,
             colours: true,
+            isolate_colours: false,
+            uvs: true,
+            flat_shading: false,
+            recompute_normals: false,
+            clipping_plane: None,
+            solo: None,
+            raymarch: false,
+            bounds_preview: false,
+            triangle_budget: Some(resources::RenderParameters::DEFAULT_TRIANGLE_BUDGET),
+            infinite_bounds_half_size: Some(
+                resources::RenderParameters::DEFAULT_INFINITE_BOUNDS_HALF_SIZE,
+            ),
         })
         .insert_resource(resources::MeshGenerationResult::Unbuilt)
         .insert_resource(resources::OccupiedScreenSpace::default())
-        .insert_resource(resources::NetworkState::new(shutdown.clone(), tx, rx))
+        .init_resource::<resources::Lighting>()
+        .init_resource::<resources::CameraBindings>()
+        .insert_resource(network_state)
         .add_plugins(DefaultPlugins)
         .add_plugin(bevy::pbr::wireframe::WireframePlugin)
         .add_plugin(bevy::diagnostic::FrameTimeDiagnosticsPlugin)
-        .add_system(synchronise_network_to_local);
+        .add_system(synchronise_network_to_local)
+        .add_system(update_lighting);
 
     #[cfg(target_arch = "wasm32")]
     app.add_plugin(bevy_web_fullscreen::FullViewportPlugin);
@@ -99,6 +112,8 @@ pub async fn main() -> anyhow::Result<()> {
     app.add_plugin(EguiPlugin)
         .add_plugin(ui::UiPlugin)
         .add_plugin(mesh_generation::MeshGenerationPlugin)
+        .add_plugin(animation::AnimationPlugin)
+        .add_plugin(raymarch::RaymarchPlugin)
         .add_startup_system(setup)
         .add_system(camera::pan_orbit_camera)
         .run();
@@ -106,88 +121,83 @@ pub async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn create_network_tasks(
-    host: &str,
-    port: u16,
-    rx: Arc<Mutex<Vec<GraphChange>>>,
-    tx: Arc<Mutex<Vec<PeerOutgoingMessage>>>,
-    shutdown: Arc<AtomicBool>,
-) -> anyhow::Result<(
-    tokio::task::JoinHandle<anyhow::Result<()>>,
-    tokio::task::JoinHandle<anyhow::Result<()>>,
-)> {
-    let (socket_rx, socket_tx) = TcpStream::connect((host, port)).await?.into_split();
-
-    let read_task = tokio::spawn({
-        let mut socket_rx = socket_rx;
-        let shutdown = shutdown.clone();
-        let rx = rx.clone();
-
-        async move {
-            loop {
-                if shutdown.load(Ordering::SeqCst) {
-                    break;
-                }
-
-                let message = match shared::protocol::read(&mut socket_rx).await {
-                    Some(Ok(PeerIncomingMessage::GraphChange(cmd))) => cmd,
-                    Some(Err(err)) => return Err(err),
-                    None => break,
-                };
-                rx.lock().unwrap().push(message);
+fn synchronise_network_to_local(
+    mut graph: ResMut<Graph>,
+    mut network_state: ResMut<resources::NetworkState>,
+    input_mouse: Res<Input<MouseButton>>,
+) {
+    let messages: Vec<_> = network_state.rx.lock().unwrap().drain(..).collect();
+    for message in messages {
+        match message {
+            PeerIncomingMessage::GraphChange(change) => network_state.apply_changes(&[change]),
+            PeerIncomingMessage::CommandAccepted(id, changes) => network_state.accept(id, changes),
+            PeerIncomingMessage::CommandRejected(id, err) => network_state.reject(id, err),
+            PeerIncomingMessage::RoomClosed => network_state.room_closed = true,
+            PeerIncomingMessage::RoomRenamed(room) => network_state.room = room,
+            PeerIncomingMessage::RenameRejected(reason) => {
+                network_state.rename_rejection = Some(reason)
             }
-
-            anyhow::Ok(())
+            PeerIncomingMessage::PeerCount(count) => network_state.peer_count = Some(count),
         }
-    });
-    let write_task = tokio::spawn({
-        let mut socket_tx = socket_tx;
-        let shutdown = shutdown.clone();
-        let tx = tx.clone();
-
-        async move {
-            loop {
-                if shutdown.load(Ordering::SeqCst) {
-                    break;
-                }
-
-                let to_send: Vec<_> = tx
-                    .lock()
-                    .map(|mut ms| ms.drain(..).collect())
-                    .unwrap_or_default();
-                for message in to_send {
-                    shared::protocol::write(&mut socket_tx, message).await?;
-                }
-            }
-
-            anyhow::Ok(())
+    }
+    let predicted = network_state.reconcile();
+    let (local_diffs, has_new_local_diffs) = network_state.take_local_diffs();
+    if predicted.is_some() || has_new_local_diffs {
+        if let Some(predicted) = predicted {
+            *graph = predicted;
         }
-    });
 
-    Ok((read_task, write_task))
+        // Reapply our local diffs on top, as the server may not have seen them yet
+        let local_changes: Vec<_> = local_diffs
+            .iter()
+            .filter(|(node_id, _)| graph.get(*node_id).is_some())
+            .map(|(node_id, diff)| GraphChange::ApplyDiff(*node_id, diff.clone()))
+            .collect();
+        graph.apply_changes(&local_changes);
+    }
+
+    let interacting = input_mouse.get_pressed().next().is_some();
+    network_state.update_diffs(interacting);
 }
 
-fn synchronise_network_to_local(
-    mut graph: ResMut<Graph>,
-    network_state: Res<resources::NetworkState>,
+/// Marks the light that [resources::Lighting] controls.
+#[derive(Component)]
+struct KeyLight;
+
+fn update_lighting(
+    lighting: Res<resources::Lighting>,
+    mut clear_colour: ResMut<ClearColor>,
+    mut key_lights: Query<(&mut PointLight, &mut Transform), With<KeyLight>>,
 ) {
-    let changes = &mut network_state.rx.lock().unwrap();
-    if !changes.is_empty() {
-        graph.apply_changes(changes);
-        changes.clear();
+    // The light is spawned by a startup system, so it's also caught on the first frame
+    if !lighting.is_changed() {
+        return;
+    }
+    let [r, g, b] = lighting.background;
+    clear_colour.0 = Color::rgb(r, g, b);
+
+    let direction = lighting
+        .key_light_direction
+        .try_normalize()
+        .unwrap_or(glam::Vec3::Y);
+    let position = Vec3::from(direction.to_array()) * resources::Lighting::KEY_LIGHT_DISTANCE;
+    for (mut light, mut transform) in key_lights.iter_mut() {
+        light.intensity = lighting.key_light_intensity;
+        transform.translation = position;
     }
 }
 
 fn setup(mut commands: Commands) {
-    commands.spawn_bundle(PointLightBundle {
-        point_light: PointLight {
-            intensity: 1500.0,
-            shadows_enabled: true,
+    // The light is positioned by `update_lighting`
+    commands
+        .spawn_bundle(PointLightBundle {
+            point_light: PointLight {
+                shadows_enabled: true,
+                ..default()
+            },
             ..default()
-        },
-        transform: Transform::from_xyz(4.0, 8.0, 4.0),
-        ..default()
-    });
+        })
+        .insert(KeyLight);
     let eye = Vec3::new(-2.0, 5.0, 5.0);
     let target = Vec3::new(0., 0., 0.);
     let transform = Transform::from_translation(eye).looking_at(target, Vec3::Y);
@@ -198,6 +208,7 @@ fn setup(mut commands: Commands) {
         })
         .insert(camera::PanOrbitCamera {
             radius: eye.distance(target),
+            target_radius: eye.distance(target),
             ..Default::default()
         });
 }
@@ -1,68 +1,265 @@
-use bevy::prelude::*;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    Arc, Mutex,
+};
 
-use crate::resources::{MeshGenerationResult, RenderParameters};
+use bevy::{
+    prelude::*,
+    render::camera::Projection,
+    tasks::AsyncComputeTaskPool,
+    utils::{Duration, Instant},
+    window::RequestRedraw,
+};
+use shared::mesh::CompilationOutput;
+
+use crate::{
+    animation::AnimatedGraph,
+    camera::PanOrbitCamera,
+    resources::{
+        CurrentFile, MeshGenerationProgress, MeshGenerationResult, RenderParameters, WireframeMode,
+    },
+};
 
 struct CurrentEntity(Option<Entity>);
 
+/// The most recently started mesh generation, which runs in the background so that large
+/// meshes don't stall the UI. Replacing or clearing it cancels the generation.
+#[derive(Default)]
+struct PendingGeneration(Option<Pending>);
+
+struct Pending {
+    /// The fraction of the work that's done, stored as the bits of an `f32`.
+    progress: Arc<AtomicU32>,
+    output: Arc<Mutex<Option<Output>>>,
+    cancel: Arc<AtomicBool>,
+}
+
+struct Output {
+    result: shared::mesh::Result<CompilationOutput>,
+    generation_time: Duration,
+}
+impl Drop for Pending {
+    fn drop(&mut self) {
+        // Nothing will read the output any more, so stop working on it
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
 pub struct MeshGenerationPlugin;
 impl Plugin for MeshGenerationPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(CurrentEntity(None))
-            .add_system(keep_rebuilding_mesh);
+            .init_resource::<PendingGeneration>()
+            .init_resource::<MeshGenerationProgress>()
+            .add_system(start_mesh_generation)
+            .add_system(finish_mesh_generation);
     }
 }
 
-fn keep_rebuilding_mesh(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    mut current_entity: ResMut<CurrentEntity>,
+fn start_mesh_generation(
+    current_entity: Res<CurrentEntity>,
+    mut pending: ResMut<PendingGeneration>,
     mut mesh_generation_result: ResMut<MeshGenerationResult>,
+    mut mesh_query: Query<&mut Visibility>,
     render_parameters: Res<RenderParameters>,
     graph: Res<shared::Graph>,
+    animated_graph: Res<AnimatedGraph>,
 ) {
-    if !(render_parameters.is_changed() || graph.is_added() || graph.is_changed()) {
+    if !(render_parameters.is_changed()
+        || graph.is_added()
+        || graph.is_changed()
+        || animated_graph.is_changed())
+    {
         return;
     }
-
-    if let Some(entity) = current_entity.0.take() {
-        commands.entity(entity).despawn();
+    if render_parameters.raymarch {
+        // The raymarcher draws the model instead, so don't spend time building a mesh
+        pending.0 = None;
+        *mesh_generation_result = MeshGenerationResult::Unbuilt;
+        if let Some(mut visibility) = current_entity.0.and_then(|e| mesh_query.get_mut(e).ok()) {
+            visibility.is_visible = false;
+        }
+        return;
     }
+    let graph = animated_graph.0.as_ref().unwrap_or(&*graph).clone();
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let options = shared::mesh::CompilationOptions {
+        colours_enabled: render_parameters.colours,
+        isolate_colours: render_parameters.isolate_colours,
+        clipping_plane: render_parameters.clipping_plane.clone(),
+        solo: render_parameters.solo,
+        recompute_normals: render_parameters.recompute_normals,
+        cancel: Some(cancel.clone()),
+        triangle_budget: render_parameters.triangle_budget,
+        infinite_bounds_half_size: render_parameters.infinite_bounds_half_size,
+    };
+    let generation = Pending {
+        progress: Arc::new(AtomicU32::new(0.0f32.to_bits())),
+        output: Arc::new(Mutex::new(None)),
+        cancel,
+    };
+    let (progress, output) = (generation.progress.clone(), generation.output.clone());
+    let bounds_preview = render_parameters.bounds_preview;
+    AsyncComputeTaskPool::get()
+        .spawn(async move {
+            let start = Instant::now();
+            let result = if bounds_preview {
+                shared::mesh::generate_bounds_mesh(&graph, &options)
+            } else {
+                shared::mesh::generate_mesh_with_progress(&graph, &options, &mut |fraction| {
+                    progress.store(fraction.to_bits(), Ordering::Relaxed)
+                })
+            };
+            *output.lock().unwrap() = Some(Output {
+                result,
+                generation_time: start.elapsed(),
+            });
+        })
+        .detach();
+    pending.0 = Some(generation);
+}
+
+fn finish_mesh_generation(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut current_entity: ResMut<CurrentEntity>,
+    mut pending: ResMut<PendingGeneration>,
+    mut progress: ResMut<MeshGenerationProgress>,
+    mut mesh_generation_result: ResMut<MeshGenerationResult>,
+    mut mesh_query: Query<(&Handle<Mesh>, &Handle<StandardMaterial>, &mut Visibility)>,
+    mut camera_query: Query<(&mut PanOrbitCamera, &Projection)>,
+    mut current_file: ResMut<CurrentFile>,
+    mut redraw: EventWriter<RequestRedraw>,
+    render_parameters: Res<RenderParameters>,
+) {
+    let generation = match &pending.0 {
+        Some(generation) => generation,
+        None => {
+            if progress.0.is_some() {
+                progress.0 = None;
+            }
+            return;
+        }
+    };
+    let output = generation.output.lock().unwrap().take();
+    let output = match output {
+        Some(output) => output,
+        None => {
+            progress.0 = Some(f32::from_bits(generation.progress.load(Ordering::Relaxed)));
+            // Frames are only drawn on input, so keep them coming until the mesh is done
+            redraw.send(RequestRedraw);
+            return;
+        }
+    };
+    pending.0 = None;
+    progress.0 = None;
 
-    let mesh = match shared::mesh::generate_mesh(&graph, render_parameters.colours) {
+    let (mesh, material) = match output.result {
         Ok(result) => {
             *mesh_generation_result = MeshGenerationResult::Successful {
                 exo_node_count: result.exo_node_count,
                 triangle_count: result.triangle_count,
                 volume: result.volume,
+                center_of_mass: result.center_of_mass,
+                mass_properties_approximate: result.mass_properties_approximate,
+                generation_time: output.generation_time,
             };
-            result.mesh
+            if !current_file.view_fitted {
+                fit_view(&result.mesh, &mut camera_query);
+                current_file.view_fitted = true;
+            }
+            (result.mesh, result.material)
         }
         Err(err) => {
             *mesh_generation_result = MeshGenerationResult::Failure(err);
+            // Hide the existing mesh instead of despawning it, so that it can be reused
+            // once the graph is valid again
+            if let Some((_, _, mut visibility)) =
+                current_entity.0.and_then(|e| mesh_query.get_mut(e).ok())
+            {
+                visibility.is_visible = false;
+            }
             return;
         }
     };
-    let mesh = convert_to_bevy_mesh(mesh);
+    let mesh = convert_to_bevy_mesh(mesh, &render_parameters);
+    let material = convert_to_bevy_material(material, render_parameters.wireframe);
 
-    let mut spawn_bundle = commands.spawn_bundle(PbrBundle {
-        mesh: meshes.add(mesh),
-        material: materials.add(Color::WHITE.into()),
-        transform: Transform::from_xyz(0.0, 0.0, 0.0),
-        ..default()
-    });
-    if render_parameters.wireframe {
-        spawn_bundle.insert(bevy::pbr::wireframe::Wireframe);
+    // Only spawn an entity on the first build; after that, update its assets in place
+    let entity = match current_entity.0.and_then(|e| mesh_query.get_mut(e).ok()) {
+        Some((mesh_handle, material_handle, mut visibility)) => {
+            if let Some(existing_mesh) = meshes.get_mut(mesh_handle) {
+                *existing_mesh = mesh;
+            }
+            if let Some(existing_material) = materials.get_mut(material_handle) {
+                *existing_material = material;
+            }
+            visibility.is_visible = true;
+            current_entity.0.unwrap()
+        }
+        None => commands
+            .spawn_bundle(PbrBundle {
+                mesh: meshes.add(mesh),
+                material: materials.add(material),
+                transform: Transform::from_xyz(0.0, 0.0, 0.0),
+                ..default()
+            })
+            .id(),
+    };
+    if render_parameters.wireframe != WireframeMode::Solid {
+        commands
+            .entity(entity)
+            .insert(bevy::pbr::wireframe::Wireframe);
+    } else {
+        commands
+            .entity(entity)
+            .remove::<bevy::pbr::wireframe::Wireframe>();
+    }
+    current_entity.0 = Some(entity);
+}
+
+/// Frames the whole mesh, so that newly-loaded models aren't off-screen or tiny.
+fn fit_view(
+    mesh: &shared::mesh::Mesh,
+    camera_query: &mut Query<(&mut PanOrbitCamera, &Projection)>,
+) {
+    let (min, max) = mesh.positions.iter().fold(
+        (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+        |(min, max), position| {
+            let position = Vec3::from_array(*position);
+            (min.min(position), max.max(position))
+        },
+    );
+    if !(min.is_finite() && max.is_finite()) {
+        return;
+    }
+    for (mut pan_orbit, projection) in camera_query.iter_mut() {
+        if let Projection::Perspective(projection) = projection {
+            pan_orbit.fit(min, max, projection.fov);
+        }
     }
-    current_entity.0 = Some(spawn_bundle.id());
 }
 
-fn convert_to_bevy_mesh(raw_mesh: shared::mesh::Mesh) -> Mesh {
+fn convert_to_bevy_mesh(
+    raw_mesh: shared::mesh::Mesh,
+    render_parameters: &RenderParameters,
+) -> Mesh {
     use bevy::render::mesh as brm;
 
-    let uvs = std::iter::repeat([0.0, 0.0])
-        .take(raw_mesh.positions.len())
-        .collect::<Vec<_>>();
+    let raw_mesh = if render_parameters.flat_shading {
+        raw_mesh.flat_shaded()
+    } else {
+        raw_mesh
+    };
+    let uvs = if render_parameters.uvs {
+        raw_mesh.planar_uvs()
+    } else {
+        std::iter::repeat([0.0, 0.0])
+            .take(raw_mesh.positions.len())
+            .collect::<Vec<_>>()
+    };
     let colours = raw_mesh
         .colors
         .into_iter()
@@ -77,3 +274,31 @@ fn convert_to_bevy_mesh(raw_mesh: shared::mesh::Mesh) -> Mesh {
     mesh.set_indices(Some(brm::Indices::U32(raw_mesh.indices)));
     mesh
 }
+
+/// As the mesh only has one material, the opacity of the dominant material applies to the
+/// whole mesh. Per-node opacity would need a material per colour region.
+///
+/// Wireframes are drawn on top of the surface, so to show only the wireframe, the surface
+/// is made fully transparent.
+fn convert_to_bevy_material(
+    material: shared::Material,
+    wireframe: WireframeMode,
+) -> StandardMaterial {
+    let (r, g, b) = material.emissive;
+    let opacity = match wireframe {
+        WireframeMode::Wireframe => 0.0,
+        WireframeMode::Solid | WireframeMode::Both => material.opacity,
+    };
+    StandardMaterial {
+        base_color: Color::rgba(1.0, 1.0, 1.0, opacity),
+        emissive: Color::rgb(r, g, b),
+        metallic: material.metallic,
+        perceptual_roughness: material.roughness,
+        alpha_mode: if opacity < 1.0 {
+            AlphaMode::Blend
+        } else {
+            AlphaMode::Opaque
+        },
+        ..default()
+    }
+}
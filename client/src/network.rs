@@ -0,0 +1,104 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+use shared::protocol::{PeerIncomingMessage, PeerOutgoingMessage};
+use tokio::net::TcpStream;
+
+use crate::resources::ConnectionStatus;
+
+/// Connects to the server and shuttles messages between it and the queues until either
+/// side stops, keeping `status` up to date along the way.
+pub async fn run_connection(
+    host: String,
+    port: u16,
+    rx: Arc<Mutex<Vec<PeerIncomingMessage>>>,
+    tx: Arc<Mutex<Vec<PeerOutgoingMessage>>>,
+    shutdown: Arc<AtomicBool>,
+    status: Arc<Mutex<ConnectionStatus>>,
+) {
+    let (read_task, write_task) =
+        match create_network_tasks(&host, port, rx, tx, shutdown.clone()).await {
+            Ok(tasks) => tasks,
+            Err(err) => {
+                *status.lock().unwrap() = ConnectionStatus::Disconnected(Some(err.to_string()));
+                return;
+            }
+        };
+    *status.lock().unwrap() = ConnectionStatus::Connected;
+
+    let result = tokio::select! {
+        result = read_task => result,
+        result = write_task => result,
+    };
+    // Stop whichever task is still going
+    shutdown.store(true, Ordering::SeqCst);
+    let error = match result {
+        Ok(Ok(())) => "the server closed the connection".to_string(),
+        Ok(Err(err)) => err.to_string(),
+        Err(err) => err.to_string(),
+    };
+    *status.lock().unwrap() = ConnectionStatus::Disconnected(Some(error));
+}
+
+async fn create_network_tasks(
+    host: &str,
+    port: u16,
+    rx: Arc<Mutex<Vec<PeerIncomingMessage>>>,
+    tx: Arc<Mutex<Vec<PeerOutgoingMessage>>>,
+    shutdown: Arc<AtomicBool>,
+) -> anyhow::Result<(
+    tokio::task::JoinHandle<anyhow::Result<()>>,
+    tokio::task::JoinHandle<anyhow::Result<()>>,
+)> {
+    let (socket_rx, socket_tx) = TcpStream::connect((host, port)).await?.into_split();
+
+    let read_task = tokio::spawn({
+        let mut socket_rx = socket_rx;
+        let shutdown = shutdown.clone();
+        let rx = rx.clone();
+
+        async move {
+            loop {
+                if shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let message = match shared::protocol::read(&mut socket_rx).await {
+                    Some(Ok(message)) => message,
+                    Some(Err(err)) => return Err(err),
+                    None => break,
+                };
+                rx.lock().unwrap().push(message);
+            }
+
+            anyhow::Ok(())
+        }
+    });
+    let write_task = tokio::spawn({
+        let mut socket_tx = socket_tx;
+        let shutdown = shutdown.clone();
+        let tx = tx.clone();
+
+        async move {
+            loop {
+                if shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let to_send: Vec<_> = tx
+                    .lock()
+                    .map(|mut ms| ms.drain(..).collect())
+                    .unwrap_or_default();
+                for message in to_send {
+                    shared::protocol::write(&mut socket_tx, message).await?;
+                }
+            }
+
+            anyhow::Ok(())
+        }
+    });
+
+    Ok((read_task, write_task))
+}
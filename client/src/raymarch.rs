@@ -0,0 +1,195 @@
+//! Renders the graph by raymarching its distance function on the GPU instead of building
+//! a mesh, so that edits show up without waiting for the mesher.
+//!
+//! The distance function is the one from the WGSL export, with every value baked in as a
+//! constant, so the shader is regenerated and recompiled whenever the graph changes. That
+//! recompile is the main cost of an edit, and dragging a value causes one per frame. Every
+//! pixel covered by the model's bounds evaluates the whole function up to [MAX_STEPS]
+//! times, so the cost of drawing grows with the number of nodes: a few dozen nodes are
+//! fine, but deep trees with hundreds of nodes will be noticeably slower than the mesh.
+//! Colours and materials aren't shown, as the export doesn't include them.
+use bevy::{
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        mesh::{Indices, PrimitiveTopology},
+        render_resource::{AsBindGroup, ShaderRef},
+    },
+};
+
+use crate::{animation::AnimatedGraph, resources::RenderParameters};
+
+const MAX_STEPS: u32 = 128;
+/// The size of the box drawn when the model's bounds can't be found, like when it
+/// contains a plane.
+const FALLBACK_HALF_SIZE: f32 = 100.0;
+const RAYMARCH_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 0x5d1f_43e6_8a27_9c0b);
+
+pub struct RaymarchPlugin;
+impl Plugin for RaymarchPlugin {
+    fn build(&self, app: &mut App) {
+        app.world
+            .resource_mut::<Assets<Shader>>()
+            .set_untracked(RAYMARCH_SHADER_HANDLE, raymarch_shader(None));
+        app.add_plugin(MaterialPlugin::<RaymarchMaterial>::default())
+            .insert_resource(CurrentEntity(None))
+            .add_system(keep_updating_raymarch);
+    }
+}
+
+#[derive(AsBindGroup, TypeUuid, Clone)]
+#[uuid = "0b8c3d5e-2f7a-4e91-b6c4-7d2a9e1f5c38"]
+pub struct RaymarchMaterial {
+    #[uniform(0)]
+    colour: Color,
+}
+impl Material for RaymarchMaterial {
+    fn fragment_shader() -> ShaderRef {
+        RAYMARCH_SHADER_HANDLE.typed().into()
+    }
+}
+
+struct CurrentEntity(Option<Entity>);
+
+fn keep_updating_raymarch(
+    mut commands: Commands,
+    mut shaders: ResMut<Assets<Shader>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<RaymarchMaterial>>,
+    mut current_entity: ResMut<CurrentEntity>,
+    render_parameters: Res<RenderParameters>,
+    graph: Res<shared::Graph>,
+    animated_graph: Res<AnimatedGraph>,
+) {
+    if !(render_parameters.is_changed() || graph.is_changed() || animated_graph.is_changed()) {
+        return;
+    }
+    if let Some(entity) = current_entity.0.take() {
+        commands.entity(entity).despawn();
+    }
+    if !render_parameters.raymarch {
+        return;
+    }
+
+    let graph = animated_graph.0.as_ref().unwrap_or(&*graph);
+    if graph.root_node_id().is_none() {
+        return;
+    }
+    shaders.set_untracked(RAYMARCH_SHADER_HANDLE, raymarch_shader(Some(graph)));
+
+    // Rays start at the camera, so the box only limits which pixels are marched
+    let (min, max) = graph
+        .bounds()
+        .map(|(min, max)| {
+            let padding = glam::Vec3::splat(0.01);
+            (min - padding, max + padding)
+        })
+        .unwrap_or((
+            glam::Vec3::splat(-FALLBACK_HALF_SIZE),
+            glam::Vec3::splat(FALLBACK_HALF_SIZE),
+        ));
+    let entity = commands
+        .spawn_bundle(MaterialMeshBundle {
+            mesh: meshes.add(double_sided_box(min.to_array(), max.to_array())),
+            material: materials.add(RaymarchMaterial {
+                colour: Color::WHITE,
+            }),
+            ..default()
+        })
+        .id();
+    current_entity.0 = Some(entity);
+}
+
+fn raymarch_shader(graph: Option<&shared::Graph>) -> Shader {
+    let map = match graph {
+        Some(graph) => shared::export::wgsl::graph_to_wgsl(graph),
+        None => "fn map(p: vec3<f32>) -> f32 {\n    return 1e10;\n}\n".to_string(),
+    };
+    Shader::from_wgsl(format!(
+        "#import bevy_pbr::mesh_view_bindings
+
+struct RaymarchMaterial {{
+    colour: vec4<f32>,
+}};
+@group(1) @binding(0)
+var<uniform> material: RaymarchMaterial;
+
+{map}
+fn normal_at(p: vec3<f32>) -> vec3<f32> {{
+    let e = vec2<f32>(0.001, 0.0);
+    return normalize(vec3<f32>(
+        map(p + e.xyy) - map(p - e.xyy),
+        map(p + e.yxy) - map(p - e.yxy),
+        map(p + e.yyx) - map(p - e.yyx),
+    ));
+}}
+
+@fragment
+fn fragment(
+    #import bevy_pbr::mesh_vertex_output
+) -> @location(0) vec4<f32> {{
+    let origin = view.world_position;
+    let direction = normalize(world_position.xyz - origin);
+    var t = 0.0;
+    var hit = false;
+    for (var i = 0u; i < {MAX_STEPS}u; i = i + 1u) {{
+        let distance = map(origin + direction * t);
+        if (distance < 0.0005 * t) {{
+            hit = true;
+            break;
+        }}
+        t = t + distance;
+        if (t > 1000.0) {{
+            break;
+        }}
+    }}
+    if (!hit) {{
+        discard;
+    }}
+    let light = max(dot(normal_at(origin + direction * t), normalize(vec3<f32>(4.0, 8.0, 4.0))), 0.0);
+    return vec4<f32>(material.colour.rgb * (0.2 + 0.8 * light), 1.0);
+}}
+"
+    ))
+}
+
+/// A box with both its inside and outside faces, so that it's still drawn when the
+/// camera is inside it.
+fn double_sided_box(min: [f32; 3], max: [f32; 3]) -> Mesh {
+    // Corner `i` takes its x, y and z from `max` if bits 0, 1 and 2 of `i` are set
+    let positions: Vec<[f32; 3]> = (0..8)
+        .map(|i| {
+            let pick = |axis: usize| {
+                if i & (1 << axis) != 0 {
+                    max[axis]
+                } else {
+                    min[axis]
+                }
+            };
+            [pick(0), pick(1), pick(2)]
+        })
+        .collect();
+    // Each face as a quad, in either winding
+    let faces: [[u32; 4]; 6] = [
+        [0, 2, 3, 1],
+        [4, 5, 7, 6],
+        [0, 1, 5, 4],
+        [2, 6, 7, 3],
+        [0, 4, 6, 2],
+        [1, 3, 7, 5],
+    ];
+    let indices = faces
+        .iter()
+        .flat_map(|[a, b, c, d]| [a, b, c, a, c, d, a, c, b, a, d, c])
+        .copied()
+        .collect();
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    // The mesh pipeline expects normals and UVs, even though the shader ignores them
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 1.0, 0.0]; 8]);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0.0, 0.0]; 8]);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
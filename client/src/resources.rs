@@ -1,12 +1,54 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Mutex,
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use bevy::input::mouse::MouseButton;
+use shared::{
+    protocol::CommandId, Graph, GraphChange, GraphCommand, NodeData, NodeDiff, NodeId, Transform,
 };
 
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq)]
 pub struct RenderParameters {
-    pub wireframe: bool,
+    pub wireframe: WireframeMode,
     pub colours: bool,
+    /// Colours each primitive distinctly, to show which surfaces it contributes.
+    pub isolate_colours: bool,
+    pub uvs: bool,
+    pub flat_shading: bool,
+    pub recompute_normals: bool,
+    pub clipping_plane: Option<shared::Plane>,
+    /// Only this node's subtree is built, if set.
+    pub solo: Option<shared::NodeId>,
+    /// Draws the model by raymarching its distance function instead of building a mesh.
+    /// Exports are still meshed.
+    pub raymarch: bool,
+    /// Draws a box around each primitive instead of meshing the model, for quick layout.
+    pub bounds_preview: bool,
+    /// Meshes with more triangles than this aren't shown, as they'd slow down the editor.
+    /// Exports aren't limited.
+    pub triangle_budget: Option<usize>,
+    /// How far from the origin models with infinite bounds, like ones with planes, are
+    /// cut off. `None` leaves them unbuilt.
+    pub infinite_bounds_half_size: Option<f32>,
+}
+impl RenderParameters {
+    pub const DEFAULT_TRIANGLE_BUDGET: usize = 2_000_000;
+    pub const DEFAULT_INFINITE_BOUNDS_HALF_SIZE: f32 = 10.0;
+}
+
+/// How the mesh's surface and edges are drawn.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum WireframeMode {
+    #[default]
+    Solid,
+    /// Only the edges, with the surface hidden.
+    Wireframe,
+    /// The edges drawn over the shaded surface.
+    Both,
 }
 
 pub enum MeshGenerationResult {
@@ -16,9 +58,124 @@ pub enum MeshGenerationResult {
         exo_node_count: usize,
         triangle_count: usize,
         volume: f32,
+        center_of_mass: glam::Vec3,
+        mass_properties_approximate: bool,
+        /// How long the mesh took to build, not counting the wait to start building it.
+        generation_time: Duration,
     },
 }
 
+/// How much of the mesh being generated in the background is done, from 0 to 1, if one
+/// is being generated.
+#[derive(Default)]
+pub struct MeshGenerationProgress(pub Option<f32>);
+
+#[derive(Default)]
+pub struct CurrentFile {
+    pub path: Option<std::path::PathBuf>,
+    pub error: Option<String>,
+    /// Whether the camera has been fitted to the model since it was loaded. This starts
+    /// out unset, so that the model from the server is fitted when joining a room.
+    pub view_fitted: bool,
+}
+
+pub struct ExportSettings {
+    /// The fraction of the triangles to keep when exporting a mesh.
+    pub triangle_ratio: f32,
+}
+impl Default for ExportSettings {
+    fn default() -> Self {
+        Self {
+            triangle_ratio: 1.0,
+        }
+    }
+}
+
+/// The increments that edited transforms are rounded to. `None` allows free editing.
+#[derive(Default)]
+pub struct SnapSettings {
+    pub translation: Option<f32>,
+    /// In degrees, as rotations are displayed.
+    pub rotation: Option<f32>,
+}
+
+/// The parameters copied from a node, to be pasted onto others. They're kept here rather
+/// than on the clipboard, so that they keep their types.
+#[derive(Default)]
+pub struct CopiedParameters(pub Option<NodeParameters>);
+
+#[derive(Clone)]
+pub struct NodeParameters {
+    pub data: NodeData,
+    pub transform: Transform,
+    pub rgb: (f32, f32, f32),
+}
+
+/// The mouse buttons that move the camera while held.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct CameraBindings {
+    pub orbit: MouseButton,
+    pub pan: MouseButton,
+    pub zoom: MouseButton,
+}
+impl Default for CameraBindings {
+    fn default() -> Self {
+        Self {
+            orbit: MouseButton::Left,
+            pan: MouseButton::Middle,
+            zoom: MouseButton::Right,
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum GizmoMode {
+    #[default]
+    Translate,
+    Rotate,
+    Scale,
+}
+
+#[derive(Default)]
+pub struct GizmoState {
+    pub mode: GizmoMode,
+    /// Set while a gizmo handle is being dragged, so that the camera ignores the mouse.
+    pub dragging: bool,
+}
+
+/// How the scene around the model is presented. Kept apart from [RenderParameters], as
+/// changing these shouldn't rebuild the mesh.
+pub struct Lighting {
+    pub background: [f32; 3],
+    pub key_light_intensity: f32,
+    /// Points from the model towards the key light. Doesn't need to be normalised.
+    pub key_light_direction: glam::Vec3,
+}
+impl Lighting {
+    /// How far the key light is placed from the origin.
+    pub const KEY_LIGHT_DISTANCE: f32 = 9.8;
+}
+impl Default for Lighting {
+    fn default() -> Self {
+        Self {
+            // Bevy's default clear colour
+            background: [0.4, 0.4, 0.4],
+            key_light_intensity: 1500.0,
+            key_light_direction: glam::Vec3::new(4.0, 8.0, 4.0),
+        }
+    }
+}
+
+/// Extra things drawn over the scene. Kept apart from [RenderParameters], as changing
+/// these shouldn't rebuild the mesh.
+#[derive(Default)]
+pub struct ViewportOverlays {
+    /// Draws a box around the bounds of each selected node.
+    pub bounds: bool,
+    /// Shows the frame rate and how long the last mesh took to build.
+    pub performance: bool,
+}
+
 #[derive(Default)]
 pub struct OccupiedScreenSpace {
     pub left: f32,
@@ -27,25 +184,293 @@ pub struct OccupiedScreenSpace {
     pub bottom: f32,
 }
 
+/// Where the connection to the server is at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connecting,
+    Connected,
+    /// With the reason, unless we disconnected ourselves.
+    Disconnected(Option<String>),
+}
+
+/// Talks to the server on the graph's behalf.
+///
+/// Commands other than diffs are applied locally as soon as they're made, rather than
+/// waiting for the server to send back their changes. The graph as the server last told
+/// us is kept apart from the one shown, which is always that graph with our pending
+/// commands replayed on top. The server answers each command in order, either accepting
+/// it along with its changes, or rejecting it; either way, it's no longer pending, and
+/// the shown graph is rebuilt. This resolves conflicts as follows:
+/// - The server's order wins. Changes from other peers are applied before our pending
+///   commands are replayed, as that's the order the server will see them in.
+/// - A pending command that no longer applies, say because another peer deleted its
+///   node, is skipped locally, and will be rejected by the server.
+/// - A rejected command is rolled back by dropping it and rebuilding the shown graph.
+/// - Nodes created by a pending command may not have the IDs that the server will give
+///   them, and are replaced by the server's once the command is accepted.
+///
+/// Diffs are handled separately, as they're sent in bulk while dragging: see
+/// [Self::take_local_diffs].
 pub struct NetworkState {
+    /// Runs the connection's tasks, as the systems that connect aren't run by tokio.
+    runtime: tokio::runtime::Handle,
+    /// Stops the current connection. Each connection gets its own, along with its own
+    /// queues and status, so that a connection on its way out can't touch the next one.
     shutdown: Arc<AtomicBool>,
+    status: Arc<Mutex<ConnectionStatus>>,
     pub tx: Arc<Mutex<Vec<shared::protocol::PeerOutgoingMessage>>>,
-    pub rx: Arc<Mutex<Vec<shared::GraphChange>>>,
+    pub rx: Arc<Mutex<Vec<shared::protocol::PeerIncomingMessage>>>,
+    /// Diffs that have been made locally, but haven't been sent to the server yet.
+    unsent_diffs: Vec<(NodeId, NodeDiff)>,
+    /// Diffs made since the last synchronisation that need to be applied to the local graph.
+    new_local_diffs: Vec<(NodeId, NodeDiff)>,
+    /// All diffs made during the current interaction. These are reapplied on top of any
+    /// incoming changes, so that the server echoing older values doesn't undo them.
+    local_diffs: Vec<(NodeId, NodeDiff)>,
+    last_diff_flush: Instant,
+    /// The graph as the server last told us, without any of our pending commands.
+    confirmed: Graph,
+    /// Commands that have been sent, but not yet accepted or rejected, in the order they
+    /// were sent.
+    pending: Vec<(CommandId, GraphCommand)>,
+    /// Set when `confirmed` or `pending` change, so that the shown graph is rebuilt.
+    stale: bool,
+    next_command_id: u64,
+    /// Whether we joined as a spectator, in which case nothing we do is sent.
+    spectator: bool,
+    /// The server last connected to, or to connect to by default.
+    pub host: String,
+    pub port: u16,
+    /// How many peers are in the room, including us, once the server has told us.
+    pub peer_count: Option<usize>,
+    /// Why the server rejected our last rejected command, until it's dismissed.
+    pub rejection: Option<shared::GraphCommandError>,
+    /// Set once the server closes the room, after which our changes go nowhere.
+    pub room_closed: bool,
+    /// The name of the room we're in, which follows any renames.
+    pub room: String,
+    /// Why the server rejected our last rename, until it's dismissed.
+    pub rename_rejection: Option<String>,
 }
 impl NetworkState {
-    pub fn new(
-        shutdown: Arc<AtomicBool>,
-        tx: Arc<Mutex<Vec<shared::protocol::PeerOutgoingMessage>>>,
-        rx: Arc<Mutex<Vec<shared::GraphChange>>>,
-    ) -> Self {
-        Self { shutdown, tx, rx }
+    /// While the user is interacting, diffs are sent no more often than this.
+    const DIFF_SEND_INTERVAL: Duration = Duration::from_millis(50);
+
+    /// Starts out disconnected; see [Self::connect].
+    pub fn new(runtime: tokio::runtime::Handle, spectator: bool) -> Self {
+        Self {
+            runtime,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            status: Arc::new(Mutex::new(ConnectionStatus::Disconnected(None))),
+            tx: Arc::new(Mutex::new(vec![])),
+            rx: Arc::new(Mutex::new(vec![])),
+            unsent_diffs: vec![],
+            new_local_diffs: vec![],
+            local_diffs: vec![],
+            last_diff_flush: Instant::now(),
+            confirmed: Graph::new_client(),
+            pending: vec![],
+            stale: false,
+            next_command_id: 0,
+            spectator,
+            host: "localhost".to_string(),
+            port: shared::DEFAULT_PORT,
+            peer_count: None,
+            rejection: None,
+            room_closed: false,
+            room: String::new(),
+            rename_rejection: None,
+        }
+    }
+
+    /// Leaves the current room, if any, and joins `room` on the given server. The server
+    /// sends the room's graph once we've joined, which replaces the local one, unless
+    /// we're rejoining and the graph we last heard of is still up to date.
+    pub fn connect(&mut self, host: String, port: u16, room: String, spectator: bool) {
+        self.disconnect();
+        // These were meant for the old room, which won't answer them now
+        self.pending.clear();
+        self.stale = true;
+
+        let rejoining =
+            !self.room.is_empty() && (&host, port, &room) == (&self.host, self.port, &self.room);
+        self.shutdown = Arc::new(AtomicBool::new(false));
+        self.status = Arc::new(Mutex::new(ConnectionStatus::Connecting));
+        self.rx = Arc::new(Mutex::new(vec![]));
+        self.tx = Arc::new(Mutex::new(vec![shared::protocol::RequestJoin {
+            room: room.clone(),
+            graph_hash: rejoining.then(|| self.confirmed.content_hash()),
+            spectator,
+        }
+        .into()]));
+        self.runtime.spawn(crate::network::run_connection(
+            host.clone(),
+            port,
+            self.rx.clone(),
+            self.tx.clone(),
+            self.shutdown.clone(),
+            self.status.clone(),
+        ));
+
+        self.host = host;
+        self.port = port;
+        self.room = room;
+        self.spectator = spectator;
+    }
+
+    /// Drops the connection. The local graph is kept, along with any commands the server
+    /// didn't answer, but nothing more is sent or received.
+    pub fn disconnect(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.status = Arc::new(Mutex::new(ConnectionStatus::Disconnected(None)));
+        self.rx = Arc::new(Mutex::new(vec![]));
+        self.tx = Arc::new(Mutex::new(vec![]));
+        // Anything unsent belonged to the old room
+        self.unsent_diffs.clear();
+        self.new_local_diffs.clear();
+        self.local_diffs.clear();
+        self.peer_count = None;
+        self.rejection = None;
+        self.room_closed = false;
+        self.rename_rejection = None;
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub fn is_spectator(&self) -> bool {
+        self.spectator
+    }
+
+    /// Queues commands to be sent to the server. Diffs are applied locally straight away,
+    /// and are coalesced so that dragging a value doesn't send an update every frame.
+    /// Spectators can't edit, so their commands are discarded.
+    pub fn send(&mut self, commands: &[GraphCommand]) {
+        // The server would drop these anyway, but this stops them from being applied locally
+        if self.spectator {
+            return;
+        }
+        for command in commands {
+            match command {
+                GraphCommand::ApplyDiff(node_id, diff) => {
+                    merge_diff_into(&mut self.unsent_diffs, *node_id, diff.clone());
+                    self.new_local_diffs.push((*node_id, diff.clone()));
+                }
+                command => {
+                    // Flush first to ensure that the server sees the commands in order
+                    self.flush_diffs();
+                    let id = self.send_command(command.clone());
+                    self.pending.push((id, command.clone()));
+                    self.stale = true;
+                }
+            }
+        }
+    }
+
+    /// Asks the server for the whole graph again, in case we've fallen out of sync.
+    pub fn request_resync(&mut self) {
+        self.flush_diffs();
+        self.tx
+            .lock()
+            .unwrap()
+            .push(shared::protocol::PeerOutgoingMessage::RequestResync);
+    }
+
+    /// Asks the server to rename the room. The name only changes once the server agrees.
+    pub fn rename_room(&mut self, name: String) {
+        if self.spectator {
+            return;
+        }
+        self.rename_rejection = None;
+        self.tx
+            .lock()
+            .unwrap()
+            .push(shared::protocol::PeerOutgoingMessage::RenameRoom(name));
+    }
+
+    /// Applies changes from the server. These must be passed on in the order they arrived
+    /// in, along with any answers to our commands.
+    pub fn apply_changes(&mut self, changes: &[GraphChange]) {
+        self.confirmed.apply_changes(changes);
+        self.stale = true;
+    }
+
+    /// Applies an accepted command's changes, and stops predicting it.
+    pub fn accept(&mut self, id: CommandId, changes: Vec<GraphChange>) {
+        self.apply_changes(&changes);
+        self.pending.retain(|(pending_id, _)| *pending_id != id);
+    }
+
+    /// Stops predicting a rejected command, which rolls it back.
+    pub fn reject(&mut self, id: CommandId, err: shared::GraphCommandError) {
+        self.pending.retain(|(pending_id, _)| *pending_id != id);
+        self.stale = true;
+        self.rejection = Some(err);
     }
 
-    pub fn send(&mut self, commands: &[shared::GraphCommand]) {
+    /// If anything has changed since the last call, returns the graph to show: the
+    /// server's, with our pending commands replayed on top.
+    pub fn reconcile(&mut self) -> Option<Graph> {
+        if !self.stale {
+            return None;
+        }
+        self.stale = false;
+        let commands: Vec<_> = self
+            .pending
+            .iter()
+            .map(|(_, command)| command.clone())
+            .collect();
+        Some(self.confirmed.predict(&commands))
+    }
+
+    /// Sends any unsent diffs. While the user is interacting, this is rate-limited; once
+    /// they stop, everything is sent, so the final value always makes it to the server.
+    pub fn update_diffs(&mut self, interacting: bool) {
+        if !interacting {
+            self.flush_diffs();
+            self.local_diffs.clear();
+        } else if self.last_diff_flush.elapsed() >= Self::DIFF_SEND_INTERVAL {
+            self.flush_diffs();
+        }
+    }
+
+    /// Returns the diffs that should be applied on top of the server's state, and whether
+    /// any of them are new.
+    pub fn take_local_diffs(&mut self) -> (&[(NodeId, NodeDiff)], bool) {
+        let has_new_diffs = !self.new_local_diffs.is_empty();
+        for (node_id, diff) in self.new_local_diffs.drain(..) {
+            merge_diff_into(&mut self.local_diffs, node_id, diff);
+        }
+        (&self.local_diffs, has_new_diffs)
+    }
+
+    fn flush_diffs(&mut self) {
+        self.last_diff_flush = Instant::now();
+        if self.unsent_diffs.is_empty() {
+            return;
+        }
+        for (node_id, diff) in std::mem::take(&mut self.unsent_diffs) {
+            self.send_command(GraphCommand::ApplyDiff(node_id, diff));
+        }
+    }
+
+    fn send_command(&mut self, command: GraphCommand) -> CommandId {
+        let id = CommandId(self.next_command_id);
+        self.next_command_id += 1;
         self.tx
             .lock()
             .unwrap()
-            .extend(commands.into_iter().map(|cmd| cmd.clone().into()));
+            .push(shared::protocol::PeerOutgoingMessage::GraphCommand(
+                id, command,
+            ));
+        id
+    }
+}
+fn merge_diff_into(diffs: &mut Vec<(NodeId, NodeDiff)>, node_id: NodeId, diff: NodeDiff) {
+    match diffs.iter_mut().find(|(id, _)| *id == node_id) {
+        Some((_, existing)) => *existing = existing.clone().merge(diff),
+        None => diffs.push((node_id, diff)),
     }
 }
 impl Drop for NetworkState {
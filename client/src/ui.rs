@@ -1,38 +1,80 @@
+use std::collections::HashMap;
+
 use bevy::{diagnostic::Diagnostics, prelude::*};
 use bevy_egui::{egui, EguiContext};
 
-use crate::resources;
-use shared::{Graph, GraphCommand, Node, NodeData, NodeDataDiff, NodeDataMeta, NodeDiff, NodeId};
+use crate::{animation, resources};
+use shared::{
+    save::Format, Graph, GraphCommand, Node, NodeData, NodeDataDiff, NodeDataMeta, NodeDiff,
+    NodeId, TransformDiff,
+};
 
+mod bounds;
+mod connection;
+mod gizmo;
+mod help;
+mod palette;
+mod performance;
 mod util;
 
+/// The selected nodes, in the order they were selected. Clicking a node selects only
+/// that node; Shift- or Ctrl-clicking adds it to or removes it from the selection.
 #[derive(Default, PartialEq)]
 enum SelectedNode {
     #[default]
     Uninitialized,
-    Initialized(Option<NodeId>),
+    Initialized(Vec<NodeId>),
 }
 impl SelectedNode {
     fn is_selected(&self, node_id: NodeId) -> bool {
+        self.ids().contains(&node_id)
+    }
+
+    fn ids(&self) -> &[NodeId] {
         match self {
-            Self::Uninitialized => false,
-            Self::Initialized(inside_node_id) => *inside_node_id == Some(node_id),
+            Self::Uninitialized => &[],
+            Self::Initialized(ids) => ids,
         }
     }
 
     fn select(&mut self, node_id: NodeId) {
-        *self = Self::Initialized(match *self {
-            Self::Initialized(Some(selected_node_id)) if selected_node_id == node_id => None,
-            _ => Some(node_id),
+        *self = Self::Initialized(if self.ids() == [node_id] {
+            vec![]
+        } else {
+            vec![node_id]
         });
     }
+
+    fn toggle(&mut self, node_id: NodeId) {
+        let mut ids = self.ids().to_vec();
+        match ids.iter().position(|id| *id == node_id) {
+            Some(index) => {
+                ids.remove(index);
+            }
+            None => ids.push(node_id),
+        }
+        *self = Self::Initialized(ids);
+    }
 }
 
 pub struct UiPlugin;
 impl Plugin for UiPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<SelectedNode>()
-            .add_system(sdf_code_editor);
+            .init_resource::<resources::CurrentFile>()
+            .init_resource::<resources::ExportSettings>()
+            .init_resource::<resources::SnapSettings>()
+            .init_resource::<resources::CopiedParameters>()
+            .init_resource::<resources::GizmoState>()
+            .init_resource::<resources::ViewportOverlays>()
+            .add_system(sdf_code_editor)
+            .add_system(bounds::selection_bounds)
+            .add_system(performance::performance_overlay.after(sdf_code_editor))
+            .add_system(gizmo::transform_gizmo.before(crate::camera::pan_orbit_camera))
+            .add_system(help::help_window)
+            .add_system(connection::connection_window)
+            .add_system(palette::persist_palette)
+            .add_system(update_window_title);
     }
 }
 
@@ -41,32 +83,59 @@ fn sdf_code_editor(
     mut selected_node: ResMut<SelectedNode>,
     mut occupied_screen_space: ResMut<resources::OccupiedScreenSpace>,
     mut network_state: ResMut<resources::NetworkState>,
-    render_parameters: ResMut<resources::RenderParameters>,
+    mut current_file: ResMut<resources::CurrentFile>,
+    mut export_settings: ResMut<resources::ExportSettings>,
+    mut snap_settings: ResMut<resources::SnapSettings>,
+    mut gizmo_state: ResMut<resources::GizmoState>,
+    mut viewport_overlays: ResMut<resources::ViewportOverlays>,
+    mut lighting: ResMut<resources::Lighting>,
+    mut animation_tracks: ResMut<animation::AnimationTracks>,
+    mut playhead: ResMut<animation::Playhead>,
+    mut render_parameters: ResMut<resources::RenderParameters>,
     graph: Res<Graph>,
-    mesh_generation_result: Res<resources::MeshGenerationResult>,
+    // Grouped to stay within the limit on a system's parameters
+    (mesh_generation_result, mesh_generation_progress, mut copied_parameters): (
+        Res<resources::MeshGenerationResult>,
+        Res<resources::MeshGenerationProgress>,
+        ResMut<resources::CopiedParameters>,
+    ),
     diagnostics: Res<Diagnostics>,
 ) {
     let ctx = egui_context.ctx_mut();
     let mut commands = vec![];
 
-    match *selected_node {
+    match &*selected_node {
         SelectedNode::Uninitialized => {
             if let Some(root_node_id) = graph.root_node_id() {
                 selected_node.select(root_node_id);
             }
         }
-        SelectedNode::Initialized(Some(selected_node_id)) => {
-            // clear the selected node if the node no longer exists in the graph
-            if graph.get(selected_node_id).is_none() {
-                *selected_node = SelectedNode::Initialized(None);
+        SelectedNode::Initialized(ids) => {
+            // remove any selected nodes that no longer exist in the graph
+            if ids.iter().any(|id| graph.get(*id).is_none()) {
+                let ids = ids.iter().copied().filter(|id| graph.get(*id).is_some());
+                *selected_node = SelectedNode::Initialized(ids.collect());
             }
         }
-        _ => {}
     }
 
     occupied_screen_space.top = egui::TopBottomPanel::top("top_panel")
         .show(ctx, |ui| {
-            egui::menu::bar(ui, |_ui| {});
+            egui::menu::bar(ui, |ui| {
+                file_menu(
+                    ui,
+                    &graph,
+                    &mut current_file,
+                    &mut export_settings,
+                    &mut commands,
+                );
+                if ui.button("Connection").clicked() {
+                    connection::toggle_connection(ui.ctx());
+                }
+                if ui.button("Help").clicked() {
+                    help::toggle_help(ui.ctx());
+                }
+            });
         })
         .response
         .rect
@@ -84,6 +153,77 @@ fn sdf_code_editor(
                     }
                 };
                 ui.label(text);
+                if let mgr::Failure(shared::mesh::CompilationError::BudgetExceeded {
+                    triangles,
+                    ..
+                }) = mesh_generation_result.as_ref()
+                {
+                    if ui
+                        .button("Build anyway")
+                        .on_hover_text("Raise the triangle budget to fit this mesh")
+                        .clicked()
+                    {
+                        render_parameters.triangle_budget = Some(*triangles);
+                    }
+                }
+                if let Some(progress) = mesh_generation_progress.0 {
+                    ui.spinner();
+                    ui.add(
+                        egui::ProgressBar::new(progress)
+                            .desired_width(100.0)
+                            .show_percentage(),
+                    );
+                }
+                ui.separator();
+                let connected = network_state.status() == resources::ConnectionStatus::Connected;
+                if ui
+                    .selectable_label(false, connection::status_text(&network_state))
+                    .on_hover_text("Open the connection window")
+                    .clicked()
+                {
+                    connection::toggle_connection(ui.ctx());
+                }
+                ui.separator();
+                if connected && network_state.is_spectator() {
+                    ui.label("Spectating: changes won't be sent");
+                    ui.separator();
+                }
+                if connected {
+                    render_room(ui, &mut network_state);
+                    ui.separator();
+                }
+                if network_state.room_closed {
+                    ui.colored_label(
+                        egui::Color32::RED,
+                        "The server closed the room: reconnect to keep editing",
+                    );
+                    ui.separator();
+                }
+                if let Some(rejection) = &network_state.rejection {
+                    ui.colored_label(
+                        egui::Color32::RED,
+                        format!("Server rejected change: {rejection}"),
+                    );
+                    if ui.small_button("✖").clicked() {
+                        network_state.rejection = None;
+                    }
+                    ui.separator();
+                }
+                if ui
+                    .button("Resync")
+                    .on_hover_text("Fetch the whole graph from the server again")
+                    .clicked()
+                {
+                    network_state.request_resync();
+                }
+                ui.separator();
+                render_timeline(
+                    ui,
+                    &graph,
+                    &selected_node,
+                    &mut animation_tracks,
+                    &mut playhead,
+                );
             });
         })
         .response
@@ -93,9 +233,28 @@ fn sdf_code_editor(
     occupied_screen_space.left = egui::SidePanel::left("left_panel")
         .default_width(400.0)
         .show(ctx, |ui| {
+            let mut tree_context = TreeContext {
+                graph: &graph,
+                selected_node: &mut selected_node,
+                error_node_id: match mesh_generation_result.as_ref() {
+                    resources::MeshGenerationResult::Failure(err) => err.node_id(),
+                    _ => None,
+                },
+                solo: render_parameters.solo,
+                snap_settings: &snap_settings,
+                export_settings: &export_settings,
+                copied_parameters: &mut copied_parameters,
+                error: None,
+            };
             egui::ScrollArea::vertical().show(ui, |ui| {
-                left_panel(ui, &graph, &mut selected_node, &mut commands);
+                left_panel(ui, &mut tree_context, &mut commands);
             });
+            if tree_context.solo != render_parameters.solo {
+                render_parameters.solo = tree_context.solo;
+            }
+            if let Some(error) = tree_context.error {
+                current_file.error = Some(error);
+            }
         })
         .response
         .rect
@@ -105,52 +264,536 @@ fn sdf_code_editor(
         .default_width(400.0)
         .show(ctx, |ui| {
             egui::ScrollArea::vertical().show(ui, |ui| {
-                right_panel(ui, render_parameters, &mesh_generation_result, &diagnostics);
+                right_panel(
+                    ui,
+                    render_parameters,
+                    &mut snap_settings,
+                    &mut gizmo_state,
+                    &mut viewport_overlays,
+                    &mut lighting,
+                    &graph,
+                    &mut commands,
+                    &mesh_generation_result,
+                    &diagnostics,
+                );
             });
         })
         .response
         .rect
         .width();
 
+    handle_shortcuts(ctx, &graph, &mut selected_node, &mut commands);
     network_state.send(&commands);
 }
 
-fn left_panel(
-    ui: &mut egui::Ui,
+/// The keyboard shortcuts handled by [handle_shortcuts], as `(keys, description)`. These
+/// are listed in the help window.
+const SHORTCUTS: &[(&str, &str)] = &[
+    ("Delete", "Remove the selected nodes"),
+    (
+        "Enter",
+        "Add a sphere to each selected node that can have children",
+    ),
+    ("Up / Down", "Select the previous or next node in the tree"),
+];
+
+/// Handles the [SHORTCUTS], unless a widget is taking keyboard input, like a text field
+/// being typed in.
+fn handle_shortcuts(
+    ctx: &egui::Context,
     graph: &Graph,
     selected_node: &mut SelectedNode,
     commands: &mut Vec<GraphCommand>,
 ) {
-    if let Some(root_node_id) = graph.root_node_id() {
-        commands.append(&mut render_egui_tree(
+    if ctx.wants_keyboard_input() {
+        return;
+    }
+    let input = ctx.input();
+
+    if input.key_pressed(egui::Key::Delete) {
+        // Removing a node removes its subtree, so selected descendants are already covered
+        let ids = selected_node.ids();
+        let is_covered = |node_id: NodeId| {
+            std::iter::successors(graph.parent_of(node_id), |id| graph.parent_of(*id))
+                .any(|ancestor_id| ids.contains(&ancestor_id))
+        };
+        commands.extend(
+            ids.iter()
+                .filter(|id| graph.get(**id).is_some() && !is_covered(**id))
+                .map(|id| GraphCommand::Remove(*id)),
+        );
+    }
+
+    if input.key_pressed(egui::Key::Enter) {
+        commands.extend(
+            selected_node
+                .ids()
+                .iter()
+                .filter_map(|id| graph.get(*id))
+                .filter(|node| node.data.can_have_children())
+                .map(|node| {
+                    GraphCommand::AddChild(node.id, None, NodeData::Sphere(Default::default()))
+                }),
+        );
+    }
+
+    let step = if input.key_pressed(egui::Key::ArrowUp) {
+        -1
+    } else if input.key_pressed(egui::Key::ArrowDown) {
+        1
+    } else {
+        0
+    };
+    if step != 0 {
+        // Move from the most recently selected node, in the order the tree shows them
+        let ids: Vec<_> = graph
+            .reachable_node_ids()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        let current = selected_node
+            .ids()
+            .last()
+            .and_then(|selected_id| ids.iter().position(|id| id == selected_id));
+        let next = match current {
+            Some(index) => index.saturating_add_signed(step).min(ids.len() - 1),
+            None => 0,
+        };
+        if let Some(next_id) = ids.get(next) {
+            *selected_node = SelectedNode::Initialized(vec![*next_id]);
+        }
+    }
+}
+
+fn file_menu(
+    ui: &mut egui::Ui,
+    graph: &Graph,
+    current_file: &mut resources::CurrentFile,
+    export_settings: &mut resources::ExportSettings,
+    commands: &mut Vec<GraphCommand>,
+) {
+    fn file_dialog() -> rfd::FileDialog {
+        rfd::FileDialog::new().add_filter("Exoform model", Format::EXTENSIONS)
+    }
+
+    ui.menu_button("File", |ui| {
+        if ui.button("Open...").clicked() {
+            ui.close_menu();
+            if let Some(path) = file_dialog().pick_file() {
+                let result = std::fs::read_to_string(&path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|contents| {
+                        Ok(shared::save::from_str(&contents, Format::from_path(&path))?)
+                    });
+                match result {
+                    Ok(loaded) => {
+                        commands.push(GraphCommand::Replace(loaded.to_components()));
+                        current_file.path = Some(path);
+                        current_file.error = None;
+                        current_file.view_fitted = false;
+                    }
+                    Err(err) => {
+                        current_file.error = Some(format!("Failed to open {path:?}: {err}"));
+                    }
+                }
+            }
+        }
+        if ui.button("Save As...").clicked() {
+            ui.close_menu();
+            if let Some(path) = file_dialog().save_file() {
+                // Save in the same format as the server, so that the file can be used as a room
+                let to_save = Graph::authoritative_from_components(graph.to_components());
+                let result = shared::save::to_string(&to_save, Format::from_path(&path))
+                    .map_err(anyhow::Error::from)
+                    .and_then(|contents| Ok(std::fs::write(&path, contents)?));
+                match result {
+                    Ok(_) => {
+                        current_file.path = Some(path);
+                        current_file.error = None;
+                    }
+                    Err(err) => {
+                        current_file.error = Some(format!("Failed to save {path:?}: {err}"));
+                    }
+                }
+            }
+        }
+        ui.menu_button("Export Mesh", |ui| {
+            ui.add(
+                egui::Slider::new(&mut export_settings.triangle_ratio, 0.01..=1.0)
+                    .text("Triangles to keep"),
+            );
+            if ui.button("Export OBJ...").clicked() {
+                ui.close_menu();
+                let dialog = rfd::FileDialog::new().add_filter("Wavefront OBJ", &["obj"]);
+                if let Some(path) = dialog.save_file() {
+                    let result = export_obj(graph, None, export_settings.triangle_ratio, &path);
+                    if let Err(err) = result {
+                        current_file.error = Some(format!("Failed to export {path:?}: {err}"));
+                    }
+                }
+            }
+        });
+    });
+
+    if let Some(error) = current_file.error.clone() {
+        ui.colored_label(egui::Color32::RED, error);
+        if ui.small_button("✖").clicked() {
+            current_file.error = None;
+        }
+    }
+}
+
+fn update_window_title(current_file: Res<resources::CurrentFile>, mut windows: ResMut<Windows>) {
+    if !current_file.is_changed() {
+        return;
+    }
+
+    let mut title = format!("Exoform {}", env!("CARGO_PKG_VERSION"));
+    if let Some(file_name) = current_file.path.as_ref().and_then(|p| p.file_name()) {
+        title += &format!(" - {}", file_name.to_string_lossy());
+    }
+    if let Some(window) = windows.get_primary_mut() {
+        window.set_title(title);
+    }
+}
+
+/// State shared by every node in the tree.
+struct TreeContext<'a> {
+    graph: &'a Graph,
+    selected_node: &'a mut SelectedNode,
+    /// The node that caused the last compilation error, if any.
+    error_node_id: Option<NodeId>,
+    solo: Option<NodeId>,
+    snap_settings: &'a resources::SnapSettings,
+    export_settings: &'a resources::ExportSettings,
+    copied_parameters: &'a mut resources::CopiedParameters,
+    /// Set if an action started from the tree failed.
+    error: Option<String>,
+}
+
+fn left_panel(ui: &mut egui::Ui, ctx: &mut TreeContext, commands: &mut Vec<GraphCommand>) {
+    if ctx.selected_node.ids().len() > 1 {
+        commands.extend(render_bulk_edit(
             ui,
-            &graph,
-            selected_node,
-            None,
-            root_node_id,
-            0,
+            ctx.graph,
+            ctx.selected_node.ids(),
+            ctx.snap_settings,
         ));
-    } else {
-        let new_child = util::render_add_button_max_width(ui, util::depth_to_colour(0, false));
-        if let Some(node_data) = new_child {
-            commands.push(GraphCommand::CreateNewRoot(node_data));
+    }
+    render_validation(ui, ctx);
+
+    let root_node_ids = ctx.graph.root_node_ids();
+    for root_node_id in &root_node_ids {
+        commands.append(&mut render_egui_tree(ui, ctx, None, *root_node_id, 0));
+    }
+
+    // If there's already a root, these add another root as a disjoint object
+    if !root_node_ids.is_empty() {
+        ui.separator();
+        ui.label("Add another object:");
+    }
+    let new_root = util::render_add_button_max_width(ui, util::depth_to_colour(0, false));
+    if let Some(node_data) = new_root {
+        commands.push(GraphCommand::CreateNewRoot(node_data));
+    }
+    if ui.button("Paste").clicked() {
+        if let Some(subtree) = paste_from_clipboard() {
+            commands.push(GraphCommand::AddSubtree(None, None, subtree));
+        }
+    }
+}
+
+/// Lists the problems with the graph, so that they can be fixed before meshing fails.
+/// Clicking a problem selects the node it's about.
+fn render_validation(ui: &mut egui::Ui, ctx: &mut TreeContext) {
+    let problems = ctx.graph.validate();
+    if problems.is_empty() {
+        return;
+    }
+    egui::CollapsingHeader::new(format!("Warnings ({})", problems.len()))
+        .default_open(true)
+        .show(ui, |ui| {
+            for problem in problems {
+                let text = egui::RichText::new(format!("⚠ {problem}")).color(egui::Color32::YELLOW);
+                let response = ui.add(egui::Label::new(text).sense(egui::Sense::click()));
+                if let Some(node_id) = problem.node_id() {
+                    if response.on_hover_text("Select the node").clicked() {
+                        ctx.selected_node.select(node_id);
+                    }
+                }
+            }
+        });
+}
+
+/// Meshes the graph, or just the subtree of `solo` if it's set, and writes it to an OBJ
+/// file with the given fraction of its triangles.
+fn export_obj(
+    graph: &Graph,
+    solo: Option<NodeId>,
+    triangle_ratio: f32,
+    path: &std::path::Path,
+) -> anyhow::Result<()> {
+    let options = shared::mesh::CompilationOptions {
+        colours_enabled: true,
+        solo,
+        ..Default::default()
+    };
+    let output = match shared::mesh::generate_mesh(graph, &options) {
+        Err(shared::mesh::CompilationError::NoVolume) => {
+            anyhow::bail!("there's nothing to export, as the shape has no volume")
+        }
+        result => result?,
+    };
+    let mesh = shared::mesh::simplify_mesh(&output.mesh, triangle_ratio);
+    Ok(std::fs::write(path, mesh.to_obj())?)
+}
+
+/// Saves the node and its descendants as a model of their own.
+fn extract_to_file(graph: &Graph, node_id: NodeId, path: &std::path::Path) -> anyhow::Result<()> {
+    let subtree = graph
+        .subtree(node_id)
+        .ok_or_else(|| anyhow::anyhow!("node {node_id:?} does not exist"))?;
+    let extracted = Graph::authoritative_from_subtree(&subtree, graph.variables().clone());
+    let contents = shared::save::to_string(&extracted, Format::from_path(path))?;
+    Ok(std::fs::write(path, contents)?)
+}
+
+/// The offset and number of copies last used to duplicate a node, kept so that the
+/// same step can be repeated.
+const DUPLICATE_OPTIONS_ID: &str = "duplicate_options";
+
+/// Edits how a node is duplicated, returning the command to make the copies once the
+/// button is clicked.
+fn render_duplicate(ui: &mut egui::Ui, graph: &Graph, node_id: NodeId) -> Option<GraphCommand> {
+    let id = egui::Id::new(DUPLICATE_OPTIONS_ID);
+    let (mut offset, mut count) = ui.data().get_temp(id).unwrap_or((glam::Vec3::X, 1));
+    util::grid(ui, |ui| {
+        if let Some(new_offset) =
+            util::with_label(ui, "Offset", |ui| util::vec3(ui, offset, glam::Vec3::X))
+        {
+            offset = new_offset;
         }
+        ui.label("Copies");
+        ui.add(egui::DragValue::new(&mut count).clamp_range(1..=100));
+        ui.end_row();
+    });
+    ui.data().insert_temp(id, (offset, count));
+
+    if !ui.button("Duplicate").clicked() {
+        return None;
     }
+    ui.close_menu();
+    graph
+        .duplicate_with_offset(node_id, offset, count)
+        .map(GraphCommand::Batch)
+}
+
+const RECOLOUR_ID: &str = "recolour_subtree";
+
+/// Picks a colour for a node and everything beneath it, returning the command to apply
+/// it once the button is clicked. The colour starts as the node's own.
+fn render_recolour(ui: &mut egui::Ui, graph: &Graph, node: &Node) -> Option<GraphCommand> {
+    let id = egui::Id::new(RECOLOUR_ID).with(node.id);
+    let (r, g, b) = ui.data().get_temp(id).unwrap_or(node.rgb);
+    let mut rgb = [r, g, b];
+    ui.horizontal(|ui| util::colour_edit(ui, "recolour", &mut rgb));
+    ui.data().insert_temp(id, (rgb[0], rgb[1], rgb[2]));
+
+    if !ui.button("Apply to Subtree").clicked() {
+        return None;
+    }
+    ui.close_menu();
+    ui.data().remove::<(f32, f32, f32)>(id);
+    graph
+        .recolour_subtree(node.id, (rgb[0], rgb[1], rgb[2]))
+        .map(GraphCommand::Batch)
+}
+
+/// Offers to paste the copied parameters onto the node. The node's data can only be
+/// replaced by data of the same type, so only the transform and colour are offered
+/// otherwise.
+fn render_paste_parameters(
+    ui: &mut egui::Ui,
+    node: &Node,
+    copied: &resources::NodeParameters,
+) -> Option<GraphCommand> {
+    let same_type = copied.data.name() == node.data.name();
+    let data = same_type.then(|| copied.data.clone().into());
+    let transform_and_colour = NodeDiff {
+        transform: Some(copied.transform.into()),
+        rgb: Some(copied.rgb),
+        ..Default::default()
+    };
+
+    let diff = if ui
+        .add_enabled(same_type, egui::widgets::Button::new("Everything"))
+        .on_disabled_hover_text(format!(
+            "The parameters were copied from a {}",
+            copied.data.name()
+        ))
+        .clicked()
+    {
+        NodeDiff {
+            data,
+            ..transform_and_colour
+        }
+    } else if ui
+        .add_enabled(same_type, egui::widgets::Button::new("Data"))
+        .clicked()
+    {
+        NodeDiff {
+            data,
+            ..Default::default()
+        }
+    } else if ui.button("Transform and Colour").clicked() {
+        transform_and_colour
+    } else {
+        return None;
+    };
+    ui.close_menu();
+    Some(GraphCommand::ApplyDiff(node.id, diff))
+}
+
+fn copy_to_clipboard(subtree: &shared::Subtree) {
+    let result = serde_json::to_string(subtree)
+        .map_err(anyhow::Error::from)
+        .and_then(|json| Ok(arboard::Clipboard::new()?.set_text(json)?));
+    if let Err(err) = result {
+        println!("failed to copy to clipboard: {err}");
+    }
+}
+
+/// Returns the subtree on the clipboard, ignoring anything that isn't a valid subtree.
+fn paste_from_clipboard() -> Option<shared::Subtree> {
+    let text = arboard::Clipboard::new().ok()?.get_text().ok()?;
+    serde_json::from_str::<shared::Subtree>(&text)
+        .ok()
+        .filter(shared::Subtree::is_valid)
+}
+
+fn render_bulk_edit(
+    ui: &mut egui::Ui,
+    graph: &Graph,
+    node_ids: &[NodeId],
+    snap_settings: &resources::SnapSettings,
+) -> Vec<GraphCommand> {
+    // Locked nodes are left out, as the server would reject the edits
+    let nodes: Vec<_> = node_ids
+        .iter()
+        .filter_map(|id| graph.get(*id))
+        .filter(|node| !node.locked)
+        .collect();
+    let transforms: Vec<_> = nodes.iter().map(|node| &node.transform).collect();
+
+    let mut commands: Vec<_> =
+        egui::CollapsingHeader::new(format!("{} nodes selected", nodes.len()))
+            .default_open(true)
+            .show(ui, |ui| {
+                util::grid(ui, |ui| {
+                    util::render_bulk_transform(
+                        ui,
+                        &transforms,
+                        snap_settings.translation,
+                        snap_settings.rotation,
+                    )
+                })
+            })
+            .body_returned
+            .flatten()
+            .map(|edit| {
+                nodes
+                    .iter()
+                    .filter_map(|node| {
+                        let transform = edit.to_diff(&node.transform)?;
+                        let diff = NodeDiff {
+                            transform: Some(transform),
+                            ..Default::default()
+                        };
+                        Some(GraphCommand::ApplyDiff(node.id, diff))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+    ui.menu_button("Group", |ui| {
+        if let Some(node_data) = util::render_add_buttons(ui, false) {
+            commands.push(GraphCommand::Group(node_ids.to_vec(), node_data));
+            ui.close_menu();
+        }
+    });
+
+    commands
 }
 
 fn right_panel(
     ui: &mut egui::Ui,
     mut render_parameters: ResMut<resources::RenderParameters>,
+    snap_settings: &mut resources::SnapSettings,
+    gizmo_state: &mut resources::GizmoState,
+    viewport_overlays: &mut ResMut<resources::ViewportOverlays>,
+    lighting: &mut ResMut<resources::Lighting>,
+    graph: &Graph,
+    commands: &mut Vec<GraphCommand>,
     mesh_generation_result: &resources::MeshGenerationResult,
     diagnostics: &Diagnostics,
 ) {
     let mut rp = render_parameters.clone();
     ui.heading("Parameters");
-    ui.checkbox(&mut rp.wireframe, "Wireframe");
+    ui.horizontal(|ui| {
+        use resources::WireframeMode;
+        ui.label("Draw");
+        ui.selectable_value(&mut rp.wireframe, WireframeMode::Solid, "Solid");
+        ui.selectable_value(&mut rp.wireframe, WireframeMode::Wireframe, "Wireframe");
+        ui.selectable_value(&mut rp.wireframe, WireframeMode::Both, "Both");
+    });
     ui.checkbox(&mut rp.colours, "Colours");
+    ui.checkbox(&mut rp.isolate_colours, "Isolate primitives")
+        .on_hover_text("Give each primitive its own colour, to see which surfaces it makes");
+    ui.checkbox(&mut rp.uvs, "UVs");
+    ui.checkbox(&mut rp.flat_shading, "Flat shading");
+    ui.checkbox(&mut rp.recompute_normals, "Face-averaged normals");
+    ui.checkbox(&mut rp.raymarch, "Raymarched preview")
+        .on_hover_text("Faster to update than the mesh, but without colours or materials");
+    ui.checkbox(&mut rp.bounds_preview, "Bounding box preview")
+        .on_hover_text("Draw a box around each primitive instead of building the mesh");
+    render_clipping_plane(ui, &mut rp.clipping_plane);
+    render_triangle_budget(ui, &mut rp.triangle_budget);
+    render_infinite_bounds(ui, &mut rp.infinite_bounds_half_size);
     if render_parameters.as_ref() != &rp {
         *render_parameters = rp;
     }
+    // Only write back on change, so that the bounds aren't rebuilt every frame
+    let mut show_bounds = viewport_overlays.bounds;
+    ui.checkbox(&mut show_bounds, "Selection bounds");
+    if show_bounds != viewport_overlays.bounds {
+        viewport_overlays.bounds = show_bounds;
+    }
+    let mut show_performance = viewport_overlays.performance;
+    ui.checkbox(&mut show_performance, "Performance overlay");
+    if show_performance != viewport_overlays.performance {
+        viewport_overlays.performance = show_performance;
+    }
+    render_lighting(ui, lighting);
+    render_palette(ui);
+    ui.heading("Gizmo");
+    ui.horizontal(|ui| {
+        use resources::GizmoMode;
+        ui.selectable_value(&mut gizmo_state.mode, GizmoMode::Translate, "Translate");
+        ui.selectable_value(&mut gizmo_state.mode, GizmoMode::Rotate, "Rotate");
+        ui.selectable_value(&mut gizmo_state.mode, GizmoMode::Scale, "Scale");
+    });
+    ui.heading("Snapping");
+    render_snap(ui, "Translation", &mut snap_settings.translation, 0.1, "");
+    render_snap(
+        ui,
+        "Rotation",
+        &mut snap_settings.rotation,
+        util::DEFAULT_ROTATION_SNAP,
+        "°",
+    );
+    ui.heading("Variables");
+    commands.extend(render_variables(ui, graph.variables()));
+    commands.extend(render_recentre(ui, graph, mesh_generation_result));
     match mesh_generation_result {
         resources::MeshGenerationResult::Unbuilt => {}
         resources::MeshGenerationResult::Failure(_) => {}
@@ -158,6 +801,9 @@ fn right_panel(
             exo_node_count,
             triangle_count,
             volume,
+            center_of_mass,
+            mass_properties_approximate,
+            generation_time,
         } => {
             ui.heading("Statistics");
             if let Some(fps) =
@@ -180,19 +826,363 @@ fn right_panel(
                 ui.label(egui::RichText::new("Volume:").strong());
                 ui.label(volume.to_string());
             });
+            ui.horizontal(|ui| {
+                ui.label(egui::RichText::new("Generation time:").strong());
+                ui.label(format!("{} ms", generation_time.as_millis()));
+            });
+            ui.horizontal(|ui| {
+                ui.label(egui::RichText::new("Center of mass:").strong());
+                let approximate = if *mass_properties_approximate {
+                    "≈ "
+                } else {
+                    ""
+                };
+                ui.label(format!(
+                    "{approximate}({:.03}, {:.03}, {:.03})",
+                    center_of_mass.x, center_of_mass.y, center_of_mass.z
+                ))
+                .on_hover_text(if *mass_properties_approximate {
+                    "The mesh isn't closed, so this is only an estimate"
+                } else {
+                    "Assuming uniform density"
+                });
+            });
         }
     }
 }
 
-fn render_egui_tree(
+/// Moves the roots so that a point of the model ends up at the origin.
+fn render_recentre(
     ui: &mut egui::Ui,
     graph: &Graph,
-    selected_node: &mut SelectedNode,
+    mesh_generation_result: &resources::MeshGenerationResult,
+) -> Vec<GraphCommand> {
+    let bounds = graph.bounds();
+    let centre_of_mass = match mesh_generation_result {
+        resources::MeshGenerationResult::Successful { center_of_mass, .. } => Some(*center_of_mass),
+        _ => None,
+    };
+    let points = [
+        (
+            "Centre",
+            bounds.map(|(min, max)| (min + max) * 0.5),
+            "The centre of the bounds",
+        ),
+        (
+            "Min",
+            bounds.map(|(min, _)| min),
+            "The minimum corner of the bounds",
+        ),
+        (
+            "Max",
+            bounds.map(|(_, max)| max),
+            "The maximum corner of the bounds",
+        ),
+        ("Centroid", centre_of_mass, "The centre of mass of the mesh"),
+    ];
+
+    let mut origin = None;
+    ui.horizontal(|ui| {
+        ui.label("Move to origin:");
+        for (label, point, hover_text) in points {
+            let button = ui
+                .add_enabled(point.is_some(), egui::Button::new(label))
+                .on_hover_text(hover_text);
+            if button.clicked() {
+                origin = point;
+            }
+        }
+    });
+
+    let origin = match origin {
+        Some(origin) if origin != glam::Vec3::ZERO => origin,
+        _ => return vec![],
+    };
+    graph
+        .root_node_ids()
+        .into_iter()
+        .filter_map(|node_id| graph.get(node_id))
+        .map(|node| {
+            let diff = NodeDiff {
+                transform: Some(TransformDiff {
+                    translation: Some(node.transform.translation - origin),
+                    rotation: None,
+                    scale: None,
+                }),
+                ..Default::default()
+            };
+            GraphCommand::ApplyDiff(node.id, diff)
+        })
+        .collect()
+}
+
+fn render_lighting(ui: &mut egui::Ui, lighting: &mut ResMut<resources::Lighting>) {
+    let default = resources::Lighting::default();
+    ui.heading("Lighting");
+    util::grid(ui, |ui| {
+        let background = util::with_label(ui, "Background", |ui| {
+            util::with_reset_button(ui, lighting.background, default.background, |ui, rgb| {
+                egui::widgets::color_picker::color_edit_button_rgb(ui, rgb).changed()
+            })
+        });
+        let intensity = util::with_label(ui, "Key light intensity", |ui| {
+            util::with_reset_button(
+                ui,
+                lighting.key_light_intensity,
+                default.key_light_intensity,
+                |ui, intensity| {
+                    ui.add(egui::widgets::Slider::new(intensity, 0.0..=5000.0))
+                        .changed()
+                },
+            )
+        });
+        let direction = util::with_label(ui, "Key light direction", |ui| {
+            util::vec3(
+                ui,
+                lighting.key_light_direction,
+                default.key_light_direction,
+            )
+        });
+
+        // Only write back on change, so that the lighting isn't reapplied every frame
+        if let Some(background) = background {
+            lighting.background = background;
+        }
+        if let Some(intensity) = intensity {
+            lighting.key_light_intensity = intensity;
+        }
+        if let Some(direction) = direction {
+            lighting.key_light_direction = direction;
+        }
+    });
+}
+
+/// Picks the colours that node categories are shown in.
+fn render_palette(ui: &mut egui::Ui) {
+    let mut palette = palette::Palette::current(ui.ctx());
+    ui.horizontal(|ui| {
+        ui.label("Palette");
+        for option in palette::Palette::ALL {
+            ui.selectable_value(&mut palette, option, option.name());
+        }
+    });
+    palette::Palette::set(ui.ctx(), palette);
+}
+
+fn render_variables(ui: &mut egui::Ui, variables: &HashMap<String, f32>) -> Vec<GraphCommand> {
+    let mut commands = vec![];
+    let mut names: Vec<_> = variables.keys().collect();
+    names.sort();
+    for name in names {
+        ui.horizontal(|ui| {
+            if ui.small_button("✖").clicked() {
+                commands.push(GraphCommand::SetVariable(name.clone(), None));
+            }
+            ui.label(name);
+            let mut value = variables[name];
+            if util::dragger_with_no_reset(ui, &mut value).changed() {
+                commands.push(GraphCommand::SetVariable(name.clone(), Some(value)));
+            }
+        });
+    }
+
+    ui.horizontal(|ui| {
+        let buffer_id = ui.make_persistent_id("new_variable_name");
+        let mut buffer = ui.data().get_temp::<String>(buffer_id).unwrap_or_default();
+        ui.add(egui::TextEdit::singleline(&mut buffer).hint_text("New variable"));
+        let name = buffer.trim();
+        let can_add = !name.is_empty() && !variables.contains_key(name);
+        if ui
+            .add_enabled(can_add, egui::widgets::Button::new("Add"))
+            .clicked()
+        {
+            commands.push(GraphCommand::SetVariable(name.to_string(), Some(0.0)));
+            buffer.clear();
+        }
+        ui.data().insert_temp(buffer_id, buffer);
+    });
+    commands
+}
+
+fn render_room(ui: &mut egui::Ui, network_state: &mut resources::NetworkState) {
+    let room = network_state.room.clone();
+    if network_state.is_spectator() {
+        ui.label(format!("Room: {room}"));
+    } else {
+        ui.menu_button(format!("Room: {room}"), |ui| {
+            let buffer_id = ui.make_persistent_id("room_name");
+            let mut buffer = ui
+                .data()
+                .get_temp::<String>(buffer_id)
+                .unwrap_or_else(|| room.clone());
+            ui.add(egui::TextEdit::singleline(&mut buffer).hint_text("Room name"));
+            let name = buffer.trim();
+            let can_rename = !name.is_empty() && name != room;
+            if ui
+                .add_enabled(can_rename, egui::widgets::Button::new("Rename"))
+                .clicked()
+            {
+                network_state.rename_room(name.to_string());
+                ui.data().remove::<String>(buffer_id);
+                ui.close_menu();
+                return;
+            }
+            ui.data().insert_temp(buffer_id, buffer);
+        });
+    }
+    if let Some(rejection) = &network_state.rename_rejection {
+        ui.colored_label(
+            egui::Color32::RED,
+            format!("Couldn't rename the room: {rejection}"),
+        );
+        if ui.small_button("✖").clicked() {
+            network_state.rename_rejection = None;
+        }
+    }
+}
+
+fn render_snap(
+    ui: &mut egui::Ui,
+    label: &str,
+    increment: &mut Option<f32>,
+    default_increment: f32,
+    suffix: &str,
+) {
+    ui.horizontal(|ui| {
+        let mut enabled = increment.is_some();
+        ui.checkbox(&mut enabled, label);
+        if enabled != increment.is_some() {
+            *increment = enabled.then_some(default_increment);
+        }
+        if let Some(increment) = increment {
+            ui.add(
+                egui::widgets::DragValue::new(increment)
+                    .speed(default_increment / 10.0)
+                    .clamp_range(0.0..=f32::MAX)
+                    .suffix(suffix),
+            );
+        }
+    });
+}
+
+fn render_timeline(
+    ui: &mut egui::Ui,
+    graph: &Graph,
+    selected_node: &SelectedNode,
+    animation_tracks: &mut ResMut<animation::AnimationTracks>,
+    playhead: &mut ResMut<animation::Playhead>,
+) {
+    let play_text = if playhead.playing { "⏸" } else { "▶" };
+    if ui.button(play_text).clicked() {
+        playhead.playing = !playhead.playing;
+    }
+
+    let mut time = playhead.time;
+    let duration = playhead.duration;
+    if ui
+        .add(egui::widgets::Slider::new(&mut time, 0.0..=duration).suffix("s"))
+        .changed()
+    {
+        playhead.time = time;
+    }
+
+    let selected_nodes: Vec<_> = selected_node
+        .ids()
+        .iter()
+        .filter_map(|id| graph.get(*id))
+        .collect();
+    if ui
+        .add_enabled(
+            !selected_nodes.is_empty(),
+            egui::widgets::Button::new("Add keyframe"),
+        )
+        .on_hover_text("Records the selected nodes' current values at the playhead")
+        .clicked()
+    {
+        for node in selected_nodes {
+            animation_tracks.add_keyframe(node, time);
+        }
+    }
+
+    let keyframe_count = animation_tracks.keyframe_count();
+    ui.label(format!("{keyframe_count} keyframes"));
+    if keyframe_count > 0 && ui.button("Clear").clicked() {
+        animation_tracks.0.clear();
+    }
+}
+
+fn render_triangle_budget(ui: &mut egui::Ui, triangle_budget: &mut Option<usize>) {
+    ui.horizontal(|ui| {
+        let mut enabled = triangle_budget.is_some();
+        ui.checkbox(&mut enabled, "Triangle budget");
+        if enabled != triangle_budget.is_some() {
+            *triangle_budget =
+                enabled.then_some(resources::RenderParameters::DEFAULT_TRIANGLE_BUDGET);
+        }
+        if let Some(budget) = triangle_budget {
+            ui.add(
+                egui::DragValue::new(budget)
+                    .speed(1000.0)
+                    .clamp_range(1..=usize::MAX),
+            );
+        }
+    });
+}
+
+fn render_infinite_bounds(ui: &mut egui::Ui, half_size: &mut Option<f32>) {
+    ui.horizontal(|ui| {
+        let mut enabled = half_size.is_some();
+        ui.checkbox(&mut enabled, "Cut off infinite models")
+            .on_hover_text("Build models with planes inside a cube of this half-size");
+        if enabled != half_size.is_some() {
+            *half_size =
+                enabled.then_some(resources::RenderParameters::DEFAULT_INFINITE_BOUNDS_HALF_SIZE);
+        }
+        if let Some(half_size) = half_size {
+            ui.add(
+                egui::DragValue::new(half_size)
+                    .speed(0.1)
+                    .clamp_range(0.1..=1000.0),
+            );
+        }
+    });
+}
+
+fn render_clipping_plane(ui: &mut egui::Ui, clipping_plane: &mut Option<shared::Plane>) {
+    let mut enabled = clipping_plane.is_some();
+    ui.checkbox(&mut enabled, "Clipping plane");
+    if enabled != clipping_plane.is_some() {
+        *clipping_plane = enabled.then(shared::Plane::default);
+    }
+
+    if let Some(plane) = clipping_plane {
+        let default = shared::Plane::default();
+        util::grid(ui, |ui| {
+            if let Some(normal) = util::with_label(ui, "Normal", |ui| {
+                util::vec3(ui, plane.normal, default.normal)
+            }) {
+                plane.normal = normal;
+            }
+            if let Some(distance) = util::dragger_row(
+                ui,
+                "Distance",
+                plane.distance_from_origin,
+                default.distance_from_origin,
+            ) {
+                plane.distance_from_origin = distance;
+            }
+        });
+    }
+}
+
+fn render_egui_tree(
+    ui: &mut egui::Ui,
+    ctx: &mut TreeContext,
     parent_node_id: Option<NodeId>,
     node_id: NodeId,
     depth: usize,
 ) -> Vec<GraphCommand> {
-    let node = graph.get(node_id).unwrap();
+    let node = ctx.graph.get(node_id).unwrap();
 
     let mut commands = vec![];
     ui.push_id(node_id, |ui| {
@@ -200,23 +1190,22 @@ fn render_egui_tree(
 
         egui::collapsing_header::CollapsingState::load_with_default_open(ui.ctx(), id, true)
             .show_header(ui, |ui| {
-                commands.extend(render_header(
-                    ui,
-                    graph,
-                    selected_node,
-                    parent_node_id,
-                    node_id,
-                    depth,
-                ))
+                commands.extend(render_header(ui, ctx, parent_node_id, node_id, depth))
             })
             .body(|ui| {
                 egui::CollapsingHeader::new("Parameters")
                     .default_open(true)
                     .show(ui, |ui| {
-                        commands.extend(render_selected_node(ui, node, depth));
+                        commands.extend(render_selected_node(
+                            ui,
+                            node,
+                            depth,
+                            ctx.graph,
+                            ctx.snap_settings,
+                        ));
                     });
                 if node.data.can_have_children() {
-                    commands.extend(render_children(ui, graph, selected_node, node, depth));
+                    commands.extend(render_children(ui, ctx, node, depth));
                 }
             });
     });
@@ -226,8 +1215,7 @@ fn render_egui_tree(
 
 fn render_header(
     ui: &mut egui::Ui,
-    graph: &Graph,
-    selected_node: &mut SelectedNode,
+    ctx: &mut TreeContext,
     parent_node_id: Option<NodeId>,
     node_id: NodeId,
     depth: usize,
@@ -235,12 +1223,33 @@ fn render_header(
     let mut commands = vec![];
 
     let interact_size = ui.spacing().interact_size;
-    let is_selected = selected_node.is_selected(node_id);
-    let name = graph.get(node_id).unwrap().data.name();
-    let (bg_colour, fg_colour) = (
-        util::depth_to_colour(depth, is_selected),
-        egui::Color32::WHITE,
-    );
+    let is_selected = ctx.selected_node.is_selected(node_id);
+    let is_soloed = ctx.solo == Some(node_id);
+    let node = ctx.graph.get(node_id).unwrap();
+    let name = match (&node.label, &node.data) {
+        (Some(label), _) => label.as_str(),
+        // Comments show their first line, so they can be read without expanding them
+        (None, NodeData::Comment(comment)) if !comment.text.trim().is_empty() => {
+            comment.text.trim().lines().next().unwrap_or_default()
+        }
+        (None, data) => data.name(),
+    };
+    let name = if is_soloed {
+        format!("{name} (solo)")
+    } else {
+        name.to_string()
+    };
+    let name = if node.locked {
+        format!("🔒 {name}")
+    } else {
+        name
+    };
+    let (name, fg_colour) = if ctx.error_node_id == Some(node_id) {
+        (format!("⚠ {name}"), egui::Color32::from_rgb(255, 120, 120))
+    } else {
+        (name, egui::Color32::WHITE)
+    };
+    let bg_colour = util::depth_to_colour(depth, is_selected);
 
     let response = ui.add_sized(
         egui::Vec2::new(ui.available_width(), interact_size.y),
@@ -253,9 +1262,40 @@ fn render_header(
         .sense(egui::Sense::click()),
     );
     if response.clicked_by(egui::PointerButton::Primary) {
-        selected_node.select(node_id);
+        let modifiers = ui.input().modifiers;
+        if modifiers.shift || modifiers.command {
+            ctx.selected_node.toggle(node_id);
+        } else {
+            ctx.selected_node.select(node_id);
+        }
     }
     response.context_menu(|ui| {
+        let lock_text = if node.locked { "Unlock" } else { "Lock" };
+        if ui
+            .button(lock_text)
+            .on_hover_text("Locked nodes can't be edited, but can still be selected")
+            .clicked()
+        {
+            commands.push(GraphCommand::ApplyDiff(
+                node_id,
+                NodeDiff {
+                    locked: Some(!node.locked),
+                    ..Default::default()
+                },
+            ));
+            ui.close_menu();
+        }
+
+        if let Some(label) = util::label_edit(ui, node.label.as_deref(), node.data.name()) {
+            commands.push(GraphCommand::ApplyDiff(
+                node_id,
+                NodeDiff {
+                    label: Some(label),
+                    ..Default::default()
+                },
+            ));
+        }
+
         ui.menu_button("Add Parent", |ui| {
             if let Some(node_data) = util::render_add_buttons(ui, false) {
                 commands.push(GraphCommand::AddNewParent(
@@ -266,6 +1306,127 @@ fn render_header(
                 ui.close_menu();
             }
         });
+        ui.menu_button("Change Type", |ui| {
+            if node.children.iter().any(Option::is_some) {
+                ui.colored_label(
+                    egui::Color32::YELLOW,
+                    "⚠ Types that can't have children will remove this node's children",
+                );
+                ui.separator();
+            }
+            if let Some(node_data) = util::render_add_buttons(ui, true) {
+                if node_data.name() != node.data.name() {
+                    commands.push(GraphCommand::ChangeType(node_id, node_data));
+                }
+                ui.close_menu();
+            }
+        });
+        ui.menu_button("Mirror", |ui| {
+            for axis in shared::Axis::ALL {
+                if ui
+                    .button(format!("Across {}", axis.name()))
+                    .on_hover_text(
+                        "Adds a mirrored copy alongside this node, reflected across the plane \
+                         through its parent's origin",
+                    )
+                    .clicked()
+                {
+                    commands.push(GraphCommand::Mirror(node_id, axis));
+                    ui.close_menu();
+                }
+            }
+        });
+
+        if ui.button("Reset").clicked() {
+            commands.push(GraphCommand::ApplyDiff(
+                node_id,
+                NodeDiff {
+                    rgb: Some(Node::DEFAULT_COLOUR),
+                    transform: Some(shared::Transform::new().into()),
+                    data: Some(node.data.to_default().into()),
+                    ..Default::default()
+                },
+            ));
+            ui.close_menu();
+        }
+
+        let solo_text = if is_soloed { "Exit Solo" } else { "Solo" };
+        if ui.button(solo_text).clicked() {
+            ctx.solo = (!is_soloed).then_some(node_id);
+            ui.close_menu();
+        }
+
+        if ui.button("Copy").clicked() {
+            if let Some(subtree) = ctx.graph.subtree(node_id) {
+                copy_to_clipboard(&subtree);
+            }
+            ui.close_menu();
+        }
+        ui.menu_button("Duplicate", |ui| {
+            commands.extend(render_duplicate(ui, ctx.graph, node_id));
+        });
+        if ui.button("Copy Parameters").clicked() {
+            ctx.copied_parameters.0 = Some(resources::NodeParameters {
+                data: node.data.clone(),
+                transform: node.transform,
+                rgb: node.rgb,
+            });
+            ui.close_menu();
+        }
+        if let Some(copied) = &ctx.copied_parameters.0 {
+            ui.menu_button("Paste Parameters", |ui| {
+                commands.extend(render_paste_parameters(ui, node, copied));
+            });
+        }
+        if node.data.can_have_children() {
+            ui.menu_button("Colour Subtree", |ui| {
+                commands.extend(render_recolour(ui, ctx.graph, node));
+            });
+        }
+        if node.data.can_have_children() && ui.button("Bake Transform").clicked() {
+            match ctx.graph.bake_transform(node_id) {
+                Ok(diffs) => commands.push(GraphCommand::Batch(
+                    diffs
+                        .into_iter()
+                        .map(|(node_id, diff)| GraphCommand::ApplyDiff(node_id, diff))
+                        .collect(),
+                )),
+                Err(err) => ctx.error = Some(format!("Can't bake transform: {err}")),
+            }
+            ui.close_menu();
+        }
+
+        if ui.button("Extract to File...").clicked() {
+            ui.close_menu();
+            if let Some(path) = rfd::FileDialog::new()
+                .add_filter("Exoform model", Format::EXTENSIONS)
+                .save_file()
+            {
+                if let Err(err) = extract_to_file(ctx.graph, node_id, &path) {
+                    ctx.error = Some(format!("Failed to extract to {path:?}: {err}"));
+                }
+            }
+        }
+        if ui.button("Export Mesh...").clicked() {
+            ui.close_menu();
+            let name = node.label.as_deref().unwrap_or_else(|| node.data.name());
+            if let Some(path) = rfd::FileDialog::new()
+                .add_filter("Wavefront OBJ", &["obj"])
+                .set_file_name(&format!("{}.obj", name.replace(['/', '\\'], "_")))
+                .save_file()
+            {
+                let triangle_ratio = ctx.export_settings.triangle_ratio;
+                if let Err(err) = export_obj(ctx.graph, Some(node_id), triangle_ratio, &path) {
+                    ctx.error = Some(format!("Failed to export {path:?}: {err}"));
+                }
+            }
+        }
+        if node.data.can_have_children() && ui.button("Paste").clicked() {
+            if let Some(subtree) = paste_from_clipboard() {
+                commands.push(GraphCommand::AddSubtree(Some(node_id), None, subtree));
+            }
+            ui.close_menu();
+        }
 
         if ui.button("Delete").clicked() {
             commands.push(GraphCommand::Remove(node_id));
@@ -278,8 +1439,7 @@ fn render_header(
 
 fn render_children(
     ui: &mut egui::Ui,
-    graph: &Graph,
-    selected_node: &mut SelectedNode,
+    ctx: &mut TreeContext,
     parent: &Node,
     depth: usize,
 ) -> Vec<GraphCommand> {
@@ -289,16 +1449,15 @@ fn render_children(
         .iter()
         .enumerate()
         .flat_map(|(idx, child_id)| match *child_id {
-            Some(child_id) => {
-                render_egui_tree(ui, graph, selected_node, Some(parent.id), child_id, depth)
-            }
+            Some(child_id) => render_egui_tree(ui, ctx, Some(parent.id), child_id, depth),
             None => util::render_add_button(ui, depth, parent.id, Some(idx))
                 .into_iter()
                 .collect(),
         })
         .collect();
 
-    if parent.data.can_have_children() {
+    // A trailing empty slot already has its own add button
+    if parent.data.can_have_children() && parent.children.last() != Some(&None) {
         let new_child = util::render_add_button_max_width(ui, util::depth_to_colour(depth, false));
         if let Some(node_data) = new_child {
             commands.push(GraphCommand::AddChild(parent.id, None, node_data));
@@ -308,16 +1467,36 @@ fn render_children(
     commands
 }
 
-fn render_selected_node(ui: &mut egui::Ui, node: &Node, depth: usize) -> Option<GraphCommand> {
+fn render_selected_node(
+    ui: &mut egui::Ui,
+    node: &Node,
+    depth: usize,
+    graph: &Graph,
+    snap_settings: &resources::SnapSettings,
+) -> Vec<GraphCommand> {
+    // Locked nodes can still be inspected, just not edited
+    ui.add_enabled_ui(!node.locked, |ui| {
+        render_selected_node_fields(ui, node, depth, graph, snap_settings)
+    })
+    .inner
+}
+
+fn render_selected_node_fields(
+    ui: &mut egui::Ui,
+    node: &Node,
+    depth: usize,
+    graph: &Graph,
+    snap_settings: &resources::SnapSettings,
+) -> Vec<GraphCommand> {
     util::grid(ui, |ui| {
-        NodeDiff {
+        let diff = NodeDiff {
+            label: None,
             rgb: util::with_label(ui, "Colour", |ui| {
                 let depth_colour = util::depth_to_colour(depth, false);
 
                 util::with_reset_button(ui, node.rgb, Node::DEFAULT_COLOUR, |ui, (r, g, b)| {
                     let mut rgb = [*r, *g, *b];
-                    let widget_changed =
-                        egui::widgets::color_picker::color_edit_button_rgb(ui, &mut rgb).changed();
+                    let widget_changed = util::colour_edit(ui, "colour", &mut rgb);
                     let button_clicked = ui
                         .add(util::coloured_button("Depth", depth_colour))
                         .clicked();
@@ -328,21 +1507,145 @@ fn render_selected_node(ui: &mut egui::Ui, node: &Node, depth: usize) -> Option<
                     widget_changed || button_clicked
                 })
             }),
-            transform: util::render_transform(ui, &node.transform),
-            data: render_selected_node_data(ui, node),
+            material: util::render_material(ui, &node.material).map(Box::new),
+            transform: util::render_transform(
+                ui,
+                &node.transform,
+                snap_settings.translation,
+                snap_settings.rotation,
+            ),
+            data: render_selected_node_data(ui, node, graph, snap_settings),
             children: None,
+            locked: None,
         }
         .into_option()
-        .map(|d| GraphCommand::ApplyDiff(node.id, d))
+        .map(|d| GraphCommand::ApplyDiff(node.id, d));
+        diff.into_iter()
+            .chain(render_expressions(ui, node, graph.variables()))
+            .collect()
+    })
+}
+
+/// Lets each of the node's numeric fields be bound to an expression, which can refer to
+/// variables. Expressions are only sent once editing has finished.
+fn render_expressions(
+    ui: &mut egui::Ui,
+    node: &Node,
+    variables: &HashMap<String, f32>,
+) -> Vec<GraphCommand> {
+    let mut commands = vec![];
+    for (field, name) in node.data.float_fields() {
+        let bound = node.bindings.get(*field).map(String::as_str);
+        let new_expression = util::with_label(ui, &format!("{name} expression"), |ui| {
+            ui.vertical(|ui| {
+                let buffer_id = ui.make_persistent_id(("expression_buffer", field));
+                let mut buffer = ui
+                    .data()
+                    .get_temp::<String>(buffer_id)
+                    .unwrap_or_else(|| bound.unwrap_or_default().to_string());
+                let response = ui
+                    .add(egui::TextEdit::singleline(&mut buffer).hint_text("e.g. width / 2"))
+                    .on_hover_text(if variables.is_empty() {
+                        "No variables have been defined".to_string()
+                    } else {
+                        let mut names: Vec<_> = variables.keys().map(String::as_str).collect();
+                        names.sort_unstable();
+                        format!("Variables: {}", names.join(", "))
+                    });
+
+                let source = if response.has_focus() {
+                    buffer.as_str()
+                } else {
+                    bound.unwrap_or_default()
+                };
+                if !source.trim().is_empty() {
+                    match shared::expression::evaluate(source, variables) {
+                        Ok(value) => {
+                            ui.weak(format!("= {value}"));
+                        }
+                        Err(err @ shared::expression::ExpressionError::UnknownVariable(_)) => {
+                            ui.colored_label(
+                                egui::Color32::YELLOW,
+                                format!("{err}; using the value above"),
+                            );
+                        }
+                        Err(err) => {
+                            ui.colored_label(egui::Color32::RED, err.to_string());
+                        }
+                    }
+                }
+
+                if response.has_focus() {
+                    ui.data().insert_temp(buffer_id, buffer);
+                    return None;
+                }
+                ui.data().remove::<String>(buffer_id);
+                let buffer = buffer.trim();
+                let new_expression = (!buffer.is_empty()).then_some(buffer);
+                (response.lost_focus() && new_expression != bound)
+                    .then(|| new_expression.map(str::to_string))
+            })
+            .inner
+        });
+        if let Some(new_expression) = new_expression {
+            commands.push(GraphCommand::SetBinding(
+                node.id,
+                field.to_string(),
+                new_expression,
+            ));
+        }
+    }
+    commands
+}
+
+/// Picks the node that an instance builds, from every node in the graph other than the
+/// instance itself.
+fn render_instance_target(
+    ui: &mut egui::Ui,
+    instance_id: NodeId,
+    target: Option<NodeId>,
+    graph: &Graph,
+) -> Option<Option<NodeId>> {
+    let node_name = |node: &Node| {
+        node.label
+            .clone()
+            .unwrap_or_else(|| node.data.name().to_string())
+    };
+    util::with_label(ui, "Target", |ui| {
+        let selected_text = match target.map(|id| graph.get(id)) {
+            Some(Some(node)) => node_name(node),
+            Some(None) => "(missing)".to_string(),
+            None => "None".to_string(),
+        };
+        let mut new_target = target;
+        egui::ComboBox::from_id_source("instance_target")
+            .selected_text(selected_text)
+            .show_ui(ui, |ui| {
+                for (node_id, depth) in graph.reachable_node_ids() {
+                    if node_id == instance_id {
+                        continue;
+                    }
+                    let node = graph.get(node_id).unwrap();
+                    let text = format!("{}{}", "  ".repeat(depth), node_name(node));
+                    ui.selectable_value(&mut new_target, Some(node_id), text);
+                }
+            });
+        (new_target != target).then_some(new_target)
     })
 }
 
-fn render_selected_node_data(ui: &mut egui::Ui, node: &Node) -> Option<NodeDataDiff> {
+fn render_selected_node_data(
+    ui: &mut egui::Ui,
+    node: &Node,
+    graph: &Graph,
+    snap_settings: &resources::SnapSettings,
+) -> Option<NodeDataDiff> {
     use shared::{
-        BiconvexLens, BiconvexLensDiff, Box, BoxDiff, Capsule, CapsuleDiff, Cone, ConeDiff,
-        Cylinder, CylinderDiff, Intersect, IntersectDiff, Plane, Sphere, SphereDiff, Subtract,
-        SubtractDiff, TaperedCapsule, TaperedCapsuleDiff, Torus, TorusDiff, TorusSector,
-        TorusSectorDiff, Union, UnionDiff,
+        BiconvexLens, BiconvexLensDiff, Box, BoxDiff, Capsule, CapsuleDiff, Comment, CommentDiff,
+        Cone, ConeDiff, Cylinder, CylinderDiff, Instance, InstanceDiff, Intersect, IntersectDiff,
+        Plane, RadialRepeat, RadialRepeatDiff, Sphere, SphereDiff, Subtract, SubtractDiff,
+        TaperedCapsule, TaperedCapsuleDiff, Torus, TorusDiff, TorusSector, TorusSectorDiff,
+        TransformationDiff, Union, UnionDiff,
     };
     use util::dragger_row as row;
 
@@ -478,6 +1781,9 @@ fn render_selected_node_data(ui: &mut egui::Ui, node: &Node) -> Option<NodeDataD
                 chord: row(ui, "Chord", *chord, default.chord),
             })
         }
+        NodeData::Instance(Instance { target }) => apply_diff!(InstanceDiff {
+            target: render_instance_target(ui, node.id, *target, graph),
+        }),
 
         NodeData::Union(Union { factor }) => {
             let default = Union::default();
@@ -497,5 +1803,28 @@ fn render_selected_node_data(ui: &mut egui::Ui, node: &Node) -> Option<NodeDataD
                 factor: util::factor_slider(ui, *factor, default.factor)
             })
         }
+        NodeData::RadialRepeat(RadialRepeat { count }) => {
+            let default = RadialRepeat::default();
+            apply_diff!(RadialRepeatDiff {
+                count: util::count_row(ui, "Count", *count, default.count, RadialRepeat::MAX_COUNT)
+            })
+        }
+
+        NodeData::Transformation(transformation) => {
+            // The node's own transform is edited above, so this is headed to tell them apart
+            ui.label("Applied to children");
+            ui.end_row();
+            util::render_transform(
+                ui,
+                &transformation.transform(),
+                snap_settings.translation,
+                snap_settings.rotation,
+            )
+            .map(|diff| TransformationDiff::from(diff).into())
+        }
+
+        NodeData::Comment(Comment { text }) => apply_diff!(CommentDiff {
+            text: util::with_label(ui, "Text", |ui| util::text_edit(ui, text)),
+        }),
     }
 }
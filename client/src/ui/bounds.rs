@@ -0,0 +1,101 @@
+use bevy::{
+    prelude::*,
+    render::mesh::{Indices, PrimitiveTopology},
+};
+use shared::{Graph, NodeId};
+
+use super::SelectedNode;
+use crate::{animation::AnimatedGraph, resources};
+
+/// Marks the wireframe boxes drawn around the selected nodes. These are separate from
+/// the generated mesh, so they're never part of it.
+#[derive(Component)]
+pub(super) struct SelectionBounds;
+
+/// Draws a box around each selected node's subtree while the overlay is enabled. The
+/// boxes are only rebuilt when the selection, the graph or the overlay changes.
+pub(super) fn selection_bounds(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut last_selection: Local<Vec<NodeId>>,
+    overlays: Res<resources::ViewportOverlays>,
+    selected_node: Res<SelectedNode>,
+    graph: Res<Graph>,
+    animated_graph: Res<AnimatedGraph>,
+    existing_bounds: Query<Entity, With<SelectionBounds>>,
+) {
+    // The selection is mutably borrowed by the UI every frame, so compare it directly
+    // instead of relying on change detection
+    let selection_changed = *last_selection != selected_node.ids();
+    if !(selection_changed
+        || overlays.is_changed()
+        || graph.is_changed()
+        || animated_graph.is_changed())
+    {
+        return;
+    }
+    *last_selection = selected_node.ids().to_vec();
+
+    for entity in existing_bounds.iter() {
+        commands.entity(entity).despawn();
+    }
+    if !overlays.bounds {
+        return;
+    }
+
+    // Match what's being rendered, which may be mid-animation
+    let graph = animated_graph.0.as_ref().unwrap_or(&*graph);
+    let material = materials.add(StandardMaterial {
+        base_color: Color::YELLOW,
+        unlit: true,
+        ..default()
+    });
+    for (min, max) in selected_node
+        .ids()
+        .iter()
+        .filter_map(|node_id| graph.subtree_bounds(*node_id))
+    {
+        commands
+            .spawn_bundle(PbrBundle {
+                mesh: meshes.add(box_lines(min.to_array(), max.to_array())),
+                material: material.clone(),
+                ..default()
+            })
+            .insert(SelectionBounds);
+    }
+}
+
+/// The twelve edges of the box between `min` and `max`, as a line list.
+fn box_lines(min: [f32; 3], max: [f32; 3]) -> Mesh {
+    // Corner `i` takes its x, y and z from `max` if bits 0, 1 and 2 of `i` are set
+    let positions: Vec<[f32; 3]> = (0..8)
+        .map(|i| {
+            let pick = |axis: usize| {
+                if i & (1 << axis) != 0 {
+                    max[axis]
+                } else {
+                    min[axis]
+                }
+            };
+            [pick(0), pick(1), pick(2)]
+        })
+        .collect();
+    // Each edge joins two corners that differ in exactly one bit
+    let indices = (0..8u32)
+        .flat_map(|i| {
+            [1, 2, 4]
+                .into_iter()
+                .filter(move |bit| i & bit == 0)
+                .flat_map(move |bit| [i, i | bit])
+        })
+        .collect();
+
+    let mut mesh = Mesh::new(PrimitiveTopology::LineList);
+    // The PBR pipeline expects normals and UVs, even though an unlit material ignores them
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 1.0, 0.0]; 8]);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0.0, 0.0]; 8]);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
@@ -0,0 +1,144 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+
+use crate::resources::{self, ConnectionStatus};
+
+/// Whether the connection window is open. This is kept in egui's memory, so that the menu
+/// bar can toggle it as well.
+const CONNECTION_OPEN_ID: &str = "connection_open";
+/// What's been typed into the connection window, which is kept until it's used to connect.
+const CONNECTION_FORM_ID: &str = "connection_form";
+
+#[derive(Clone)]
+struct ConnectionForm {
+    host: String,
+    port: u16,
+    room: String,
+    spectator: bool,
+}
+impl ConnectionForm {
+    fn new(network_state: &resources::NetworkState) -> Self {
+        Self {
+            host: network_state.host.clone(),
+            port: network_state.port,
+            room: network_state.room.clone(),
+            spectator: network_state.is_spectator(),
+        }
+    }
+}
+
+pub(super) fn toggle_connection(ctx: &egui::Context) {
+    let mut data = ctx.data();
+    let open = data.get_temp_mut_or_default::<bool>(egui::Id::new(CONNECTION_OPEN_ID));
+    *open = !*open;
+}
+
+/// A short description of the connection, for the status bar.
+pub(super) fn status_text(network_state: &resources::NetworkState) -> String {
+    match network_state.status() {
+        ConnectionStatus::Connecting => format!("Connecting to {}...", network_state.host),
+        ConnectionStatus::Connected => match network_state.peer_count {
+            Some(1) => "Connected: only you".to_string(),
+            Some(count) => format!("Connected: {count} peers"),
+            None => "Connected".to_string(),
+        },
+        ConnectionStatus::Disconnected(None) => "Offline".to_string(),
+        ConnectionStatus::Disconnected(Some(err)) => format!("Disconnected: {err}"),
+    }
+}
+
+/// Shows the window for joining a room on a server, and leaving it again, while it's open.
+pub(super) fn connection_window(
+    mut egui_context: ResMut<EguiContext>,
+    mut network_state: ResMut<resources::NetworkState>,
+) {
+    let ctx = egui_context.ctx_mut();
+    let mut open = ctx
+        .data()
+        .get_temp::<bool>(egui::Id::new(CONNECTION_OPEN_ID))
+        .unwrap_or_default();
+    if !open {
+        return;
+    }
+
+    let form_id = egui::Id::new(CONNECTION_FORM_ID);
+    let mut form = ctx
+        .data()
+        .get_temp::<ConnectionForm>(form_id)
+        .unwrap_or_else(|| ConnectionForm::new(&network_state));
+    let status = network_state.status();
+    egui::Window::new("Connection")
+        .open(&mut open)
+        .collapsible(false)
+        .resizable(false)
+        .show(ctx, |ui| {
+            egui::Grid::new("connection_grid")
+                .num_columns(2)
+                .show(ui, |ui| {
+                    ui.label("Host");
+                    ui.text_edit_singleline(&mut form.host);
+                    ui.end_row();
+
+                    ui.label("Port");
+                    ui.add(egui::DragValue::new(&mut form.port));
+                    ui.end_row();
+
+                    ui.label("Room");
+                    ui.text_edit_singleline(&mut form.room);
+                    ui.end_row();
+                });
+            ui.checkbox(&mut form.spectator, "Join as a spectator")
+                .on_hover_text("Watch the room without being able to edit it");
+
+            ui.separator();
+            let message = status_text(&network_state);
+            match &status {
+                ConnectionStatus::Disconnected(Some(_)) => {
+                    ui.colored_label(egui::Color32::RED, message);
+                }
+                _ => {
+                    ui.label(message);
+                }
+            }
+            if status == ConnectionStatus::Connected {
+                ui.label(format!("In room {:?}", network_state.room));
+            }
+
+            ui.horizontal(|ui| {
+                let (host, room) = (form.host.trim(), form.room.trim());
+                let can_connect = !host.is_empty() && !room.is_empty();
+                let label = match status {
+                    ConnectionStatus::Disconnected(_) => "Connect",
+                    _ => "Switch",
+                };
+                if ui
+                    .add_enabled(can_connect, egui::widgets::Button::new(label))
+                    .on_hover_text("Join the room, replacing the current graph with its own")
+                    .clicked()
+                {
+                    network_state.connect(
+                        host.to_string(),
+                        form.port,
+                        room.to_string(),
+                        form.spectator,
+                    );
+                }
+                let connected = !matches!(status, ConnectionStatus::Disconnected(_));
+                if ui
+                    .add_enabled(connected, egui::widgets::Button::new("Disconnect"))
+                    .on_hover_text("Leave the room, keeping the graph to look at")
+                    .clicked()
+                {
+                    network_state.disconnect();
+                }
+            });
+        });
+    // Start afresh next time, in case the room has been renamed in the meantime
+    if open {
+        ctx.data().insert_temp(form_id, form);
+    } else {
+        ctx.data().remove::<ConnectionForm>(form_id);
+    }
+    ctx.data()
+        .insert_temp(egui::Id::new(CONNECTION_OPEN_ID), open);
+}
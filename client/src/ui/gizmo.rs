@@ -0,0 +1,299 @@
+use bevy::prelude::{
+    Camera, GlobalTransform, Input, Local, MouseButton, Query, Res, ResMut, Windows, With,
+};
+use bevy_egui::{egui, EguiContext};
+use glam::{Quat, Vec3};
+use shared::{Graph, GraphCommand, NodeDiff, NodeId, Transform, TransformDiff};
+
+use super::SelectedNode;
+use crate::{camera::PanOrbitCamera, resources};
+
+/// The on-screen length of the handles, in points.
+const HANDLE_LENGTH: f32 = 80.0;
+/// How close the cursor needs to be to a handle to grab it, in points.
+const GRAB_DISTANCE: f32 = 8.0;
+const AXIS_COLOURS: [egui::Color32; 3] = [
+    egui::Color32::from_rgb(230, 80, 80),
+    egui::Color32::from_rgb(110, 210, 90),
+    egui::Color32::from_rgb(80, 130, 240),
+];
+
+pub(super) struct Drag {
+    node_id: NodeId,
+    axis: usize,
+    last_cursor_position: egui::Pos2,
+}
+
+struct Handle {
+    axis: usize,
+    points: Vec<egui::Pos2>,
+    /// The on-screen movement of the handle's end per unit of translation along its axis.
+    screen_axis: egui::Vec2,
+}
+
+/// Draws a gizmo for the selected node's transform over the viewport, and turns drags on
+/// its handles into diffs. The gizmo works in the parent's space, as that's the space the
+/// node's transform is in, and is rebuilt from the graph every frame, so it always
+/// matches the node's current transform.
+pub(super) fn transform_gizmo(
+    mut egui_context: ResMut<EguiContext>,
+    mut gizmo_state: ResMut<resources::GizmoState>,
+    mut network_state: ResMut<resources::NetworkState>,
+    mut drag: Local<Option<Drag>>,
+    selected_node: Res<SelectedNode>,
+    occupied_screen_space: Res<resources::OccupiedScreenSpace>,
+    windows: Res<Windows>,
+    input_mouse: Res<Input<MouseButton>>,
+    graph: Res<Graph>,
+    camera_query: Query<(&Camera, &GlobalTransform), With<PanOrbitCamera>>,
+) {
+    let drag: &mut Option<Drag> = &mut drag;
+    if !input_mouse.pressed(MouseButton::Left) {
+        *drag = None;
+    }
+    gizmo_state.dragging = drag.is_some();
+
+    // Locked nodes can't be moved, so they don't get a gizmo
+    let node = match selected_node.ids() {
+        [node_id] => graph.get(*node_id).filter(|node| !node.locked),
+        _ => None,
+    };
+    let (node, window, (camera, camera_transform)) =
+        match (node, windows.get_primary(), camera_query.get_single().ok()) {
+            (Some(node), Some(window), Some(camera)) => (node, window, camera),
+            _ => {
+                *drag = None;
+                gizmo_state.dragging = false;
+                return;
+            }
+        };
+
+    // Bevy's window coordinates start at the bottom left, while egui's start at the top left
+    let window_height = window.height();
+    let project = |point: Vec3| {
+        camera
+            .world_to_viewport(
+                camera_transform,
+                bevy::math::Vec3::from_array(point.to_array()),
+            )
+            .map(|position| egui::pos2(position.x, window_height - position.y))
+    };
+    let cursor_position = window
+        .cursor_position()
+        .map(|position| egui::pos2(position.x, window_height - position.y));
+    let viewport = egui::Rect::from_min_max(
+        egui::pos2(occupied_screen_space.left, occupied_screen_space.top),
+        egui::pos2(
+            window.width() - occupied_screen_space.right,
+            window_height - occupied_screen_space.bottom,
+        ),
+    );
+
+    let parent_transform = parent_world_transform(&graph, node.id);
+    let transform = node.transform;
+    let origin = transform_point(&parent_transform, transform.translation);
+    let screen_origin = match project(origin).filter(|p| viewport.contains(*p)) {
+        Some(screen_origin) => screen_origin,
+        None => return,
+    };
+
+    let axes = [Vec3::X, Vec3::Y, Vec3::Z];
+    let screen_axes = axes.map(|axis| {
+        project(transform_point(
+            &parent_transform,
+            transform.translation + axis,
+        ))
+        .map(|end| end - screen_origin)
+        .unwrap_or_default()
+    });
+    let handles: Vec<_> = (0..3)
+        .map(|axis| {
+            let screen_axis = screen_axes[axis];
+            let points = match gizmo_state.mode {
+                // Axes that point straight at the camera can't be dragged along, so hide them
+                resources::GizmoMode::Translate | resources::GizmoMode::Scale
+                    if screen_axis.length_sq() < 1.0 =>
+                {
+                    vec![]
+                }
+                resources::GizmoMode::Translate | resources::GizmoMode::Scale => vec![
+                    screen_origin,
+                    screen_origin + screen_axis.normalized() * HANDLE_LENGTH,
+                ],
+                resources::GizmoMode::Rotate => {
+                    // A ring around the axis, sized so that it's roughly as big as the
+                    // other handles regardless of the zoom level
+                    let units_per_point = 1.0
+                        / screen_axes
+                            .iter()
+                            .map(|axis| axis.length())
+                            .fold(f32::EPSILON, f32::max);
+                    let radius = HANDLE_LENGTH * units_per_point;
+                    let (u, v) = (axes[(axis + 1) % 3], axes[(axis + 2) % 3]);
+                    (0..=32)
+                        .filter_map(|i| {
+                            let angle = i as f32 / 32.0 * std::f32::consts::TAU;
+                            let offset = radius * (angle.cos() * u + angle.sin() * v);
+                            project(transform_point(
+                                &parent_transform,
+                                transform.translation + offset,
+                            ))
+                        })
+                        .collect()
+                }
+            };
+            Handle {
+                axis,
+                points,
+                screen_axis,
+            }
+        })
+        .collect();
+
+    let ctx = egui_context.ctx_mut();
+    if drag.is_none() && input_mouse.just_pressed(MouseButton::Left) && !ctx.wants_pointer_input() {
+        if let Some(cursor_position) = cursor_position.filter(|p| viewport.contains(*p)) {
+            *drag = closest_handle(&handles, cursor_position).map(|handle| Drag {
+                node_id: node.id,
+                axis: handle.axis,
+                last_cursor_position: cursor_position,
+            });
+            gizmo_state.dragging = drag.is_some();
+        }
+    }
+
+    if let (Some(drag), Some(cursor_position)) = (drag.as_mut(), cursor_position) {
+        let delta = cursor_position - drag.last_cursor_position;
+        if drag.node_id == node.id && delta != egui::Vec2::ZERO {
+            let screen_axis = handles[drag.axis].screen_axis;
+            let axis = axes[drag.axis];
+            let diff = match gizmo_state.mode {
+                resources::GizmoMode::Translate => {
+                    let amount = delta.dot(screen_axis) / screen_axis.length_sq().max(f32::EPSILON);
+                    TransformDiff {
+                        translation: Some(transform.translation + axis * amount),
+                        rotation: None,
+                        scale: None,
+                    }
+                }
+                resources::GizmoMode::Rotate => {
+                    // Rotate by how far the cursor has moved around the node's origin, in
+                    // the direction that matches the cursor from the camera's point of view
+                    let (from, to) = (
+                        drag.last_cursor_position - screen_origin,
+                        cursor_position - screen_origin,
+                    );
+                    // egui's y axis points down, so this is clockwise on screen
+                    let clockwise_angle = (from.x * to.y - from.y * to.x).atan2(from.dot(to));
+                    let camera_position = Vec3::from(camera_transform.translation().to_array());
+                    let world_axis = parent_transform.rotation * axis;
+                    let facing_camera = world_axis.dot(camera_position - origin) > 0.0;
+                    let angle = if facing_camera {
+                        -clockwise_angle
+                    } else {
+                        clockwise_angle
+                    };
+                    TransformDiff {
+                        translation: None,
+                        rotation: Some(Quat::from_axis_angle(axis, angle) * transform.rotation),
+                        scale: None,
+                    }
+                }
+                resources::GizmoMode::Scale => {
+                    // Scaling is uniform, so every handle scales the whole node
+                    let amount = delta.dot(screen_axis.normalized()) / HANDLE_LENGTH;
+                    TransformDiff {
+                        translation: None,
+                        rotation: None,
+                        scale: Some((transform.scale * (1.0 + amount)).max(0.001)),
+                    }
+                }
+            };
+            if let Some(transform) = diff.into_option() {
+                let diff = NodeDiff {
+                    transform: Some(transform),
+                    ..Default::default()
+                };
+                network_state.send(&[GraphCommand::ApplyDiff(node.id, diff)]);
+            }
+            drag.last_cursor_position = cursor_position;
+        }
+    }
+
+    let hovered_axis = match drag.as_ref() {
+        Some(drag) => Some(drag.axis),
+        None => cursor_position
+            .filter(|_| !ctx.wants_pointer_input())
+            .and_then(|p| closest_handle(&handles, p))
+            .map(|handle| handle.axis),
+    };
+    let painter = ctx
+        .layer_painter(egui::LayerId::new(
+            egui::Order::Middle,
+            egui::Id::new("transform_gizmo"),
+        ))
+        .with_clip_rect(viewport);
+    for handle in &handles {
+        let colour = if hovered_axis == Some(handle.axis) {
+            egui::Color32::YELLOW
+        } else {
+            AXIS_COLOURS[handle.axis]
+        };
+        painter.add(egui::Shape::line(
+            handle.points.clone(),
+            egui::Stroke::new(3.0, colour),
+        ));
+        if let Some(end) = handle.points.last().filter(|_| handle.points.len() == 2) {
+            match gizmo_state.mode {
+                resources::GizmoMode::Scale => painter.rect_filled(
+                    egui::Rect::from_center_size(*end, egui::vec2(10.0, 10.0)),
+                    0.0,
+                    colour,
+                ),
+                _ => painter.circle_filled(*end, 5.0, colour),
+            }
+        }
+    }
+}
+
+/// The transform of everything above `node_id`, composed into one.
+fn parent_world_transform(graph: &Graph, node_id: NodeId) -> Transform {
+    let mut world_transform = Transform::new();
+    let mut parent_id = graph.parent_of(node_id);
+    while let Some(node) = parent_id.and_then(|id| graph.get(id)) {
+        if let Some(transform) = node.data.children_transform() {
+            world_transform = transform.compose(&world_transform);
+        }
+        world_transform = node.transform.compose(&world_transform);
+        parent_id = graph.parent_of(node.id);
+    }
+    world_transform
+}
+
+/// Matches the order that `shared::mesh` applies transforms in: scale, rotate, translate.
+fn transform_point(transform: &Transform, point: Vec3) -> Vec3 {
+    transform.translation + transform.rotation * (transform.scale * point)
+}
+
+fn closest_handle(handles: &[Handle], position: egui::Pos2) -> Option<&Handle> {
+    handles
+        .iter()
+        .map(|handle| {
+            let distance = handle
+                .points
+                .windows(2)
+                .map(|segment| distance_to_segment(position, segment[0], segment[1]))
+                .fold(f32::INFINITY, f32::min);
+            (handle, distance)
+        })
+        .filter(|(_, distance)| *distance <= GRAB_DISTANCE)
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(handle, _)| handle)
+}
+
+fn distance_to_segment(position: egui::Pos2, start: egui::Pos2, end: egui::Pos2) -> f32 {
+    let segment = end - start;
+    let t =
+        ((position - start).dot(segment) / segment.length_sq().max(f32::EPSILON)).clamp(0.0, 1.0);
+    position.distance(start + segment * t)
+}
@@ -0,0 +1,100 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+use shared::NodeDataMeta;
+
+use super::SHORTCUTS;
+use crate::resources::CameraBindings;
+
+/// Whether the help window is open. This is kept in egui's memory, so that the menu bar
+/// can toggle it as well.
+const HELP_OPEN_ID: &str = "help_open";
+
+pub(super) fn toggle_help(ctx: &egui::Context) {
+    let mut data = ctx.data();
+    let open = data.get_temp_mut_or_default::<bool>(egui::Id::new(HELP_OPEN_ID));
+    *open = !*open;
+}
+
+/// Shows the help window while it's open, and toggles it when `?` is typed outside of a
+/// text field.
+pub(super) fn help_window(mut egui_context: ResMut<EguiContext>, bindings: Res<CameraBindings>) {
+    let ctx = egui_context.ctx_mut();
+    let toggle_pressed = !ctx.wants_keyboard_input()
+        && ctx
+            .input()
+            .events
+            .iter()
+            .any(|event| matches!(event, egui::Event::Text(text) if text == "?"));
+    if toggle_pressed {
+        toggle_help(ctx);
+    }
+
+    let mut open = ctx
+        .data()
+        .get_temp::<bool>(egui::Id::new(HELP_OPEN_ID))
+        .unwrap_or_default();
+    if !open {
+        return;
+    }
+    egui::Window::new("Help")
+        .open(&mut open)
+        .collapsible(false)
+        .show(ctx, |ui| {
+            for (heading, rows) in help_sections(&bindings) {
+                ui.heading(heading);
+                egui::Grid::new(heading)
+                    .num_columns(2)
+                    .spacing([40.0, 4.0])
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for (input, description) in rows {
+                            ui.label(input);
+                            ui.label(description);
+                            ui.end_row();
+                        }
+                    });
+            }
+        });
+    ctx.data().insert_temp(egui::Id::new(HELP_OPEN_ID), open);
+}
+
+/// The contents of the help window, as headings with rows of `(input, description)`. New
+/// keyboard shortcuts only need to be added to [SHORTCUTS] to show up here.
+fn help_sections(bindings: &CameraBindings) -> Vec<(&'static str, Vec<(String, String)>)> {
+    let camera = vec![
+        (format!("{:?} drag", bindings.orbit), "Orbit".to_string()),
+        (format!("{:?} drag", bindings.pan), "Pan".to_string()),
+        (
+            format!("{:?} drag", bindings.zoom),
+            "Zoom, by dragging sideways".to_string(),
+        ),
+    ];
+
+    let mut shortcuts: Vec<_> = SHORTCUTS
+        .iter()
+        .map(|(keys, description)| (keys.to_string(), description.to_string()))
+        .collect();
+    shortcuts.push(("?".to_string(), "Show or hide this window".to_string()));
+    shortcuts.push((
+        "Ctrl + drag an angle".to_string(),
+        "Snap the angle, to 15° if rotation snapping is off".to_string(),
+    ));
+
+    let mut categories: Vec<(String, String)> = vec![];
+    for default in shared::NODE_DATA_DEFAULTS {
+        let category = format!("{:?}", default.category());
+        match categories.iter_mut().find(|(c, _)| *c == category) {
+            Some((_, names)) => {
+                names.push_str(", ");
+                names.push_str(default.name());
+            }
+            None => categories.push((category, default.name().to_string())),
+        }
+    }
+
+    vec![
+        ("Camera", camera),
+        ("Shortcuts", shortcuts),
+        ("Node types", categories),
+    ]
+}
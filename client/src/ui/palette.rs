@@ -0,0 +1,120 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+use shared::NodeCategory;
+
+/// The chosen palette. This is kept in egui's memory, so that any widget can look it up.
+const PALETTE_ID: &str = "palette";
+/// Where settings are kept between sessions, relative to the working directory.
+#[cfg(not(target_arch = "wasm32"))]
+const SETTINGS_PATH: &str = "exoform-settings.json";
+
+/// The colours that node categories are shown in.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum Palette {
+    #[default]
+    Standard,
+    /// Okabe and Ito's colours, which stay distinct with the common kinds of colour
+    /// blindness. Each category is also marked with its own symbol, so that colour isn't
+    /// the only cue.
+    ColourBlind,
+}
+impl Palette {
+    pub const ALL: [Palette; 2] = [Palette::Standard, Palette::ColourBlind];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Palette::Standard => "Standard",
+            Palette::ColourBlind => "Colour-blind",
+        }
+    }
+
+    fn key(&self) -> &'static str {
+        match self {
+            Palette::Standard => "standard",
+            Palette::ColourBlind => "colour_blind",
+        }
+    }
+
+    pub fn current(ctx: &egui::Context) -> Palette {
+        ctx.data()
+            .get_temp(egui::Id::new(PALETTE_ID))
+            .unwrap_or_default()
+    }
+
+    pub fn set(ctx: &egui::Context, palette: Palette) {
+        ctx.data().insert_temp(egui::Id::new(PALETTE_ID), palette);
+    }
+
+    pub fn category_colour(&self, category: NodeCategory) -> egui::Color32 {
+        let (r, g, b) = match (self, category) {
+            (Palette::Standard, NodeCategory::Primitive) => (78, 205, 196),
+            (Palette::Standard, NodeCategory::Operation) => (199, 244, 100),
+            (Palette::Standard, NodeCategory::Metadata) => (255, 107, 107),
+            (Palette::Standard, NodeCategory::Transform) => (238, 130, 238),
+            (Palette::ColourBlind, NodeCategory::Primitive) => (86, 180, 233),
+            (Palette::ColourBlind, NodeCategory::Operation) => (230, 159, 0),
+            (Palette::ColourBlind, NodeCategory::Metadata) => (240, 228, 66),
+            (Palette::ColourBlind, NodeCategory::Transform) => (204, 121, 167),
+        };
+        egui::Color32::from_rgb(r, g, b)
+    }
+
+    /// `text` marked with its category's symbol, if this palette uses them.
+    pub fn category_label(&self, category: NodeCategory, text: &str) -> String {
+        let symbol = match (self, category) {
+            (Palette::Standard, _) => return text.to_string(),
+            (Palette::ColourBlind, NodeCategory::Primitive) => "●",
+            (Palette::ColourBlind, NodeCategory::Operation) => "■",
+            (Palette::ColourBlind, NodeCategory::Metadata) => "▲",
+            (Palette::ColourBlind, NodeCategory::Transform) => "◆",
+        };
+        format!("{symbol} {text}")
+    }
+}
+
+/// Loads the palette chosen in an earlier session, and saves it whenever it changes.
+pub(super) fn persist_palette(
+    mut egui_context: ResMut<EguiContext>,
+    mut saved: Local<Option<Palette>>,
+) {
+    let ctx = egui_context.ctx_mut();
+    match *saved {
+        None => {
+            let palette = load_palette().unwrap_or_default();
+            Palette::set(ctx, palette);
+            *saved = Some(palette);
+        }
+        Some(saved_palette) => {
+            let palette = Palette::current(ctx);
+            if palette != saved_palette {
+                save_palette(palette);
+                *saved = Some(palette);
+            }
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn load_palette() -> Option<Palette> {
+    let contents = std::fs::read_to_string(SETTINGS_PATH).ok()?;
+    let settings: serde_json::Value = serde_json::from_str(&contents).ok()?;
+    let key = settings.get("palette")?.as_str()?;
+    Palette::ALL
+        .into_iter()
+        .find(|palette| palette.key() == key)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn save_palette(palette: Palette) {
+    let settings = serde_json::json!({ "palette": palette.key() });
+    // Losing the setting isn't worth interrupting anyone over, so failures are ignored
+    let _ = std::fs::write(SETTINGS_PATH, settings.to_string());
+}
+
+#[cfg(target_arch = "wasm32")]
+fn load_palette() -> Option<Palette> {
+    None
+}
+
+#[cfg(target_arch = "wasm32")]
+fn save_palette(_palette: Palette) {}
@@ -0,0 +1,64 @@
+use bevy::{
+    diagnostic::{Diagnostics, FrameTimeDiagnosticsPlugin},
+    prelude::*,
+};
+use bevy_egui::{egui, EguiContext};
+
+use crate::resources;
+
+/// Shows the frame rate and how long the last mesh took to build in the corner of the
+/// viewport, to tell whether the editor is slow to draw or slow to mesh. Frames are only
+/// drawn on input, so the frame rate is that of the last burst of activity.
+pub(super) fn performance_overlay(
+    mut egui_context: ResMut<EguiContext>,
+    overlays: Res<resources::ViewportOverlays>,
+    occupied_screen_space: Res<resources::OccupiedScreenSpace>,
+    diagnostics: Res<Diagnostics>,
+    mesh_generation_result: Res<resources::MeshGenerationResult>,
+) {
+    if !overlays.performance {
+        return;
+    }
+    let average = |id| {
+        diagnostics
+            .get(id)
+            .and_then(|diagnostic| diagnostic.average())
+    };
+    let fps = average(FrameTimeDiagnosticsPlugin::FPS);
+    // Measured in seconds
+    let frame_time = average(FrameTimeDiagnosticsPlugin::FRAME_TIME);
+    let generation_time = match mesh_generation_result.as_ref() {
+        resources::MeshGenerationResult::Successful {
+            generation_time, ..
+        } => Some(*generation_time),
+        _ => None,
+    };
+
+    let margin = 8.0;
+    egui::Area::new("performance_overlay")
+        .anchor(
+            egui::Align2::RIGHT_TOP,
+            [
+                -(occupied_screen_space.right + margin),
+                occupied_screen_space.top + margin,
+            ],
+        )
+        .interactable(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            egui::Frame::popup(ui.style()).show(ui, |ui| {
+                let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+                ui.monospace(format!(
+                    "FPS:   {}",
+                    or_dash(fps.map(|fps| format!("{fps:.1}")))
+                ));
+                ui.monospace(format!(
+                    "Frame: {}",
+                    or_dash(frame_time.map(|seconds| format!("{:.2} ms", seconds * 1000.0)))
+                ));
+                ui.monospace(format!(
+                    "Mesh:  {}",
+                    or_dash(generation_time.map(|time| format!("{} ms", time.as_millis())))
+                ));
+            });
+        });
+}
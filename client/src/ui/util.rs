@@ -1,6 +1,10 @@
 use bevy_egui::egui;
 use glam::{Quat, Vec3};
-use shared::{GraphCommand, NodeData, NodeDataMeta, NodeId, TransformDiff};
+use shared::{
+    GraphCommand, MaterialDiff, NodeCategory, NodeData, NodeDataMeta, NodeId, TransformDiff,
+};
+
+use super::palette::Palette;
 
 pub fn coloured_button(text: &str, color: egui::color::Hsva) -> egui::Button {
     egui::widgets::Button::new(egui::RichText::new(text).color(color)).stroke(egui::Stroke {
@@ -47,6 +51,24 @@ pub fn dragger_with_no_reset(ui: &mut egui::Ui, value: &mut f32) -> egui::Respon
     )
 }
 
+/// Rounds `value` to the nearest multiple of `increment`, if there is one.
+fn snap(value: f32, increment: Option<f32>) -> f32 {
+    match increment {
+        Some(increment) if increment > 0.0 => (value / increment).round() * increment,
+        _ => value,
+    }
+}
+
+/// Like [dragger_with_no_reset], but rounds edited values to the nearest `increment`.
+/// The value is left alone if it wasn't edited, even if it isn't a multiple.
+fn snapped_dragger(ui: &mut egui::Ui, value: &mut f32, increment: Option<f32>) -> bool {
+    let old_value = *value;
+    if dragger_with_no_reset(ui, value).changed() {
+        *value = snap(*value, increment);
+    }
+    *value != old_value
+}
+
 fn dragger(ui: &mut egui::Ui, value: f32, default_value: f32) -> Option<f32> {
     with_reset_button(ui, value, default_value, |ui, value| {
         dragger_with_no_reset(ui, value).changed()
@@ -57,33 +79,105 @@ pub fn dragger_row(ui: &mut egui::Ui, label: &str, value: f32, default_value: f3
     with_label(ui, label, |ui| dragger(ui, value, default_value))
 }
 
+pub fn count_row(
+    ui: &mut egui::Ui,
+    label: &str,
+    value: u32,
+    default_value: u32,
+    max: u32,
+) -> Option<u32> {
+    with_label(ui, label, |ui| {
+        with_reset_button(ui, value, default_value, |ui, value| {
+            ui.add(
+                egui::widgets::DragValue::new(value)
+                    .speed(0.05)
+                    .clamp_range(0..=max),
+            )
+            .changed()
+        })
+    })
+}
+
 pub fn vec3(ui: &mut egui::Ui, value: Vec3, default_value: Vec3) -> Option<Vec3> {
+    snapped_vec3(ui, value, default_value, None)
+}
+
+pub fn snapped_vec3(
+    ui: &mut egui::Ui,
+    value: Vec3,
+    default_value: Vec3,
+    increment: Option<f32>,
+) -> Option<Vec3> {
     with_reset_button(ui, value, default_value, |ui, value| {
         ui.horizontal(|ui| {
-            dragger_with_no_reset(ui, &mut value.x).changed()
-                || dragger_with_no_reset(ui, &mut value.y).changed()
-                || dragger_with_no_reset(ui, &mut value.z).changed()
+            snapped_dragger(ui, &mut value.x, increment)
+                || snapped_dragger(ui, &mut value.y, increment)
+                || snapped_dragger(ui, &mut value.z, increment)
         })
         .inner
     })
 }
 
+fn unit_slider(ui: &mut egui::Ui, value: f32, default_value: f32) -> Option<f32> {
+    with_reset_button(ui, value, default_value, |ui, value| {
+        ui.add(egui::widgets::Slider::new(value, 0.0..=1.0))
+            .changed()
+    })
+}
+
 pub fn factor_slider(ui: &mut egui::Ui, value: f32, default_value: f32) -> Option<f32> {
     with_label(ui, "Factor", |ui| {
         with_reset_button(ui, value, default_value, |ui, value| {
-            ui.add(egui::widgets::Slider::new(value, 0.0..=1.0))
+            ui.add(egui::widgets::Slider::new(value, shared::FACTOR_RANGE))
                 .changed()
         })
     })
 }
 
-pub fn angle(ui: &mut egui::Ui, value: Quat, default_value: Quat) -> Option<Quat> {
+/// What rotations snap to, in degrees, when asked to without an increment having been set.
+pub const DEFAULT_ROTATION_SNAP: f32 = 15.0;
+
+/// Edits a rotation as Euler angles. `increment` is in degrees. Rotation is free without
+/// one, but holding Ctrl while dragging snaps to [DEFAULT_ROTATION_SNAP] anyway, and the
+/// snap button rounds all three angles at once.
+pub fn angle(
+    ui: &mut egui::Ui,
+    value: Quat,
+    default_value: Quat,
+    increment: Option<f32>,
+) -> Option<Quat> {
+    let snap_increment = increment.unwrap_or(DEFAULT_ROTATION_SNAP);
+    let drag_increment = if ui.input().modifiers.command {
+        Some(snap_increment)
+    } else {
+        increment
+    };
+    let snapped_drag_angle = |ui: &mut egui::Ui, radians: &mut f32| {
+        let changed = ui.drag_angle(radians).changed();
+        if changed {
+            *radians = snap(radians.to_degrees(), drag_increment).to_radians();
+        }
+        changed
+    };
     with_reset_button(ui, value, default_value, |ui, value| {
         let (mut yaw, mut pitch, mut roll) = value.to_euler(glam::EulerRot::YXZ);
         let response = ui.horizontal(|ui| {
-            ui.drag_angle(&mut yaw).changed()
-                || ui.drag_angle(&mut pitch).changed()
-                || ui.drag_angle(&mut roll).changed()
+            let dragged = snapped_drag_angle(ui, &mut yaw)
+                || snapped_drag_angle(ui, &mut pitch)
+                || snapped_drag_angle(ui, &mut roll);
+            let snap_clicked = ui
+                .small_button("∠")
+                .on_hover_text(format!(
+                    "Round each angle to the nearest {snap_increment}°. \
+                     Hold Ctrl while dragging to snap as you go."
+                ))
+                .clicked();
+            if snap_clicked {
+                for radians in [&mut yaw, &mut pitch, &mut roll] {
+                    *radians = snap(radians.to_degrees(), Some(snap_increment)).to_radians();
+                }
+            }
+            dragged || snap_clicked
         });
         *value = glam::Quat::from_euler(glam::EulerRot::YXZ, yaw, pitch, roll);
         response.inner
@@ -97,16 +191,264 @@ pub fn with_label<T>(ui: &mut egui::Ui, label: &str, f: impl Fn(&mut egui::Ui) -
     result
 }
 
-pub fn render_transform(ui: &mut egui::Ui, transform: &shared::Transform) -> Option<TransformDiff> {
+/// Edits an optional label, only returning the new value once editing has finished.
+/// An empty label is treated as no label.
+pub fn label_edit(ui: &mut egui::Ui, label: Option<&str>, hint: &str) -> Option<Option<String>> {
+    ui.horizontal(|ui| {
+        ui.label("Label");
+
+        let buffer_id = ui.make_persistent_id("label_buffer");
+        let mut buffer = ui
+            .data()
+            .get_temp::<String>(buffer_id)
+            .unwrap_or_else(|| label.unwrap_or_default().to_string());
+        let response = ui.add(egui::TextEdit::singleline(&mut buffer).hint_text(hint));
+        if response.has_focus() {
+            ui.data().insert_temp(buffer_id, buffer);
+            return None;
+        }
+
+        ui.data().remove::<String>(buffer_id);
+        let new_label = (!buffer.is_empty()).then_some(buffer);
+        (response.lost_focus() && new_label.as_deref() != label).then_some(new_label)
+    })
+    .inner
+}
+
+/// Edits a block of text, like [label_edit], only returning the new text once editing
+/// has finished.
+pub fn text_edit(ui: &mut egui::Ui, text: &str) -> Option<String> {
+    let buffer_id = ui.make_persistent_id("text_buffer");
+    let mut buffer = ui
+        .data()
+        .get_temp::<String>(buffer_id)
+        .unwrap_or_else(|| text.to_string());
+    let response = ui.add(egui::TextEdit::multiline(&mut buffer).desired_rows(3));
+    if response.has_focus() {
+        ui.data().insert_temp(buffer_id, buffer);
+        return None;
+    }
+
+    ui.data().remove::<String>(buffer_id);
+    (response.lost_focus() && buffer != text).then_some(buffer)
+}
+
+pub fn render_transform(
+    ui: &mut egui::Ui,
+    transform: &shared::Transform,
+    translation_snap: Option<f32>,
+    rotation_snap: Option<f32>,
+) -> Option<TransformDiff> {
     let tr = transform;
     TransformDiff {
-        translation: with_label(ui, "Translation", |ui| vec3(ui, tr.translation, Vec3::ZERO)),
-        rotation: with_label(ui, "Rotation", |ui| angle(ui, tr.rotation, Quat::IDENTITY)),
+        translation: with_label(ui, "Translation", |ui| {
+            snapped_vec3(ui, tr.translation, Vec3::ZERO, translation_snap)
+        }),
+        rotation: with_label(ui, "Rotation", |ui| {
+            angle(ui, tr.rotation, Quat::IDENTITY, rotation_snap)
+        }),
         scale: with_label(ui, "Scale", |ui| dragger(ui, tr.scale, 1.0)),
     }
     .into_option()
 }
 
+/// The transform components to apply to every node in a multi-selection. Translation is
+/// tracked per axis, so that moving the selection along one axis keeps the others intact.
+#[derive(Default)]
+pub struct BulkTransformEdit {
+    pub translation: [Option<f32>; 3],
+    pub rotation: Option<Quat>,
+    pub scale: Option<f32>,
+}
+impl BulkTransformEdit {
+    pub fn to_diff(&self, transform: &shared::Transform) -> Option<TransformDiff> {
+        let mut translation = transform.translation;
+        for (axis, value) in self.translation.iter().enumerate() {
+            if let Some(value) = value {
+                translation[axis] = *value;
+            }
+        }
+        TransformDiff {
+            translation: (translation != transform.translation).then_some(translation),
+            rotation: self.rotation,
+            scale: self.scale,
+        }
+        .into_option()
+    }
+}
+
+/// Edits the transforms of several nodes at once. Values are shown from the first
+/// transform, and prefixed with `~` where the transforms disagree.
+pub fn render_bulk_transform(
+    ui: &mut egui::Ui,
+    transforms: &[&shared::Transform],
+    translation_snap: Option<f32>,
+    rotation_snap: Option<f32>,
+) -> Option<BulkTransformEdit> {
+    let first = transforms.first()?;
+    let is_mixed = |f: &dyn Fn(&shared::Transform) -> bool| !transforms.iter().all(|t| f(t));
+    let mixed_translation =
+        [0, 1, 2].map(|axis| is_mixed(&|t| t.translation[axis] == first.translation[axis]));
+    let mixed_rotation = is_mixed(&|t| t.rotation == first.rotation);
+    let mixed_scale = is_mixed(&|t| t.scale == first.scale);
+
+    let edit = BulkTransformEdit {
+        translation: with_label(ui, "Translation", |ui| {
+            with_reset_button(ui, [None; 3], [Some(0.0); 3], |ui, axes| {
+                ui.horizontal(|ui| {
+                    let mut changed = false;
+                    for (axis, new_value) in axes.iter_mut().enumerate() {
+                        let mut value = first.translation[axis];
+                        if mixed_dragger(ui, &mut value, mixed_translation[axis]).changed() {
+                            *new_value = Some(snap(value, translation_snap));
+                            changed = true;
+                        }
+                    }
+                    changed
+                })
+                .inner
+            })
+        })
+        .unwrap_or_default(),
+        rotation: with_label(
+            ui,
+            if mixed_rotation {
+                "~Rotation"
+            } else {
+                "Rotation"
+            },
+            |ui| angle(ui, first.rotation, Quat::IDENTITY, rotation_snap),
+        ),
+        scale: with_label(ui, "Scale", |ui| {
+            with_reset_button(ui, first.scale, 1.0, |ui, value| {
+                mixed_dragger(ui, value, mixed_scale).changed()
+            })
+        }),
+    };
+    (edit.translation.iter().any(Option::is_some)
+        || edit.rotation.is_some()
+        || edit.scale.is_some())
+    .then_some(edit)
+}
+
+fn mixed_dragger(ui: &mut egui::Ui, value: &mut f32, mixed: bool) -> egui::Response {
+    ui.add(
+        egui::widgets::DragValue::new(value)
+            .fixed_decimals(2)
+            .speed(0.01)
+            .prefix(if mixed { "~" } else { "" }),
+    )
+}
+
+/// Edits a linear RGB colour with egui's picker, sliders for either its RGB or its HSV
+/// components, and a field for its sRGB hex code. Every component stays within 0..=1.
+pub fn colour_edit(ui: &mut egui::Ui, id_source: &str, rgb: &mut [f32; 3]) -> bool {
+    let mut changed = egui::widgets::color_picker::color_edit_button_rgb(ui, rgb).changed();
+
+    // The choice between RGB and HSV is shared by every colour
+    let hsv_id = egui::Id::new("colour_edit_hsv");
+    let hsv = ui.data().get_temp::<bool>(hsv_id).unwrap_or_default();
+    if ui
+        .selectable_label(hsv, "HSV")
+        .on_hover_text("Edit the hue, saturation and value instead of red, green and blue")
+        .clicked()
+    {
+        ui.data().insert_temp(hsv_id, !hsv);
+    }
+
+    let mut components = if hsv {
+        let hsva = egui::color::Hsva::from_rgb(*rgb);
+        [hsva.h, hsva.s, hsva.v]
+    } else {
+        *rgb
+    };
+    let prefixes = if hsv {
+        ["H ", "S ", "V "]
+    } else {
+        ["R ", "G ", "B "]
+    };
+    let mut components_changed = false;
+    for (component, prefix) in components.iter_mut().zip(prefixes) {
+        components_changed |= ui
+            .add(
+                egui::DragValue::new(component)
+                    .clamp_range(0.0..=1.0)
+                    .speed(0.005)
+                    .prefix(prefix),
+            )
+            .changed();
+    }
+    if components_changed {
+        *rgb = if hsv {
+            let [h, s, v] = components;
+            egui::color::Hsva::new(h, s, v, 1.0).to_rgb()
+        } else {
+            components
+        };
+        changed = true;
+    }
+
+    let buffer_id = ui.make_persistent_id((id_source, "hex_buffer"));
+    let mut buffer = ui
+        .data()
+        .get_temp::<String>(buffer_id)
+        .unwrap_or_else(|| rgb_to_hex(*rgb));
+    let response = ui.add(egui::TextEdit::singleline(&mut buffer).desired_width(64.0));
+    if response.has_focus() {
+        ui.data().insert_temp(buffer_id, buffer);
+    } else {
+        ui.data().remove::<String>(buffer_id);
+        if let Some(parsed) = response.lost_focus().then(|| hex_to_rgb(&buffer)).flatten() {
+            changed |= parsed != *rgb;
+            *rgb = parsed;
+        }
+    }
+
+    changed
+}
+
+/// Formats a linear colour as an sRGB hex code, like `#ff8000`.
+fn rgb_to_hex([r, g, b]: [f32; 3]) -> String {
+    let [r, g, b, _] = egui::Color32::from(egui::Rgba::from_rgb(r, g, b)).to_array();
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+/// Parses an sRGB hex code, with or without the leading `#`, into a linear colour.
+fn hex_to_rgb(hex: &str) -> Option<[f32; 3]> {
+    let hex = hex.trim().trim_start_matches('#');
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let [_, r, g, b] = u32::from_str_radix(hex, 16).ok()?.to_be_bytes();
+    let rgba = egui::Rgba::from(egui::Color32::from_rgb(r, g, b));
+    Some([rgba.r(), rgba.g(), rgba.b()])
+}
+
+pub fn render_material(ui: &mut egui::Ui, material: &shared::Material) -> Option<MaterialDiff> {
+    let m = material;
+    let default = shared::Material::default();
+    MaterialDiff {
+        metallic: with_label(ui, "Metallic", |ui| {
+            unit_slider(ui, m.metallic, default.metallic)
+        }),
+        roughness: with_label(ui, "Roughness", |ui| {
+            unit_slider(ui, m.roughness, default.roughness)
+        }),
+        emissive: with_label(ui, "Emissive", |ui| {
+            with_reset_button(ui, m.emissive, default.emissive, |ui, (r, g, b)| {
+                let mut rgb = [*r, *g, *b];
+                let changed = colour_edit(ui, "emissive", &mut rgb);
+                [*r, *g, *b] = rgb;
+                changed
+            })
+        }),
+        opacity: with_label(ui, "Opacity", |ui| {
+            unit_slider(ui, m.opacity, default.opacity)
+        }),
+    }
+    .into_option()
+}
+
 pub fn render_add_dropdown(
     ui: &mut egui::Ui,
     response: egui::Response,
@@ -117,10 +459,16 @@ pub fn render_add_dropdown(
         if response.clicked() {
             ui.memory().toggle_popup(popup_id);
         }
-        egui::popup_below_widget(ui, popup_id, &response, |ui| {
+        let hidden_before = hidden_categories(ui);
+        let new_node_data = egui::popup_below_widget(ui, popup_id, &response, |ui| {
             ui.set_min_width(200.0);
             render_add_buttons(ui, include_primitives)
-        })?
+        });
+        // Any click closes the popup, so reopen it if the click was on the legend
+        if hidden_categories(ui) != hidden_before {
+            ui.memory().open_popup(popup_id);
+        }
+        new_node_data?
     })
     .inner
 }
@@ -136,26 +484,64 @@ pub fn render_add_button_max_width(
     render_add_dropdown(ui, response, true)
 }
 
+/// The categories hidden from the add menus. These are shared by every add menu, and
+/// kept between openings.
+const HIDDEN_CATEGORIES_ID: &str = "add_menu_hidden_categories";
+
+fn hidden_categories(ui: &egui::Ui) -> Vec<NodeCategory> {
+    ui.data()
+        .get_temp(egui::Id::new(HIDDEN_CATEGORIES_ID))
+        .unwrap_or_default()
+}
+
+/// Lists the node types that can be added, with a legend of their categories' colours
+/// that can be clicked to hide or show each category. Without primitives, only the types
+/// that can have children are listed, for when the new node will be a parent.
 pub fn render_add_buttons(ui: &mut egui::Ui, include_primitives: bool) -> Option<NodeData> {
-    let mut new_node_data = None;
-    for default in shared::NODE_DATA_DEFAULTS.iter() {
-        let category_color = match default.category() {
-            shared::NodeCategory::Primitive => {
-                if !include_primitives {
-                    continue;
+    let defaults: Vec<_> = shared::NODE_DATA_DEFAULTS
+        .iter()
+        .filter(|default| include_primitives || default.can_have_children())
+        .collect();
+    let mut categories = vec![];
+    for default in &defaults {
+        if !categories.contains(&default.category()) {
+            categories.push(default.category());
+        }
+    }
+
+    let palette = Palette::current(ui.ctx());
+    // Filtering only makes sense when there's more than one category to choose from
+    let mut hidden = vec![];
+    if categories.len() > 1 {
+        hidden = hidden_categories(ui);
+        ui.horizontal(|ui| {
+            for category in &categories {
+                let shown = !hidden.contains(category);
+                let label = palette.category_label(*category, &format!("{category:?}"));
+                let text = egui::RichText::new(label).color(palette.category_colour(*category));
+                if ui.selectable_label(shown, text).clicked() {
+                    if shown {
+                        hidden.push(*category);
+                    } else {
+                        hidden.retain(|c| c != category);
+                    }
                 }
-                egui::Color32::from_rgb(78, 205, 196)
             }
-            shared::NodeCategory::Operation => egui::Color32::from_rgb(199, 244, 100),
-            shared::NodeCategory::Metadata => egui::Color32::from_rgb(255, 107, 107),
-            shared::NodeCategory::Transform => egui::Color32::from_rgb(238, 130, 238),
-        };
-        if ui
-            .add(egui::widgets::Button::new(
-                egui::RichText::new(default.name()).color(category_color),
-            ))
-            .clicked()
-        {
+        });
+        ui.separator();
+        ui.data()
+            .insert_temp(egui::Id::new(HIDDEN_CATEGORIES_ID), hidden.clone());
+    }
+
+    let mut new_node_data = None;
+    for default in defaults {
+        if hidden.contains(&default.category()) {
+            continue;
+        }
+        let category = default.category();
+        let text = egui::RichText::new(palette.category_label(category, default.name()))
+            .color(palette.category_colour(category));
+        if ui.add(egui::widgets::Button::new(text)).clicked() {
             new_node_data = Some(default.clone());
         }
     }
@@ -90,9 +90,69 @@ pub fn node_type(
     let change_field_checks = fields
         .iter()
         .map(|(ident, _, _)| quote! { self.#ident.is_some() });
+    let bound = |attrs: &HashMap<String, Expr>, key: &str| match attrs.get(key) {
+        Some(value) => quote! { Some(#value) },
+        None => quote! { None },
+    };
+    let checked = |attrs: &HashMap<String, Expr>| {
+        matches!(
+            attrs.get("checked"),
+            Some(Expr::Lit(ExprLit {
+                lit: Lit::Bool(LitBool { value: true, .. }),
+                ..
+            }))
+        )
+    };
+    // `FieldValue` is implemented for `Option<T>`, so this works for both the struct and its diff
+    let valid_checks: Vec<_> = fields
+        .iter()
+        .map(|(ident, _, _)| quote! { FieldValue::is_valid(&self.#ident) })
+        .collect();
+    let bound_checks: Vec<_> = fields
+        .iter()
+        .filter(|(_, _, attrs)| checked(attrs))
+        .map(|(ident, _, attrs)| {
+            let (min, max) = (bound(attrs, "min"), bound(attrs, "max"));
+            quote! { FieldValue::is_within(&self.#ident, #min, #max) }
+        })
+        .collect();
+    let merge_fields = fields
+        .iter()
+        .map(|(ident, _, _)| quote! { #ident: newer.#ident.or(self.#ident) });
+    let lerp_fields: Vec<_> = fields
+        .iter()
+        .map(|(ident, _, _)| quote! { #ident: FieldValue::lerp(&self.#ident, &other.#ident, t) })
+        .collect();
+    let into_diff_fields = fields
+        .iter()
+        .map(|(ident, _, _)| quote! { #ident: Some(data.#ident) });
+    // Only `f32` fields can be bound to variables
+    let float_fields: Vec<_> = fields
+        .iter()
+        .filter(|(_, ty, _)| quote!(#ty).to_string() == "f32")
+        .collect();
+    let float_field_names = float_fields.iter().map(|(ident, _, attrs)| {
+        let field = ident.to_string();
+        let name = attrs.get("name").expect("expected name field in attribute");
+        quote! { (#field, #name) }
+    });
+    let set_float_arms = float_fields.iter().map(|(ident, _, _)| {
+        let field = ident.to_string();
+        quote! { #field => { self.#ident = value; true } }
+    });
+    let field_metas = fields.iter().map(|(ident, ty, attrs)| {
+        let field = ident.to_string();
+        let name = attrs.get("name").expect("expected name field in attribute");
+        let ty = quote!(#ty).to_string().replace(' ', "");
+        let (min, max) = (bound(attrs, "min"), bound(attrs, "max"));
+        let checked = checked(attrs);
+        quote! {
+            FieldMeta { field: #field, name: #name, ty: #ty, min: #min, max: #max, checked: #checked }
+        }
+    });
     let apply_stmts = fields
         .iter()
-        .map(|(ident, _, _)| quote! { self.#ident = diff.#ident.unwrap_or(self.#ident) });
+        .map(|(ident, _, _)| quote! { if let Some(value) = diff.#ident { self.#ident = value } });
 
     let ts = quote! {
         #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -106,6 +166,19 @@ pub fn node_type(
                 }
             }
         }
+        impl #item_name {
+            pub fn is_valid(&self) -> bool {
+                #(#valid_checks)&&*
+            }
+            pub fn is_within_bounds(&self) -> bool {
+                true #(&& #bound_checks)*
+            }
+            pub fn lerp(&self, other: &Self, t: f32) -> Self {
+                Self {
+                    #(#lerp_fields),*
+                }
+            }
+        }
         impl Default for #item_name {
             fn default() -> Self {
                 Self::new()
@@ -131,10 +204,48 @@ pub fn node_type(
                 let has_changes = #(#change_field_checks)||*;
                 has_changes.then_some(self)
             }
+            pub fn is_valid(&self) -> bool {
+                #(#valid_checks)&&*
+            }
+            pub fn is_within_bounds(&self) -> bool {
+                true #(&& #bound_checks)*
+            }
+            pub fn merge(self, newer: Self) -> Self {
+                Self {
+                    #(#merge_fields),*
+                }
+            }
+            pub fn lerp(&self, other: &Self, t: f32) -> Self {
+                Self {
+                    #(#lerp_fields),*
+                }
+            }
+        }
+        impl From<#item_name> for #item_diff_name {
+            fn from(data: #item_name) -> Self {
+                Self {
+                    #(#into_diff_fields),*
+                }
+            }
         }
         impl #item_name {
+            /// Every field, in the order they're declared.
+            pub const FIELDS: &'static [FieldMeta] = &[
+                #(#field_metas),*
+            ];
+            /// The fields that can be bound to variables, as `(field, display name)`.
+            pub const FLOAT_FIELDS: &'static [(&'static str, &'static str)] = &[
+                #(#float_field_names),*
+            ];
+            /// Sets one of the [Self::FLOAT_FIELDS] by name, returning whether it exists.
+            pub fn set_float_field(&mut self, field: &str, value: f32) -> bool {
+                match field {
+                    #(#set_float_arms)*
+                    _ => false,
+                }
+            }
             pub fn apply(&mut self, diff: #item_diff_name) {
-                #(#apply_stmts);*;
+                #(#apply_stmts)*
             }
         }
     };
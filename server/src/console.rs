@@ -0,0 +1,41 @@
+use super::coordinator::{CoordinatorHandle, CoordinatorMessage};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+/// Reads commands for the operator from the server's standard input. Each command is a
+/// line; the only command is `snapshot <room>`, which saves a copy of an open room's
+/// graph without touching its usual save file.
+pub fn spawn(coordinator: CoordinatorHandle) -> JoinHandle<anyhow::Result<()>> {
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        // Ends when standard input is closed, e.g. when running as a service
+        while let Some(line) = lines.next_line().await? {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            match line.split_once(' ') {
+                Some(("snapshot", room)) => {
+                    let (reply_sender, mut reply_receiver) = mpsc::channel(1);
+                    coordinator
+                        .send(CoordinatorMessage::Snapshot(
+                            room.trim().to_string(),
+                            reply_sender,
+                        ))
+                        .await?;
+                    match reply_receiver.recv().await {
+                        Some(Ok(path)) => println!("console: saved snapshot to {path:?}"),
+                        Some(Err(err)) => println!("console: snapshot failed: {err}"),
+                        None => println!("console: snapshot failed: room stopped"),
+                    }
+                }
+                _ => println!("console: unknown command {line:?}; try `snapshot <room>`"),
+            }
+        }
+
+        anyhow::Ok(())
+    })
+}
@@ -1,29 +1,64 @@
 use super::{
+    console, http,
+    metrics::{ByteCounters, Usage},
     peer::{PeerHandle, PeerMessage},
-    room::{RoomHandle, RoomMessage},
+    room::{Room, RoomHandle, RoomMessage, SnapshotReply},
     util,
 };
-use std::{collections::HashMap, net::SocketAddr};
-use tokio::{sync::mpsc, task::JoinHandle};
+use serde_json::json;
+use shared::protocol::RequestJoin;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tokio_rustls::TlsAcceptor;
 
 pub struct Coordinator {
     peers: HashMap<SocketAddr, PeerHandle>,
     sender: mpsc::Sender<CoordinatorMessage>,
     receiver: mpsc::Receiver<CoordinatorMessage>,
     _listener_task: JoinHandle<anyhow::Result<()>>,
+    _metrics_kicker_task: JoinHandle<anyhow::Result<()>>,
+    _console_task: JoinHandle<anyhow::Result<()>>,
+    _http_task: Option<JoinHandle<anyhow::Result<()>>>,
     rooms: HashMap<String, RoomHandle>,
+    /// How long a room can go without an edit before it's closed.
+    room_idle_timeout: Option<Duration>,
+    /// The byte counters for each peer, along with the room they're in.
+    peer_counters: HashMap<SocketAddr, (Option<String>, Arc<ByteCounters>)>,
 }
 
 #[derive(Debug, Clone)]
 pub enum CoordinatorMessage {
-    PeerJoin(SocketAddr, PeerHandle),
+    PeerJoin(SocketAddr, PeerHandle, Arc<ByteCounters>),
     PeerLeave(SocketAddr),
-    PeerJoinRoom(SocketAddr, String),
+    PeerJoinRoom(SocketAddr, RequestJoin),
+    /// A peer asking to rename the room it's in.
+    RenameRoom(SocketAddr, String),
     RoomShutdown(String),
+    /// The operator asking for a copy of an open room's graph to be saved.
+    Snapshot(String, SnapshotReply),
+    ReportMetrics,
+    /// The HTTP server asking for the bandwidth used since the last report, as JSON.
+    Metrics(MetricsReply),
 }
 
+/// Where the coordinator replies with its metrics.
+pub type MetricsReply = mpsc::Sender<serde_json::Value>;
+
 impl Coordinator {
-    async fn new(host: &str, port: u16) -> anyhow::Result<Self> {
+    /// How often the bandwidth used by each room and peer is logged.
+    const METRICS_INTERVAL: Duration = Duration::from_secs(60);
+
+    async fn new(
+        host: &str,
+        port: u16,
+        tls: Option<TlsAcceptor>,
+        room_idle_timeout: Option<Duration>,
+        metrics_address: Option<(String, u16)>,
+    ) -> anyhow::Result<Self> {
         let (sender, receiver) = mpsc::channel(8);
 
         let listener_task = tokio::spawn({
@@ -33,10 +68,38 @@ impl Coordinator {
                 let listener = tokio::net::TcpListener::bind((host, port)).await?;
                 loop {
                     let (stream, address) = listener.accept().await?;
-                    let peer = PeerHandle::new(CoordinatorHandle(sender.clone()), stream, address);
-                    sender
-                        .send(CoordinatorMessage::PeerJoin(address, peer))
-                        .await?;
+                    let sender = sender.clone();
+                    match tls.clone() {
+                        // Handshake separately, so that a slow peer doesn't hold up the others
+                        Some(acceptor) => {
+                            tokio::spawn(async move {
+                                match acceptor.accept(stream).await {
+                                    Ok(stream) => Self::add_peer(sender, stream, address).await,
+                                    Err(err) => {
+                                        println!("peer {address:?}: TLS handshake failed: {err}");
+                                        anyhow::Ok(())
+                                    }
+                                }
+                            });
+                        }
+                        None => Self::add_peer(sender, stream, address).await?,
+                    }
+                }
+
+                #[allow(unreachable_code)]
+                anyhow::Ok(())
+            }
+        });
+
+        let metrics_kicker_task = tokio::spawn({
+            let sender = sender.clone();
+            async move {
+                let mut interval = tokio::time::interval(Self::METRICS_INTERVAL);
+                // The first tick completes immediately, when there's nothing to report
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    sender.send(CoordinatorMessage::ReportMetrics).await?;
                 }
 
                 #[allow(unreachable_code)]
@@ -44,20 +107,30 @@ impl Coordinator {
             }
         });
 
+        let console_task = console::spawn(CoordinatorHandle(sender.clone()));
+        let http_task = metrics_address
+            .map(|(host, port)| http::spawn(host, port, CoordinatorHandle(sender.clone())));
+
         Ok(Self {
             peers: HashMap::new(),
             sender,
             receiver,
             _listener_task: listener_task,
+            _metrics_kicker_task: metrics_kicker_task,
+            _console_task: console_task,
+            _http_task: http_task,
             rooms: HashMap::new(),
+            room_idle_timeout,
+            peer_counters: HashMap::new(),
         })
     }
 
     async fn run(&mut self) -> anyhow::Result<()> {
         while let Some(msg) = self.receiver.recv().await {
             match msg {
-                CoordinatorMessage::PeerJoin(addr, peer) => {
+                CoordinatorMessage::PeerJoin(addr, peer, counters) => {
                     self.peers.insert(addr, peer);
+                    self.peer_counters.insert(addr, (None, counters));
                     println!("peer {addr:?}: joined");
                 }
                 CoordinatorMessage::PeerLeave(addr) => {
@@ -68,34 +141,208 @@ impl Coordinator {
                         .expect("received peer leave request from untracked peer");
                     peer.send(PeerMessage::SetRoom(None)).await?;
                     self.peers.remove(&addr);
-                    println!("peer {addr:?}: left");
+                    if let Some((_, counters)) = self.peer_counters.remove(&addr) {
+                        println!(
+                            "peer {addr:?}: left ({} since the last report)",
+                            counters.take()
+                        );
+                    } else {
+                        println!("peer {addr:?}: left");
+                    }
                 }
-                CoordinatorMessage::PeerJoinRoom(addr, room_name) => {
+                CoordinatorMessage::PeerJoinRoom(addr, req) => {
                     let peer = self
                         .peers
                         .get(&addr)
                         .cloned()
                         .expect("received peer join request from untracked peer");
 
-                    let room = self.rooms.entry(room_name.clone()).or_insert_with(|| {
-                        RoomHandle::new(room_name, CoordinatorHandle(self.sender.clone()))
+                    if let Some((peer_room, _)) = self.peer_counters.get_mut(&addr) {
+                        *peer_room = Some(req.room.clone());
+                    }
+
+                    let room = self.rooms.entry(req.room.clone()).or_insert_with(|| {
+                        RoomHandle::new(
+                            req.room,
+                            CoordinatorHandle(self.sender.clone()),
+                            self.room_idle_timeout,
+                        )
                     });
 
                     peer.send(PeerMessage::SetRoom(Some(room.clone()))).await?;
-                    room.send(RoomMessage::PeerJoin(addr, peer.clone())).await?;
+                    room.send(RoomMessage::PeerJoin(addr, peer.clone(), req.graph_hash))
+                        .await?;
                 }
+                CoordinatorMessage::RenameRoom(addr, name) => self.rename_room(addr, name).await?,
                 CoordinatorMessage::RoomShutdown(room) => {
-                    self.rooms.remove(&room);
+                    // The room may have already been shut down, or renamed since it asked
+                    let handle = match self.rooms.remove(&room) {
+                        Some(handle) => handle,
+                        None => continue,
+                    };
+                    for (peer_room, _) in self.peer_counters.values_mut() {
+                        if peer_room.as_deref() == Some(room.as_str()) {
+                            *peer_room = None;
+                        }
+                    }
+                    // No more peers are sent to the room, so it can stop
+                    handle.send(RoomMessage::Shutdown).await?;
                     println!("room {room:?}: shutdown");
                 }
+                CoordinatorMessage::Snapshot(room, reply) => match self.rooms.get(&room) {
+                    Some(handle) => handle.send(RoomMessage::Snapshot(reply)).await?,
+                    None => {
+                        // The console may have given up waiting, which isn't our problem
+                        let _ = reply
+                            .send(Err(format!("there's no open room called {room:?}")))
+                            .await;
+                    }
+                },
+                CoordinatorMessage::ReportMetrics => self.report_metrics(),
+                CoordinatorMessage::Metrics(reply) => {
+                    // The request may have been dropped, which isn't our problem
+                    let _ = reply.send(self.metrics()).await;
+                }
             }
         }
 
         anyhow::Ok(())
     }
 
-    pub async fn coordinate(host: &str, port: u16) -> anyhow::Result<()> {
-        let mut coordinator = Coordinator::new(host, port).await?;
+    /// Moves the peer's room to a new name, unless another room, open or saved, already
+    /// has it. The room renames its save file and tells its peers itself.
+    async fn rename_room(&mut self, addr: SocketAddr, name: String) -> anyhow::Result<()> {
+        let peer = self
+            .peers
+            .get(&addr)
+            .cloned()
+            .expect("received rename request from untracked peer");
+        let old_name = match self
+            .peer_counters
+            .get(&addr)
+            .and_then(|(room, _)| room.clone())
+        {
+            Some(old_name) => old_name,
+            None => {
+                return peer
+                    .send(PeerMessage::RenameRejected("not in a room".to_string()))
+                    .await
+            }
+        };
+
+        let rejection = if name.trim().is_empty() {
+            Some("room names can't be empty".to_string())
+        } else if name.contains(['/', '\\', '.']) {
+            // Dots would be taken as the save file's extension
+            Some(format!("{name:?} isn't a valid room name"))
+        } else if self.rooms.contains_key(&name) || Room::has_save(&name) {
+            Some(format!("there's already a room called {name:?}"))
+        } else {
+            None
+        };
+        if let Some(rejection) = rejection {
+            println!("room {old_name:?}: rejected rename from {addr:?}: {rejection}");
+            return peer.send(PeerMessage::RenameRejected(rejection)).await;
+        }
+
+        let room = match self.rooms.remove(&old_name) {
+            Some(room) => room,
+            // The room shut down before the request got here
+            None => return Ok(()),
+        };
+        for (room, _) in self.peer_counters.values_mut() {
+            if room.as_deref() == Some(old_name.as_str()) {
+                *room = Some(name.clone());
+            }
+        }
+        room.send(RoomMessage::Rename(name.clone())).await?;
+        self.rooms.insert(name, room);
+        Ok(())
+    }
+
+    /// Logs the bandwidth used by each peer and room since the last report, and resets
+    /// the counters.
+    fn report_metrics(&self) {
+        let peer_usage = self.peer_usage(ByteCounters::take);
+        for (addr, _, usage) in &peer_usage {
+            println!("peer {addr:?}: {usage}");
+        }
+        for (room, usage) in Self::room_usage(&peer_usage) {
+            match room {
+                Some(room) => println!("room {room:?}: {usage}"),
+                None => println!("not in a room: {usage}"),
+            }
+        }
+    }
+
+    /// The bandwidth used by each peer and room since the last report, without resetting
+    /// the counters. Peers that aren't in a room are counted under a `null` room.
+    fn metrics(&self) -> serde_json::Value {
+        let usage_json = |usage: Usage| json!({ "read": usage.read, "written": usage.written });
+        let peer_usage = self.peer_usage(ByteCounters::get);
+        let peers: Vec<_> = peer_usage
+            .iter()
+            .map(|(addr, room, usage)| {
+                json!({ "address": addr.to_string(), "room": room, "usage": usage_json(*usage) })
+            })
+            .collect();
+        let rooms: Vec<_> = Self::room_usage(&peer_usage)
+            .into_iter()
+            .map(|(room, usage)| json!({ "room": room, "usage": usage_json(usage) }))
+            .collect();
+        json!({ "peers": peers, "rooms": rooms })
+    }
+
+    /// Reads each peer's counters with `read`, along with the room the peer is in.
+    fn peer_usage(
+        &self,
+        read: impl Fn(&ByteCounters) -> Usage,
+    ) -> Vec<(SocketAddr, Option<&str>, Usage)> {
+        self.peer_counters
+            .iter()
+            .map(|(addr, (room, counters))| (*addr, room.as_deref(), read(counters)))
+            .collect()
+    }
+
+    /// Adds up the peers' usage by room.
+    fn room_usage<'a>(
+        peer_usage: &[(SocketAddr, Option<&'a str>, Usage)],
+    ) -> HashMap<Option<&'a str>, Usage> {
+        let mut room_usage: HashMap<Option<&str>, Usage> = HashMap::new();
+        for (_, room, usage) in peer_usage {
+            *room_usage.entry(*room).or_default() += *usage;
+        }
+        room_usage
+    }
+
+    async fn add_peer<S: AsyncRead + AsyncWrite + Send + 'static>(
+        sender: mpsc::Sender<CoordinatorMessage>,
+        stream: S,
+        address: SocketAddr,
+    ) -> anyhow::Result<()> {
+        let counters = Arc::new(ByteCounters::default());
+        let peer = PeerHandle::new(
+            CoordinatorHandle(sender.clone()),
+            stream,
+            address,
+            counters.clone(),
+        );
+        sender
+            .send(CoordinatorMessage::PeerJoin(address, peer, counters))
+            .await?;
+        Ok(())
+    }
+
+    /// Listens for peers, over TLS if an acceptor is given.
+    pub async fn coordinate(
+        host: &str,
+        port: u16,
+        tls: Option<TlsAcceptor>,
+        room_idle_timeout: Option<Duration>,
+        metrics_address: Option<(String, u16)>,
+    ) -> anyhow::Result<()> {
+        let mut coordinator =
+            Coordinator::new(host, port, tls, room_idle_timeout, metrics_address).await?;
         coordinator.run().await
     }
 }
@@ -0,0 +1,189 @@
+use super::coordinator::{CoordinatorHandle, CoordinatorMessage};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+/// Requests are only a request line and headers, so anything bigger isn't read.
+const MAX_REQUEST_SIZE: u64 = 8 * 1024;
+/// How long a client has to send its request before it's dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves the server's metrics over plain HTTP for the operator. The only route is
+/// `GET /metrics`, which gives the bandwidth used by each peer and room since the counters
+/// were last logged, as JSON. `HEAD` is answered with the same headers, but no body.
+pub fn spawn(
+    host: String,
+    port: u16,
+    coordinator: CoordinatorHandle,
+) -> JoinHandle<anyhow::Result<()>> {
+    tokio::spawn(async move {
+        let listener = TcpListener::bind((host.as_str(), port)).await?;
+        println!("http: serving metrics on {host}:{port}");
+        loop {
+            let (stream, address) = listener.accept().await?;
+            let coordinator = coordinator.clone();
+            tokio::spawn(async move {
+                if let Err(err) = respond(stream, &coordinator).await {
+                    println!("http: request from {address:?} failed: {err}");
+                }
+            });
+        }
+
+        #[allow(unreachable_code)]
+        anyhow::Ok(())
+    })
+}
+
+async fn respond(mut stream: TcpStream, coordinator: &CoordinatorHandle) -> anyhow::Result<()> {
+    let request_line = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await
+    {
+        Ok(request_line) => request_line?,
+        Err(_) => anyhow::bail!("timed out waiting for the request"),
+    };
+    let request_line = match request_line {
+        Some(request_line) => request_line,
+        None => return reply(stream, "400 Bad Request", "{}", true).await,
+    };
+
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next(), parts.next());
+    let (status, body) = match (method, path) {
+        (Some("GET" | "HEAD"), Some("/metrics")) => {
+            let (reply_sender, mut reply_receiver) = mpsc::channel(1);
+            coordinator
+                .send(CoordinatorMessage::Metrics(reply_sender))
+                .await?;
+            match reply_receiver.recv().await {
+                Some(metrics) => ("200 OK", metrics.to_string()),
+                None => ("503 Service Unavailable", "{}".to_string()),
+            }
+        }
+        _ => ("404 Not Found", "{}".to_string()),
+    };
+    reply(stream, status, &body, method != Some("HEAD")).await
+}
+
+/// Reads the request line, and skips the headers, which don't matter. Gives `None` if the
+/// request is cut off, or doesn't end within [MAX_REQUEST_SIZE].
+async fn read_request(stream: &mut TcpStream) -> anyhow::Result<Option<String>> {
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_SIZE));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if reader.read_line(&mut header).await? == 0 {
+            return Ok(None);
+        }
+        if header.trim().is_empty() {
+            return Ok(Some(request_line));
+        }
+    }
+}
+
+/// Sends a response with `body`. The headers always describe the body, but it's only sent
+/// if `send_body` is set.
+async fn reply(
+    mut stream: TcpStream,
+    status: &str,
+    body: &str,
+    send_body: bool,
+) -> anyhow::Result<()> {
+    let body_sent = if send_body { body } else { "" };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body_sent}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(stream.shutdown().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends `request` to a server answering metrics with `metrics`, and returns the
+    /// response.
+    async fn send(request: &str, metrics: serde_json::Value) -> String {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        // Stands in for the coordinator
+        let (sender, mut receiver) = mpsc::channel(8);
+        tokio::spawn(async move {
+            while let Some(CoordinatorMessage::Metrics(reply)) = receiver.recv().await {
+                reply.send(metrics.clone()).await.unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            respond(stream, &CoordinatorHandle::from_sender(sender))
+                .await
+                .unwrap();
+        });
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    async fn request(path: &str, metrics: serde_json::Value) -> String {
+        send(
+            &format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            metrics,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_as_json() {
+        let metrics = serde_json::json!({ "peers": [], "rooms": [] });
+        let response = request("/metrics", metrics.clone()).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(body).unwrap(),
+            metrics
+        );
+
+        let response = request("/elsewhere", metrics).await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[tokio::test]
+    async fn head_gets_the_headers_without_the_body() {
+        let metrics = serde_json::json!({ "peers": [], "rooms": [] });
+        let get = request("/metrics", metrics.clone()).await;
+        let head = send(
+            "HEAD /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            metrics.clone(),
+        )
+        .await;
+        let (get_headers, body) = get.split_once("\r\n\r\n").unwrap();
+        assert!(!body.is_empty());
+        assert_eq!(head, format!("{get_headers}\r\n\r\n"));
+
+        let head = send(
+            "HEAD /elsewhere HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            metrics,
+        )
+        .await;
+        assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(head.ends_with("\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn oversized_requests_are_rejected() {
+        // Headers that never end, filling the limit exactly so that nothing is left unread
+        let mut request = "GET /metrics HTTP/1.1\r\nX-Padding: ".to_string();
+        let padding = MAX_REQUEST_SIZE as usize - request.len();
+        request.push_str(&"a".repeat(padding));
+        let response = send(&request, serde_json::json!({})).await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+}
@@ -1,8 +1,14 @@
+mod console;
 mod coordinator;
+mod http;
+mod metrics;
 mod peer;
 mod room;
+mod tls;
 mod util;
 
+use std::{path::PathBuf, time::Duration};
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     use clap::Parser;
@@ -14,10 +20,38 @@ async fn main() -> anyhow::Result<()> {
         host: String,
         #[clap(short, long)]
         port: Option<u16>,
+        /// PEM file with the certificate chain to serve over TLS. Without this and
+        /// `--key`, connections are plaintext.
+        #[clap(long, requires = "key")]
+        cert: Option<PathBuf>,
+        /// PEM file with the PKCS#8 private key for `--cert`.
+        #[clap(long, requires = "cert")]
+        key: Option<PathBuf>,
+        /// Seconds without an edit after which a room is saved and closed, even if peers
+        /// are still connected. Rooms stay open while anyone is connected if not given.
+        #[clap(long)]
+        idle_timeout: Option<u64>,
+        /// Port to serve `GET /metrics` on over plain HTTP. Metrics are only logged if not
+        /// given.
+        #[clap(long)]
+        metrics_port: Option<u16>,
+        /// Host to serve metrics on. They include every peer's address, so they're only
+        /// served locally unless this says otherwise.
+        #[clap(long, default_value_t = String::from("127.0.0.1"))]
+        metrics_host: String,
     }
 
     let args = Args::parse();
     let port = args.port.unwrap_or(shared::DEFAULT_PORT);
+    let tls = match (&args.cert, &args.key) {
+        (Some(cert), Some(key)) => Some(tls::acceptor(cert, key)?),
+        _ => None,
+    };
+
+    let idle_timeout = args.idle_timeout.map(Duration::from_secs);
 
-    coordinator::Coordinator::coordinate(&args.host, port).await
+    let metrics_address = args
+        .metrics_port
+        .map(|port| (args.metrics_host.clone(), port));
+    coordinator::Coordinator::coordinate(&args.host, port, tls, idle_timeout, metrics_address).await
 }
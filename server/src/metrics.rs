@@ -0,0 +1,51 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counts the bytes sent and received over a peer's connection. These are updated by the
+/// peer's socket tasks and periodically collected by the coordinator.
+#[derive(Debug, Default)]
+pub struct ByteCounters {
+    read: AtomicU64,
+    written: AtomicU64,
+}
+impl ByteCounters {
+    pub fn add_read(&self, bytes: usize) {
+        self.read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_written(&self, bytes: usize) {
+        self.written.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the bytes read and written since the last call to [ByteCounters::take].
+    pub fn get(&self) -> Usage {
+        Usage {
+            read: self.read.load(Ordering::Relaxed),
+            written: self.written.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the bytes read and written since the last call, and resets both to zero.
+    pub fn take(&self) -> Usage {
+        Usage {
+            read: self.read.swap(0, Ordering::Relaxed),
+            written: self.written.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Usage {
+    pub read: u64,
+    pub written: u64,
+}
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, rhs: Self) {
+        self.read += rhs.read;
+        self.written += rhs.written;
+    }
+}
+impl std::fmt::Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bytes in, {} bytes out", self.read, self.written)
+    }
+}
@@ -1,15 +1,24 @@
-use std::net::SocketAddr;
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use super::{
     coordinator::{CoordinatorHandle, CoordinatorMessage},
+    metrics::ByteCounters,
     room::{RoomHandle, RoomMessage},
     util,
 };
-use tokio::{net, sync::mpsc, task::JoinHandle};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+};
 
 use shared::{
-    protocol::{PeerIncomingMessage, PeerOutgoingMessage, RequestJoin},
-    GraphChange, GraphCommand,
+    protocol::{CommandId, PeerIncomingMessage, PeerOutgoingMessage, RequestJoin},
+    GraphChange, GraphCommand, GraphCommandError,
 };
 
 pub struct Peer {
@@ -20,23 +29,38 @@ pub struct Peer {
     write_sender: mpsc::Sender<PeerIncomingMessage>,
     coordinator: CoordinatorHandle,
     room: Option<RoomHandle>,
+    spectator: bool,
+    last_resync: Option<Instant>,
 }
 
 #[derive(Debug, Clone)]
 pub enum PeerMessage {
     RequestJoin(RequestJoin),
     Disconnect,
-    GraphCommand(GraphCommand),
+    GraphCommand(CommandId, GraphCommand),
+    RequestResync,
+    RenameRoom(String),
     GraphChange(GraphChange),
+    CommandAccepted(CommandId, Vec<GraphChange>),
+    CommandRejected(CommandId, GraphCommandError),
+    /// The room closed without us leaving it.
+    RoomClosed,
+    RoomRenamed(String),
+    RenameRejected(String),
+    PeerCount(usize),
     SetRoom(Option<RoomHandle>),
 }
 
 impl Peer {
+    /// Resyncs send the whole graph, so a peer can't ask for one more often than this.
+    const MIN_RESYNC_INTERVAL: Duration = Duration::from_secs(5);
+
     async fn handle_message(&mut self, msg: PeerMessage) -> anyhow::Result<()> {
         match msg {
             PeerMessage::RequestJoin(req) => {
+                self.spectator = req.spectator;
                 self.coordinator
-                    .send(CoordinatorMessage::PeerJoinRoom(self.address, req.room))
+                    .send(CoordinatorMessage::PeerJoinRoom(self.address, req))
                     .await?
             }
             PeerMessage::Disconnect => {
@@ -44,25 +68,90 @@ impl Peer {
                     .send(CoordinatorMessage::PeerLeave(self.address))
                     .await?
             }
-            PeerMessage::GraphCommand(gc) => {
-                if let Some(room) = &self.room {
-                    room.send(RoomMessage::GraphCommand(gc)).await?;
+            PeerMessage::GraphCommand(id, gc) => {
+                if self.spectator {
+                    println!("peer {:?}: dropped command from spectator", self.address);
+                    return Ok(());
+                }
+                self.send_to_room(RoomMessage::GraphCommand(self.address, id, Box::new(gc)))
+                    .await;
+            }
+            PeerMessage::RequestResync => {
+                if self
+                    .last_resync
+                    .is_some_and(|last| last.elapsed() < Self::MIN_RESYNC_INTERVAL)
+                {
+                    println!("peer {:?}: dropped resync request", self.address);
+                    return Ok(());
+                }
+                if self.room.is_some() {
+                    self.last_resync = Some(Instant::now());
+                    self.send_to_room(RoomMessage::RequestResync(self.address))
+                        .await;
+                }
+            }
+            PeerMessage::RenameRoom(name) => {
+                if self.spectator {
+                    println!("peer {:?}: dropped rename from spectator", self.address);
+                    return Ok(());
                 }
+                self.coordinator
+                    .send(CoordinatorMessage::RenameRoom(self.address, name))
+                    .await?
             }
             PeerMessage::GraphChange(gc) => {
                 self.write_sender
                     .send(PeerIncomingMessage::GraphChange(gc))
                     .await?;
             }
+            PeerMessage::CommandAccepted(id, changes) => {
+                self.write_sender
+                    .send(PeerIncomingMessage::CommandAccepted(id, changes))
+                    .await?;
+            }
+            PeerMessage::CommandRejected(id, err) => {
+                self.write_sender
+                    .send(PeerIncomingMessage::CommandRejected(id, err))
+                    .await?;
+            }
+            PeerMessage::RoomClosed => {
+                self.room = None;
+                self.write_sender
+                    .send(PeerIncomingMessage::RoomClosed)
+                    .await?;
+            }
+            PeerMessage::RoomRenamed(name) => {
+                self.write_sender
+                    .send(PeerIncomingMessage::RoomRenamed(name))
+                    .await?;
+            }
+            PeerMessage::RenameRejected(reason) => {
+                self.write_sender
+                    .send(PeerIncomingMessage::RenameRejected(reason))
+                    .await?;
+            }
+            PeerMessage::PeerCount(count) => {
+                self.write_sender
+                    .send(PeerIncomingMessage::PeerCount(count))
+                    .await?;
+            }
             PeerMessage::SetRoom(room) => {
-                if let Some(room) = &self.room {
-                    room.send(RoomMessage::PeerLeave(self.address)).await?;
-                }
+                self.send_to_room(RoomMessage::PeerLeave(self.address))
+                    .await;
                 self.room = room;
             }
         }
         Ok(())
     }
+    /// Sends to the peer's room, if it's in one. The room may have stopped before the peer
+    /// heard that it closed, in which case the peer forgets about it.
+    async fn send_to_room(&mut self, msg: RoomMessage) {
+        if let Some(room) = &self.room {
+            if room.send(msg).await.is_err() {
+                self.room = None;
+            }
+        }
+    }
     async fn run(&mut self) {
         while let Some(msg) = self.receiver.recv().await {
             self.handle_message(msg).await.unwrap();
@@ -72,24 +161,34 @@ impl Peer {
 
 util::make_handle_type!(PeerHandle, PeerMessage);
 impl PeerHandle {
-    pub fn new(
+    /// Sends without waiting, failing if the peer has fallen behind or gone away. The
+    /// message is dropped either way.
+    pub fn try_send(&self, msg: PeerMessage) -> Result<(), TrySendError<()>> {
+        self.0.try_send(msg).map_err(|err| match err {
+            TrySendError::Full(_) => TrySendError::Full(()),
+            TrySendError::Closed(_) => TrySendError::Closed(()),
+        })
+    }
+
+    /// Starts a peer on the stream, which is either a plain TCP connection or TLS over one.
+    pub fn new<S: AsyncRead + AsyncWrite + Send + 'static>(
         coordinator: CoordinatorHandle,
-        stream: net::TcpStream,
+        stream: S,
         address: SocketAddr,
+        counters: Arc<ByteCounters>,
     ) -> Self {
         let (sender, receiver) = mpsc::channel(8);
 
-        let (mut read, mut write) = stream.into_split();
+        let (mut read, mut write) = tokio::io::split(stream);
         let read_task = tokio::spawn({
             let sender = sender.clone();
+            let counters = counters.clone();
             async move {
                 loop {
-                    let message = match shared::protocol::read(&mut read).await {
-                        Some(Ok(PeerOutgoingMessage::RequestJoin(req))) => {
-                            PeerMessage::RequestJoin(req)
-                        }
-                        Some(Ok(PeerOutgoingMessage::GraphCommand(cmd))) => {
-                            PeerMessage::GraphCommand(cmd)
+                    let message = match shared::protocol::read_with_size(&mut read).await {
+                        Some(Ok((message, size))) => {
+                            counters.add_read(size);
+                            message
                         }
                         Some(Err(err)) => return Err(err),
                         None => {
@@ -97,6 +196,14 @@ impl PeerHandle {
                             break;
                         }
                     };
+                    let message = match message {
+                        PeerOutgoingMessage::RequestJoin(req) => PeerMessage::RequestJoin(req),
+                        PeerOutgoingMessage::GraphCommand(id, cmd) => {
+                            PeerMessage::GraphCommand(id, cmd)
+                        }
+                        PeerOutgoingMessage::RequestResync => PeerMessage::RequestResync,
+                        PeerOutgoingMessage::RenameRoom(name) => PeerMessage::RenameRoom(name),
+                    };
                     sender.send(message).await?;
                 }
 
@@ -107,7 +214,7 @@ impl PeerHandle {
         let (write_sender, mut write_receiver) = mpsc::channel(8);
         let write_task = tokio::spawn(async move {
             while let Some(message) = write_receiver.recv().await {
-                shared::protocol::write(&mut write, message).await?;
+                counters.add_written(shared::protocol::write(&mut write, message).await?);
             }
 
             anyhow::Ok(())
@@ -121,9 +228,51 @@ impl PeerHandle {
             write_sender,
             coordinator,
             room: None,
+            spectator: false,
+            last_resync: None,
         };
         tokio::spawn(async move { peer.run().await });
 
         Self(sender)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::{NodeData, Sphere};
+
+    fn peer(spectator: bool, room: RoomHandle) -> Peer {
+        let (_, receiver) = mpsc::channel(8);
+        let (write_sender, _) = mpsc::channel(8);
+        let (coordinator, _) = mpsc::channel(8);
+        Peer {
+            address: "127.0.0.1:1234".parse().unwrap(),
+            receiver,
+            _read_task: tokio::spawn(async { anyhow::Ok(()) }),
+            _write_task: tokio::spawn(async { anyhow::Ok(()) }),
+            write_sender,
+            coordinator: CoordinatorHandle::from_sender(coordinator),
+            room: Some(room),
+            spectator,
+            last_resync: None,
+        }
+    }
+
+    /// Whether a command from the peer makes it to its room.
+    async fn command_reaches_room(spectator: bool) -> bool {
+        let (sender, mut receiver) = mpsc::channel(8);
+        let mut peer = peer(spectator, RoomHandle::from_sender(sender));
+        let command = GraphCommand::CreateNewRoot(NodeData::Sphere(Sphere::default()));
+        peer.handle_message(PeerMessage::GraphCommand(CommandId(0), command))
+            .await
+            .unwrap();
+        matches!(receiver.try_recv(), Ok(RoomMessage::GraphCommand(..)))
+    }
+
+    #[tokio::test]
+    async fn spectator_commands_are_dropped() {
+        assert!(!command_reaches_room(true).await);
+        assert!(command_reaches_room(false).await);
+    }
+}
@@ -4,59 +4,161 @@ use super::{
     peer::{PeerHandle, PeerMessage},
     util,
 };
-use shared::{Graph, GraphChange, GraphCommand};
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
-use tokio::{sync::mpsc, task::JoinHandle};
+use shared::{protocol::CommandId, save::Format, Graph, GraphChange, GraphCommand};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+};
 
 pub struct Room {
     name: String,
     peers: HashMap<SocketAddr, PeerHandle>,
-    _save_kicker_task: JoinHandle<anyhow::Result<()>>,
+    /// Peers that couldn't keep up, and are sent the whole graph instead once they have
+    /// room for it, followed by any other messages that were held back meanwhile.
+    lagging: HashMap<SocketAddr, VecDeque<PeerMessage>>,
+    save_kicker_task: JoinHandle<anyhow::Result<()>>,
     graph: Graph,
     receiver: mpsc::Receiver<RoomMessage>,
     coordinator: CoordinatorHandle,
+    idle_timeout: Option<Duration>,
+    last_edit: Instant,
+    /// Whether the room has asked the coordinator to shut it down.
+    shutdown_requested: bool,
+    /// The save file that couldn't be moved when the room was last renamed, which the room
+    /// keeps saving to instead.
+    unmoved_save: Option<PathBuf>,
 }
 
+/// Where a room replies with the path its snapshot was saved to.
+pub type SnapshotReply = mpsc::Sender<Result<PathBuf, String>>;
+
 #[derive(Debug, Clone)]
 pub enum RoomMessage {
-    PeerJoin(SocketAddr, PeerHandle),
+    /// A peer joining, with the hash of the graph it already has, if any.
+    PeerJoin(SocketAddr, PeerHandle, Option<u64>),
     PeerLeave(SocketAddr),
-    GraphCommand(GraphCommand),
+    /// A command from the peer at the given address. Boxed, as commands can be large.
+    GraphCommand(SocketAddr, CommandId, Box<GraphCommand>),
+    /// A peer asking for the whole graph again.
+    RequestResync(SocketAddr),
+    /// The coordinator has moved the room to a new name, which its save file follows.
+    Rename(String),
+    /// Saves a timestamped copy of the graph alongside the room's save file.
+    Snapshot(SnapshotReply),
     Save,
+    /// The coordinator has stopped sending peers to the room, so it can save and stop.
+    Shutdown,
 }
 
 impl Room {
     async fn handle_message(&mut self, msg: RoomMessage) -> anyhow::Result<()> {
         match msg {
-            RoomMessage::PeerJoin(address, peer) => {
-                peer.send(PeerMessage::GraphChange(GraphChange::Initialize(
-                    self.graph.to_components(),
-                )))
-                .await?;
+            RoomMessage::PeerJoin(address, peer, graph_hash) => {
                 self.peers.insert(address, peer);
                 println!("room {:?}: {:?} joined", self.name, address);
+                // A peer rejoining with an up-to-date graph doesn't need the whole thing again
+                if graph_hash != Some(self.graph.content_hash()) {
+                    self.resync(address);
+                }
+                self.broadcast_peer_count();
             }
             RoomMessage::PeerLeave(address) => {
-                self.peers.remove(&address);
+                // Peers may still leave after the room has closed, which shouldn't close it again
+                if self.peers.remove(&address).is_none() {
+                    return Ok(());
+                }
+                self.lagging.remove(&address);
                 println!("room {:?}: {:?} left", self.name, address);
 
                 if self.peers.is_empty() {
+                    self.request_shutdown().await?;
+                } else {
+                    self.broadcast_peer_count();
+                }
+            }
+            RoomMessage::GraphCommand(address, id, gc) => {
+                let changes = match self.graph.apply_command(&gc) {
+                    Ok(changes) => changes,
+                    Err(err) => {
+                        println!("room {:?}: rejected {:?}: {}", self.name, gc, err);
+                        self.send_to(address, PeerMessage::CommandRejected(id, err));
+                        return Ok(());
+                    }
+                };
+                self.last_edit = Instant::now();
+                // A batch's changes come as one change, so peers never see it half-applied
+                for change in &changes {
+                    self.broadcast(change, address);
+                }
+                // The sender gets its changes along with the answer, so that it can swap
+                // its prediction for them in one go
+                self.send_to(address, PeerMessage::CommandAccepted(id, changes));
+                self.catch_up_lagging();
+            }
+            RoomMessage::RequestResync(address) => {
+                if self.peers.contains_key(&address) {
+                    println!("room {:?}: resyncing {:?}", self.name, address);
+                    self.resync(address);
+                }
+            }
+            RoomMessage::Rename(name) => {
+                let old_path = self.path();
+                let old_name = std::mem::replace(&mut self.name, name);
+                // Keep the format the room was saved in
+                let new_path = PathBuf::from("models")
+                    .join(&self.name)
+                    .with_extension(old_path.extension().unwrap_or_default());
+                self.unmoved_save = None;
+                // A room that hasn't been saved yet has nothing to move
+                if old_path.exists() {
+                    if let Err(err) = tokio::fs::rename(&old_path, &new_path).await {
+                        println!(
+                            "room {:?}: couldn't move save to {:?}, so it stays at {:?}: {}",
+                            self.name, new_path, old_path, err
+                        );
+                        self.unmoved_save = Some(old_path);
+                    }
+                }
+                println!("room {:?}: renamed from {:?}", self.name, old_name);
+                let addresses: Vec<_> = self.peers.keys().copied().collect();
+                for address in addresses {
+                    self.send_to(address, PeerMessage::RoomRenamed(self.name.clone()));
+                }
+                // The coordinator didn't know the room by its old name when it was asked
+                // to shut it down, so ask again
+                if self.shutdown_requested {
                     self.coordinator
                         .send(CoordinatorMessage::RoomShutdown(self.name.clone()))
                         .await?;
                 }
             }
-            RoomMessage::GraphCommand(gc) => {
-                let changes = self.graph.apply_command(&gc);
-                for change in changes {
-                    for peer in self.peers.values() {
-                        peer.send(PeerMessage::GraphChange(change.clone())).await?;
-                    }
+            RoomMessage::Snapshot(reply) => {
+                let result = self.snapshot().await;
+                match &result {
+                    Ok(path) => println!("room {:?}: saved snapshot to {:?}", self.name, path),
+                    Err(err) => println!("room {:?}: snapshot failed: {}", self.name, err),
                 }
+                // The console may have given up waiting, which isn't our problem
+                let _ = reply.send(result.map_err(|err| err.to_string())).await;
             }
             RoomMessage::Save => {
+                self.catch_up_lagging();
                 self.save().await?;
+                if self
+                    .idle_timeout
+                    .is_some_and(|timeout| self.last_edit.elapsed() >= timeout)
+                    && !self.peers.is_empty()
+                {
+                    self.close().await?;
+                }
             }
+            RoomMessage::Shutdown => self.shut_down().await?,
         }
         Ok(())
     }
@@ -66,30 +168,216 @@ impl Room {
         }
     }
 
+    /// Sends a change to every peer without waiting on any of them, so that one slow peer
+    /// can't hold up the rest. A peer whose queue is full is marked as lagging and skipped
+    /// until it's caught up with the whole graph. The peer at `sender` is skipped, as it's
+    /// sent the change with its answer.
+    fn broadcast(&mut self, change: &GraphChange, sender: SocketAddr) {
+        for (address, peer) in &self.peers {
+            if *address == sender || self.lagging.contains_key(address) {
+                continue;
+            }
+            match peer.try_send(PeerMessage::GraphChange(change.clone())) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    println!(
+                        "room {:?}: {:?} is too slow, resyncing once it catches up",
+                        self.name, address
+                    );
+                    self.lagging.insert(*address, VecDeque::new());
+                }
+                // The peer is going away, and will leave the room by itself
+                Err(TrySendError::Closed(_)) => {}
+            }
+        }
+    }
+
+    /// Sends a message to one peer without waiting on it, for the same reason as
+    /// [Self::broadcast]. If the peer is lagging, or its queue is full, the message is held
+    /// back until the peer has been sent the whole graph, which has any changes that came
+    /// with the message in it.
+    fn send_to(&mut self, address: SocketAddr, msg: PeerMessage) {
+        let peer = match self.peers.get(&address) {
+            Some(peer) => peer,
+            None => return,
+        };
+        if !self.lagging.contains_key(&address) {
+            match peer.try_send(msg.clone()) {
+                Ok(()) => return,
+                Err(TrySendError::Full(_)) => {
+                    println!(
+                        "room {:?}: {:?} is too slow, resyncing once it catches up",
+                        self.name, address
+                    );
+                }
+                // The peer is going away, and will leave the room by itself
+                Err(TrySendError::Closed(_)) => return,
+            }
+        }
+        let msg = match msg {
+            PeerMessage::CommandAccepted(id, _) => PeerMessage::CommandAccepted(id, vec![]),
+            msg => msg,
+        };
+        self.lagging.entry(address).or_default().push_back(msg);
+    }
+
+    /// Sends the whole graph to the peer, along with anything it's been held back from.
+    fn resync(&mut self, address: SocketAddr) {
+        self.lagging.entry(address).or_default();
+        self.catch_up_lagging();
+    }
+
+    /// Tells every peer how many are in the room. This is only informational, so a peer
+    /// that can't take it right now just misses out until the next join or leave.
+    fn broadcast_peer_count(&self) {
+        for peer in self.peers.values() {
+            let _ = peer.try_send(PeerMessage::PeerCount(self.peers.len()));
+        }
+    }
+
+    /// Sends the whole graph to each lagging peer that has room for it, followed by the
+    /// messages it was held back from. A peer that doesn't have room for all of them stays
+    /// lagging, as it'll miss changes until it does, and is sent the graph again next time.
+    fn catch_up_lagging(&mut self) {
+        if self.lagging.is_empty() {
+            return;
+        }
+        let components = self.graph.to_components();
+        let peers = &self.peers;
+        let name = &self.name;
+        self.lagging.retain(|address, held_back| {
+            let peer = match peers.get(address) {
+                Some(peer) => peer,
+                None => return false,
+            };
+            match peer.try_send(PeerMessage::GraphChange(GraphChange::Initialize(
+                components.clone(),
+            ))) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => return true,
+                Err(TrySendError::Closed(_)) => return false,
+            }
+            while let Some(msg) = held_back.front() {
+                match peer.try_send(msg.clone()) {
+                    Ok(()) => {
+                        held_back.pop_front();
+                    }
+                    Err(TrySendError::Full(_)) => return true,
+                    Err(TrySendError::Closed(_)) => return false,
+                }
+            }
+            println!("room {name:?}: {address:?} caught up");
+            false
+        });
+    }
+
+    /// Disconnects everyone from the room after it's been idle, so that it can shut down.
+    async fn close(&mut self) -> anyhow::Result<()> {
+        println!("room {:?}: closing after being idle", self.name);
+        self.remove_peers();
+        self.request_shutdown().await
+    }
+
+    /// Tells every peer that the room's closed, and forgets them.
+    fn remove_peers(&mut self) {
+        for (_, peer) in self.peers.drain() {
+            // The room won't be around to catch a lagging peer up, so it's told by a task
+            // of its own instead of holding up the room
+            if let Err(TrySendError::Full(_)) = peer.try_send(PeerMessage::RoomClosed) {
+                tokio::spawn(async move { peer.send(PeerMessage::RoomClosed).await });
+            }
+        }
+        self.lagging.clear();
+    }
+
+    /// Asks the coordinator to shut the room down. It stops sending peers here, and then
+    /// tells the room to stop.
+    async fn request_shutdown(&mut self) -> anyhow::Result<()> {
+        if std::mem::replace(&mut self.shutdown_requested, true) {
+            return Ok(());
+        }
+        self.coordinator
+            .send(CoordinatorMessage::RoomShutdown(self.name.clone()))
+            .await
+    }
+
+    /// Saves the room for the last time and stops taking messages, which ends [Room::run]
+    /// once the ones already sent are handled. Peers that joined after the room asked to
+    /// shut down are told it's closed.
+    async fn shut_down(&mut self) -> anyhow::Result<()> {
+        println!("room {:?}: stopping", self.name);
+        self.save_kicker_task.abort();
+        self.remove_peers();
+        self.receiver.close();
+        self.save().await
+    }
+
+    /// Rooms are saved as JSON, unless there's already a RON file for the room.
     fn path(&self) -> PathBuf {
-        PathBuf::from("models")
-            .join(&self.name)
-            .with_extension("json")
+        match &self.unmoved_save {
+            Some(path) => path.clone(),
+            None => Self::path_for(&self.name),
+        }
+    }
+    fn path_for(name: &str) -> PathBuf {
+        let path = PathBuf::from("models").join(name);
+        let ron_path = path.with_extension("ron");
+        if ron_path.exists() {
+            ron_path
+        } else {
+            path.with_extension("json")
+        }
+    }
+
+    /// Whether a room by this name has been saved before, even if it isn't open.
+    pub fn has_save(name: &str) -> bool {
+        Self::path_for(name).exists()
     }
 
     async fn load(&mut self) -> anyhow::Result<()> {
-        if let Ok(contents) = tokio::fs::read_to_string(self.path()).await {
-            self.graph = serde_json::from_str(&contents)?;
+        let path = self.path();
+        if let Ok(contents) = tokio::fs::read_to_string(&path).await {
+            self.graph = shared::save::from_str(&contents, Format::from_path(&path))?;
         }
         Ok(())
     }
     async fn save(&mut self) -> anyhow::Result<()> {
-        if let Some(path) = self.path().parent() {
+        self.write_to(&self.path()).await
+    }
+    /// Snapshots go in their own directory, so that they can't be mistaken for rooms, and
+    /// are named after the room and the Unix time they were taken at.
+    async fn snapshot(&self) -> anyhow::Result<PathBuf> {
+        let save_path = self.path();
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+        let extension = save_path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or("json");
+        let path = PathBuf::from("models")
+            .join("snapshots")
+            .join(format!("{}-{}.{}", self.name, timestamp, extension));
+        self.write_to(&path).await?;
+        Ok(path)
+    }
+    async fn write_to(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(path) = path.parent() {
             tokio::fs::create_dir_all(path).await?;
         }
-        Ok(tokio::fs::write(self.path(), serde_json::to_string_pretty(&self.graph)?).await?)
+        let contents = shared::save::to_string(&self.graph, Format::from_path(path))?;
+        Ok(tokio::fs::write(path, contents).await?)
     }
 }
 
 util::make_handle_type!(RoomHandle, RoomMessage);
 
 impl RoomHandle {
-    pub fn new(name: String, coordinator: CoordinatorHandle) -> RoomHandle {
+    pub fn new(
+        name: String,
+        coordinator: CoordinatorHandle,
+        idle_timeout: Option<Duration>,
+    ) -> RoomHandle {
         let (sender, receiver) = mpsc::channel(8);
 
         let graph = Graph::new_authoritative();
@@ -111,10 +399,15 @@ impl RoomHandle {
         let mut room = Room {
             name,
             peers: HashMap::new(),
-            _save_kicker_task: save_kicker_task,
+            lagging: HashMap::new(),
+            save_kicker_task,
             graph,
             receiver,
             coordinator,
+            idle_timeout,
+            last_edit: Instant::now(),
+            shutdown_requested: false,
+            unmoved_save: None,
         };
         tokio::spawn(async move {
             room.load().await.unwrap();
@@ -124,3 +417,178 @@ impl RoomHandle {
         RoomHandle(sender)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::{NodeData, Sphere};
+
+    fn room() -> Room {
+        let (_, receiver) = mpsc::channel(8);
+        let (coordinator, _) = mpsc::channel(8);
+        Room {
+            name: "test".to_string(),
+            peers: HashMap::new(),
+            lagging: HashMap::new(),
+            save_kicker_task: tokio::spawn(async { anyhow::Ok(()) }),
+            graph: Graph::new_authoritative(),
+            receiver,
+            coordinator: CoordinatorHandle::from_sender(coordinator),
+            idle_timeout: None,
+            last_edit: Instant::now(),
+            shutdown_requested: false,
+            unmoved_save: None,
+        }
+    }
+
+    /// Whether a peer joining with the given graph hash is sent the whole graph.
+    async fn join_is_initialized(room: &mut Room, graph_hash: Option<u64>) -> bool {
+        let address: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let (sender, mut receiver) = mpsc::channel(8);
+        let peer = PeerHandle::from_sender(sender);
+        room.handle_message(RoomMessage::PeerJoin(address, peer, graph_hash))
+            .await
+            .unwrap();
+        std::iter::from_fn(|| receiver.try_recv().ok())
+            .any(|msg| matches!(msg, PeerMessage::GraphChange(GraphChange::Initialize(_))))
+    }
+
+    #[tokio::test]
+    async fn rejoining_with_the_same_graph_skips_initialize() {
+        let mut room = room();
+        let command = GraphCommand::CreateNewRoot(NodeData::Sphere(Sphere::default()));
+        room.graph.apply_command(&command).unwrap();
+        let hash = room.graph.content_hash();
+
+        assert!(!join_is_initialized(&mut room, Some(hash)).await);
+        assert!(join_is_initialized(&mut room, Some(hash.wrapping_add(1))).await);
+        assert!(join_is_initialized(&mut room, None).await);
+    }
+
+    #[tokio::test]
+    async fn stuck_peer_does_not_hold_up_the_room() {
+        let mut room = room();
+        let address: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        // Nothing is read from this until the end, like a peer that's stuck sending to
+        // the room
+        let (sender, mut receiver) = mpsc::channel(8);
+        let join = RoomMessage::PeerJoin(address, PeerHandle::from_sender(sender), None);
+        let commands = (0..32).map(|id| {
+            let command = GraphCommand::CreateNewRoot(NodeData::Sphere(Sphere::default()));
+            RoomMessage::GraphCommand(address, CommandId(id), Box::new(command))
+        });
+        let messages = std::iter::once(join).chain(commands).chain([
+            RoomMessage::Rename("renamed".to_string()),
+            RoomMessage::RequestResync(address),
+        ]);
+        for msg in messages {
+            tokio::time::timeout(Duration::from_secs(1), room.handle_message(msg))
+                .await
+                .expect("the room waited on the peer")
+                .unwrap();
+        }
+        assert!(room.lagging.contains_key(&address));
+
+        // Once the peer gets going again, it's caught up with every answer, in order
+        let mut accepted = vec![];
+        let mut renamed = false;
+        let mut additional_roots = 0;
+        loop {
+            while let Ok(msg) = receiver.try_recv() {
+                match msg {
+                    PeerMessage::CommandAccepted(id, _) => accepted.push(id),
+                    PeerMessage::RoomRenamed(name) => renamed = name == "renamed",
+                    PeerMessage::GraphChange(GraphChange::Initialize(components)) => {
+                        additional_roots = components.2.len();
+                    }
+                    _ => {}
+                }
+            }
+            if !room.lagging.contains_key(&address) {
+                break;
+            }
+            room.catch_up_lagging();
+        }
+        assert_eq!(additional_roots, 31);
+        assert_eq!(accepted, (0..32).map(CommandId).collect::<Vec<_>>());
+        assert!(renamed);
+    }
+
+    #[tokio::test]
+    async fn idle_room_stops_once_shut_down() {
+        let (sender, receiver) = mpsc::channel(8);
+        let (coordinator, mut coordinator_receiver) = mpsc::channel(8);
+        // Joining an absolute path onto `models` gives the absolute path, which keeps the
+        // save out of the working directory
+        let name = std::env::temp_dir()
+            .join(format!("exoform-room-test-{}", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let mut room = Room {
+            name: name.clone(),
+            receiver,
+            coordinator: CoordinatorHandle::from_sender(coordinator),
+            idle_timeout: Some(Duration::ZERO),
+            save_kicker_task: tokio::spawn(std::future::pending()),
+            ..room()
+        };
+        let task = tokio::spawn(async move {
+            room.run().await;
+            room
+        });
+
+        let address: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let (peer, mut peer_receiver) = mpsc::channel(8);
+        let peer = PeerHandle::from_sender(peer);
+        sender
+            .send(RoomMessage::PeerJoin(address, peer, None))
+            .await
+            .unwrap();
+        sender.send(RoomMessage::Save).await.unwrap();
+        assert!(matches!(
+            coordinator_receiver.recv().await,
+            Some(CoordinatorMessage::RoomShutdown(shut_down)) if shut_down == name
+        ));
+        assert!(std::iter::from_fn(|| peer_receiver.try_recv().ok())
+            .any(|msg| matches!(msg, PeerMessage::RoomClosed)));
+
+        sender.send(RoomMessage::Shutdown).await.unwrap();
+        let room = tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .expect("the room kept running after it was shut down")
+            .unwrap();
+        assert!(sender.send(RoomMessage::Save).await.is_err());
+        assert!(room.save_kicker_task.await.unwrap_err().is_cancelled());
+        std::fs::remove_file(Room::path_for(&name)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_rename_keeps_saving_to_the_old_file() {
+        let directory = std::env::temp_dir();
+        let name = directory
+            .join(format!("exoform-rename-test-{}", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let mut room = Room {
+            name: name.clone(),
+            ..room()
+        };
+        room.save().await.unwrap();
+        let old_path = room.path();
+
+        // The save can't be moved into a directory that doesn't exist
+        let new_name = directory
+            .join("exoform-missing-directory")
+            .join("renamed")
+            .to_string_lossy()
+            .into_owned();
+        room.handle_message(RoomMessage::Rename(new_name.clone()))
+            .await
+            .unwrap();
+        assert_eq!(room.name, new_name);
+        assert_eq!(room.path(), old_path);
+        room.save().await.unwrap();
+        assert!(!Room::has_save(&new_name));
+        std::fs::remove_file(old_path).unwrap();
+    }
+}
@@ -0,0 +1,31 @@
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+use anyhow::Context;
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    TlsAcceptor,
+};
+
+/// Loads the certificate chain and private key from PEM files, for accepting TLS
+/// connections.
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> anyhow::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .with_context(|| format!("failed to read certificates from {cert_path:?}"))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in {cert_path:?}");
+    }
+    let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .with_context(|| format!("failed to read private key from {key_path:?}"))?
+        .into_iter()
+        .next()
+        .with_context(|| format!("no PKCS#8 private key found in {key_path:?}"))?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            certs.into_iter().map(Certificate).collect(),
+            PrivateKey(key),
+        )?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
@@ -6,6 +6,13 @@ macro_rules! make_handle_type {
             pub async fn send(&self, msg: $message_type) -> anyhow::Result<()> {
                 Ok(self.0.send(msg).await?)
             }
+
+            /// Wraps the sending end of a channel, so that tests can stand in for the actor.
+            #[cfg(test)]
+            #[allow(dead_code)]
+            pub fn from_sender(sender: ::tokio::sync::mpsc::Sender<$message_type>) -> Self {
+                Self(sender)
+            }
         }
     };
 }
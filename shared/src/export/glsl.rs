@@ -0,0 +1,132 @@
+//! Exports a graph as a GLSL distance function, `float map(vec3 p)`, that can be pasted
+//! into a raymarcher (e.g. on Shadertoy).
+use glam::Vec3;
+
+use super::ir::{self, Backend, Helper, Type};
+use crate::Graph;
+
+pub fn graph_to_glsl(graph: &Graph) -> String {
+    ir::emit(&ir::lower(graph), &Glsl)
+}
+
+struct Glsl;
+impl Backend for Glsl {
+    fn helper_source(&self, helper: Helper) -> &'static str {
+        match helper {
+            Helper::RoundedCylinder => {
+                "float sdRoundedCylinder(vec3 p, float radius, float halfHeight, float rounding) {
+    vec2 d = vec2(length(p.xz) - radius + rounding, abs(p.y) - halfHeight + rounding);
+    return min(max(d.x, d.y), 0.0) + length(max(d, 0.0)) - rounding;
+}
+"
+            }
+            Helper::Torus => {
+                "float sdTorus(vec3 p, float bigR, float smallR) {
+    return length(vec2(length(p.xz) - bigR, p.y)) - smallR;
+}
+"
+            }
+            Helper::Capsule => {
+                "float sdCapsule(vec3 p, vec3 a, vec3 b, float radius) {
+    vec3 pa = p - a;
+    vec3 ba = b - a;
+    float h = clamp(dot(pa, ba) / dot(ba, ba), 0.0, 1.0);
+    return length(pa - ba * h) - radius;
+}
+"
+            }
+            Helper::TaperedCapsule => {
+                "float sdTaperedCapsule(vec3 p, vec3 a, vec3 b, float r1, float r2) {
+    vec3 ba = b - a;
+    float l2 = dot(ba, ba);
+    float rr = r1 - r2;
+    float a2 = l2 - rr * rr;
+    float il2 = 1.0 / l2;
+    vec3 pa = p - a;
+    float y = dot(pa, ba);
+    float z = y - l2;
+    vec3 xv = pa * l2 - ba * y;
+    float x2 = dot(xv, xv);
+    float y2 = y * y * l2;
+    float z2 = z * z * l2;
+    float k = sign(rr) * rr * rr * x2;
+    if (sign(z) * a2 * z2 > k) return sqrt(x2 + z2) * il2 - r2;
+    if (sign(y) * a2 * y2 < k) return sqrt(x2 + y2) * il2 - r1;
+    return (sqrt(x2 * a2 * il2) + y * rr) * il2 - r1;
+}
+"
+            }
+            Helper::Cone => {
+                "float sdCone(vec3 p, float radius, float height) {
+    float h = height * 0.5;
+    vec2 q = vec2(length(p.xz), p.y);
+    vec2 k1 = vec2(0.0, h);
+    vec2 k2 = vec2(-radius, 2.0 * h);
+    vec2 ca = vec2(q.x - min(q.x, q.y < 0.0 ? radius : 0.0), abs(q.y) - h);
+    vec2 cb = q - k1 + k2 * clamp(dot(k1 - q, k2) / dot(k2, k2), 0.0, 1.0);
+    float s = (cb.x < 0.0 && ca.y < 0.0) ? -1.0 : 1.0;
+    return s * sqrt(min(dot(ca, ca), dot(cb, cb)));
+}
+"
+            }
+            Helper::RoundedBox => {
+                "float sdRoundedBox(vec3 p, vec3 halfSize, float rounding) {
+    vec3 q = abs(p) - halfSize + rounding;
+    return length(max(q, 0.0)) + min(max(q.x, max(q.y, q.z)), 0.0) - rounding;
+}
+"
+            }
+            Helper::TorusSector => {
+                "float sdTorusSector(vec3 p, float bigR, float smallR, float halfAngle) {
+    vec2 sc = vec2(sin(halfAngle), cos(halfAngle));
+    vec2 q = vec2(abs(p.x), p.z);
+    float k = (sc.y * q.x > sc.x * q.y) ? dot(q, sc) : length(q);
+    return sqrt(dot(p, p) + bigR * bigR - 2.0 * bigR * k) - smallR;
+}
+"
+            }
+            Helper::SmoothUnion => {
+                "float opSmoothUnion(float a, float b, float k) {
+    float h = clamp(0.5 + 0.5 * (b - a) / k, 0.0, 1.0);
+    return mix(b, a, h) - k * h * (1.0 - h);
+}
+"
+            }
+            Helper::SmoothIntersect => {
+                "float opSmoothIntersect(float a, float b, float k) {
+    float h = clamp(0.5 - 0.5 * (b - a) / k, 0.0, 1.0);
+    return mix(b, a, h) + k * h * (1.0 - h);
+}
+"
+            }
+            Helper::SmoothSubtract => {
+                "float opSmoothSubtract(float a, float b, float k) {
+    float h = clamp(0.5 - 0.5 * (b + a) / k, 0.0, 1.0);
+    return mix(a, -b, h) + k * h * (1.0 - h);
+}
+"
+            }
+        }
+    }
+
+    fn map_signature(&self) -> &'static str {
+        "float map(vec3 p) {"
+    }
+
+    fn declaration(&self, ty: Type, name: &str, value: &str) -> String {
+        let ty = match ty {
+            Type::Float => "float",
+            Type::Vec3 => "vec3",
+        };
+        format!("{ty} {name} = {value};")
+    }
+
+    fn vec3(&self, value: Vec3) -> String {
+        let [x, y, z] = value.to_array().map(ir::float);
+        format!("vec3({x}, {y}, {z})")
+    }
+
+    fn mat3(&self, columns: [String; 9]) -> String {
+        format!("mat3({})", columns.join(", "))
+    }
+}
@@ -0,0 +1,609 @@
+//! A backend-agnostic description of a graph's distance function. The graph is lowered
+//! to a list of local variables once, and each shader language only needs to describe
+//! how to spell them, so the exporters can't diverge in what they compute.
+use std::collections::BTreeSet;
+
+use glam::{Mat3, Vec3};
+
+// `node_data::Box` shadows `std::boxed::Box`, so the latter is always written out in full
+use crate::{node_data::*, Graph, NodeId, Transform};
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub(super) enum Type {
+    Float,
+    Vec3,
+}
+
+/// Distance functions that are too long to inline, and are emitted once before `map`.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) enum Helper {
+    RoundedCylinder,
+    Torus,
+    Capsule,
+    TaperedCapsule,
+    Cone,
+    RoundedBox,
+    TorusSector,
+    SmoothUnion,
+    SmoothIntersect,
+    SmoothSubtract,
+}
+impl Helper {
+    pub fn name(self) -> &'static str {
+        match self {
+            Helper::RoundedCylinder => "sdRoundedCylinder",
+            Helper::Torus => "sdTorus",
+            Helper::Capsule => "sdCapsule",
+            Helper::TaperedCapsule => "sdTaperedCapsule",
+            Helper::Cone => "sdCone",
+            Helper::RoundedBox => "sdRoundedBox",
+            Helper::TorusSector => "sdTorusSector",
+            Helper::SmoothUnion => "opSmoothUnion",
+            Helper::SmoothIntersect => "opSmoothIntersect",
+            Helper::SmoothSubtract => "opSmoothSubtract",
+        }
+    }
+}
+
+pub(super) enum Expr {
+    Var(String),
+    Float(f32),
+    Vec3(Vec3),
+    Mat3(Mat3),
+    Binary(std::boxed::Box<Expr>, &'static str, std::boxed::Box<Expr>),
+    Neg(std::boxed::Box<Expr>),
+    /// A function built into both GLSL and WGSL, like `length` or `min`.
+    Builtin(&'static str, Vec<Expr>),
+    Helper(Helper, Vec<Expr>),
+}
+impl Expr {
+    fn binary(lhs: Expr, op: &'static str, rhs: Expr) -> Expr {
+        Expr::Binary(std::boxed::Box::new(lhs), op, std::boxed::Box::new(rhs))
+    }
+}
+
+pub(super) struct Statement {
+    pub name: String,
+    pub ty: Type,
+    pub value: Expr,
+}
+
+pub(super) struct Function {
+    pub helpers: BTreeSet<Helper>,
+    pub statements: Vec<Statement>,
+    pub result: Expr,
+}
+
+/// The distance used when there's nothing to hit.
+const FAR_AWAY: f32 = 1e10;
+
+/// Sagittas this small are treated as flat, as the sphere that would pass through the
+/// chord's rim is too large to be represented precisely.
+const FLAT_SAGITTA: f32 = 1e-4;
+
+pub(super) fn lower(graph: &Graph) -> Function {
+    let mut ctx = Context {
+        graph,
+        instance_stack: vec![],
+        function: Function {
+            helpers: BTreeSet::new(),
+            statements: vec![],
+            result: Expr::Float(FAR_AWAY),
+        },
+    };
+    // Multiple roots are disjoint objects, so they're combined with a union
+    let distances = graph
+        .root_node_ids()
+        .into_iter()
+        .filter(|root_id| !graph.get(*root_id).unwrap().data.is_metadata())
+        .map(|root_id| ctx.node(root_id, Expr::Var("p".to_string())))
+        .collect();
+    ctx.function.result = ctx.fold(distances, 0.0, "min", Helper::SmoothUnion, |_, rhs| rhs);
+    ctx.function
+}
+
+struct Context<'a> {
+    graph: &'a Graph,
+    /// The instances currently being lowered, for the same reason as when meshing.
+    instance_stack: Vec<NodeId>,
+    function: Function,
+}
+impl Context<'_> {
+    /// Declares a new local variable with the given value, and returns a reference to it.
+    fn declare(&mut self, ty: Type, value: Expr) -> Expr {
+        let name = format!("v{}", self.function.statements.len());
+        self.function.statements.push(Statement {
+            name: name.clone(),
+            ty,
+            value,
+        });
+        Expr::Var(name)
+    }
+
+    fn helper(&mut self, helper: Helper, args: Vec<Expr>) -> Expr {
+        self.function.helpers.insert(helper);
+        Expr::Helper(helper, args)
+    }
+
+    /// Lowers the distance to `node_id` at `point`.
+    fn node(&mut self, node_id: NodeId, point: Expr) -> Expr {
+        let node = self.graph.get(node_id).unwrap();
+        self.transformed(&node.transform, point, |ctx, point| {
+            // Invalid expressions stop the mesh from being built, so they'll already have
+            // been reported; all that can be done here is to fall back to the fields' own
+            // values
+            let data = ctx
+                .graph
+                .resolved_data(node)
+                .unwrap_or(std::borrow::Cow::Borrowed(&node.data));
+            ctx.node_data(node_id, &data, &node.children, point)
+        })
+    }
+
+    /// Lowers the distance that `distance` gives for a point in the space that `transform`
+    /// moves things into. Transforms are undone in the reverse order to how they're
+    /// applied when meshing.
+    fn transformed(
+        &mut self,
+        transform: &Transform,
+        point: Expr,
+        distance: impl FnOnce(&mut Self, Expr) -> Expr,
+    ) -> Expr {
+        let mut point = point;
+        if transform.translation.length_squared() != 0.0 {
+            let value = Expr::binary(point, "-", Expr::Vec3(transform.translation));
+            point = self.declare(Type::Vec3, value);
+        }
+        if !transform.rotation.is_near_identity() {
+            let inverse = Mat3::from_quat(transform.rotation.inverse());
+            let value = Expr::binary(Expr::Mat3(inverse), "*", point);
+            point = self.declare(Type::Vec3, value);
+        }
+        if transform.scale != 1.0 {
+            let value = Expr::binary(point, "/", Expr::Float(transform.scale));
+            point = self.declare(Type::Vec3, value);
+        }
+
+        let distance = distance(self, point);
+        if transform.scale != 1.0 {
+            let value = Expr::binary(distance, "*", Expr::Float(transform.scale));
+            self.declare(Type::Float, value)
+        } else {
+            distance
+        }
+    }
+
+    fn node_data(
+        &mut self,
+        node_id: NodeId,
+        node_data: &NodeData,
+        children: &[Option<NodeId>],
+        p: Expr,
+    ) -> Expr {
+        use Expr::{Builtin, Float, Vec3 as V};
+
+        let value = match node_data {
+            NodeData::Sphere(Sphere { radius }) => {
+                Expr::binary(Builtin("length", vec![p]), "-", Float(*radius))
+            }
+            NodeData::Cylinder(Cylinder {
+                cylinder_radius,
+                half_height,
+                rounding_radius,
+            }) => self.helper(
+                Helper::RoundedCylinder,
+                vec![
+                    p,
+                    Float(*cylinder_radius),
+                    Float(*half_height),
+                    Float(*rounding_radius),
+                ],
+            ),
+            NodeData::Torus(Torus { big_r, small_r }) => {
+                self.helper(Helper::Torus, vec![p, Float(*big_r), Float(*small_r)])
+            }
+            NodeData::Plane(Plane {
+                normal,
+                distance_from_origin,
+            }) => Expr::binary(
+                Builtin("dot", vec![p, V(*normal)]),
+                "+",
+                Float(*distance_from_origin),
+            ),
+            NodeData::Capsule(Capsule {
+                point_1,
+                point_2,
+                radius,
+            }) => self.helper(
+                Helper::Capsule,
+                vec![p, V(*point_1), V(*point_2), Float(*radius)],
+            ),
+            NodeData::TaperedCapsule(TaperedCapsule {
+                point_1,
+                point_2,
+                radius_1,
+                radius_2,
+            }) => self.helper(
+                Helper::TaperedCapsule,
+                vec![
+                    p,
+                    V(*point_1),
+                    V(*point_2),
+                    Float(*radius_1),
+                    Float(*radius_2),
+                ],
+            ),
+            NodeData::Cone(Cone { radius, height }) => {
+                // The helper is centred on the origin, while saft's cone stands on it
+                let offset = Expr::binary(p, "-", V(Vec3::new(0.0, height / 2.0, 0.0)));
+                let p = self.declare(Type::Vec3, offset);
+                self.helper(Helper::Cone, vec![p, Float(*radius), Float(*height)])
+            }
+            NodeData::Box(Box {
+                half_size,
+                rounding_radius,
+            }) => self.helper(
+                Helper::RoundedBox,
+                vec![p, V(half_size.abs()), Float(*rounding_radius)],
+            ),
+            NodeData::TorusSector(TorusSector {
+                big_r,
+                small_r,
+                angle,
+            }) => self.helper(
+                Helper::TorusSector,
+                vec![p, Float(*big_r), Float(*small_r), Float(angle / 2.0)],
+            ),
+            NodeData::BiconvexLens(BiconvexLens {
+                lower_sagitta,
+                upper_sagitta,
+                chord,
+            }) => {
+                // The intersection of two spheres, each passing through the chord's rim. A
+                // side with no sagitta would need a sphere of infinite radius, so it's the
+                // plane through the chord instead.
+                let half_chord = chord / 2.0;
+                let side = |sagitta: f32, outwards: Vec3| {
+                    if !sagitta.is_finite() || sagitta <= FLAT_SAGITTA {
+                        return Builtin("dot", vec![point_ref(&p), V(outwards)]);
+                    }
+                    let radius = (half_chord * half_chord + sagitta * sagitta) / (2.0 * sagitta);
+                    let offset = Expr::binary(point_ref(&p), "-", V(outwards * (sagitta - radius)));
+                    Expr::binary(Builtin("length", vec![offset]), "-", Float(radius))
+                };
+                Builtin(
+                    "max",
+                    vec![
+                        side(*lower_sagitta, -Vec3::Y),
+                        side(*upper_sagitta, Vec3::Y),
+                    ],
+                )
+            }
+            // Broken instances stop the mesh from being built, so like invalid
+            // expressions, they'll already have been reported
+            NodeData::Instance(Instance { target }) => match target
+                .filter(|id| self.graph.get(*id).is_some())
+                .filter(|_| !self.instance_stack.contains(&node_id))
+            {
+                Some(target_id) => {
+                    self.instance_stack.push(node_id);
+                    let distance = self.node(target_id, p);
+                    self.instance_stack.pop();
+                    distance
+                }
+                None => Float(FAR_AWAY),
+            },
+            NodeData::Transformation(transformation) => {
+                self.transformed(&transformation.transform(), p, |ctx, point| {
+                    // Like when meshing, the children are unioned first
+                    let distances = ctx.children(children, point);
+                    ctx.fold(distances, 0.0, "min", Helper::SmoothUnion, |_, rhs| rhs)
+                })
+            }
+            // Only reachable through an instance, as metadata is skipped everywhere else
+            NodeData::Comment(_) => Float(FAR_AWAY),
+
+            NodeData::Union(Union { factor }) => {
+                let distances = self.children(children, p);
+                self.fold(
+                    distances,
+                    clamp_factor(*factor),
+                    "min",
+                    Helper::SmoothUnion,
+                    |_, rhs| rhs,
+                )
+            }
+            NodeData::Intersect(Intersect { factor }) => {
+                // Like when meshing, the rest of the children are intersected as one shape
+                let mut distances = self.children(children, p);
+                let rest = distances.split_off(distances.len().min(1));
+                if !rest.is_empty() {
+                    let rest = self.fold(rest, 0.0, "max", Helper::SmoothIntersect, |_, rhs| rhs);
+                    distances.push(rest);
+                }
+                self.fold(
+                    distances,
+                    clamp_factor(*factor),
+                    "max",
+                    Helper::SmoothIntersect,
+                    |_, rhs| rhs,
+                )
+            }
+            NodeData::Subtract(Subtract { factor }) => {
+                // Like when meshing, the rest of the children are subtracted as one shape
+                let mut distances = self.children(children, p);
+                let rest = distances.split_off(distances.len().min(1));
+                if !rest.is_empty() {
+                    let rest = self.fold(rest, 0.0, "min", Helper::SmoothUnion, |_, rhs| rhs);
+                    distances.push(rest);
+                }
+                self.fold(
+                    distances,
+                    clamp_factor(*factor),
+                    "max",
+                    Helper::SmoothSubtract,
+                    |smooth, rhs| {
+                        if smooth {
+                            rhs
+                        } else {
+                            Expr::Neg(std::boxed::Box::new(rhs))
+                        }
+                    },
+                )
+            }
+            NodeData::RadialRepeat(RadialRepeat { count }) => {
+                // Like when meshing, the children are unioned, and then so are rotated
+                // copies of that union
+                let count = (*count).clamp(1, RadialRepeat::MAX_COUNT);
+                let copies: Vec<_> = (0..count)
+                    .map(|i| {
+                        let point = if i == 0 {
+                            point_ref(&p)
+                        } else {
+                            let angle = i as f32 / count as f32 * std::f32::consts::TAU;
+                            let inverse = Mat3::from_rotation_y(-angle);
+                            let value = Expr::binary(Expr::Mat3(inverse), "*", point_ref(&p));
+                            self.declare(Type::Vec3, value)
+                        };
+                        let distances = self.children(children, point);
+                        self.fold(distances, 0.0, "min", Helper::SmoothUnion, |_, rhs| rhs)
+                    })
+                    .collect();
+                self.fold(copies, 0.0, "min", Helper::SmoothUnion, |_, rhs| rhs)
+            }
+        };
+        self.declare(Type::Float, value)
+    }
+
+    fn children(&mut self, children: &[Option<NodeId>], point: Expr) -> Vec<Expr> {
+        children
+            .iter()
+            .filter_map(|id| *id)
+            .filter(|id| !self.graph.get(*id).unwrap().data.is_metadata())
+            .map(|id| self.node(id, point_ref(&point)))
+            .collect()
+    }
+
+    /// Combines the distances left-to-right with either the hard `builtin` or the smooth
+    /// `helper`. `rhs` lets the operation adjust the right-hand side for the hard case.
+    fn fold(
+        &mut self,
+        distances: Vec<Expr>,
+        factor: f32,
+        builtin: &'static str,
+        helper: Helper,
+        rhs: impl Fn(bool, Expr) -> Expr,
+    ) -> Expr {
+        let smooth = factor != 0.0;
+        let mut distances = distances.into_iter();
+        let first = distances.next().unwrap_or(Expr::Float(FAR_AWAY));
+        distances.fold(first, |lhs, next| {
+            let next = rhs(smooth, next);
+            if smooth {
+                self.helper(helper, vec![lhs, next, Expr::Float(factor)])
+            } else {
+                Expr::Builtin(builtin, vec![lhs, next])
+            }
+        })
+    }
+}
+
+/// Points are always stored in variables, so that they're cheap to reuse.
+fn point_ref(point: &Expr) -> Expr {
+    match point {
+        Expr::Var(name) => Expr::Var(name.clone()),
+        _ => unreachable!("points are always stored in variables"),
+    }
+}
+
+/// How a shader language spells the parts of a [`Function`].
+pub(super) trait Backend {
+    /// The source of `helper`, including a trailing newline.
+    fn helper_source(&self, helper: Helper) -> &'static str;
+    /// The opening line of the `map` function, which takes `p`.
+    fn map_signature(&self) -> &'static str;
+    fn declaration(&self, ty: Type, name: &str, value: &str) -> String;
+    fn vec3(&self, value: Vec3) -> String;
+    /// Both GLSL and WGSL matrices are column-major, like glam's.
+    fn mat3(&self, columns: [String; 9]) -> String;
+}
+
+pub(super) fn emit(function: &Function, backend: &impl Backend) -> String {
+    let mut output = String::new();
+    for helper in &function.helpers {
+        output += backend.helper_source(*helper);
+        output += "\n";
+    }
+    output += backend.map_signature();
+    output += "\n";
+    for statement in &function.statements {
+        let value = expr(backend, &statement.value);
+        output += "    ";
+        output += &backend.declaration(statement.ty, &statement.name, &value);
+        output += "\n";
+    }
+    output += &format!("    return {};\n", expr(backend, &function.result));
+    output += "}\n";
+    output
+}
+
+fn expr(backend: &impl Backend, value: &Expr) -> String {
+    let operand = |value: &Expr| match value {
+        Expr::Binary(..) => format!("({})", expr(backend, value)),
+        _ => expr(backend, value),
+    };
+    let args = |args: &[Expr]| {
+        args.iter()
+            .map(|arg| expr(backend, arg))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    match value {
+        Expr::Var(name) => name.clone(),
+        Expr::Float(value) => float(*value),
+        Expr::Vec3(value) => backend.vec3(*value),
+        Expr::Mat3(value) => backend.mat3(value.to_cols_array().map(float)),
+        Expr::Binary(lhs, op, rhs) => format!("{} {op} {}", operand(lhs), operand(rhs)),
+        Expr::Neg(value) => format!("-{}", operand(value)),
+        Expr::Builtin(name, arguments) => format!("{name}({})", args(arguments)),
+        Expr::Helper(helper, arguments) => format!("{}({})", helper.name(), args(arguments)),
+    }
+}
+
+pub(super) fn float(value: f32) -> String {
+    format!("{value:?}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use glam::Vec2;
+
+    use super::*;
+    use crate::GraphCommand;
+
+    #[derive(Copy, Clone, Debug)]
+    enum Value {
+        Float(f32),
+        Vec3(Vec3),
+        Mat3(Mat3),
+    }
+    impl Value {
+        fn float(self) -> f32 {
+            match self {
+                Value::Float(value) => value,
+                _ => panic!("expected a float, got {self:?}"),
+            }
+        }
+        fn vec3(self) -> Vec3 {
+            match self {
+                Value::Vec3(value) => value,
+                _ => panic!("expected a vec3, got {self:?}"),
+            }
+        }
+    }
+
+    /// Evaluates the lowered function at `p`, as a shader would. Helpers are ported from
+    /// the GLSL as they're needed.
+    fn evaluate(function: &Function, p: Vec3) -> f32 {
+        let mut variables = HashMap::from([("p".to_string(), Value::Vec3(p))]);
+        for statement in &function.statements {
+            let value = evaluate_expr(&statement.value, &variables);
+            variables.insert(statement.name.clone(), value);
+        }
+        evaluate_expr(&function.result, &variables).float()
+    }
+
+    fn evaluate_expr(value: &Expr, variables: &HashMap<String, Value>) -> Value {
+        use Value as V;
+        let args = |args: &[Expr]| -> Vec<Value> {
+            args.iter()
+                .map(|arg| evaluate_expr(arg, variables))
+                .collect()
+        };
+        match value {
+            Expr::Var(name) => variables[name],
+            Expr::Float(value) => V::Float(*value),
+            Expr::Vec3(value) => V::Vec3(*value),
+            Expr::Mat3(value) => V::Mat3(*value),
+            Expr::Binary(lhs, op, rhs) => {
+                let (lhs, rhs) = (evaluate_expr(lhs, variables), evaluate_expr(rhs, variables));
+                match (lhs, *op, rhs) {
+                    (V::Float(a), "+", V::Float(b)) => V::Float(a + b),
+                    (V::Float(a), "-", V::Float(b)) => V::Float(a - b),
+                    (V::Float(a), "*", V::Float(b)) => V::Float(a * b),
+                    (V::Vec3(a), "-", V::Vec3(b)) => V::Vec3(a - b),
+                    (V::Vec3(a), "/", V::Float(b)) => V::Vec3(a / b),
+                    (V::Mat3(a), "*", V::Vec3(b)) => V::Vec3(a * b),
+                    _ => panic!("unsupported operation {lhs:?} {op} {rhs:?}"),
+                }
+            }
+            Expr::Neg(value) => V::Float(-evaluate_expr(value, variables).float()),
+            Expr::Builtin(name, arguments) => match (*name, &args(arguments)[..]) {
+                ("length", [V::Vec3(a)]) => V::Float(a.length()),
+                ("dot", [V::Vec3(a), V::Vec3(b)]) => V::Float(a.dot(*b)),
+                ("min", [V::Float(a), V::Float(b)]) => V::Float(a.min(*b)),
+                ("max", [V::Float(a), V::Float(b)]) => V::Float(a.max(*b)),
+                (name, arguments) => panic!("unsupported builtin {name}({arguments:?})"),
+            },
+            Expr::Helper(helper, arguments) => {
+                let arguments = args(arguments);
+                match helper {
+                    Helper::Cone => V::Float(sd_cone(
+                        arguments[0].vec3(),
+                        arguments[1].float(),
+                        arguments[2].float(),
+                    )),
+                    _ => panic!("{} hasn't been ported", helper.name()),
+                }
+            }
+        }
+    }
+
+    /// `sdCone` from the GLSL backend.
+    fn sd_cone(p: Vec3, radius: f32, height: f32) -> f32 {
+        let h = height * 0.5;
+        let q = Vec2::new(Vec2::new(p.x, p.z).length(), p.y);
+        let k1 = Vec2::new(0.0, h);
+        let k2 = Vec2::new(-radius, 2.0 * h);
+        let ca = Vec2::new(
+            q.x - q.x.min(if q.y < 0.0 { radius } else { 0.0 }),
+            q.y.abs() - h,
+        );
+        let cb = q - k1 + k2 * ((k1 - q).dot(k2) / k2.dot(k2)).clamp(0.0, 1.0);
+        let s = if cb.x < 0.0 && ca.y < 0.0 { -1.0 } else { 1.0 };
+        s * ca.dot(ca).min(cb.dot(cb)).sqrt()
+    }
+
+    #[test]
+    fn cone_matches_saft() {
+        let (radius, height) = (0.5, 1.5);
+        let mut graph = Graph::new_authoritative();
+        graph
+            .apply_command(&GraphCommand::CreateNewRoot(NodeData::Cone(Cone {
+                radius,
+                height,
+            })))
+            .unwrap();
+        let function = lower(&graph);
+
+        let points = [
+            Vec3::ZERO,
+            Vec3::new(0.0, height, 0.0),
+            Vec3::new(0.0, -0.5, 0.0),
+            Vec3::new(0.0, height + 0.5, 0.0),
+            Vec3::new(0.2, 0.3, 0.1),
+            Vec3::new(1.0, 0.75, 0.0),
+            Vec3::new(-0.3, 1.2, 0.4),
+        ];
+        for point in points {
+            let expected = saft::sd_cone_f(point, radius, height);
+            let actual = evaluate(&function, point);
+            assert!(
+                (actual - expected).abs() < 1e-4,
+                "at {point}: exported {actual}, saft {expected}"
+            );
+        }
+    }
+}
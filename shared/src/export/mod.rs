@@ -0,0 +1,5 @@
+//! Exports graphs to formats that can be used outside of Exoform.
+mod ir;
+
+pub mod glsl;
+pub mod wgsl;
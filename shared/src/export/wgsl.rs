@@ -0,0 +1,132 @@
+//! Exports a graph as a WGSL distance function, `fn map(p: vec3<f32>) -> f32`, for use
+//! in wgpu shaders.
+use glam::Vec3;
+
+use super::ir::{self, Backend, Helper, Type};
+use crate::Graph;
+
+pub fn graph_to_wgsl(graph: &Graph) -> String {
+    ir::emit(&ir::lower(graph), &Wgsl)
+}
+
+struct Wgsl;
+impl Backend for Wgsl {
+    fn helper_source(&self, helper: Helper) -> &'static str {
+        match helper {
+            Helper::RoundedCylinder => {
+                "fn sdRoundedCylinder(p: vec3<f32>, radius: f32, half_height: f32, rounding: f32) -> f32 {
+    let d = vec2<f32>(length(p.xz) - radius + rounding, abs(p.y) - half_height + rounding);
+    return min(max(d.x, d.y), 0.0) + length(max(d, vec2<f32>(0.0))) - rounding;
+}
+"
+            }
+            Helper::Torus => {
+                "fn sdTorus(p: vec3<f32>, big_r: f32, small_r: f32) -> f32 {
+    return length(vec2<f32>(length(p.xz) - big_r, p.y)) - small_r;
+}
+"
+            }
+            Helper::Capsule => {
+                "fn sdCapsule(p: vec3<f32>, a: vec3<f32>, b: vec3<f32>, radius: f32) -> f32 {
+    let pa = p - a;
+    let ba = b - a;
+    let h = clamp(dot(pa, ba) / dot(ba, ba), 0.0, 1.0);
+    return length(pa - ba * h) - radius;
+}
+"
+            }
+            Helper::TaperedCapsule => {
+                "fn sdTaperedCapsule(p: vec3<f32>, a: vec3<f32>, b: vec3<f32>, r1: f32, r2: f32) -> f32 {
+    let ba = b - a;
+    let l2 = dot(ba, ba);
+    let rr = r1 - r2;
+    let a2 = l2 - rr * rr;
+    let il2 = 1.0 / l2;
+    let pa = p - a;
+    let y = dot(pa, ba);
+    let z = y - l2;
+    let xv = pa * l2 - ba * y;
+    let x2 = dot(xv, xv);
+    let y2 = y * y * l2;
+    let z2 = z * z * l2;
+    let k = sign(rr) * rr * rr * x2;
+    if (sign(z) * a2 * z2 > k) {
+        return sqrt(x2 + z2) * il2 - r2;
+    }
+    if (sign(y) * a2 * y2 < k) {
+        return sqrt(x2 + y2) * il2 - r1;
+    }
+    return (sqrt(x2 * a2 * il2) + y * rr) * il2 - r1;
+}
+"
+            }
+            Helper::Cone => {
+                "fn sdCone(p: vec3<f32>, radius: f32, height: f32) -> f32 {
+    let h = height * 0.5;
+    let q = vec2<f32>(length(p.xz), p.y);
+    let k1 = vec2<f32>(0.0, h);
+    let k2 = vec2<f32>(-radius, 2.0 * h);
+    let ca = vec2<f32>(q.x - min(q.x, select(0.0, radius, q.y < 0.0)), abs(q.y) - h);
+    let cb = q - k1 + k2 * clamp(dot(k1 - q, k2) / dot(k2, k2), 0.0, 1.0);
+    let s = select(1.0, -1.0, cb.x < 0.0 && ca.y < 0.0);
+    return s * sqrt(min(dot(ca, ca), dot(cb, cb)));
+}
+"
+            }
+            Helper::RoundedBox => {
+                "fn sdRoundedBox(p: vec3<f32>, half_size: vec3<f32>, rounding: f32) -> f32 {
+    let q = abs(p) - half_size + rounding;
+    return length(max(q, vec3<f32>(0.0))) + min(max(q.x, max(q.y, q.z)), 0.0) - rounding;
+}
+"
+            }
+            Helper::TorusSector => {
+                "fn sdTorusSector(p: vec3<f32>, big_r: f32, small_r: f32, half_angle: f32) -> f32 {
+    let sc = vec2<f32>(sin(half_angle), cos(half_angle));
+    let q = vec2<f32>(abs(p.x), p.z);
+    let k = select(length(q), dot(q, sc), sc.y * q.x > sc.x * q.y);
+    return sqrt(dot(p, p) + big_r * big_r - 2.0 * big_r * k) - small_r;
+}
+"
+            }
+            Helper::SmoothUnion => {
+                "fn opSmoothUnion(a: f32, b: f32, k: f32) -> f32 {
+    let h = clamp(0.5 + 0.5 * (b - a) / k, 0.0, 1.0);
+    return mix(b, a, h) - k * h * (1.0 - h);
+}
+"
+            }
+            Helper::SmoothIntersect => {
+                "fn opSmoothIntersect(a: f32, b: f32, k: f32) -> f32 {
+    let h = clamp(0.5 - 0.5 * (b - a) / k, 0.0, 1.0);
+    return mix(b, a, h) + k * h * (1.0 - h);
+}
+"
+            }
+            Helper::SmoothSubtract => {
+                "fn opSmoothSubtract(a: f32, b: f32, k: f32) -> f32 {
+    let h = clamp(0.5 - 0.5 * (b + a) / k, 0.0, 1.0);
+    return mix(a, -b, h) + k * h * (1.0 - h);
+}
+"
+            }
+        }
+    }
+
+    fn map_signature(&self) -> &'static str {
+        "fn map(p: vec3<f32>) -> f32 {"
+    }
+
+    fn declaration(&self, _ty: Type, name: &str, value: &str) -> String {
+        format!("let {name} = {value};")
+    }
+
+    fn vec3(&self, value: Vec3) -> String {
+        let [x, y, z] = value.to_array().map(ir::float);
+        format!("vec3<f32>({x}, {y}, {z})")
+    }
+
+    fn mat3(&self, columns: [String; 9]) -> String {
+        format!("mat3x3<f32>({})", columns.join(", "))
+    }
+}
@@ -0,0 +1,205 @@
+//! The arithmetic expressions that node fields can be bound to, like `0.5 * 2` or
+//! `(width - wall) / 2`, where `width` and `wall` are variables.
+use std::{collections::HashMap, iter::Peekable, str::Chars};
+
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ExpressionError {
+    #[error("unexpected `{0}`")]
+    UnexpectedCharacter(char),
+    #[error("unexpected end of expression")]
+    UnexpectedEnd,
+    #[error("`{0}` is not a number")]
+    InvalidNumber(String),
+    #[error("unknown variable `{0}`")]
+    UnknownVariable(String),
+    #[error("the result is not a finite number")]
+    NotFinite,
+    #[error("the result is outside the field's bounds")]
+    OutOfBounds,
+    #[error("the expression is longer than {} characters", Expression::MAX_LENGTH)]
+    TooLong,
+    #[error("the expression is nested more than {} deep", Expression::MAX_DEPTH)]
+    TooDeep,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Number(f32),
+    Variable(String),
+    Neg(Box<Expression>),
+    Binary(Box<Expression>, Operator, Box<Expression>),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl Expression {
+    /// Bindings come from peers, so they're kept short enough that evaluating them can't
+    /// run out of stack, however they're nested.
+    pub const MAX_LENGTH: usize = 256;
+    /// How deeply parentheses and unary operators can be nested.
+    pub const MAX_DEPTH: usize = 32;
+
+    /// Parses `+`, `-`, `*` and `/` with the usual precedence, unary minus, parentheses,
+    /// numbers and variable names. A source that's exactly the name of a variable is
+    /// always that variable, even if the name isn't otherwise a valid identifier.
+    pub fn parse(source: &str, variables: &HashMap<String, f32>) -> Result<Self, ExpressionError> {
+        let source = source.trim();
+        if source.len() > Self::MAX_LENGTH {
+            return Err(ExpressionError::TooLong);
+        }
+        if variables.contains_key(source) {
+            return Ok(Expression::Variable(source.to_string()));
+        }
+
+        let mut parser = Parser {
+            chars: source.chars().peekable(),
+            depth: 0,
+        };
+        let expression = parser.sum()?;
+        match parser.peek() {
+            Some(c) => Err(ExpressionError::UnexpectedCharacter(c)),
+            None => Ok(expression),
+        }
+    }
+
+    pub fn evaluate(&self, variables: &HashMap<String, f32>) -> Result<f32, ExpressionError> {
+        let value = match self {
+            Expression::Number(value) => *value,
+            Expression::Variable(name) => *variables
+                .get(name)
+                .ok_or_else(|| ExpressionError::UnknownVariable(name.clone()))?,
+            Expression::Neg(expression) => -expression.evaluate(variables)?,
+            Expression::Binary(lhs, operator, rhs) => {
+                let (lhs, rhs) = (lhs.evaluate(variables)?, rhs.evaluate(variables)?);
+                match operator {
+                    Operator::Add => lhs + rhs,
+                    Operator::Sub => lhs - rhs,
+                    Operator::Mul => lhs * rhs,
+                    Operator::Div => lhs / rhs,
+                }
+            }
+        };
+        value
+            .is_finite()
+            .then_some(value)
+            .ok_or(ExpressionError::NotFinite)
+    }
+}
+
+/// Parses and evaluates `source` in one go.
+pub fn evaluate(source: &str, variables: &HashMap<String, f32>) -> Result<f32, ExpressionError> {
+    Expression::parse(source, variables)?.evaluate(variables)
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    /// How many parentheses and unary operators the parser is inside.
+    depth: usize,
+}
+impl Parser<'_> {
+    /// The next character that isn't whitespace, without consuming it.
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+        self.chars.peek().copied()
+    }
+
+    fn sum(&mut self) -> Result<Expression, ExpressionError> {
+        let mut lhs = self.product()?;
+        loop {
+            let operator = match self.peek() {
+                Some('+') => Operator::Add,
+                Some('-') => Operator::Sub,
+                _ => return Ok(lhs),
+            };
+            self.chars.next();
+            lhs = Expression::Binary(Box::new(lhs), operator, Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expression, ExpressionError> {
+        let mut lhs = self.unary()?;
+        loop {
+            let operator = match self.peek() {
+                Some('*') => Operator::Mul,
+                Some('/') => Operator::Div,
+                _ => return Ok(lhs),
+            };
+            self.chars.next();
+            lhs = Expression::Binary(Box::new(lhs), operator, Box::new(self.unary()?));
+        }
+    }
+
+    /// Parses with `parse` one level further in, failing if that's too deep.
+    fn nested(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<Expression, ExpressionError>,
+    ) -> Result<Expression, ExpressionError> {
+        if self.depth >= Expression::MAX_DEPTH {
+            return Err(ExpressionError::TooDeep);
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn unary(&mut self) -> Result<Expression, ExpressionError> {
+        match self.peek() {
+            Some('-') => {
+                self.chars.next();
+                let expression = self.nested(Self::unary)?;
+                Ok(Expression::Neg(Box::new(expression)))
+            }
+            Some('+') => {
+                self.chars.next();
+                self.nested(Self::unary)
+            }
+            _ => self.atom(),
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expression, ExpressionError> {
+        match self.peek() {
+            Some('(') => {
+                self.chars.next();
+                let expression = self.nested(Self::sum)?;
+                match self.peek() {
+                    Some(')') => {
+                        self.chars.next();
+                        Ok(expression)
+                    }
+                    Some(c) => Err(ExpressionError::UnexpectedCharacter(c)),
+                    None => Err(ExpressionError::UnexpectedEnd),
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let number = self.take_while(|c| c.is_ascii_digit() || c == '.');
+                number
+                    .parse()
+                    .map(Expression::Number)
+                    .map_err(|_| ExpressionError::InvalidNumber(number))
+            }
+            Some(c) if c.is_alphabetic() || c == '_' => Ok(Expression::Variable(
+                self.take_while(|c| c.is_alphanumeric() || c == '_'),
+            )),
+            Some(c) => Err(ExpressionError::UnexpectedCharacter(c)),
+            None => Err(ExpressionError::UnexpectedEnd),
+        }
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> String {
+        let mut result = String::new();
+        while let Some(c) = self.chars.next_if(|c| predicate(*c)) {
+            result.push(c);
+        }
+        result
+    }
+}
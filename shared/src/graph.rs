@@ -1,13 +1,48 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+};
 
-use serde::{Deserialize, Serialize};
+use glam::{Quat, Vec3};
+use serde::{Deserialize, Serialize, Serializer};
+use thiserror::Error;
 
-use crate::{node_data::*, NodeDiff, Transform};
+use crate::{
+    expression::{self, Expression, ExpressionError},
+    node_data::*,
+    Axis, NodeDiff, Transform,
+};
 use crate::{Node, NodeId};
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Serializes a map in the order of its keys, so that saving the same graph twice gives
+/// the same output, and saved files diff cleanly.
+fn serialize_sorted_map<K: Ord + Serialize, V: Serialize, S: Serializer>(
+    map: &HashMap<K, V>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(map.iter().collect::<BTreeMap<_, _>>())
+}
+
+/// Serializes a set in order, like [serialize_sorted_map].
+fn serialize_sorted_set<T: Ord + Serialize, S: Serializer>(
+    set: &HashSet<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(set.iter().collect::<BTreeSet<_>>())
+}
+
+/// FNV-1a, which is stable across builds and platforms, unlike the standard library's
+/// hasher.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct IdGenerator {
     last_id: NodeId,
+    #[serde(serialize_with = "serialize_sorted_set")]
     returned_ids: HashSet<NodeId>,
 }
 impl IdGenerator {
@@ -18,6 +53,20 @@ impl IdGenerator {
         }
     }
 
+    /// Rebuilds a generator that won't hand out any of the given ids.
+    fn from_existing_ids(ids: impl Iterator<Item = NodeId>) -> IdGenerator {
+        let ids: HashSet<_> = ids.collect();
+        let last_id = NodeId(ids.iter().map(|id| id.0 + 1).max().unwrap_or(0));
+        let returned_ids = (0..last_id.0)
+            .map(NodeId)
+            .filter(|id| !ids.contains(id))
+            .collect();
+        IdGenerator {
+            last_id,
+            returned_ids,
+        }
+    }
+
     pub fn generate(&mut self) -> NodeId {
         if !self.returned_ids.is_empty() {
             let id = *self.returned_ids.iter().next().unwrap();
@@ -31,17 +80,132 @@ impl IdGenerator {
     }
 }
 
-pub type GraphComponents = (HashMap<NodeId, Node>, Option<NodeId>);
+/// The nodes, the root node, any additional root nodes, and the variables.
+pub type GraphComponents = (
+    HashMap<NodeId, Node>,
+    Option<NodeId>,
+    Vec<NodeId>,
+    HashMap<String, f32>,
+);
+
+/// A node and all of its descendants, detached from any graph. The ids are only
+/// meaningful within the subtree, and are replaced when the subtree is added to a graph.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Subtree {
+    pub root: NodeId,
+    #[serde(serialize_with = "serialize_sorted_map")]
+    pub nodes: HashMap<NodeId, Node>,
+}
+impl Subtree {
+    pub fn is_valid(&self) -> bool {
+        Graph::validate_components(&self.nodes, &[self.root]).is_ok()
+    }
+
+    /// A copy of the subtree reflected across the plane through its parent's origin
+    /// that's perpendicular to `axis`. Negative scales aren't allowed, so each node is
+    /// moved and turned to where its reflection would be, and primitives that aren't
+    /// symmetric across the plane are flipped by changing their data or turning them
+    /// around. Instances can't be mirrored, as their target would be left as it is.
+    pub fn mirrored(&self, axis: Axis) -> Result<Subtree, GraphCommandError> {
+        let mut nodes = self.nodes.clone();
+        for node in nodes.values_mut() {
+            node.transform = node.transform.mirrored(axis);
+            match (&mut node.data, axis) {
+                (NodeData::Plane(Plane { normal, .. }), _) => *normal = axis.reflect(*normal),
+                (
+                    NodeData::Capsule(Capsule {
+                        point_1, point_2, ..
+                    })
+                    | NodeData::TaperedCapsule(TaperedCapsule {
+                        point_1, point_2, ..
+                    }),
+                    _,
+                ) => {
+                    *point_1 = axis.reflect(*point_1);
+                    *point_2 = axis.reflect(*point_2);
+                }
+                // Both are round about one axis, so turning them halfway around another
+                // flips them along the third
+                (NodeData::Cone(_), Axis::Y) => {
+                    node.transform.rotation *= Quat::from_rotation_x(std::f32::consts::PI);
+                }
+                (NodeData::TorusSector(_), Axis::Z) => {
+                    node.transform.rotation *= Quat::from_rotation_y(std::f32::consts::PI);
+                }
+                (
+                    NodeData::BiconvexLens(BiconvexLens {
+                        lower_sagitta,
+                        upper_sagitta,
+                        ..
+                    }),
+                    Axis::Y,
+                ) => {
+                    std::mem::swap(lower_sagitta, upper_sagitta);
+                    let lower = node.bindings.remove("lower_sagitta");
+                    let upper = node.bindings.remove("upper_sagitta");
+                    node.bindings.extend(
+                        [("lower_sagitta", upper), ("upper_sagitta", lower)]
+                            .into_iter()
+                            .filter_map(|(field, source)| Some((field.to_string(), source?))),
+                    );
+                }
+                (NodeData::Instance(_), _) => {
+                    return Err(GraphCommandError::CannotMirrorInstance(node.id))
+                }
+                (NodeData::Transformation(transformation), _) => {
+                    let transform = transformation.transform().mirrored(axis);
+                    transformation.translation = transform.translation;
+                    transformation.rotation = transform.rotation;
+                }
+                // Everything else is symmetric across every axis plane. Mirroring a radial
+                // repeat reverses the order of its copies, but they're all still there.
+                _ => {}
+            }
+        }
+        Ok(Subtree {
+            root: self.root,
+            nodes,
+        })
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum GraphCommand {
     AddChild(NodeId, Option<usize>, NodeData),
     AddNewParent(Option<NodeId>, NodeId, NodeData),
+    /// Creates a new root node, or an additional root node if the graph already has a root.
     CreateNewRoot(NodeData),
+    /// Adds a copy of the subtree as a child of the given node, or as a root if no node
+    /// is given. Instances of nodes outside of the subtree lose their target.
+    AddSubtree(Option<NodeId>, Option<usize>, Subtree),
 
     Remove(NodeId),
+    /// Wraps sibling nodes in a new node, which takes the place of the first of them. The
+    /// nodes keep the order they had under their old parent.
+    Group(Vec<NodeId>, NodeData),
+    /// Wraps the node in a union along with a copy of it that's been mirrored across the
+    /// given axis, as described by [Subtree::mirrored].
+    Mirror(NodeId, Axis),
 
     ApplyDiff(NodeId, NodeDiff),
+    /// Replaces the node's data with the given data, which may be of another type. The
+    /// node keeps its transform, colour and label, but loses any bindings to fields that
+    /// the new type doesn't have. If the new type can't have children, the node's children
+    /// are removed along with their subtrees.
+    ChangeType(NodeId, NodeData),
+
+    /// Sets a variable, or removes it if there's no value. Fields bound to a variable that
+    /// doesn't exist fall back to their own values.
+    SetVariable(String, Option<f32>),
+    /// Binds a field of a node's data to an expression, which may just be a variable
+    /// name, or unbinds it if there's no expression.
+    SetBinding(NodeId, String, Option<String>),
+
+    Replace(GraphComponents),
+
+    /// Applies the commands in order, as one: if any of them fails, none of them are
+    /// applied, and peers receive all of their changes at once.
+    Batch(Vec<GraphCommand>),
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -51,12 +215,102 @@ pub enum GraphChange {
     DeleteNode(NodeId),
     ApplyDiff(NodeId, NodeDiff),
     SetRootNode(Option<NodeId>),
+    SetAdditionalRootNodes(Vec<NodeId>),
+    SetVariable(String, Option<f32>),
+    SetBinding(NodeId, String, Option<String>),
+    /// The changes from a [GraphCommand::Batch], to be applied together.
+    Batch(Vec<GraphChange>),
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GraphCommandError {
+    #[error("node {0:?} does not exist")]
+    NodeNotFound(NodeId),
+    #[error("node {0:?} is neither a root nor the child of another node")]
+    Detached(NodeId),
+    #[error("node {0:?} is not the parent of node {1:?}")]
+    NotParent(NodeId, NodeId),
+    #[error("child index {0} is out of range")]
+    ChildIndexOutOfRange(usize),
+    #[error("node {0:?} already has a parent")]
+    AlreadyHasParent(NodeId),
+    #[error("node {0:?} is a root, so it can't be a child")]
+    IsRoot(NodeId),
+    #[error("node {0:?} is given as a child more than once")]
+    DuplicateChild(NodeId),
+    #[error("referencing node {0:?} would create a cycle")]
+    Cycle(NodeId),
+    #[error("node {0:?} can't have children")]
+    CannotHaveChildren(NodeId),
+    #[error("{0} nodes can't have children")]
+    TypeCannotHaveChildren(String),
+    #[error("the nodes to group do not share a parent")]
+    NotSiblings,
+    #[error("instance {0:?} can't be mirrored, as its target wouldn't be")]
+    CannotMirrorInstance(NodeId),
+    #[error("node {0:?} is locked")]
+    Locked(NodeId),
+    #[error("the command contains non-finite values")]
+    InvalidValue,
+    #[error("the binding for {0} isn't a valid expression")]
+    InvalidBinding(String),
+}
+
+/// Why a node's transform can't be pushed down into its children.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum BakeTransformError {
+    #[error("node {0:?} does not exist")]
+    NodeNotFound(NodeId),
+    #[error("{0} nodes have no children to move their transform into")]
+    NoChildren(String),
+    #[error("radial repeats are around their own axis, which moving the transform would change")]
+    RadialRepeat,
+    #[error("transform nodes apply the transform they hold first, which moving it would change")]
+    Transformation,
+    #[error("smoothing isn't scaled along with the children, so it would change shape")]
+    ScaledSmoothing,
+}
+
+/// A problem with a graph that doesn't stop it from being edited, but that will stop it
+/// from being built, or is unlikely to be what was intended. See [Graph::validate].
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Validation {
+    #[error("there's no root, so there's nothing to build")]
+    NoRoot,
+    #[error("{1} node {0:?} has no children, so it can't be built")]
+    EmptyOperation(NodeId, String),
+    #[error("{1} node {0:?} has only one child, so it has no effect")]
+    SingleOperand(NodeId, String),
+    #[error("instance {0:?} has no target")]
+    MissingInstanceTarget(NodeId),
+    #[error("node {0:?} isn't connected to a root, so it won't be built")]
+    Disconnected(NodeId),
+}
+impl Validation {
+    /// The node with the problem, if the problem is specific to a node.
+    pub fn node_id(&self) -> Option<NodeId> {
+        match self {
+            Validation::NoRoot => None,
+            Validation::EmptyOperation(node_id, _)
+            | Validation::SingleOperand(node_id, _)
+            | Validation::MissingInstanceTarget(node_id)
+            | Validation::Disconnected(node_id) => Some(*node_id),
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Graph {
+    #[serde(serialize_with = "serialize_sorted_map")]
     nodes: HashMap<NodeId, Node>,
     root_node_id: Option<NodeId>,
+    /// Disjoint objects that are combined with the root when building the mesh. These are
+    /// only present if there's a root.
+    #[serde(default)]
+    additional_root_node_ids: Vec<NodeId>,
+    /// Named values that node fields can be bound to.
+    #[serde(default, serialize_with = "serialize_sorted_map")]
+    variables: HashMap<String, f32>,
 
     id_generator: Option<IdGenerator>,
 }
@@ -66,6 +320,8 @@ impl Graph {
         Graph {
             nodes: HashMap::new(),
             root_node_id: None,
+            additional_root_node_ids: vec![],
+            variables: HashMap::new(),
             id_generator: Some(IdGenerator::new()),
         }
     }
@@ -74,20 +330,96 @@ impl Graph {
         Graph {
             nodes: HashMap::new(),
             root_node_id: None,
+            additional_root_node_ids: vec![],
+            variables: HashMap::new(),
             id_generator: None,
         }
     }
 
-    fn from_components((nodes, root_node_id): GraphComponents) -> Graph {
+    pub fn authoritative_from_components(
+        (nodes, root_node_id, additional_root_node_ids, variables): GraphComponents,
+    ) -> Graph {
+        let id_generator = IdGenerator::from_existing_ids(nodes.keys().copied());
+        Graph {
+            nodes,
+            root_node_id,
+            additional_root_node_ids,
+            variables,
+            id_generator: Some(id_generator),
+        }
+    }
+
+    /// Builds a standalone graph from the subtree, with its ids renumbered from zero in
+    /// depth-first order. Instances of nodes outside of the subtree lose their target.
+    pub fn authoritative_from_subtree(subtree: &Subtree, variables: HashMap<String, f32>) -> Graph {
+        fn assign_ids(subtree: &Subtree, node_id: NodeId, ids: &mut HashMap<NodeId, NodeId>) {
+            ids.insert(node_id, NodeId(ids.len() as u32));
+            for child_id in subtree.nodes[&node_id].children.iter().flatten() {
+                assign_ids(subtree, *child_id, ids);
+            }
+        }
+        let mut ids = HashMap::new();
+        assign_ids(subtree, subtree.root, &mut ids);
+
+        let nodes = subtree
+            .nodes
+            .iter()
+            .filter_map(|(old_id, node)| {
+                let id = *ids.get(old_id)?;
+                let mut node = Node {
+                    id,
+                    children: node
+                        .children
+                        .iter()
+                        .map(|child_id| child_id.map(|child_id| ids[&child_id]))
+                        .collect(),
+                    ..node.clone()
+                };
+                if let NodeData::Instance(instance) = &mut node.data {
+                    instance.target = instance.target.and_then(|t| ids.get(&t).copied());
+                }
+                Some((id, node))
+            })
+            .collect();
+
+        Graph::authoritative_from_components((nodes, Some(ids[&subtree.root]), vec![], variables))
+    }
+
+    fn from_components(
+        (nodes, root_node_id, additional_root_node_ids, variables): GraphComponents,
+    ) -> Graph {
         Graph {
             nodes,
             root_node_id,
+            additional_root_node_ids,
+            variables,
             id_generator: None,
         }
     }
 
+    /// A hash of the nodes, roots and variables, which is the same for any two graphs with
+    /// the same contents regardless of the order they were built in. This is FNV-1a over a
+    /// sorted serialisation, so it's stable across builds and platforms.
+    pub fn content_hash(&self) -> u64 {
+        let nodes: BTreeMap<_, _> = self.nodes.iter().map(|(id, node)| (id.0, node)).collect();
+        let variables: BTreeMap<_, _> = self.variables.iter().collect();
+        let bytes = bincode::serialize(&(
+            nodes,
+            self.root_node_id,
+            &self.additional_root_node_ids,
+            variables,
+        ))
+        .expect("graph contents should always serialise");
+        fnv1a(&bytes)
+    }
+
     pub fn to_components(&self) -> GraphComponents {
-        (self.nodes.clone(), self.root_node_id)
+        (
+            self.nodes.clone(),
+            self.root_node_id,
+            self.additional_root_node_ids.clone(),
+            self.variables.clone(),
+        )
     }
 
     fn is_authoritative(&self) -> bool {
@@ -98,11 +430,8 @@ impl Graph {
         assert!(self.is_authoritative());
         let id = self.id_generator.as_mut().unwrap().generate();
         let node = Node {
-            id,
-            rgb: Node::DEFAULT_COLOUR,
             transform,
-            data,
-            children: vec![],
+            ..Node::new(id, data)
         };
         self.nodes.insert(id, node.clone());
         (id, GraphChange::CreateNode(id, node))
@@ -125,15 +454,16 @@ impl Graph {
         }
     }
 
+    /// Removes every node that can't be reached from a root, which is every node if there
+    /// are no roots left.
     fn garbage_collect(&mut self) -> Vec<GraphChange> {
         assert!(self.is_authoritative());
-        let root_node_id = match self.root_node_id {
-            Some(it) => it,
-            _ => return vec![],
-        };
+        let root_node_ids = self.root_node_ids();
         let all: HashSet<_> = self.nodes.keys().copied().collect();
         let mut seen = HashSet::new();
-        self.find_all_reachable_nodes(root_node_id, &mut seen);
+        for root_node_id in root_node_ids {
+            self.find_all_reachable_nodes(root_node_id, &mut seen);
+        }
 
         let ids: Vec<_> = all.difference(&seen).cloned().collect();
         for id in &ids {
@@ -143,37 +473,571 @@ impl Graph {
         ids.into_iter().map(GraphChange::DeleteNode).collect()
     }
 
-    fn apply_command_impl(&mut self, command: &GraphCommand) -> Option<Vec<GraphChange>> {
+    /// Copies the node and its descendants out of the graph.
+    pub fn subtree(&self, node_id: NodeId) -> Option<Subtree> {
+        self.get(node_id)?;
+        let mut reachable = HashSet::new();
+        self.find_all_reachable_nodes(node_id, &mut reachable);
+        Some(Subtree {
+            root: node_id,
+            nodes: reachable
+                .into_iter()
+                .map(|id| (id, self.nodes[&id].clone()))
+                .collect(),
+        })
+    }
+
+    /// The commands that add `count` copies of the node's subtree alongside it, each moved
+    /// by `offset` from the one before, so that rows of objects can be laid out in one go.
+    /// The offset is in the space of the node's parent, like its own translation.
+    pub fn duplicate_with_offset(
+        &self,
+        node_id: NodeId,
+        offset: Vec3,
+        count: usize,
+    ) -> Option<Vec<GraphCommand>> {
+        let subtree = self.subtree(node_id)?;
+        let parent_id = self.parent_of(node_id);
+        let commands = (1..=count)
+            .map(|i| {
+                let mut copy = subtree.clone();
+                if let Some(root) = copy.nodes.get_mut(&copy.root) {
+                    root.transform.translation += offset * i as f32;
+                }
+                GraphCommand::AddSubtree(parent_id, None, copy)
+            })
+            .collect();
+        Some(commands)
+    }
+
+    /// The commands that set the colour of the node and everything beneath it. Locked
+    /// nodes keep their colours, as they'd reject the change anyway.
+    pub fn recolour_subtree(
+        &self,
+        node_id: NodeId,
+        rgb: (f32, f32, f32),
+    ) -> Option<Vec<GraphCommand>> {
+        self.get(node_id)?;
+        let mut reachable = HashSet::new();
+        self.find_all_reachable_nodes(node_id, &mut reachable);
+        let mut node_ids: Vec<_> = reachable.into_iter().collect();
+        node_ids.sort();
+        let commands = node_ids
+            .into_iter()
+            .filter(|id| !self.nodes[id].locked && self.nodes[id].rgb != rgb)
+            .map(|id| {
+                let diff = NodeDiff {
+                    rgb: Some(rgb),
+                    ..Default::default()
+                };
+                GraphCommand::ApplyDiff(id, diff)
+            })
+            .collect();
+        Some(commands)
+    }
+
+    /// Adds the subtree's nodes to the graph with fresh ids, returning the id of its root.
+    /// Like [Self::authoritative_from_subtree], instances of nodes within the subtree are
+    /// pointed at the copies, and instances of nodes outside of it lose their target.
+    fn add_subtree(&mut self, subtree: &Subtree, changes: &mut Vec<GraphChange>) -> NodeId {
+        // Children are numbered and added before their parents
+        fn assign_ids(
+            subtree: &Subtree,
+            node_id: NodeId,
+            id_generator: &mut IdGenerator,
+            ids: &mut Vec<(NodeId, NodeId)>,
+        ) {
+            for child_id in subtree.nodes[&node_id].children.iter().flatten() {
+                assign_ids(subtree, *child_id, id_generator, ids);
+            }
+            ids.push((node_id, id_generator.generate()));
+        }
+        let mut order = vec![];
+        let id_generator = self.id_generator.as_mut().unwrap();
+        assign_ids(subtree, subtree.root, id_generator, &mut order);
+        let ids: HashMap<_, _> = order.iter().copied().collect();
+
+        for (old_id, id) in order {
+            let old_node = &subtree.nodes[&old_id];
+            let mut node = Node {
+                id,
+                children: old_node
+                    .children
+                    .iter()
+                    .map(|child_id| child_id.map(|child_id| ids[&child_id]))
+                    .collect(),
+                ..old_node.clone()
+            };
+            if let NodeData::Instance(instance) = &mut node.data {
+                instance.target = instance.target.and_then(|t| ids.get(&t).copied());
+            }
+            self.nodes.insert(id, node.clone());
+            changes.push(GraphChange::CreateNode(id, node));
+        }
+        ids[&subtree.root]
+    }
+
+    pub fn variables(&self) -> &HashMap<String, f32> {
+        &self.variables
+    }
+
+    /// The node's data with any bound fields replaced by the values of their expressions.
+    /// Fields whose expressions refer to missing variables keep their own values, while
+    /// any other error is returned along with the field it's for.
+    pub fn resolved_data<'a>(
+        &self,
+        node: &'a Node,
+    ) -> Result<Cow<'a, NodeData>, (&'a str, ExpressionError)> {
+        let mut data = Cow::Borrowed(&node.data);
+        for (field, source) in &node.bindings {
+            match expression::evaluate(source, &self.variables) {
+                // Bindings can't be used to get around the bounds that edits are held to
+                Ok(value) if !Self::within_checked_bounds(&data, field, value) => {
+                    return Err((field, ExpressionError::OutOfBounds))
+                }
+                Ok(value) => {
+                    data.to_mut().set_float_field(field, value);
+                }
+                Err(ExpressionError::UnknownVariable(_)) => {}
+                Err(err) => return Err((field, err)),
+            }
+        }
+        Ok(data)
+    }
+
+    fn within_checked_bounds(data: &NodeData, field: &str, value: f32) -> bool {
+        data.fields()
+            .iter()
+            .filter(|meta| meta.field == field && meta.checked)
+            .all(|meta| value.is_within(meta.min, meta.max))
+    }
+
+    /// Checks that a binding can be parsed, so that bindings can't be used to send peers
+    /// something too big or too deeply nested to evaluate.
+    fn validate_binding(
+        field: &str,
+        source: &str,
+        variables: &HashMap<String, f32>,
+    ) -> Result<(), GraphCommandError> {
+        Expression::parse(source, variables)
+            .map(|_| ())
+            .map_err(|_| GraphCommandError::InvalidBinding(field.to_string()))
+    }
+
+    /// The node that has `id` as a child, if any.
+    pub fn parent_of(&self, id: NodeId) -> Option<NodeId> {
+        self.nodes
+            .values()
+            .find(|node| node.children.contains(&Some(id)))
+            .map(|node| node.id)
+    }
+
+    fn validate_node_data(node_data: &NodeData) -> Result<(), GraphCommandError> {
+        (node_data.is_valid() && node_data.is_within_bounds())
+            .then_some(())
+            .ok_or(GraphCommandError::InvalidValue)
+    }
+
+    /// The diffs that move the node's transform into its children, leaving it with the
+    /// identity transform while keeping the same shape. This only works for nodes whose
+    /// result is the same whether the transform is applied to it or to each child:
+    /// primitives have no children to move it into, radial repeats would rotate around a
+    /// different axis, transform nodes would apply it before their own instead of after,
+    /// and smoothing would change if the transform scales.
+    pub fn bake_transform(
+        &self,
+        node_id: NodeId,
+    ) -> Result<Vec<(NodeId, NodeDiff)>, BakeTransformError> {
+        use BakeTransformError as E;
+        let node = self.get(node_id).ok_or(E::NodeNotFound(node_id))?;
+        let transform = node.transform;
+
+        let data = self
+            .resolved_data(node)
+            .unwrap_or(Cow::Borrowed(&node.data));
+        let factor = match data.as_ref() {
+            NodeData::Union(Union { factor })
+            | NodeData::Intersect(Intersect { factor })
+            | NodeData::Subtract(Subtract { factor }) => *factor,
+            NodeData::RadialRepeat(_) => return Err(E::RadialRepeat),
+            NodeData::Transformation(_) => return Err(E::Transformation),
+            data => return Err(E::NoChildren(data.name().to_string())),
+        };
+        if factor != 0.0 && transform.scale != 1.0 {
+            return Err(E::ScaledSmoothing);
+        }
+
+        let mut diffs = vec![(
+            node_id,
+            NodeDiff {
+                transform: Some(Transform::new().into()),
+                ..Default::default()
+            },
+        )];
+        for child in node
+            .children
+            .iter()
+            .flatten()
+            .filter_map(|id| self.get(*id))
+        {
+            let diff = NodeDiff {
+                transform: Some(transform.compose(&child.transform).into()),
+                ..Default::default()
+            };
+            diffs.push((child.id, diff));
+        }
+        Ok(diffs)
+    }
+
+    /// Checks that the data is valid, and is for a node that can hold children.
+    fn validate_container_data(node_data: &NodeData) -> Result<(), GraphCommandError> {
+        if !node_data.can_have_children() {
+            return Err(GraphCommandError::TypeCannotHaveChildren(
+                node_data.name().to_string(),
+            ));
+        }
+        Self::validate_node_data(node_data)
+    }
+
+    /// Checks that `children` can become the children of `node_id` without referencing
+    /// missing nodes, creating a cycle, or giving a node multiple parents. Roots can't be
+    /// adopted, as they'd be built twice and left behind when removed.
+    fn validate_children(
+        &self,
+        node_id: NodeId,
+        children: &[Option<NodeId>],
+    ) -> Result<(), GraphCommandError> {
+        use GraphCommandError as E;
+
+        let mut ancestors = vec![node_id];
+        while let Some(parent_id) = self.parent_of(*ancestors.last().unwrap()) {
+            if ancestors.contains(&parent_id) {
+                break;
+            }
+            ancestors.push(parent_id);
+        }
+
+        let root_node_ids = self.root_node_ids();
+        let mut seen = HashSet::new();
+        for child_id in children.iter().filter_map(|id| *id) {
+            self.get(child_id).ok_or(E::NodeNotFound(child_id))?;
+            if !seen.insert(child_id) {
+                return Err(E::DuplicateChild(child_id));
+            }
+            if ancestors.contains(&child_id) {
+                return Err(E::Cycle(child_id));
+            }
+            if root_node_ids.contains(&child_id) {
+                return Err(E::IsRoot(child_id));
+            }
+            if let Some(parent_id) = self.parent_of(child_id) {
+                if parent_id != node_id {
+                    return Err(E::AlreadyHasParent(child_id));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that making `instance_id` an instance of `target_id` won't make it contain
+    /// itself, either directly or through other instances.
+    fn validate_instance_target(
+        &self,
+        instance_id: NodeId,
+        target_id: NodeId,
+    ) -> Result<(), GraphCommandError> {
+        use GraphCommandError as E;
+
+        self.get(target_id).ok_or(E::NodeNotFound(target_id))?;
+        let mut visited = HashSet::new();
+        let mut stack = vec![target_id];
+        while let Some(node_id) = stack.pop() {
+            if node_id == instance_id {
+                return Err(E::Cycle(target_id));
+            }
+            let node = match self.get(node_id) {
+                Some(node) if visited.insert(node_id) => node,
+                _ => continue,
+            };
+            stack.extend(node.children.iter().filter_map(|id| *id));
+            if let NodeData::Instance(Instance {
+                target: Some(target_id),
+            }) = node.data
+            {
+                stack.push(target_id);
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_components(
+        nodes: &HashMap<NodeId, Node>,
+        root_node_ids: &[NodeId],
+    ) -> Result<(), GraphCommandError> {
+        use GraphCommandError as E;
+
+        for (id, node) in nodes {
+            if *id != node.id || !node.is_valid() {
+                return Err(E::InvalidValue);
+            }
+            for child_id in node.children.iter().filter_map(|id| *id) {
+                nodes.get(&child_id).ok_or(E::NodeNotFound(child_id))?;
+            }
+        }
+
+        // Only check the nodes reachable from the root, as the rest will be collected
+        fn visit(
+            nodes: &HashMap<NodeId, Node>,
+            node_id: NodeId,
+            path: &mut Vec<NodeId>,
+        ) -> Result<(), GraphCommandError> {
+            if path.contains(&node_id) {
+                return Err(E::Cycle(node_id));
+            }
+            path.push(node_id);
+            for child_id in nodes[&node_id].children.iter().filter_map(|id| *id) {
+                visit(nodes, child_id, path)?;
+            }
+            path.pop();
+            Ok(())
+        }
+        for &root_node_id in root_node_ids {
+            nodes
+                .get(&root_node_id)
+                .ok_or(E::NodeNotFound(root_node_id))?;
+            visit(nodes, root_node_id, &mut vec![])?;
+        }
+        Ok(())
+    }
+
+    /// Checks that a command can be applied in full, so that a bad command from a peer
+    /// can't leave the graph half-modified or corrupted.
+    fn validate_command(&self, command: &GraphCommand) -> Result<(), GraphCommandError> {
+        use GraphCommandError as E;
+        // Nodes that have been cut off from the graph are on their way out, and commands
+        // that assume that they have a place in it would fail part way through
+        let root_node_ids = self.root_node_ids();
+        let get = |id: NodeId| {
+            let node = self.get(id).ok_or(E::NodeNotFound(id))?;
+            if !root_node_ids.contains(&id) && self.parent_of(id).is_none() {
+                return Err(E::Detached(id));
+            }
+            Ok(node)
+        };
+
+        match command {
+            GraphCommand::AddChild(parent_id, index, node_data) => {
+                let parent = get(*parent_id)?;
+                if !parent.data.can_have_children() {
+                    return Err(E::CannotHaveChildren(*parent_id));
+                }
+                if let Some(index) = *index {
+                    if index > parent.children.len() {
+                        return Err(E::ChildIndexOutOfRange(index));
+                    }
+                }
+                Self::validate_node_data(node_data)
+            }
+            GraphCommand::AddNewParent(parent_id, child_id, node_data) => {
+                get(*child_id)?;
+                if let Some(parent_id) = *parent_id {
+                    if !get(parent_id)?.children.contains(&Some(*child_id)) {
+                        return Err(E::NotParent(parent_id, *child_id));
+                    }
+                }
+                Self::validate_container_data(node_data)
+            }
+            GraphCommand::CreateNewRoot(node_data) => Self::validate_node_data(node_data),
+            GraphCommand::AddSubtree(parent_id, index, subtree) => {
+                if let Some(parent_id) = *parent_id {
+                    let parent = get(parent_id)?;
+                    if !parent.data.can_have_children() {
+                        return Err(E::CannotHaveChildren(parent_id));
+                    }
+                    if let Some(index) = *index {
+                        if index > parent.children.len() {
+                            return Err(E::ChildIndexOutOfRange(index));
+                        }
+                    }
+                }
+                Self::validate_components(&subtree.nodes, &[subtree.root])?;
+                // The subtree's instances can only point within it once it's been added,
+                // so a copy of it on its own has the same instances to check
+                let standalone = Self::authoritative_from_subtree(subtree, HashMap::new());
+                for node in standalone.nodes.values() {
+                    if let NodeData::Instance(Instance {
+                        target: Some(target_id),
+                    }) = node.data
+                    {
+                        standalone.validate_instance_target(node.id, target_id)?;
+                    }
+                }
+                Ok(())
+            }
+            GraphCommand::Remove(node_id) => get(*node_id).map(|_| ()),
+            GraphCommand::Group(node_ids, node_data) => {
+                let unique: HashSet<_> = node_ids.iter().collect();
+                if node_ids.is_empty() || unique.len() != node_ids.len() {
+                    return Err(E::InvalidValue);
+                }
+                let parent_id = self.parent_of(node_ids[0]);
+                for node_id in node_ids {
+                    get(*node_id)?;
+                    let is_sibling = match parent_id {
+                        Some(_) => self.parent_of(*node_id) == parent_id,
+                        None => root_node_ids.contains(node_id),
+                    };
+                    if !is_sibling {
+                        return Err(E::NotSiblings);
+                    }
+                }
+                Self::validate_container_data(node_data)
+            }
+            GraphCommand::Mirror(node_id, axis) => {
+                self.validate_command(&GraphCommand::Group(vec![*node_id], Union::new().into()))?;
+                self.subtree(*node_id)
+                    .ok_or(E::NodeNotFound(*node_id))?
+                    .mirrored(*axis)
+                    .map(|_| ())
+            }
+            GraphCommand::ApplyDiff(node_id, diff) => {
+                let node = get(*node_id)?;
+                if !diff.is_valid() || !diff.data.iter().all(NodeDataDiff::is_within_bounds) {
+                    return Err(E::InvalidValue);
+                }
+                if node.locked && diff.edits_locked_fields() {
+                    return Err(E::Locked(*node_id));
+                }
+                if let Some(NodeDataDiff::InstanceDiff(InstanceDiff {
+                    target: Some(Some(target_id)),
+                })) = diff.data
+                {
+                    self.validate_instance_target(*node_id, target_id)?;
+                }
+                match &diff.children {
+                    Some(children) => {
+                        if !node.data.can_have_children() && children.iter().any(Option::is_some) {
+                            return Err(E::CannotHaveChildren(*node_id));
+                        }
+                        self.validate_children(*node_id, children)
+                    }
+                    None => Ok(()),
+                }
+            }
+            GraphCommand::ChangeType(node_id, node_data) => {
+                if get(*node_id)?.locked {
+                    return Err(E::Locked(*node_id));
+                }
+                if let NodeData::Instance(Instance {
+                    target: Some(target_id),
+                }) = node_data
+                {
+                    self.validate_instance_target(*node_id, *target_id)?;
+                }
+                Self::validate_node_data(node_data)
+            }
+            GraphCommand::SetVariable(_, value) => {
+                value.is_valid().then_some(()).ok_or(E::InvalidValue)
+            }
+            GraphCommand::SetBinding(node_id, field, source) => {
+                let node = get(*node_id)?;
+                if node.locked {
+                    return Err(E::Locked(*node_id));
+                }
+                if !node
+                    .data
+                    .float_fields()
+                    .iter()
+                    .any(|(name, _)| name == field)
+                {
+                    return Err(E::InvalidValue);
+                }
+                match source {
+                    Some(source) => Self::validate_binding(field, source, &self.variables),
+                    None => Ok(()),
+                }
+            }
+            GraphCommand::Replace((nodes, root_node_id, additional_root_node_ids, variables)) => {
+                if root_node_id.is_none() && !additional_root_node_ids.is_empty() {
+                    return Err(E::InvalidValue);
+                }
+                if !variables.values().all(FieldValue::is_valid) {
+                    return Err(E::InvalidValue);
+                }
+                let root_node_ids: Vec<_> = root_node_id
+                    .iter()
+                    .chain(additional_root_node_ids)
+                    .copied()
+                    .collect();
+                for node in nodes.values() {
+                    for (field, source) in &node.bindings {
+                        Self::validate_binding(field, source, variables)?;
+                    }
+                }
+                Self::validate_components(nodes, &root_node_ids)
+            }
+            GraphCommand::Batch(_) => unreachable!("batches are applied by `apply_batch`"),
+        }
+    }
+
+    /// Applies a validated command. Validation should catch anything that would make this
+    /// fail, but if it doesn't, the graph may be left half-modified, so this is only
+    /// applied to a copy of the graph.
+    fn apply_command_impl(
+        &mut self,
+        command: &GraphCommand,
+    ) -> Result<Vec<GraphChange>, GraphCommandError> {
+        use GraphCommandError as E;
         let mut changes = vec![];
         match command {
             GraphCommand::AddChild(parent_id, index, node_data) => {
                 let parent_id = *parent_id;
                 let (index, can_have_children) = {
-                    let parent = self.get(parent_id)?;
+                    let parent = self.get(parent_id).ok_or(E::NodeNotFound(parent_id))?;
                     (
                         index.unwrap_or(parent.children.len()),
                         parent.data.can_have_children(),
                     )
                 };
                 if !can_have_children {
-                    panic!("tried to add child to node without children");
+                    return Err(E::CannotHaveChildren(parent_id));
                 }
 
                 let (child_id, graph_change) = self.add(node_data.clone(), Transform::default());
                 changes.push(graph_change);
-                let add_child_diff = self.get_mut(parent_id)?.add_child(index, child_id);
+                let add_child_diff = self
+                    .get_mut(parent_id)
+                    .ok_or(E::NodeNotFound(parent_id))?
+                    .add_child(index, child_id);
                 changes.push(GraphChange::ApplyDiff(parent_id, add_child_diff));
             }
             GraphCommand::AddNewParent(parent_id, child_id, node_data) => {
-                assert!(node_data.can_have_children());
+                if !node_data.can_have_children() {
+                    return Err(E::TypeCannotHaveChildren(node_data.name().to_string()));
+                }
 
                 let (new_parent_id, graph_change) = {
-                    let child_transform = self.get(*child_id)?.transform;
+                    let child_transform = self
+                        .get(*child_id)
+                        .ok_or(E::NodeNotFound(*child_id))?
+                        .transform;
                     self.add(node_data.clone(), child_transform)
                 };
                 changes.push(graph_change);
 
-                let new_child_diff = self.get_mut(new_parent_id)?.add_child(0, *child_id);
+                // Leave an empty slot after the wrapped node, so that operations that combine
+                // several nodes can have their next operand added straight away
+                let new_child_diff = {
+                    let new_parent = self
+                        .get_mut(new_parent_id)
+                        .ok_or(E::NodeNotFound(new_parent_id))?;
+                    new_parent.add_child(0, *child_id);
+                    new_parent.children.push(None);
+                    NodeDiff {
+                        children: Some(new_parent.children.clone()),
+                        ..Default::default()
+                    }
+                };
                 changes.push(GraphChange::ApplyDiff(new_parent_id, new_child_diff));
 
                 {
@@ -181,59 +1045,234 @@ impl Graph {
                         transform: Some(Transform::default().into()),
                         ..Default::default()
                     };
-                    self.get_mut(*child_id)?.apply(transform_diff.clone());
+                    self.get_mut(*child_id)
+                        .ok_or(E::NodeNotFound(*child_id))?
+                        .apply(transform_diff.clone());
                     changes.push(GraphChange::ApplyDiff(*child_id, transform_diff));
                 }
 
                 if let Some(parent_id) = *parent_id {
-                    let parent = self.get_mut(parent_id)?;
+                    let parent = self.get_mut(parent_id).ok_or(E::NodeNotFound(parent_id))?;
                     let replace_child_diff = parent.replace_child(*child_id, new_parent_id);
                     changes.push(GraphChange::ApplyDiff(parent_id, replace_child_diff));
                 } else if self.root_node_id == Some(*child_id) {
                     self.root_node_id = Some(new_parent_id);
                     changes.push(GraphChange::SetRootNode(self.root_node_id));
+                } else if let Some(root_node_id) = self
+                    .additional_root_node_ids
+                    .iter_mut()
+                    .find(|id| **id == *child_id)
+                {
+                    *root_node_id = new_parent_id;
+                    changes.push(GraphChange::SetAdditionalRootNodes(
+                        self.additional_root_node_ids.clone(),
+                    ));
                 }
             }
             GraphCommand::CreateNewRoot(node_data) => {
-                assert!(self.root_node_id.is_none());
-
                 let (node_id, graph_change) = self.add(node_data.clone(), Transform::default());
                 changes.push(graph_change);
-
-                self.root_node_id = Some(node_id);
-                changes.push(GraphChange::SetRootNode(self.root_node_id));
+                changes.push(self.add_root(node_id));
+            }
+            GraphCommand::AddSubtree(parent_id, index, subtree) => {
+                let node_id = self.add_subtree(subtree, &mut changes);
+                match *parent_id {
+                    Some(parent_id) => {
+                        let parent = self.get_mut(parent_id).ok_or(E::NodeNotFound(parent_id))?;
+                        let index = index.unwrap_or(parent.children.len());
+                        let add_child_diff = parent.add_child(index, node_id);
+                        changes.push(GraphChange::ApplyDiff(parent_id, add_child_diff));
+                    }
+                    None => changes.push(self.add_root(node_id)),
+                }
             }
 
             GraphCommand::Remove(node_id) => {
                 if self.root_node_id == Some(*node_id) {
-                    self.root_node_id = None;
-                    changes.push(GraphChange::SetRootNode(None));
+                    // Promote the next root, so that there's always a root if there are any
+                    if self.additional_root_node_ids.is_empty() {
+                        self.root_node_id = None;
+                    } else {
+                        self.root_node_id = Some(self.additional_root_node_ids.remove(0));
+                        changes.push(GraphChange::SetAdditionalRootNodes(
+                            self.additional_root_node_ids.clone(),
+                        ));
+                    }
+                    changes.push(GraphChange::SetRootNode(self.root_node_id));
+                } else if self.additional_root_node_ids.contains(node_id) {
+                    self.additional_root_node_ids.retain(|id| id != node_id);
+                    changes.push(GraphChange::SetAdditionalRootNodes(
+                        self.additional_root_node_ids.clone(),
+                    ));
                 } else {
                     let parent = self
                         .nodes
                         .iter_mut()
-                        .find(|node| node.1.children.contains(&Some(*node_id)))?
+                        .find(|node| node.1.children.contains(&Some(*node_id)))
+                        .ok_or(E::Detached(*node_id))?
                         .1;
                     let remove_child_diff = parent.remove_child(*node_id);
                     changes.push(GraphChange::ApplyDiff(parent.id, remove_child_diff));
                 }
             }
 
+            GraphCommand::Group(node_ids, node_data) => {
+                let (group_id, graph_change) = self.add(node_data.clone(), Transform::default());
+                changes.push(graph_change);
+
+                let members = match self.parent_of(node_ids[0]) {
+                    Some(parent_id) => {
+                        let parent = self.get_mut(parent_id).ok_or(E::NodeNotFound(parent_id))?;
+                        let (children, members) =
+                            Self::group_slots(&parent.children, node_ids, group_id);
+                        let children_diff = NodeDiff {
+                            children: Some(children),
+                            ..Default::default()
+                        };
+                        parent.apply(children_diff.clone());
+                        changes.push(GraphChange::ApplyDiff(parent_id, children_diff));
+                        members
+                    }
+                    None => {
+                        let roots: Vec<_> = self.root_node_ids().into_iter().map(Some).collect();
+                        let (roots, members) = Self::group_slots(&roots, node_ids, group_id);
+                        let roots: Vec<_> = roots.into_iter().flatten().collect();
+                        self.root_node_id = roots.first().copied();
+                        self.additional_root_node_ids = roots.into_iter().skip(1).collect();
+                        changes.push(GraphChange::SetRootNode(self.root_node_id));
+                        changes.push(GraphChange::SetAdditionalRootNodes(
+                            self.additional_root_node_ids.clone(),
+                        ));
+                        members
+                    }
+                };
+
+                let group_diff = NodeDiff {
+                    children: Some(members.into_iter().map(Some).collect()),
+                    ..Default::default()
+                };
+                self.get_mut(group_id)
+                    .ok_or(E::NodeNotFound(group_id))?
+                    .apply(group_diff.clone());
+                changes.push(GraphChange::ApplyDiff(group_id, group_diff));
+            }
+            GraphCommand::Mirror(node_id, axis) => {
+                let mirrored = self
+                    .subtree(*node_id)
+                    .ok_or(E::NodeNotFound(*node_id))?
+                    .mirrored(*axis)?;
+                let union = Union::new().into();
+                changes
+                    .extend(self.apply_command_impl(&GraphCommand::Group(vec![*node_id], union))?);
+                let union_id = self.parent_of(*node_id).ok_or(E::Detached(*node_id))?;
+                changes.extend(self.apply_command_impl(&GraphCommand::AddSubtree(
+                    Some(union_id),
+                    None,
+                    mirrored,
+                ))?);
+            }
+
             GraphCommand::ApplyDiff(node_id, diff) => {
-                self.get_mut(*node_id)?.apply(diff.clone());
+                self.get_mut(*node_id)
+                    .ok_or(E::NodeNotFound(*node_id))?
+                    .apply(diff.clone());
                 changes.push(GraphChange::ApplyDiff(*node_id, diff.clone()));
             }
+            GraphCommand::ChangeType(node_id, node_data) => {
+                let node = self.get_mut(*node_id).ok_or(E::NodeNotFound(*node_id))?;
+                node.data = node_data.clone();
+                let float_fields = node.data.float_fields();
+                node.bindings
+                    .retain(|field, _| float_fields.iter().any(|(name, _)| name == field));
+                if !node.data.can_have_children() {
+                    // Their subtrees are garbage collected, as nothing else can reach them
+                    node.children.clear();
+                }
+                // A diff can't change the node's type, so the whole node is sent instead
+                changes.push(GraphChange::CreateNode(*node_id, node.clone()));
+            }
+
+            GraphCommand::SetVariable(name, value) => {
+                self.set_variable(name, *value);
+                changes.push(GraphChange::SetVariable(name.clone(), *value));
+            }
+            GraphCommand::SetBinding(node_id, field, variable) => {
+                self.get_mut(*node_id)
+                    .ok_or(E::NodeNotFound(*node_id))?
+                    .set_binding(field, variable.clone());
+                changes.push(GraphChange::SetBinding(
+                    *node_id,
+                    field.clone(),
+                    variable.clone(),
+                ));
+            }
+
+            GraphCommand::Replace(components) => {
+                *self = Self::authoritative_from_components(components.clone());
+                changes.push(GraphChange::Initialize(self.to_components()));
+            }
+            GraphCommand::Batch(_) => unreachable!("batches are applied by `apply_batch`"),
         }
-        Some(changes)
+        Ok(changes)
     }
 
-    pub fn apply_command(&mut self, command: &GraphCommand) -> Vec<GraphChange> {
+    /// Applies the command, returning the changes to send to peers. If it fails, the
+    /// graph is left as it was.
+    pub fn apply_command(
+        &mut self,
+        command: &GraphCommand,
+    ) -> Result<Vec<GraphChange>, GraphCommandError> {
         assert!(self.is_authoritative());
-        let mut ret = self
-            .apply_command_impl(command)
-            .expect("failed to apply commands cleanly");
-        ret.append(&mut self.garbage_collect());
-        ret
+        let mut graph = self.clone();
+        let changes = graph.apply_command_to_copy(command)?;
+        *self = graph;
+        Ok(changes)
+    }
+
+    /// Like [Self::apply_command], but leaves the graph half-modified if it fails.
+    fn apply_command_to_copy(
+        &mut self,
+        command: &GraphCommand,
+    ) -> Result<Vec<GraphChange>, GraphCommandError> {
+        if let GraphCommand::Batch(commands) = command {
+            return self.apply_batch(commands);
+        }
+        self.validate_command(command)?;
+        let mut changes = self.apply_command_impl(command)?;
+        changes.append(&mut self.garbage_collect());
+        Ok(changes)
+    }
+
+    /// Each command can depend on the ones before it, so they can't be validated up front.
+    /// Instead, they're applied one after the other, and if any of them fails, the copy of
+    /// the graph they're being applied to is thrown away.
+    fn apply_batch(
+        &mut self,
+        commands: &[GraphCommand],
+    ) -> Result<Vec<GraphChange>, GraphCommandError> {
+        let mut changes = vec![];
+        for command in commands {
+            changes.extend(self.apply_command_to_copy(command)?);
+        }
+        Ok(if changes.is_empty() {
+            vec![]
+        } else {
+            vec![GraphChange::Batch(changes)]
+        })
+    }
+
+    /// Predicts what a client's graph will look like once the server has applied
+    /// `commands`, by applying them to a copy as the server would. Commands that fail are
+    /// skipped, as the server will reject them too. Nodes created this way may not get the
+    /// IDs that the server will give them, so a prediction should be replaced once the
+    /// server has answered.
+    pub fn predict(&self, commands: &[GraphCommand]) -> Graph {
+        assert!(!self.is_authoritative());
+        let mut graph = Self::authoritative_from_components(self.to_components());
+        for command in commands {
+            let _ = graph.apply_command(command);
+        }
+        Self::from_components(graph.to_components())
     }
 
     pub fn apply_changes(&mut self, changes: &[GraphChange]) {
@@ -256,6 +1295,17 @@ impl Graph {
                         .apply(diff.clone());
                 }
                 GraphChange::SetRootNode(root_node) => self.root_node_id = *root_node,
+                GraphChange::SetAdditionalRootNodes(root_nodes) => {
+                    self.additional_root_node_ids = root_nodes.clone();
+                }
+                GraphChange::SetVariable(name, value) => self.set_variable(name, *value),
+                GraphChange::SetBinding(node_id, field, variable) => {
+                    self.nodes
+                        .get_mut(node_id)
+                        .expect("failed to find node to apply change to")
+                        .set_binding(field, variable.clone());
+                }
+                GraphChange::Batch(changes) => self.apply_changes(changes),
             }
         }
     }
@@ -264,6 +1314,155 @@ impl Graph {
         self.root_node_id
     }
 
+    /// The root node, followed by any additional root nodes.
+    pub fn root_node_ids(&self) -> Vec<NodeId> {
+        self.root_node_id
+            .iter()
+            .chain(&self.additional_root_node_ids)
+            .copied()
+            .collect()
+    }
+
+    fn set_variable(&mut self, name: &str, value: Option<f32>) {
+        match value {
+            Some(value) => {
+                self.variables.insert(name.to_string(), value);
+            }
+            None => {
+                self.variables.remove(name);
+            }
+        }
+    }
+
+    /// Replaces the first of the grouped nodes in `slots` with the group and removes the
+    /// others, returning the new slots and the grouped nodes in the order they were found.
+    fn group_slots(
+        slots: &[Option<NodeId>],
+        grouped: &[NodeId],
+        group_id: NodeId,
+    ) -> (Vec<Option<NodeId>>, Vec<NodeId>) {
+        let mut members = vec![];
+        let slots = slots
+            .iter()
+            .filter_map(|slot| match slot {
+                Some(id) if grouped.contains(id) => {
+                    members.push(*id);
+                    (members.len() == 1).then_some(Some(group_id))
+                }
+                slot => Some(*slot),
+            })
+            .collect();
+        (slots, members)
+    }
+
+    fn add_root(&mut self, node_id: NodeId) -> GraphChange {
+        if self.root_node_id.is_none() {
+            self.root_node_id = Some(node_id);
+            GraphChange::SetRootNode(self.root_node_id)
+        } else {
+            self.additional_root_node_ids.push(node_id);
+            GraphChange::SetAdditionalRootNodes(self.additional_root_node_ids.clone())
+        }
+    }
+
+    /// Looks for problems that would stop the graph from being built, or that are
+    /// probably mistakes, so that they can be pointed out before meshing. The problems
+    /// are ordered by node id.
+    pub fn validate(&self) -> Vec<Validation> {
+        let mut problems = vec![];
+        let root_node_ids = self.root_node_ids();
+        if root_node_ids.is_empty() {
+            problems.push(Validation::NoRoot);
+        }
+        let mut reachable = HashSet::new();
+        for root_node_id in root_node_ids {
+            self.find_all_reachable_nodes(root_node_id, &mut reachable);
+        }
+        let children: HashSet<_> = self
+            .nodes
+            .values()
+            .flat_map(|node| node.children.iter().flatten())
+            .collect();
+
+        let mut node_ids: Vec<_> = self.nodes.keys().copied().collect();
+        node_ids.sort();
+        for node_id in node_ids {
+            if !reachable.contains(&node_id) {
+                // Only the top of each disconnected subtree is reported
+                if !children.contains(&node_id) {
+                    problems.push(Validation::Disconnected(node_id));
+                }
+                continue;
+            }
+            let node = &self.nodes[&node_id];
+            // Metadata children add nothing to the shape, so they aren't operands
+            let child_count = node
+                .children
+                .iter()
+                .flatten()
+                .filter(|id| {
+                    self.get(**id)
+                        .is_some_and(|child| !child.data.is_metadata())
+                })
+                .count();
+            let name = || node.data.name().to_string();
+            match &node.data {
+                NodeData::Instance(Instance { target })
+                    if target.and_then(|id| self.get(id)).is_none() =>
+                {
+                    problems.push(Validation::MissingInstanceTarget(node_id));
+                }
+                NodeData::Intersect(_) | NodeData::Subtract(_) if child_count == 1 => {
+                    problems.push(Validation::SingleOperand(node_id, name()));
+                }
+                data if data.can_have_children() && child_count == 0 => {
+                    problems.push(Validation::EmptyOperation(node_id, name()));
+                }
+                _ => {}
+            }
+        }
+        problems
+    }
+
+    /// The axis-aligned bounds of the node's subtree as `(min, max)`, in world space, so
+    /// the transforms of the nodes above it are included. `None` if the subtree can't be
+    /// built, or has no volume or infinite bounds.
+    pub fn subtree_bounds(&self, node_id: NodeId) -> Option<(Vec3, Vec3)> {
+        crate::mesh::subtree_bounds(self, node_id)
+    }
+
+    /// The bounds of every root's subtree combined, with the same caveats as
+    /// [Graph::subtree_bounds]. `None` if there are no roots, or if any of them have no
+    /// bounds.
+    pub fn bounds(&self) -> Option<(Vec3, Vec3)> {
+        let root_node_ids = self.root_node_ids();
+        if root_node_ids.is_empty() {
+            return None;
+        }
+        root_node_ids.into_iter().try_fold(
+            (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+            |(min, max), node_id| {
+                let (root_min, root_max) = self.subtree_bounds(node_id)?;
+                Some((min.min(root_min), max.max(root_max)))
+            },
+        )
+    }
+
+    /// Every node reachable from the roots, depth-first, along with its depth.
+    pub fn reachable_node_ids(&self) -> Vec<(NodeId, usize)> {
+        fn visit(graph: &Graph, node_id: NodeId, depth: usize, ids: &mut Vec<(NodeId, usize)>) {
+            ids.push((node_id, depth));
+            for child_id in graph.get(node_id).unwrap().children.iter().flatten() {
+                visit(graph, *child_id, depth + 1, ids);
+            }
+        }
+        let mut ids = vec![];
+        for root_id in self.root_node_ids() {
+            visit(self, root_id, 0, &mut ids);
+        }
+        ids
+    }
+
     pub fn reachable_node_count(&self) -> usize {
         fn count_children(graph: &Graph, node_id: NodeId) -> usize {
             let node = graph.get(node_id).unwrap();
@@ -273,8 +1472,9 @@ impl Graph {
                 .filter_map(|id| Some(count_children(graph, (*id)?)))
                 .sum::<usize>()
         }
-        self.root_node_id
+        self.root_node_ids()
+            .into_iter()
             .map(|id| count_children(self, id))
-            .unwrap_or_default()
+            .sum()
     }
 }
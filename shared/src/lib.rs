@@ -7,8 +7,17 @@ pub use node_data::*;
 mod graph;
 pub use graph::*;
 
+mod schema;
+pub use schema::node_type_schema;
+
+pub mod expression;
+
+pub mod export;
+
 pub mod mesh;
 
+pub mod save;
+
 pub const DEFAULT_PORT: u16 = 23421;
 
 pub mod protocol;
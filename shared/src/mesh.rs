@@ -1,9 +1,19 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
 use glam::{Quat, Vec3};
 use thiserror::Error;
 
 use crate::{
+    expression::ExpressionError,
+    graph::fnv1a,
     node_data::*,
-    {Graph, NodeId},
+    {Graph, Material, Node, NodeId, Transform},
 };
 
 #[derive(Clone, Default)]
@@ -13,12 +23,289 @@ pub struct Mesh {
     pub normals: Vec<[f32; 3]>,
     pub colors: Vec<[f32; 3]>,
 }
+impl Mesh {
+    /// Adds the box between `min` and `max`, with a separate set of vertices for each face
+    /// so that the edges are sharp.
+    fn add_box(&mut self, min: Vec3, max: Vec3, rgb: [f32; 3]) {
+        let corners = [min, max];
+        for axis in 0..3 {
+            // `u` and `v` follow `axis` cyclically, so `u × v` points along `axis`
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            for side in 0..2 {
+                let mut normal = [0.0; 3];
+                normal[axis] = if side == 1 { 1.0 } else { -1.0 };
+                let first = self.positions.len() as u32;
+                for (u_side, v_side) in [(0, 0), (1, 0), (1, 1), (0, 1)] {
+                    let mut position = [0.0; 3];
+                    position[axis] = corners[side][axis];
+                    position[u] = corners[u_side][u];
+                    position[v] = corners[v_side][v];
+                    self.positions.push(position);
+                    self.normals.push(normal);
+                    self.colors.push(rgb);
+                }
+                // Wind the quad counter-clockwise when seen from outside the box
+                let quad = if side == 1 {
+                    [0, 1, 2, 0, 2, 3]
+                } else {
+                    [0, 2, 1, 0, 3, 2]
+                };
+                self.indices.extend(quad.map(|i| first + i));
+            }
+        }
+    }
+
+    /// Projects each vertex onto the axis-aligned plane that its normal faces the most.
+    /// This gives reasonable texture coordinates for SDF output, which has no natural
+    /// parameterisation, at the cost of seams where the dominant axis changes.
+    pub fn planar_uvs(&self) -> Vec<[f32; 2]> {
+        self.positions
+            .iter()
+            .zip(&self.normals)
+            .map(|([x, y, z], normal)| {
+                let [nx, ny, nz] = normal.map(f32::abs);
+                if nx >= ny && nx >= nz {
+                    [*z, *y]
+                } else if ny >= nz {
+                    [*x, *z]
+                } else {
+                    [*x, *y]
+                }
+            })
+            .collect()
+    }
+
+    /// The number of vertices with a position or normal that isn't finite.
+    pub fn non_finite_vertex_count(&self) -> usize {
+        self.positions
+            .iter()
+            .zip(&self.normals)
+            .filter(|(position, normal)| {
+                !position.iter().chain(normal.iter()).all(|v| v.is_finite())
+            })
+            .count()
+    }
+
+    /// Replaces the normals with the average of the adjacent face normals, weighted by the
+    /// faces' areas. This can look smoother than the SDF gradient at low resolutions.
+    pub fn recompute_normals(&mut self) {
+        let mut normals = vec![Vec3::ZERO; self.positions.len()];
+        for triangle in self.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| Vec3::from(self.positions[triangle[i] as usize]));
+            // The cross product's length is twice the triangle's area, which weights it
+            let face_normal = (b - a).cross(c - a);
+            for &index in triangle {
+                normals[index as usize] += face_normal;
+            }
+        }
+        self.normals = normals
+            .into_iter()
+            .map(|normal| normal.normalize_or_zero().to_array())
+            .collect();
+    }
+
+    /// Gives each triangle its own vertices with a face normal, for a faceted look.
+    pub fn flat_shaded(&self) -> Mesh {
+        let mut mesh = Mesh::default();
+        for triangle in self.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| Vec3::from(self.positions[triangle[i] as usize]));
+            let normal = (b - a).cross(c - a).normalize_or_zero().to_array();
+            for &index in triangle {
+                mesh.indices.push(mesh.positions.len() as u32);
+                mesh.positions.push(self.positions[index as usize]);
+                mesh.normals.push(normal);
+                mesh.colors.push(self.colors[index as usize]);
+            }
+        }
+        mesh
+    }
+
+    /// Writes the mesh as a Wavefront OBJ. Vertex colours are written after the positions,
+    /// which most tools that read OBJ understand.
+    pub fn to_obj(&self) -> String {
+        use std::fmt::Write;
+
+        let mut output = String::new();
+        for ([x, y, z], [r, g, b]) in self.positions.iter().zip(&self.colors) {
+            let _ = writeln!(output, "v {x} {y} {z} {r} {g} {b}");
+        }
+        for [x, y, z] in &self.normals {
+            let _ = writeln!(output, "vn {x} {y} {z}");
+        }
+        for triangle in self.indices.chunks_exact(3) {
+            // OBJ indices start at 1
+            let [a, b, c] = [0, 1, 2].map(|i| triangle[i] + 1);
+            let _ = writeln!(output, "f {a}//{a} {b}//{b} {c}//{c}");
+        }
+        output
+    }
+
+    /// Integrates over the tetrahedra formed by each triangle and the origin to find the
+    /// volume, center of mass and inertia tensor, assuming uniform unit density. The
+    /// tetrahedra outside the mesh have negative volume and cancel out, but only if the
+    /// mesh is closed, so the result is flagged as approximate otherwise.
+    pub fn mass_properties(&self) -> MassProperties {
+        let mut volume = 0.0;
+        let mut first_moment = Vec3::ZERO;
+        // The integrals of xx, yy, zz, xy, yz and zx over the volume
+        let mut second_moment = [0.0f32; 6];
+        let second_moment_axes = [(0, 0), (1, 1), (2, 2), (0, 1), (1, 2), (2, 0)];
+        for triangle in self.indices.chunks_exact(3) {
+            let points = [0, 1, 2].map(|i| Vec3::from(self.positions[triangle[i] as usize]));
+            let [a, b, c] = points;
+            let tetrahedron_volume = a.dot(b.cross(c)) / 6.0;
+            let sum = a + b + c;
+            volume += tetrahedron_volume;
+            // The origin is the fourth vertex, so it doesn't contribute to the centroid sum
+            first_moment += tetrahedron_volume * sum / 4.0;
+            let covariance = |i: usize, j: usize| {
+                points.iter().map(|p| p[i] * p[j]).sum::<f32>() + sum[i] * sum[j]
+            };
+            for (moment, (i, j)) in second_moment.iter_mut().zip(second_moment_axes) {
+                *moment += tetrahedron_volume / 20.0 * covariance(i, j);
+            }
+        }
+
+        let center_of_mass = if volume != 0.0 {
+            first_moment / volume
+        } else {
+            Vec3::ZERO
+        };
+        // Move the second moments to the center of mass, then convert them to the tensor
+        let [xx, yy, zz, xy, yz, zx] = second_moment;
+        let [cx, cy, cz] = center_of_mass.to_array();
+        let [xx, yy, zz] = [
+            xx - volume * cx * cx,
+            yy - volume * cy * cy,
+            zz - volume * cz * cz,
+        ];
+        let [xy, yz, zx] = [
+            xy - volume * cx * cy,
+            yz - volume * cy * cz,
+            zx - volume * cz * cx,
+        ];
+
+        MassProperties {
+            volume,
+            center_of_mass,
+            inertia: [yy + zz, xx + zz, xx + yy, -xy, -yz, -zx],
+            approximate: !self.is_closed(),
+        }
+    }
+
+    /// Whether every edge is shared by exactly two triangles.
+    fn is_closed(&self) -> bool {
+        let mut edge_counts = std::collections::HashMap::new();
+        for triangle in self.indices.chunks_exact(3) {
+            for (a, b) in [(0, 1), (1, 2), (2, 0)] {
+                let (a, b) = (triangle[a], triangle[b]);
+                *edge_counts.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+            }
+        }
+        edge_counts.values().all(|count| *count == 2)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MassProperties {
+    pub volume: f32,
+    pub center_of_mass: Vec3,
+    /// The inertia tensor about the center of mass, as `[Ixx, Iyy, Izz, Ixy, Iyz, Izx]`.
+    pub inertia: [f32; 6],
+    /// Set if the mesh isn't closed, in which case the other values are only estimates.
+    pub approximate: bool,
+}
+
+/// Reduces the mesh to roughly `target_ratio` of its triangles by vertex clustering: the
+/// vertices are snapped to a grid, and the vertices in each cell are merged into their
+/// average. This is fast and keeps colours and normals smooth, but it isn't aware of
+/// features, so sharp edges and thin parts smaller than a cell can be rounded off or
+/// lost entirely. Triangles that collapse to a line or a point are removed.
+pub fn simplify_mesh(mesh: &Mesh, target_ratio: f32) -> Mesh {
+    if target_ratio >= 1.0 || mesh.indices.is_empty() {
+        return mesh.clone();
+    }
+    let target_ratio = target_ratio.max(0.01);
+    let position = |index: u32| Vec3::from(mesh.positions[index as usize]);
+
+    // The number of vertices on a surface scales with the inverse square of the spacing
+    // between them, so scale the average edge length to get the cell size
+    let edge_lengths: f32 = mesh
+        .indices
+        .chunks_exact(3)
+        .map(|t| position(t[0]).distance(position(t[1])))
+        .sum();
+    let average_edge_length = edge_lengths / (mesh.indices.len() / 3) as f32;
+    let cell_size = average_edge_length / target_ratio.sqrt();
+    if !(cell_size > 0.0 && cell_size.is_finite()) {
+        return mesh.clone();
+    }
+
+    #[derive(Default)]
+    struct Cluster {
+        count: usize,
+        position: Vec3,
+        normal: Vec3,
+        colour: Vec3,
+    }
+    let mut cells = std::collections::HashMap::new();
+    let mut clusters: Vec<Cluster> = vec![];
+    let vertex_to_cluster: Vec<usize> = (0..mesh.positions.len())
+        .map(|vertex| {
+            let position = Vec3::from(mesh.positions[vertex]);
+            let cell = (position / cell_size).floor().as_ivec3().to_array();
+            let cluster_index = *cells.entry(cell).or_insert_with(|| {
+                clusters.push(Cluster::default());
+                clusters.len() - 1
+            });
+            let cluster = &mut clusters[cluster_index];
+            cluster.count += 1;
+            cluster.position += position;
+            cluster.normal += Vec3::from(mesh.normals[vertex]);
+            cluster.colour += Vec3::from(mesh.colors[vertex]);
+            cluster_index
+        })
+        .collect();
+
+    let mut simplified = Mesh::default();
+    for cluster in &clusters {
+        let count = cluster.count as f32;
+        simplified
+            .positions
+            .push((cluster.position / count).to_array());
+        simplified
+            .normals
+            .push(cluster.normal.normalize_or_zero().to_array());
+        simplified.colors.push((cluster.colour / count).to_array());
+    }
+    for triangle in mesh.indices.chunks_exact(3) {
+        let [a, b, c] = [0, 1, 2].map(|i| vertex_to_cluster[triangle[i] as usize] as u32);
+        if a == b || b == c || a == c {
+            continue;
+        }
+        let [pa, pb, pc] = [a, b, c].map(|i| Vec3::from(simplified.positions[i as usize]));
+        if (pb - pa).cross(pc - pa).length_squared() <= f32::EPSILON * cell_size * cell_size {
+            continue;
+        }
+        simplified.indices.extend([a, b, c]);
+    }
+    simplified
+}
 
 pub struct CompilationOutput {
     pub mesh: Mesh,
     pub exo_node_count: usize,
     pub triangle_count: usize,
+    /// The volume of the model's bounding box.
     pub volume: f32,
+    /// The volume enclosed by the mesh. See [MassProperties::volume].
+    pub mesh_volume: f32,
+    pub center_of_mass: Vec3,
+    /// See [MassProperties::inertia].
+    pub inertia: [f32; 6],
+    /// Set if the mass properties were computed from a mesh that isn't closed.
+    pub mass_properties_approximate: bool,
+    pub material: Material,
 }
 
 #[derive(Error, Debug)]
@@ -31,94 +318,529 @@ pub enum CompilationError {
     NoVolume,
     #[error("the mesh has infinite bounds")]
     InfiniteBounds,
-    #[error("a node has no children")]
-    NoChildren,
-    #[error("a node has negative scale")]
-    NegativeScale,
-    #[error("a node has negative size")]
-    NegativeSize,
+    #[error("node {0:?} has no children")]
+    NoChildren(NodeId),
+    #[error("node {0:?} has negative scale")]
+    NegativeScale(NodeId),
+    #[error("node {0:?} has negative size")]
+    NegativeSize(NodeId),
+    #[error("node {0:?} doesn't reference an existing node")]
+    MissingInstanceTarget(NodeId),
+    #[error("node {0:?} is an instance of itself")]
+    InstanceCycle(NodeId),
+    #[error("node {0:?} is metadata, which has no shape")]
+    NoShape(NodeId),
+    #[error("node {0:?} has an invalid expression for `{1}`: {2}")]
+    InvalidExpression(NodeId, String, ExpressionError),
+    #[error("the mesh has {0} vertices with non-finite positions or normals")]
+    NonFiniteVertices(usize),
+    #[error("mesh generation was cancelled")]
+    Cancelled,
+    #[error("the mesh has {triangles} triangles, which is over the budget of {budget}")]
+    BudgetExceeded { triangles: usize, budget: usize },
+}
+impl CompilationError {
+    /// The node that caused the error, if the error is specific to a node.
+    pub fn node_id(&self) -> Option<NodeId> {
+        match self {
+            CompilationError::NoChildren(node_id)
+            | CompilationError::NegativeScale(node_id)
+            | CompilationError::NegativeSize(node_id)
+            | CompilationError::MissingInstanceTarget(node_id)
+            | CompilationError::InstanceCycle(node_id)
+            | CompilationError::NoShape(node_id)
+            | CompilationError::InvalidExpression(node_id, ..) => Some(*node_id),
+            _ => None,
+        }
+    }
 }
 pub type Result<T> = core::result::Result<T, CompilationError>;
 
+#[derive(Debug, Clone, Default)]
+pub struct CompilationOptions {
+    pub colours_enabled: bool,
+    /// Colours each primitive with a distinct colour picked from its id, instead of the
+    /// nodes' own colours, to show which primitive each part of the surface comes from.
+    pub isolate_colours: bool,
+    /// Preview-only cut through the model; everything in front of the plane is removed.
+    pub clipping_plane: Option<Plane>,
+    /// Recomputes normals from the faces instead of using the SDF's gradient.
+    pub recompute_normals: bool,
+    /// Builds only this node's subtree, as if it were the root. Ignored if the node
+    /// doesn't exist.
+    pub solo: Option<NodeId>,
+    /// Checked between each stage of generation, which stops with
+    /// [CompilationError::Cancelled] once it's set.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// The most triangles the mesh can have. This is checked as soon as the mesh is built,
+    /// so that the work after that, and whatever the caller does with the mesh, is skipped.
+    pub triangle_budget: Option<usize>,
+    /// Models with infinite bounds, like ones containing planes, are cut down to the cube
+    /// that reaches this far from the origin along each axis, instead of failing with
+    /// [CompilationError::InfiniteBounds]. Finite models aren't affected.
+    pub infinite_bounds_half_size: Option<f32>,
+}
+impl CompilationOptions {
+    fn check_cancelled(&self) -> Result<()> {
+        match &self.cancel {
+            Some(cancel) if cancel.load(Ordering::Relaxed) => Err(CompilationError::Cancelled),
+            _ => Ok(()),
+        }
+    }
+}
+
 struct CompilationContext<'a> {
     saft_graph: &'a mut saft::Graph,
     exo_graph: &'a Graph,
     colours_enabled: bool,
+    isolate_colours: bool,
+    /// The instances currently being built, innermost last, so that an instance that
+    /// contains itself can be caught instead of recursing forever.
+    instance_stack: Vec<NodeId>,
+    /// The [subtree_key] of each node that's been looked at, or `None` if its subtree
+    /// can't be cached.
+    subtree_keys: HashMap<NodeId, Option<u64>>,
+    /// What each subtree built so far was compiled to, by [subtree_key], so that a subtree
+    /// that appears again, like the target of several instances, is only built once.
+    compiled: HashMap<u64, saft::NodeId>,
+}
+impl<'a> CompilationContext<'a> {
+    fn new(
+        saft_graph: &'a mut saft::Graph,
+        exo_graph: &'a Graph,
+        colours_enabled: bool,
+        isolate_colours: bool,
+    ) -> Self {
+        Self {
+            saft_graph,
+            exo_graph,
+            colours_enabled,
+            isolate_colours,
+            instance_stack: vec![],
+            subtree_keys: HashMap::new(),
+            compiled: HashMap::new(),
+        }
+    }
+}
+
+pub fn generate_mesh(graph: &Graph, options: &CompilationOptions) -> Result<CompilationOutput> {
+    generate_mesh_with_progress(graph, options, &mut |_| {})
 }
 
-pub fn generate_mesh(graph: &Graph, colours_enabled: bool) -> Result<CompilationOutput> {
+/// Like [generate_mesh], but calls `progress` with the fraction of the work that's done
+/// as each stage finishes. The mesher doesn't report its own progress, and it takes most
+/// of the time, so the fraction jumps from when the bounds are known to when it's done.
+pub fn generate_mesh_with_progress(
+    graph: &Graph,
+    options: &CompilationOptions,
+    progress: &mut dyn FnMut(f32),
+) -> Result<CompilationOutput> {
     let mut saft_graph = saft::Graph::default();
-    let root_id = compile_node(
-        &mut CompilationContext {
-            saft_graph: &mut saft_graph,
-            exo_graph: graph,
-            colours_enabled,
-        },
-        graph.root_node_id().ok_or(CompilationError::NoRootNode)?,
-    )?;
+    let exo_root_ids = exo_root_ids(graph, options)?;
+    let mut ctx = CompilationContext::new(
+        &mut saft_graph,
+        graph,
+        options.colours_enabled,
+        options.isolate_colours,
+    );
+    let root_ids = exo_root_ids
+        .iter()
+        .map(|id| {
+            options.check_cancelled()?;
+            compile_node(&mut ctx, *id)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    progress(0.1);
+    // Multiple roots are disjoint objects, so they're combined into a single mesh
+    let mut root_id = match root_ids[..] {
+        [root_id] => root_id,
+        _ => saft_graph.op_union_multi(root_ids),
+    };
+    if let Some(plane) = &options.clipping_plane {
+        let normal = plane.normal.normalize_or_zero();
+        if normal != Vec3::ZERO {
+            let plane_id = saft_graph.plane((normal, plane.distance_from_origin).into());
+            root_id = saft_graph.op_intersect(root_id, plane_id);
+        }
+    }
 
-    let bounding_box = saft_graph.bounding_box(root_id);
+    let mut bounding_box = saft_graph.bounding_box(root_id);
+    if let Some(half_size) = options
+        .infinite_bounds_half_size
+        .filter(|half_size| *half_size > 0.0 && half_size.is_finite())
+    {
+        if !bounding_box.is_finite() {
+            let clip_box_id = saft_graph.rounded_box(Vec3::splat(half_size), 0.0);
+            root_id = saft_graph.op_intersect(root_id, clip_box_id);
+            bounding_box = saft_graph.bounding_box(root_id);
+        }
+    }
     if bounding_box.volume() == 0.0 {
         return Err(CompilationError::NoVolume);
     }
-    if !bounding_box.is_finite() {
+    // A box can be too big for its volume to fit in an `f32`, which saft can't size a grid for
+    if !bounding_box.is_finite() || !bounding_box.volume().is_finite() {
         return Err(CompilationError::InfiniteBounds);
     }
+    progress(0.2);
+    options.check_cancelled()?;
     let mesh = saft::mesh_from_sdf(&saft_graph, root_id, saft::MeshOptions::default())?;
-    let mesh = Mesh {
+    progress(0.9);
+    options.check_cancelled()?;
+    if let Some(budget) = options.triangle_budget {
+        let triangles = mesh.indices.len() / 3;
+        if triangles > budget {
+            return Err(CompilationError::BudgetExceeded { triangles, budget });
+        }
+    }
+    let mut mesh = Mesh {
         indices: mesh.indices,
         positions: mesh.positions,
         normals: mesh.normals,
         colors: mesh.colors,
     };
+    if options.recompute_normals {
+        mesh.recompute_normals();
+    }
+    // Extreme parameters can produce NaNs, which would break anything consuming the mesh
+    let non_finite_vertices = mesh.non_finite_vertex_count();
+    if non_finite_vertices > 0 {
+        return Err(CompilationError::NonFiniteVertices(non_finite_vertices));
+    }
     let triangle_count = mesh.indices.len() / 3;
+    let mass_properties = mesh.mass_properties();
+    progress(1.0);
     Ok(CompilationOutput {
         mesh,
         exo_node_count: graph.reachable_node_count(),
         triangle_count,
         volume: bounding_box.volume(),
+        mesh_volume: mass_properties.volume,
+        center_of_mass: mass_properties.center_of_mass,
+        inertia: mass_properties.inertia,
+        mass_properties_approximate: mass_properties.approximate,
+        material: dominant_material(graph, &exo_root_ids),
     })
 }
 
-fn compile_node(ctx: &mut CompilationContext, node: NodeId) -> Result<saft::NodeId> {
-    let node = ctx.exo_graph.get(node).unwrap();
-    let mut node_id = compile_node_data(ctx, &node.data, &node.children)?;
-    let transform = &node.transform;
-    if transform.scale < 0.0 {
-        return Err(CompilationError::NegativeScale);
+/// The nodes to build the mesh from, as chosen by [CompilationOptions::solo].
+fn exo_root_ids(graph: &Graph, options: &CompilationOptions) -> Result<Vec<NodeId>> {
+    let exo_root_ids = match options.solo.filter(|id| graph.get(*id).is_some()) {
+        Some(solo_id) => vec![solo_id],
+        None => graph.root_node_ids(),
+    };
+    // Metadata has no shape, so a graph of nothing else has nothing to build
+    let exo_root_ids: Vec<_> = exo_root_ids
+        .into_iter()
+        .filter(|id| !graph.get(*id).unwrap().data.is_metadata())
+        .collect();
+    if exo_root_ids.is_empty() {
+        return Err(CompilationError::NoRootNode);
     }
-    if transform.scale != 1.0 {
-        node_id = ctx.saft_graph.op_scale(node_id, transform.scale);
+    Ok(exo_root_ids)
+}
+
+/// A quick stand-in for [generate_mesh] that draws a box around each primitive instead of
+/// meshing the distance field, for laying out a scene without waiting for the mesher.
+/// Operations are ignored, so subtracted primitives get boxes too, and the clipping plane
+/// and triangle budget don't apply. Overlapping boxes are counted twice, so the mass
+/// properties are always approximate.
+pub fn generate_bounds_mesh(
+    graph: &Graph,
+    options: &CompilationOptions,
+) -> Result<CompilationOutput> {
+    struct Primitive {
+        node_id: NodeId,
+        /// The transforms of the primitive and its ancestors, outermost first.
+        transforms: Vec<Transform>,
+        rgb: (f32, f32, f32),
     }
-    if !transform.rotation.is_near_identity() {
-        node_id = saft_graph_rotate(ctx.saft_graph, node_id, &transform.rotation);
+
+    /// Finds every primitive beneath `node_id`.
+    fn visit(
+        graph: &Graph,
+        options: &CompilationOptions,
+        node_id: NodeId,
+        transforms: &mut Vec<Transform>,
+        inherited_rgb: Option<(f32, f32, f32)>,
+        primitives: &mut Vec<Primitive>,
+    ) {
+        let node = graph.get(node_id).unwrap();
+        if node.data.is_metadata() {
+            return;
+        }
+        // Like when meshing, the outermost colour overrides the ones beneath it
+        let rgb = inherited_rgb.or_else(|| {
+            (options.colours_enabled && node.rgb != (1.0, 1.0, 1.0)).then_some(node.rgb)
+        });
+        transforms.push(node.transform);
+        if node.data.can_have_children() {
+            // Applied to the children before the node's own transform
+            let children_transform = node.data.children_transform();
+            transforms.extend(children_transform);
+            for child_id in node.children.iter().flatten() {
+                visit(graph, options, *child_id, transforms, rgb, primitives);
+            }
+            if children_transform.is_some() {
+                transforms.pop();
+            }
+        } else {
+            let rgb = if options.isolate_colours {
+                isolation_colour(node_id)
+            } else {
+                rgb.unwrap_or((1.0, 1.0, 1.0))
+            };
+            primitives.push(Primitive {
+                node_id,
+                transforms: transforms.clone(),
+                rgb,
+            });
+        }
+        transforms.pop();
     }
-    if transform.translation.length_squared() != 0.0 {
-        node_id = saft_graph_translate(ctx.saft_graph, node_id, &transform.translation);
+
+    let exo_root_ids = exo_root_ids(graph, options)?;
+    let mut primitives = vec![];
+    for root_id in &exo_root_ids {
+        visit(graph, options, *root_id, &mut vec![], None, &mut primitives);
     }
 
-    if ctx.colours_enabled && node.rgb != (1.0, 1.0, 1.0) {
-        node_id = ctx
-            .saft_graph
-            .op_rgb(node_id, [node.rgb.0, node.rgb.1, node.rgb.2]);
+    let mut mesh = Mesh::default();
+    let (mut min, mut max) = (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY));
+    for Primitive {
+        node_id,
+        transforms,
+        rgb,
+    } in primitives
+    {
+        options.check_cancelled()?;
+        let mut saft_graph = saft::Graph::default();
+        let mut ctx = CompilationContext::new(&mut saft_graph, graph, false, false);
+        // The primitive's own transform is applied when it's compiled
+        let mut saft_id = compile_node(&mut ctx, node_id)?;
+        for transform in transforms.iter().rev().skip(1) {
+            saft_id = apply_transform(&mut saft_graph, saft_id, transform);
+        }
+        let bounding_box = saft_graph.bounding_box(saft_id);
+        // Planes have no bounds to draw
+        if !bounding_box.is_finite() {
+            continue;
+        }
+        let (box_min, box_max) = (
+            Vec3::from(bounding_box.min.to_array()),
+            Vec3::from(bounding_box.max.to_array()),
+        );
+        mesh.add_box(box_min, box_max, [rgb.0, rgb.1, rgb.2]);
+        min = min.min(box_min);
+        max = max.max(box_max);
+    }
+    if mesh.indices.is_empty() {
+        return Err(CompilationError::NoVolume);
+    }
+
+    let size = max - min;
+    let mass_properties = mesh.mass_properties();
+    Ok(CompilationOutput {
+        exo_node_count: graph.reachable_node_count(),
+        triangle_count: mesh.indices.len() / 3,
+        volume: size.x * size.y * size.z,
+        mesh_volume: mass_properties.volume,
+        center_of_mass: mass_properties.center_of_mass,
+        inertia: mass_properties.inertia,
+        mass_properties_approximate: true,
+        material: dominant_material(graph, &exo_root_ids),
+        mesh,
+    })
+}
+
+/// The generated mesh can only be rendered with a single material, so pick the material
+/// that covers the most primitives. Like colours, a non-default material on an operation
+/// overrides the materials of everything beneath it.
+fn dominant_material(graph: &Graph, root_ids: &[NodeId]) -> Material {
+    fn tally(
+        graph: &Graph,
+        node_id: NodeId,
+        inherited: Option<Material>,
+        counts: &mut Vec<(Material, usize)>,
+    ) {
+        let node = graph.get(node_id).unwrap();
+        let inherited =
+            inherited.or_else(|| (node.material != Material::new()).then_some(node.material));
+        if !node.data.can_have_children() && !node.data.is_metadata() {
+            let material = inherited.unwrap_or(node.material);
+            match counts.iter_mut().find(|(m, _)| *m == material) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((material, 1)),
+            }
+        }
+        for child_id in node.children.iter().filter_map(|id| *id) {
+            tally(graph, child_id, inherited, counts);
+        }
+    }
+
+    let mut counts = vec![];
+    for root_id in root_ids {
+        tally(graph, *root_id, None, &mut counts);
+    }
+    // `max_by_key` picks the last maximum, so reverse to prefer the first material found
+    counts
+        .into_iter()
+        .rev()
+        .max_by_key(|(_, count)| *count)
+        .map(|(material, _)| material)
+        .unwrap_or_default()
+}
+
+fn compile_node(ctx: &mut CompilationContext, node: NodeId) -> Result<saft::NodeId> {
+    let key = subtree_key(ctx, node);
+    if let Some(saft_id) = key.and_then(|key| ctx.compiled.get(&key)) {
+        return Ok(*saft_id);
+    }
+
+    let node = ctx.exo_graph.get(node).unwrap();
+    let data = ctx
+        .exo_graph
+        .resolved_data(node)
+        .map_err(|(field, err)| CompilationError::InvalidExpression(node.id, field.into(), err))?;
+    let node_id = compile_node_data(ctx, node.id, &data, &node.children)?;
+    if node.transform.scale < 0.0 {
+        return Err(CompilationError::NegativeScale(node.id));
+    }
+    let mut node_id = apply_transform(ctx.saft_graph, node_id, &node.transform);
+
+    if let Some((r, g, b)) = compiled_rgb(ctx, node) {
+        node_id = ctx.saft_graph.op_rgb(node_id, [r, g, b]);
     }
 
+    if let Some(key) = key {
+        ctx.compiled.insert(key, node_id);
+    }
     Ok(node_id)
 }
 
-fn validate_size(size: &f32) -> Result<f32> {
-    if *size >= 0.0 {
-        Ok(*size)
+/// The colour that a node is built with, if any.
+fn compiled_rgb(ctx: &CompilationContext, node: &Node) -> Option<(f32, f32, f32)> {
+    // Colours on operations override their children's, so isolation only colours the
+    // primitives
+    if ctx.isolate_colours {
+        (!node.data.can_have_children()).then(|| isolation_colour(node.id))
     } else {
-        Err(CompilationError::NegativeSize)
+        (ctx.colours_enabled && node.rgb != (1.0, 1.0, 1.0)).then_some(node.rgb)
+    }
+}
+
+/// A hash of everything that goes into building a node's subtree: its resolved data,
+/// transform and colour, and the keys of its children, or of its target if it's an
+/// instance. Two subtrees with the same key build the same shape, wherever they are.
+/// Subtrees that can't be built, like ones containing an instance cycle, have no key.
+fn subtree_key(ctx: &mut CompilationContext, node_id: NodeId) -> Option<u64> {
+    if let Some(key) = ctx.subtree_keys.get(&node_id) {
+        return *key;
+    }
+    // Marks the node as in progress, so that an instance cycle ends up without a key
+    // instead of recursing forever
+    ctx.subtree_keys.insert(node_id, None);
+
+    let key = (|| {
+        let node = ctx.exo_graph.get(node_id)?;
+        let data = ctx.exo_graph.resolved_data(node).ok()?;
+        let child_keys = match data.as_ref() {
+            NodeData::Instance(Instance { target }) => {
+                let target = target.filter(|id| ctx.exo_graph.get(*id).is_some())?;
+                vec![subtree_key(ctx, target)?]
+            }
+            _ => node
+                .children
+                .iter()
+                .flatten()
+                .map(|id| subtree_key(ctx, *id))
+                .collect::<Option<Vec<_>>>()?,
+        };
+        let bytes = bincode::serialize(&(
+            data.as_ref(),
+            &node.transform,
+            compiled_rgb(ctx, node),
+            child_keys,
+        ))
+        .ok()?;
+        Some(fnv1a(&bytes))
+    })();
+    ctx.subtree_keys.insert(node_id, key);
+    key
+}
+
+/// A bright colour for [CompilationOptions::isolate_colours]. Hues are spaced by the golden
+/// ratio, so nodes with nearby ids get clearly different colours.
+fn isolation_colour(node_id: NodeId) -> (f32, f32, f32) {
+    let hue = (node_id.0 as f32 * 0.618_034).fract() * 6.0;
+    let (saturation, value) = (0.7, 0.9);
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = value - chroma;
+    (r + m, g + m, b + m)
+}
+
+fn apply_transform(
+    graph: &mut saft::Graph,
+    mut node_id: saft::NodeId,
+    transform: &Transform,
+) -> saft::NodeId {
+    if transform.scale != 1.0 {
+        node_id = graph.op_scale(node_id, transform.scale);
+    }
+    if !transform.rotation.is_near_identity() {
+        node_id = saft_graph_rotate(graph, node_id, &transform.rotation);
+    }
+    if transform.translation.length_squared() != 0.0 {
+        node_id = saft_graph_translate(graph, node_id, &transform.translation);
     }
+    node_id
+}
+
+/// See [Graph::subtree_bounds].
+pub(crate) fn subtree_bounds(graph: &Graph, node_id: NodeId) -> Option<(Vec3, Vec3)> {
+    let mut saft_graph = saft::Graph::default();
+    let mut ctx = CompilationContext::new(&mut saft_graph, graph, false, false);
+    let mut root_id = compile_node(&mut ctx, node_id).ok()?;
+    let mut parent_id = graph.parent_of(node_id);
+    while let Some(parent) = parent_id.and_then(|id| graph.get(id)) {
+        if let Some(transform) = parent.data.children_transform() {
+            root_id = apply_transform(&mut saft_graph, root_id, &transform);
+        }
+        root_id = apply_transform(&mut saft_graph, root_id, &parent.transform);
+        parent_id = graph.parent_of(parent.id);
+    }
+
+    let bounding_box = saft_graph.bounding_box(root_id);
+    (bounding_box.volume() > 0.0 && bounding_box.is_finite()).then(|| {
+        (
+            Vec3::from(bounding_box.min.to_array()),
+            Vec3::from(bounding_box.max.to_array()),
+        )
+    })
 }
 
 fn compile_node_data(
     ctx: &mut CompilationContext,
+    exo_node_id: NodeId,
     node_data: &NodeData,
     children: &[Option<NodeId>],
 ) -> Result<saft::NodeId> {
+    let validate_size = |size: &f32| {
+        if *size >= 0.0 {
+            Ok(*size)
+        } else {
+            Err(CompilationError::NegativeSize(exo_node_id))
+        }
+    };
     match node_data {
         NodeData::Sphere(Sphere { radius }) => Ok(ctx
             .saft_graph
@@ -184,54 +906,136 @@ fn compile_node_data(
             validate_size(upper_sagitta)?,
             validate_size(chord)?,
         )),
+        NodeData::Instance(Instance { target }) => {
+            let target_id = target
+                .filter(|id| ctx.exo_graph.get(*id).is_some())
+                .ok_or(CompilationError::MissingInstanceTarget(exo_node_id))?;
+            if ctx.instance_stack.contains(&exo_node_id) {
+                return Err(CompilationError::InstanceCycle(exo_node_id));
+            }
+            ctx.instance_stack.push(exo_node_id);
+            let node_id = compile_node(ctx, target_id);
+            ctx.instance_stack.pop();
+            node_id
+        }
+
+        NodeData::Transformation(transformation) => {
+            let nodes = compile_nodes(ctx, children)?;
+            let node_id = match nodes[..] {
+                [] => return Err(CompilationError::NoChildren(exo_node_id)),
+                [node_id] => node_id,
+                _ => ctx.saft_graph.op_union_multi(nodes),
+            };
+            let transform = transformation.transform();
+            if transform.scale < 0.0 {
+                return Err(CompilationError::NegativeScale(exo_node_id));
+            }
+            Ok(apply_transform(ctx.saft_graph, node_id, &transform))
+        }
+
+        NodeData::Comment(_) => Err(CompilationError::NoShape(exo_node_id)),
 
         NodeData::Union(Union { factor }) => {
+            let factor = clamped_factor(exo_node_id, *factor);
             let nodes = compile_nodes(ctx, children)?;
-            let is_unsmoothed = *factor == 0.0;
+            let is_unsmoothed = factor == 0.0;
             if nodes.is_empty() {
-                Err(CompilationError::NoChildren)
+                Err(CompilationError::NoChildren(exo_node_id))
             } else if nodes.len() == 2 {
                 let (lhs, rhs) = (nodes[0], nodes[1]);
                 if is_unsmoothed {
                     Ok(ctx.saft_graph.op_union(lhs, rhs))
                 } else {
-                    Ok(ctx.saft_graph.op_union_smooth(lhs, rhs, *factor))
+                    Ok(ctx.saft_graph.op_union_smooth(lhs, rhs, factor))
                 }
             } else if is_unsmoothed {
                 Ok(ctx.saft_graph.op_union_multi(nodes))
             } else {
-                Ok(ctx.saft_graph.op_union_multi_smooth(nodes, *factor))
+                Ok(ctx.saft_graph.op_union_multi_smooth(nodes, factor))
             }
         }
         NodeData::Intersect(Intersect { factor }) => {
+            let factor = clamped_factor(exo_node_id, *factor);
             let nodes = compile_nodes(ctx, children)?;
-            apply_infix_operation_over_array(&nodes, |lhs, rhs| {
-                if *factor == 0.0 {
+            let (lhs, rest) = nodes
+                .split_first()
+                .ok_or(CompilationError::NoChildren(exo_node_id))?;
+            // Like subtracting, the rest of the children are intersected as one shape, so
+            // that the smoothing is applied once, no matter how many children there are
+            let rhs = match rest {
+                [] => return Ok(*lhs),
+                _ => apply_infix_operation_over_array(exo_node_id, rest, |lhs, rhs| {
                     ctx.saft_graph.op_intersect(lhs, rhs)
-                } else {
-                    ctx.saft_graph.op_intersect_smooth(lhs, rhs, *factor)
-                }
-            })
+                })?,
+            };
+            if factor == 0.0 {
+                Ok(ctx.saft_graph.op_intersect(*lhs, rhs))
+            } else {
+                Ok(ctx.saft_graph.op_intersect_smooth(*lhs, rhs, factor))
+            }
         }
         NodeData::Subtract(Subtract { factor }) => {
+            let factor = clamped_factor(exo_node_id, *factor);
             let nodes = compile_nodes(ctx, children)?;
-            apply_infix_operation_over_array(&nodes, |lhs, rhs| {
-                if *factor == 0.0 {
-                    ctx.saft_graph.op_subtract(lhs, rhs)
-                } else {
-                    ctx.saft_graph.op_subtract_smooth(lhs, rhs, *factor)
-                }
-            })
+            let (lhs, rest) = nodes
+                .split_first()
+                .ok_or(CompilationError::NoChildren(exo_node_id))?;
+            // The rest of the children are subtracted as one shape, so that the smoothing
+            // is applied once, no matter how many children there are
+            let rhs = match rest {
+                [] => return Ok(*lhs),
+                [rhs] => *rhs,
+                _ => ctx.saft_graph.op_union_multi(rest.to_vec()),
+            };
+            if factor == 0.0 {
+                Ok(ctx.saft_graph.op_subtract(*lhs, rhs))
+            } else {
+                Ok(ctx.saft_graph.op_subtract_smooth(*lhs, rhs, factor))
+            }
         }
+        NodeData::RadialRepeat(RadialRepeat { count }) => {
+            let nodes = compile_nodes(ctx, children)?;
+            let node_id = match nodes[..] {
+                [] => return Err(CompilationError::NoChildren(exo_node_id)),
+                [node_id] => node_id,
+                _ => ctx.saft_graph.op_union_multi(nodes),
+            };
+            // Files aren't checked as they're loaded, so this may not have been either
+            let count = (*count).min(RadialRepeat::MAX_COUNT);
+            if count <= 1 {
+                return Ok(node_id);
+            }
+            // saft can't fold the sample point's angle, so union rotated copies instead
+            let copies = (0..count)
+                .map(|i| {
+                    let angle = i as f32 / count as f32 * std::f32::consts::TAU;
+                    saft_graph_rotate(ctx.saft_graph, node_id, &Quat::from_rotation_y(angle))
+                })
+                .collect();
+            Ok(ctx.saft_graph.op_union_multi(copies))
+        }
+    }
+}
+
+/// [clamp_factor], with a warning if the factor changes. Edits can't leave a factor out of
+/// range, so it must have come from an older save.
+fn clamped_factor(exo_node_id: NodeId, factor: f32) -> f32 {
+    let clamped = clamp_factor(factor);
+    if clamped != factor {
+        println!(
+            "node {exo_node_id:?}: smoothing factor {factor} is out of range, using {clamped}"
+        );
     }
+    clamped
 }
 
 fn apply_infix_operation_over_array(
+    exo_node_id: NodeId,
     nodes: &[saft::NodeId],
     mut operation: impl FnMut(saft::NodeId, saft::NodeId) -> saft::NodeId,
 ) -> Result<saft::NodeId> {
     if nodes.is_empty() {
-        Err(CompilationError::NoChildren)
+        Err(CompilationError::NoChildren(exo_node_id))
     } else if nodes.len() == 1 {
         Ok(nodes[0])
     } else {
@@ -250,6 +1054,7 @@ fn compile_nodes(
     nodes
         .iter()
         .filter_map(|id| *id)
+        .filter(|id| !ctx.exo_graph.get(*id).unwrap().data.is_metadata())
         .map(|id| compile_node(ctx, id))
         .collect()
 }
@@ -1,12 +1,14 @@
+use std::collections::BTreeMap;
+
 use glam::{Quat, Vec3};
 use serde::{Deserialize, Serialize};
 
 use crate::node_data::*;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct NodeId(pub(crate) u32);
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum NodeCategory {
     Primitive,
     Operation,
@@ -15,11 +17,40 @@ pub enum NodeCategory {
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedTransform")]
 pub struct Transform {
     pub translation: Vec3,
     pub rotation: Quat,
     pub scale: f32,
 }
+
+/// A [Transform] as it was stored, before its rotation has been checked. Rotations that
+/// have drifted or been edited by hand are normalised as they're loaded, as the mesher
+/// assumes unit quaternions; ones that can't be normalised are rejected.
+#[derive(Deserialize)]
+struct UncheckedTransform {
+    translation: Vec3,
+    rotation: Quat,
+    scale: f32,
+}
+impl TryFrom<UncheckedTransform> for Transform {
+    type Error = &'static str;
+
+    fn try_from(t: UncheckedTransform) -> Result<Self, Self::Error> {
+        if !t.rotation.is_finite() {
+            return Err("transform rotation is not finite");
+        }
+        // Anything this short has no meaningful direction to normalise to
+        if t.rotation.length_squared() < 1e-12 {
+            return Err("transform rotation is degenerate");
+        }
+        Ok(Transform {
+            translation: t.translation,
+            rotation: t.rotation.normalize(),
+            scale: t.scale,
+        })
+    }
+}
 impl Transform {
     pub const fn new() -> Self {
         Self {
@@ -35,6 +66,37 @@ impl Default for Transform {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+impl Axis {
+    pub const ALL: [Axis; 3] = [Axis::X, Axis::Y, Axis::Z];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Axis::X => "X",
+            Axis::Y => "Y",
+            Axis::Z => "Z",
+        }
+    }
+
+    pub fn unit(&self) -> Vec3 {
+        match self {
+            Axis::X => Vec3::X,
+            Axis::Y => Vec3::Y,
+            Axis::Z => Vec3::Z,
+        }
+    }
+
+    /// Reflects `v` across the plane through the origin that's perpendicular to this axis.
+    pub fn reflect(&self, v: Vec3) -> Vec3 {
+        v - 2.0 * v.dot(self.unit()) * self.unit()
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TransformDiff {
     pub translation: Option<Vec3>,
@@ -47,13 +109,68 @@ impl TransformDiff {
             self.translation.is_some() || self.rotation.is_some() || self.scale.is_some();
         has_changes.then_some(self)
     }
+
+    pub fn is_valid(&self) -> bool {
+        self.translation.is_valid() && self.rotation.is_valid() && self.scale.is_valid()
+    }
+
+    pub fn merge(self, newer: Self) -> Self {
+        Self {
+            translation: newer.translation.or(self.translation),
+            rotation: newer.rotation.or(self.rotation),
+            scale: newer.scale.or(self.scale),
+        }
+    }
+
+    pub fn lerp(&self, other: &Self, t: f32) -> Self {
+        Self {
+            translation: self.translation.lerp(&other.translation, t),
+            rotation: self.rotation.lerp(&other.rotation, t),
+            scale: self.scale.lerp(&other.scale, t),
+        }
+    }
 }
 impl Transform {
+    pub fn is_valid(&self) -> bool {
+        self.translation.is_valid() && self.rotation.is_valid() && self.scale.is_valid()
+    }
+
     pub fn apply(&mut self, diff: TransformDiff) {
         self.translation = diff.translation.unwrap_or(self.translation);
         self.rotation = diff.rotation.unwrap_or(self.rotation);
         self.scale = diff.scale.unwrap_or(self.scale);
     }
+
+    /// The transform that applies `inner` and then this one. Transforms scale, then
+    /// rotate, then translate, so this is exact as the scale is uniform.
+    pub fn compose(&self, inner: &Transform) -> Transform {
+        Transform {
+            translation: self.translation + self.rotation * (self.scale * inner.translation),
+            rotation: self.rotation * inner.rotation,
+            scale: self.scale * inner.scale,
+        }
+    }
+
+    /// Where this transform would put things if they were reflected across the plane
+    /// through the origin that's perpendicular to `axis`. A reflection can't be
+    /// represented without a negative scale, so this only moves and turns things; any
+    /// shape that isn't symmetric across the plane has to be flipped separately.
+    pub fn mirrored(&self, axis: Axis) -> Transform {
+        // Reflecting a rotation reflects its axis and reverses its direction, which is
+        // the same as negating the reflected axis
+        let rotation_axis =
+            -axis.reflect(Vec3::new(self.rotation.x, self.rotation.y, self.rotation.z));
+        Transform {
+            translation: axis.reflect(self.translation),
+            rotation: Quat::from_xyzw(
+                rotation_axis.x,
+                rotation_axis.y,
+                rotation_axis.z,
+                self.rotation.w,
+            ),
+            scale: self.scale,
+        }
+    }
 }
 impl From<Transform> for TransformDiff {
     fn from(t: Transform) -> Self {
@@ -65,13 +182,126 @@ impl From<Transform> for TransformDiff {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Material {
+    pub metallic: f32,
+    pub roughness: f32,
+    pub emissive: (f32, f32, f32),
+    /// How opaque the preview is, from 0 to 1. This only affects the viewport, not the
+    /// shape or any export.
+    #[serde(default = "Material::default_opacity")]
+    pub opacity: f32,
+}
+impl Material {
+    // Matches the defaults of Bevy's `StandardMaterial`, so that the look is unchanged
+    // for nodes that don't specify a material.
+    pub const fn new() -> Self {
+        Self {
+            metallic: 0.01,
+            roughness: 0.089,
+            emissive: (0.0, 0.0, 0.0),
+            opacity: 1.0,
+        }
+    }
+
+    const fn default_opacity() -> f32 {
+        Self::new().opacity
+    }
+}
+impl Default for Material {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MaterialDiff {
+    pub metallic: Option<f32>,
+    pub roughness: Option<f32>,
+    pub emissive: Option<(f32, f32, f32)>,
+    pub opacity: Option<f32>,
+}
+impl MaterialDiff {
+    pub fn into_option(self) -> Option<Self> {
+        let has_changes = self.metallic.is_some()
+            || self.roughness.is_some()
+            || self.emissive.is_some()
+            || self.opacity.is_some();
+        has_changes.then_some(self)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.metallic.is_valid()
+            && self.roughness.is_valid()
+            && self.emissive.is_valid()
+            && self.opacity.is_valid()
+    }
+
+    pub fn merge(self, newer: Self) -> Self {
+        Self {
+            metallic: newer.metallic.or(self.metallic),
+            roughness: newer.roughness.or(self.roughness),
+            emissive: newer.emissive.or(self.emissive),
+            opacity: newer.opacity.or(self.opacity),
+        }
+    }
+
+    pub fn lerp(&self, other: &Self, t: f32) -> Self {
+        Self {
+            metallic: self.metallic.lerp(&other.metallic, t),
+            roughness: self.roughness.lerp(&other.roughness, t),
+            emissive: self.emissive.lerp(&other.emissive, t),
+            opacity: self.opacity.lerp(&other.opacity, t),
+        }
+    }
+}
+impl Material {
+    pub fn is_valid(&self) -> bool {
+        self.metallic.is_valid()
+            && self.roughness.is_valid()
+            && self.emissive.is_valid()
+            && self.opacity.is_valid()
+    }
+
+    pub fn apply(&mut self, diff: MaterialDiff) {
+        self.metallic = diff.metallic.unwrap_or(self.metallic);
+        self.roughness = diff.roughness.unwrap_or(self.roughness);
+        self.emissive = diff.emissive.unwrap_or(self.emissive);
+        self.opacity = diff.opacity.unwrap_or(self.opacity);
+    }
+}
+impl From<Material> for MaterialDiff {
+    fn from(m: Material) -> Self {
+        MaterialDiff {
+            metallic: Some(m.metallic),
+            roughness: Some(m.roughness),
+            emissive: Some(m.emissive),
+            opacity: Some(m.opacity),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Node {
     pub id: NodeId,
+    #[serde(default)]
+    pub label: Option<String>,
     pub rgb: (f32, f32, f32),
+    #[serde(default)]
+    pub material: Material,
     pub transform: Transform,
     pub data: NodeData,
     pub children: Vec<Option<NodeId>>,
+    /// Fields of `data` that take their value from an expression, keyed by the field's
+    /// name. The simplest expression is just the name of a graph variable. If the
+    /// expression refers to a variable that doesn't exist, the field's own value is used
+    /// instead.
+    #[serde(default)]
+    pub bindings: BTreeMap<String, String>,
+    /// Locked nodes can't be edited, other than to unlock them, so that carefully-tuned
+    /// nodes aren't changed by accident.
+    #[serde(default)]
+    pub locked: bool,
 }
 impl Node {
     pub const DEFAULT_COLOUR: (f32, f32, f32) = (1.0, 1.0, 1.0);
@@ -79,10 +309,25 @@ impl Node {
     pub const fn new(id: NodeId, data: NodeData) -> Node {
         Node {
             id,
+            label: None,
             rgb: Self::DEFAULT_COLOUR,
+            material: Material::new(),
             transform: Transform::new(),
             data,
             children: vec![],
+            bindings: BTreeMap::new(),
+            locked: false,
+        }
+    }
+
+    pub(crate) fn set_binding(&mut self, field: &str, variable: Option<String>) {
+        match variable {
+            Some(variable) => {
+                self.bindings.insert(field.to_string(), variable);
+            }
+            None => {
+                self.bindings.remove(field);
+            }
         }
     }
 
@@ -136,25 +381,115 @@ impl Node {
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
 pub struct NodeDiff {
+    #[serde(default)]
+    pub label: Option<Option<String>>,
     pub rgb: Option<(f32, f32, f32)>,
+    /// Boxed to keep diffs, and the commands and changes that carry them, small. This is
+    /// spelled out in full, as `Box` is also a node type.
+    #[serde(default)]
+    pub material: Option<std::boxed::Box<MaterialDiff>>,
     pub transform: Option<TransformDiff>,
     pub data: Option<NodeDataDiff>,
     pub children: Option<Vec<Option<NodeId>>>,
+    #[serde(default)]
+    pub locked: Option<bool>,
 }
 impl NodeDiff {
     pub fn into_option(self) -> Option<Self> {
-        let has_changes = self.rgb.is_some()
+        let has_changes = self.label.is_some()
+            || self.rgb.is_some()
+            || self.material.is_some()
             || self.transform.is_some()
             || self.data.is_some()
-            || self.children.is_some();
+            || self.children.is_some()
+            || self.locked.is_some();
         has_changes.then_some(self)
     }
+
+    /// Whether the diff changes anything that locking a node protects. Children aren't
+    /// protected, as they're changed by adding or removing the children themselves.
+    pub fn edits_locked_fields(&self) -> bool {
+        self.label.is_some()
+            || self.rgb.is_some()
+            || self.material.is_some()
+            || self.transform.is_some()
+            || self.data.is_some()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.rgb.is_valid()
+            && self.material.iter().all(|m| m.is_valid())
+            && self.transform.iter().all(TransformDiff::is_valid)
+            && self.data.iter().all(NodeDataDiff::is_valid)
+    }
+
+    /// Combines two diffs into one that has the same effect as applying them in order.
+    pub fn merge(self, newer: NodeDiff) -> NodeDiff {
+        fn merge_with<T>(old: Option<T>, newer: Option<T>, f: impl FnOnce(T, T) -> T) -> Option<T> {
+            match (old, newer) {
+                (Some(old), Some(newer)) => Some(f(old, newer)),
+                (old, newer) => newer.or(old),
+            }
+        }
+
+        NodeDiff {
+            label: newer.label.or(self.label),
+            rgb: newer.rgb.or(self.rgb),
+            material: merge_with(self.material, newer.material, |old, newer| {
+                std::boxed::Box::new(old.merge(*newer))
+            }),
+            transform: merge_with(self.transform, newer.transform, TransformDiff::merge),
+            data: merge_with(self.data, newer.data, NodeDataDiff::merge),
+            children: newer.children.or(self.children),
+            locked: newer.locked.or(self.locked),
+        }
+    }
+
+    /// Blends between two diffs, field by field. Labels, children and locks can't be
+    /// blended, so they're taken from `self`.
+    pub fn lerp(&self, other: &NodeDiff, t: f32) -> NodeDiff {
+        fn lerp_with<T: Clone>(
+            a: &Option<T>,
+            b: &Option<T>,
+            f: impl FnOnce(&T, &T) -> T,
+        ) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(f(a, b)),
+                (a, b) => a.clone().or_else(|| b.clone()),
+            }
+        }
+
+        NodeDiff {
+            label: self.label.clone(),
+            rgb: self.rgb.lerp(&other.rgb, t),
+            material: lerp_with(&self.material, &other.material, |a, b| {
+                std::boxed::Box::new(a.lerp(b, t))
+            }),
+            transform: lerp_with(&self.transform, &other.transform, |a, b| a.lerp(b, t)),
+            data: lerp_with(&self.data, &other.data, |a, b| a.lerp(b, t)),
+            children: self.children.clone(),
+            locked: self.locked,
+        }
+    }
 }
 impl Node {
+    pub fn is_valid(&self) -> bool {
+        self.rgb.is_valid()
+            && self.material.is_valid()
+            && self.transform.is_valid()
+            && self.data.is_valid()
+    }
+
     pub fn apply(&mut self, diff: NodeDiff) {
+        if let Some(label) = diff.label {
+            self.label = label;
+        }
         if let Some(rgb) = diff.rgb {
             self.rgb = rgb;
         }
+        if let Some(d) = diff.material {
+            self.material.apply(*d);
+        }
         if let Some(d) = diff.transform {
             self.transform.apply(d);
         }
@@ -164,5 +499,8 @@ impl Node {
         if let Some(children) = diff.children {
             self.children = children;
         }
+        if let Some(locked) = diff.locked {
+            self.locked = locked;
+        }
     }
 }
@@ -1,8 +1,8 @@
 use derive_macros::node_type;
-use glam::Vec3;
+use glam::{Quat, Vec3};
 use serde::{Deserialize, Serialize};
 
-use crate::NodeCategory;
+use crate::{NodeCategory, NodeId, Transform, TransformDiff};
 
 pub trait NodeDataMeta {
     fn name(&self) -> &'static str;
@@ -10,29 +10,152 @@ pub trait NodeDataMeta {
     fn can_have_children(&self) -> bool;
 }
 
+/// Describes one of a node type's fields, for tools that work with node types generically.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FieldMeta {
+    /// The field's name in the struct, which is also its name when serialized.
+    pub field: &'static str,
+    /// The name shown in the editor.
+    pub name: &'static str,
+    /// The field's Rust type, like `f32` or `Option<NodeId>`.
+    pub ty: &'static str,
+    /// The smallest sensible value, for numeric fields that have one.
+    pub min: Option<f32>,
+    /// The largest sensible value, for numeric fields that have one.
+    pub max: Option<f32>,
+    /// Whether edits that leave the value outside the bounds are rejected. Loaded data
+    /// isn't held to them, so code using the value should still handle it being out of
+    /// range. Otherwise, the bounds are only a hint for editors. Set with `checked = true`.
+    pub checked: bool,
+}
+
+/// Implemented for every type used as a node field, so that values received from
+/// elsewhere can be checked before they're used, and so that values can be blended
+/// for animation.
+pub trait FieldValue: Sized {
+    fn is_valid(&self) -> bool;
+    /// Whether the value lies within a field's bounds, for the types that can have them.
+    fn is_within(&self, _min: Option<f32>, _max: Option<f32>) -> bool {
+        true
+    }
+    /// Blends from `self` at `t = 0` to `other` at `t = 1`.
+    fn lerp(&self, other: &Self, t: f32) -> Self;
+}
+impl FieldValue for f32 {
+    fn is_valid(&self) -> bool {
+        self.is_finite()
+    }
+    fn is_within(&self, min: Option<f32>, max: Option<f32>) -> bool {
+        !(min.is_some_and(|min| *self < min) || max.is_some_and(|max| *self > max))
+    }
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+}
+impl FieldValue for Vec3 {
+    fn is_valid(&self) -> bool {
+        self.is_finite()
+    }
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        Vec3::lerp(*self, *other, t)
+    }
+}
+impl FieldValue for Quat {
+    fn is_valid(&self) -> bool {
+        self.is_finite()
+    }
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        self.slerp(*other, t)
+    }
+}
+impl FieldValue for (f32, f32, f32) {
+    fn is_valid(&self) -> bool {
+        self.0.is_finite() && self.1.is_finite() && self.2.is_finite()
+    }
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        (
+            FieldValue::lerp(&self.0, &other.0, t),
+            FieldValue::lerp(&self.1, &other.1, t),
+            FieldValue::lerp(&self.2, &other.2, t),
+        )
+    }
+}
+impl FieldValue for u32 {
+    fn is_valid(&self) -> bool {
+        true
+    }
+    fn is_within(&self, min: Option<f32>, max: Option<f32>) -> bool {
+        (*self as f32).is_within(min, max)
+    }
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        FieldValue::lerp(&(*self as f32), &(*other as f32), t).round() as u32
+    }
+}
+/// Text can't be blended, so `self` is held until `t` reaches 1.
+impl FieldValue for String {
+    fn is_valid(&self) -> bool {
+        true
+    }
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        if t < 1.0 {
+            self.clone()
+        } else {
+            other.clone()
+        }
+    }
+}
+/// References to other nodes can't be blended, so `self` is held until `t` reaches 1.
+impl FieldValue for NodeId {
+    fn is_valid(&self) -> bool {
+        true
+    }
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        if t < 1.0 {
+            *self
+        } else {
+            *other
+        }
+    }
+}
+impl<T: FieldValue + Clone> FieldValue for Option<T> {
+    fn is_valid(&self) -> bool {
+        self.iter().all(T::is_valid)
+    }
+    fn is_within(&self, min: Option<f32>, max: Option<f32>) -> bool {
+        self.iter().all(|value| value.is_within(min, max))
+    }
+    /// A value that's only present on one side is held, rather than blended.
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        match (self, other) {
+            (Some(a), Some(b)) => Some(a.lerp(b, t)),
+            (a, b) => a.clone().or_else(|| b.clone()),
+        }
+    }
+}
+
 // Primitives
 
 #[node_type(name = "Sphere", category = NodeCategory::Primitive)]
 pub struct Sphere {
-    #[field(name = "Radius", default = 0.5)]
+    #[field(name = "Radius", default = 0.5, min = 0.0)]
     radius: f32,
 }
 
 #[node_type(name = "Cylinder", category = NodeCategory::Primitive)]
 pub struct Cylinder {
-    #[field(name = "Cylinder radius", default = 0.5)]
+    #[field(name = "Cylinder radius", default = 0.5, min = 0.0)]
     cylinder_radius: f32,
-    #[field(name = "Half-height", default = 0.5)]
+    #[field(name = "Half-height", default = 0.5, min = 0.0)]
     half_height: f32,
-    #[field(name = "Rounding radius", default = 0.0)]
+    #[field(name = "Rounding radius", default = 0.0, min = 0.0)]
     rounding_radius: f32,
 }
 
 #[node_type(name = "Torus", category = NodeCategory::Primitive)]
 pub struct Torus {
-    #[field(name = "Big radius", default = 0.5)]
+    #[field(name = "Big radius", default = 0.5, min = 0.0)]
     big_r: f32,
-    #[field(name = "Small radius", default = 0.1)]
+    #[field(name = "Small radius", default = 0.1, min = 0.0)]
     small_r: f32,
 }
 
@@ -51,7 +174,7 @@ pub struct Capsule {
     point_1: Vec3,
     #[field(name = "Point 2", default = glam::const_vec3!([0.0, 0.5, 0.0]))]
     point_2: Vec3,
-    #[field(name = "Radius", default = 0.5)]
+    #[field(name = "Radius", default = 0.5, min = 0.0)]
     radius: f32,
 }
 
@@ -61,17 +184,17 @@ pub struct TaperedCapsule {
     point_1: Vec3,
     #[field(name = "Point 2", default = glam::const_vec3!([0.0, 0.5, 0.0]))]
     point_2: Vec3,
-    #[field(name = "Radius 1", default = 0.5)]
+    #[field(name = "Radius 1", default = 0.5, min = 0.0)]
     radius_1: f32,
-    #[field(name = "Radius 2", default = 0.5)]
+    #[field(name = "Radius 2", default = 0.5, min = 0.0)]
     radius_2: f32,
 }
 
 #[node_type(name = "Cone", category = NodeCategory::Primitive)]
 pub struct Cone {
-    #[field(name = "Radius", default = 0.5)]
+    #[field(name = "Radius", default = 0.5, min = 0.0)]
     radius: f32,
-    #[field(name = "Height", default = 1.0)]
+    #[field(name = "Height", default = 1.0, min = 0.0)]
     height: f32,
 }
 
@@ -79,15 +202,15 @@ pub struct Cone {
 pub struct Box {
     #[field(name = "Half-size", default = glam::const_vec3!([0.5, 0.5, 0.5]))]
     half_size: Vec3,
-    #[field(name = "Rounding radius", default = 0.0)]
+    #[field(name = "Rounding radius", default = 0.0, min = 0.0)]
     rounding_radius: f32,
 }
 
 #[node_type(name = "Torus Sector", category = NodeCategory::Primitive)]
 pub struct TorusSector {
-    #[field(name = "Big radius", default = 0.5)]
+    #[field(name = "Big radius", default = 0.5, min = 0.0)]
     big_r: f32,
-    #[field(name = "Small radius", default = 0.1)]
+    #[field(name = "Small radius", default = 0.1, min = 0.0)]
     small_r: f32,
     #[field(name = "Angle", default = std::f32::consts::PI)]
     angle: f32,
@@ -95,34 +218,131 @@ pub struct TorusSector {
 
 #[node_type(name = "Biconvex Lens", category = NodeCategory::Primitive)]
 pub struct BiconvexLens {
-    #[field(name = "Lower sagitta", default = 0.5)]
+    #[field(name = "Lower sagitta", default = 0.5, min = 0.0)]
     lower_sagitta: f32,
-    #[field(name = "Upper sagitta", default = 0.5)]
+    #[field(name = "Upper sagitta", default = 0.5, min = 0.0)]
     upper_sagitta: f32,
-    #[field(name = "Chord", default = 1.0)]
+    #[field(name = "Chord", default = 1.0, min = 0.0)]
     chord: f32,
 }
 
+/// Builds another node's subtree in place of this node, so that edits to the original
+/// show up everywhere it's instanced.
+#[node_type(name = "Instance", category = NodeCategory::Primitive)]
+pub struct Instance {
+    #[field(name = "Target", default = None)]
+    target: Option<NodeId>,
+}
+
 // Operations
 
+/// The smoothing factors that operations support. Larger or negative factors produce
+/// degenerate meshes, so they're clamped to this range when meshing and exporting.
+pub const FACTOR_RANGE: std::ops::RangeInclusive<f32> = 0.0..=1.0;
+
+/// Clamps `factor` to [FACTOR_RANGE], treating NaN as no smoothing.
+pub fn clamp_factor(factor: f32) -> f32 {
+    if factor.is_nan() {
+        0.0
+    } else {
+        factor.clamp(*FACTOR_RANGE.start(), *FACTOR_RANGE.end())
+    }
+}
+
 #[node_type(name = "Union", category = NodeCategory::Operation, children = true)]
 pub struct Union {
-    #[field(name = "Factor", default = 0.0)]
+    #[field(name = "Factor", default = 0.0, min = 0.0, max = 1.0, checked = true)]
     factor: f32,
 }
 
+/// Intersects the first child with the intersection of the remaining children. Smoothing
+/// only applies to the first child's seams, so adding children doesn't smooth any seam
+/// more than once.
 #[node_type(name = "Intersect", category = NodeCategory::Operation, children = true)]
 pub struct Intersect {
-    #[field(name = "Factor", default = 0.0)]
+    #[field(name = "Factor", default = 0.0, min = 0.0, max = 1.0, checked = true)]
     factor: f32,
 }
 
+/// Subtracts the union of the remaining children from the first child. Smoothing only
+/// applies to the subtraction, so a third child blends the same way as a second.
 #[node_type(name = "Subtract", category = NodeCategory::Operation, children = true)]
 pub struct Subtract {
-    #[field(name = "Factor", default = 0.0)]
+    #[field(name = "Factor", default = 0.0, min = 0.0, max = 1.0, checked = true)]
     factor: f32,
 }
 
+/// Repeats its children around the Y axis, `count` times in total. A count of 0 or 1
+/// leaves them as they are.
+#[node_type(name = "Radial Repeat", category = NodeCategory::Operation, children = true)]
+pub struct RadialRepeat {
+    #[field(name = "Count", default = 6, max = RadialRepeat::MAX_COUNT as f32, checked = true)]
+    count: u32,
+}
+impl RadialRepeat {
+    /// Every copy is meshed separately, so this keeps a single edit from stalling meshing.
+    pub const MAX_COUNT: u32 = 64;
+}
+
+// Transforms
+
+/// Moves, turns and scales its children before its own transform is applied, so that a
+/// transform can be shared by everything beneath it. Several children are unioned first.
+#[node_type(name = "Transform", category = NodeCategory::Transform, children = true)]
+pub struct Transformation {
+    #[field(name = "Translation", default = Vec3::ZERO)]
+    translation: Vec3,
+    #[field(name = "Rotation", default = Quat::IDENTITY)]
+    rotation: Quat,
+    #[field(name = "Scale", default = 1.0, min = 0.0)]
+    scale: f32,
+}
+impl Transformation {
+    /// The transform to apply. Unlike a node's own transform, the rotation isn't checked
+    /// as it's received, so it's normalised here, and treated as no rotation if it can't
+    /// be.
+    pub fn transform(&self) -> Transform {
+        let rotation = if self.rotation.length_squared() > 1e-12 {
+            self.rotation.normalize()
+        } else {
+            Quat::IDENTITY
+        };
+        Transform {
+            translation: self.translation,
+            rotation,
+            scale: self.scale,
+        }
+    }
+}
+impl From<Transform> for Transformation {
+    fn from(transform: Transform) -> Self {
+        Self {
+            translation: transform.translation,
+            rotation: transform.rotation,
+            scale: transform.scale,
+        }
+    }
+}
+impl From<TransformDiff> for TransformationDiff {
+    fn from(diff: TransformDiff) -> Self {
+        Self {
+            translation: diff.translation,
+            rotation: diff.rotation,
+            scale: diff.scale,
+        }
+    }
+}
+
+// Metadata
+
+/// A note left in the graph for whoever edits it next. It's shown in the tree, but adds
+/// nothing to the shape.
+#[node_type(name = "Comment", category = NodeCategory::Metadata)]
+pub struct Comment {
+    #[field(name = "Text", default = String::new())]
+    text: String,
+}
+
 macro_rules! generate_node_data {
     ($(($ty:ident, $diff:ident)),*) => {
         #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -146,12 +366,54 @@ macro_rules! generate_node_data {
                     $(NodeData::$ty(d) => d as &dyn NodeDataMeta),*
                 }
             }
+            pub fn is_valid(&self) -> bool {
+                match self {
+                    $(NodeData::$ty(d) => d.is_valid()),*
+                }
+            }
+            /// Whether the fields with `checked = true` are within their bounds. Edits are
+            /// held to this, but data that's loaded isn't, as older saves may not meet it.
+            pub fn is_within_bounds(&self) -> bool {
+                match self {
+                    $(NodeData::$ty(d) => d.is_within_bounds()),*
+                }
+            }
             pub fn apply(&mut self, diff: NodeDataDiff) {
                 match (self, diff) {
                     $((NodeData::$ty(i), NodeDataDiff::$diff(d)) => i.apply(d)),*,
                     _ => {}
                 }
             }
+            /// The default data for the same type of node.
+            pub fn to_default(&self) -> NodeData {
+                match self {
+                    $(NodeData::$ty(_) => NodeData::$ty($ty::new())),*
+                }
+            }
+            /// Every field of this type of node, in the order they're declared.
+            pub fn fields(&self) -> &'static [FieldMeta] {
+                match self {
+                    $(NodeData::$ty(_) => $ty::FIELDS),*
+                }
+            }
+            /// The fields that can be bound to variables, as `(field, display name)`.
+            pub fn float_fields(&self) -> &'static [(&'static str, &'static str)] {
+                match self {
+                    $(NodeData::$ty(_) => $ty::FLOAT_FIELDS),*
+                }
+            }
+            pub fn set_float_field(&mut self, field: &str, value: f32) -> bool {
+                match self {
+                    $(NodeData::$ty(d) => d.set_float_field(field, value)),*
+                }
+            }
+        }
+        impl From<NodeData> for NodeDataDiff {
+            fn from(data: NodeData) -> NodeDataDiff {
+                match data {
+                    $(NodeData::$ty(d) => NodeDataDiff::$diff(d.into())),*
+                }
+            }
         }
         $(impl From<$ty> for NodeData {
             fn from(data: $ty) -> NodeData {
@@ -165,6 +427,36 @@ macro_rules! generate_node_data {
         pub enum NodeDataDiff {
             $($diff($diff)),*
         }
+        impl NodeDataDiff {
+            pub fn is_valid(&self) -> bool {
+                match self {
+                    $(NodeDataDiff::$diff(d) => d.is_valid()),*
+                }
+            }
+            /// See [NodeData::is_within_bounds].
+            pub fn is_within_bounds(&self) -> bool {
+                match self {
+                    $(NodeDataDiff::$diff(d) => d.is_within_bounds()),*
+                }
+            }
+            /// Combines two diffs, preferring the newer diff's values. If the diffs are for
+            /// different node types, only the newer diff is kept.
+            pub fn merge(self, newer: NodeDataDiff) -> NodeDataDiff {
+                match (self, newer) {
+                    $((NodeDataDiff::$diff(old), NodeDataDiff::$diff(new)) => old.merge(new).into()),*,
+                    (_, newer) => newer,
+                }
+            }
+            /// Blends between two diffs. Diffs for different node types can't be blended,
+            /// so `self` is held until `t` reaches 1.
+            pub fn lerp(&self, other: &NodeDataDiff, t: f32) -> NodeDataDiff {
+                match (self, other) {
+                    $((NodeDataDiff::$diff(a), NodeDataDiff::$diff(b)) => a.lerp(b, t).into()),*,
+                    _ if t < 1.0 => self.clone(),
+                    _ => other.clone(),
+                }
+            }
+        }
         $(impl From<$diff> for NodeDataDiff {
             fn from(diff: $diff) -> NodeDataDiff {
                 NodeDataDiff::$diff(diff)
@@ -184,7 +476,27 @@ generate_node_data!(
     (Box, BoxDiff),
     (TorusSector, TorusSectorDiff),
     (BiconvexLens, BiconvexLensDiff),
+    (Instance, InstanceDiff),
     (Union, UnionDiff),
     (Intersect, IntersectDiff),
-    (Subtract, SubtractDiff)
+    (Subtract, SubtractDiff),
+    (RadialRepeat, RadialRepeatDiff),
+    (Transformation, TransformationDiff),
+    (Comment, CommentDiff)
 );
+
+impl NodeData {
+    /// Metadata nodes describe the graph rather than the shape, so they're skipped when
+    /// meshing and exporting.
+    pub fn is_metadata(&self) -> bool {
+        self.category() == NodeCategory::Metadata
+    }
+
+    /// The transform that this node applies to its children, on top of its own.
+    pub fn children_transform(&self) -> Option<Transform> {
+        match self {
+            NodeData::Transformation(transformation) => Some(transformation.transform()),
+            _ => None,
+        }
+    }
+}
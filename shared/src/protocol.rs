@@ -1,33 +1,63 @@
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::{GraphChange, GraphCommand};
+use crate::{GraphChange, GraphCommand, GraphCommandError};
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct RequestJoin {
     pub room: String,
+    /// The [crate::Graph::content_hash] of the graph the peer already has, if any. If it
+    /// matches the room's graph, the room skips sending the whole graph on join.
+    #[serde(default)]
+    pub graph_hash: Option<u64>,
+    /// Spectators receive changes to the graph, but any commands they send are dropped.
+    #[serde(default)]
+    pub spectator: bool,
 }
 
+/// Identifies a command sent by a peer, so that the room's answer can be matched up
+/// with it. Only unique to the peer that sent it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CommandId(pub u64);
+
 // TODO: consider splitting this up into PeerOutgoingMessage and PeerIncomingMessage
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub enum PeerOutgoingMessage {
     RequestJoin(RequestJoin),
-    GraphCommand(GraphCommand),
+    /// A command to apply to the room's graph. The room answers with either
+    /// [PeerIncomingMessage::CommandAccepted] or [PeerIncomingMessage::CommandRejected].
+    GraphCommand(CommandId, GraphCommand),
+    /// Asks the room to send the whole graph again, for when the peer suspects that it's
+    /// out of sync.
+    RequestResync,
+    /// Asks to rename the room that the peer is in. Every peer in the room is told of the
+    /// new name, unless it's taken, in which case only the asking peer is told why.
+    RenameRoom(String),
 }
 impl From<RequestJoin> for PeerOutgoingMessage {
     fn from(req: RequestJoin) -> Self {
         Self::RequestJoin(req)
     }
 }
-impl From<GraphCommand> for PeerOutgoingMessage {
-    fn from(cmd: GraphCommand) -> Self {
-        Self::GraphCommand(cmd)
-    }
-}
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub enum PeerIncomingMessage {
     GraphChange(GraphChange),
+    /// Sent to the peer whose command was applied, with the changes it made. Every other
+    /// peer is sent the changes as they are.
+    CommandAccepted(CommandId, Vec<GraphChange>),
+    /// Sent to the peer whose command was rejected. The graph is left unchanged.
+    CommandRejected(CommandId, GraphCommandError),
+    /// The room was closed by the server, so nothing more will be sent or accepted until
+    /// the peer joins again.
+    RoomClosed,
+    /// The room the peer is in is now known by this name.
+    RoomRenamed(String),
+    /// Sent to the peer whose rename was rejected, with the reason.
+    RenameRejected(String),
+    /// How many peers are in the room, including this one. Sent whenever a peer joins or
+    /// leaves.
+    PeerCount(usize),
 }
 impl From<GraphChange> for PeerIncomingMessage {
     fn from(change: GraphChange) -> Self {
@@ -35,19 +65,30 @@ impl From<GraphChange> for PeerIncomingMessage {
     }
 }
 
+/// Writes a length-prefixed message, returning the number of bytes written.
 pub async fn write<W: AsyncWrite + Unpin, T: Serialize>(
     writer: &mut W,
     payload: T,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<usize> {
     let buf = bincode::serialize(&payload)?;
     let len: u32 = buf.len().try_into()?;
     writer.write_u32(len).await?;
-    Ok(writer.write_all(&buf).await?)
+    writer.write_all(&buf).await?;
+    Ok(std::mem::size_of::<u32>() + buf.len())
 }
 
 pub async fn read<'a, R: AsyncRead + Unpin, T: DeserializeOwned>(
     reader: &mut R,
 ) -> Option<anyhow::Result<T>> {
+    read_with_size(reader)
+        .await
+        .map(|result| result.map(|(message, _)| message))
+}
+
+/// Like [read], but also returns the number of bytes read.
+pub async fn read_with_size<'a, R: AsyncRead + Unpin, T: DeserializeOwned>(
+    reader: &mut R,
+) -> Option<anyhow::Result<(T, usize)>> {
     let size = match reader.read_u32().await {
         Ok(size) => size,
         Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
@@ -59,7 +100,10 @@ pub async fn read<'a, R: AsyncRead + Unpin, T: DeserializeOwned>(
         async {
             let mut buf = vec![0u8; size.try_into()?];
             reader.read_exact(&mut buf).await?;
-            Ok(bincode::deserialize(&buf)?)
+            Ok((
+                bincode::deserialize(&buf)?,
+                std::mem::size_of::<u32>() + buf.len(),
+            ))
         }
         .await,
     )
@@ -0,0 +1,150 @@
+//! The on-disk format for graphs. This wraps the serialized graph with a version,
+//! so that files written by older versions can be upgraded as they're loaded.
+//!
+//! Graphs can be saved as JSON (the default) or as RON, which is easier to edit by hand.
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use crate::{Graph, Material};
+
+/// The current version of the save format.
+///
+/// History:
+/// 1. Unversioned graph as written by `serde_json`.
+/// 2. Nodes gain a `label` and a `material`. RON files are supported from this version on.
+/// 3. Graphs gain `additional_root_node_ids`.
+/// 4. Graphs gain `variables`, and nodes gain `bindings`.
+/// 5. Materials gain `opacity`.
+/// 6. Nodes gain `locked`.
+pub const VERSION: u32 = 6;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Ron,
+}
+impl Format {
+    pub const EXTENSIONS: &'static [&'static str] = &["json", "ron"];
+
+    /// Picks the format from the path's extension, falling back to JSON.
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("ron") => Format::Ron,
+            _ => Format::Json,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SaveError {
+    #[error("failed to write JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("failed to write RON: {0}")]
+    Ron(#[from] ron::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum LoadError {
+    #[error("failed to parse save file: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("failed to parse save file: {0}")]
+    Ron(#[from] ron::Error),
+    #[error("save file is not an object")]
+    NotAnObject,
+    #[error("save file version {0} is newer than the supported version {VERSION}")]
+    UnsupportedVersion(u32),
+}
+
+#[derive(Serialize, Deserialize)]
+struct RonSaveFile<G> {
+    version: u32,
+    graph: G,
+}
+
+pub fn to_string(graph: &Graph, format: Format) -> Result<String, SaveError> {
+    match format {
+        Format::Json => {
+            let mut value = serde_json::to_value(graph)?;
+            if let Value::Object(object) = &mut value {
+                object.insert("version".to_string(), VERSION.into());
+            }
+            Ok(serde_json::to_string_pretty(&value)?)
+        }
+        Format::Ron => Ok(ron::ser::to_string_pretty(
+            &RonSaveFile {
+                version: VERSION,
+                graph,
+            },
+            ron::ser::PrettyConfig::default(),
+        )?),
+    }
+}
+
+pub fn from_str(contents: &str, format: Format) -> Result<Graph, LoadError> {
+    match format {
+        Format::Json => from_json(contents),
+        Format::Ron => from_ron(contents),
+    }
+}
+
+fn from_json(contents: &str) -> Result<Graph, LoadError> {
+    let mut value: Value = serde_json::from_str(contents)?;
+    let object = value.as_object_mut().ok_or(LoadError::NotAnObject)?;
+
+    // Files without a version predate versioning, and are treated as version 1
+    let version = match object.remove("version") {
+        Some(version) => serde_json::from_value(version)?,
+        None => 1,
+    };
+    if version > VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    for version in version..VERSION {
+        migrate(version, object)?;
+    }
+
+    Ok(serde_json::from_value(value)?)
+}
+
+fn from_ron(contents: &str) -> Result<Graph, LoadError> {
+    // Check the version before parsing the graph, so that a newer file gets a useful error
+    let RonSaveFile { version, .. } =
+        ron::from_str::<RonSaveFile<serde::de::IgnoredAny>>(contents)?;
+    // RON files only exist from version 2, and every change since has only added fields
+    // with defaults, so there's nothing to migrate yet. Future migrations that change
+    // existing fields will need to handle RON as well.
+    if version > VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    let RonSaveFile { graph, .. } = ron::from_str::<RonSaveFile<Graph>>(contents)?;
+    Ok(graph)
+}
+
+/// Upgrades `object` from `version` to `version + 1`.
+fn migrate(version: u32, object: &mut Map<String, Value>) -> Result<(), LoadError> {
+    match version {
+        1 => {
+            let material = serde_json::to_value(Material::new())?;
+            for node in nodes_mut(object) {
+                node.entry("label").or_insert(Value::Null);
+                node.entry("material").or_insert_with(|| material.clone());
+            }
+        }
+        // The new fields have defaults, so older graphs load as-is
+        2..=5 => {}
+        _ => unreachable!("no migration from version {version}"),
+    }
+    Ok(())
+}
+
+fn nodes_mut(object: &mut Map<String, Value>) -> impl Iterator<Item = &mut Map<String, Value>> {
+    object
+        .get_mut("nodes")
+        .and_then(Value::as_object_mut)
+        .into_iter()
+        .flat_map(|nodes| nodes.values_mut())
+        .filter_map(Value::as_object_mut)
+}
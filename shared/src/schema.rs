@@ -0,0 +1,45 @@
+//! A machine-readable description of every node type, so that tools outside of Exoform
+//! can build and edit graphs without hardcoding the node types.
+use serde_json::{json, Value};
+
+use crate::{NodeDataMeta, NODE_DATA_DEFAULTS};
+
+/// Describes every node type, including its fields' types, defaults and bounds. `variant`
+/// is the type's name when serialized, and defaults are given as they're serialized.
+pub fn node_type_schema() -> Value {
+    let node_types: Vec<_> = NODE_DATA_DEFAULTS
+        .iter()
+        .map(|data| {
+            // Node data is externally tagged, so this is `{ variant: { field: value } }`
+            let serialized = serde_json::to_value(data).unwrap_or(Value::Null);
+            let (variant, defaults) = serialized
+                .as_object()
+                .and_then(|object| object.iter().next())
+                .map(|(variant, defaults)| (variant.clone(), defaults.clone()))
+                .unwrap_or((String::new(), Value::Null));
+            let fields: Vec<_> = data
+                .fields()
+                .iter()
+                .map(|field| {
+                    json!({
+                        "field": field.field,
+                        "name": field.name,
+                        "type": field.ty,
+                        "default": defaults.get(field.field).cloned().unwrap_or(Value::Null),
+                        "min": field.min,
+                        "max": field.max,
+                        "checked": field.checked,
+                    })
+                })
+                .collect();
+            json!({
+                "variant": variant,
+                "name": data.name(),
+                "category": format!("{:?}", data.category()),
+                "can_have_children": data.can_have_children(),
+                "fields": fields,
+            })
+        })
+        .collect();
+    json!({ "node_types": node_types })
+}
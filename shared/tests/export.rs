@@ -0,0 +1,78 @@
+use std::path::PathBuf;
+
+use glam::Vec3;
+use shared::{
+    export::{glsl, wgsl},
+    *,
+};
+
+/// A union of two spheres, side by side along X.
+fn two_sphere_union() -> Graph {
+    let mut graph = Graph::new_authoritative();
+    graph
+        .apply_command(&GraphCommand::CreateNewRoot(NodeData::Union(
+            Union::default(),
+        )))
+        .unwrap();
+    let root = graph.root_node_id().unwrap();
+    for (index, x) in [-0.5, 0.5].into_iter().enumerate() {
+        let sphere = NodeData::Sphere(Sphere::default());
+        graph
+            .apply_command(&GraphCommand::AddChild(root, None, sphere))
+            .unwrap();
+        let child = graph.get(root).unwrap().children[index].unwrap();
+        let diff = NodeDiff {
+            transform: Some(TransformDiff {
+                translation: Some(Vec3::new(x, 0.0, 0.0)),
+                rotation: None,
+                scale: None,
+            }),
+            ..Default::default()
+        };
+        graph
+            .apply_command(&GraphCommand::ApplyDiff(child, diff))
+            .unwrap();
+    }
+    graph
+}
+
+/// Compares `output` with the golden file of the given name. Set `UPDATE_GOLDEN` to
+/// write the output to the file instead, after checking that it's what it should be.
+fn assert_golden(name: &str, output: &str) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, output).unwrap();
+        return;
+    }
+    let expected = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(output, expected, "{name} has changed");
+}
+
+#[test]
+fn two_sphere_union_matches_golden_files() {
+    let graph = two_sphere_union();
+    assert_golden("two_sphere_union.glsl", &glsl::graph_to_glsl(&graph));
+    assert_golden("two_sphere_union.wgsl", &wgsl::graph_to_wgsl(&graph));
+}
+
+#[test]
+fn flat_biconvex_lens_exports_finite_values() {
+    let mut graph = Graph::new_authoritative();
+    let lens = BiconvexLens {
+        lower_sagitta: 0.0,
+        upper_sagitta: 0.5,
+        chord: 1.0,
+    };
+    graph
+        .apply_command(&GraphCommand::CreateNewRoot(NodeData::BiconvexLens(lens)))
+        .unwrap();
+    for output in [glsl::graph_to_glsl(&graph), wgsl::graph_to_wgsl(&graph)] {
+        let output = output.to_lowercase();
+        assert!(
+            !output.contains("inf") && !output.contains("nan"),
+            "{output}"
+        );
+    }
+}
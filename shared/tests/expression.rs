@@ -0,0 +1,32 @@
+use std::collections::HashMap;
+
+use shared::expression::{self, Expression, ExpressionError};
+
+#[test]
+fn nesting_is_limited() {
+    let variables = HashMap::new();
+    let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+    assert_eq!(
+        expression::evaluate(&nested(Expression::MAX_DEPTH), &variables),
+        Ok(1.0)
+    );
+    assert_eq!(
+        expression::evaluate(&nested(Expression::MAX_DEPTH + 1), &variables),
+        Err(ExpressionError::TooDeep)
+    );
+    assert_eq!(
+        expression::evaluate(&"-".repeat(Expression::MAX_DEPTH + 1), &variables),
+        Err(ExpressionError::TooDeep)
+    );
+}
+
+#[test]
+fn long_sources_are_rejected_before_parsing() {
+    let variables = HashMap::new();
+    for source in ["(".repeat(100_000), "-".repeat(100_000), "1+".repeat(50_000) + "1"] {
+        assert_eq!(
+            expression::evaluate(&source, &variables),
+            Err(ExpressionError::TooLong)
+        );
+    }
+}
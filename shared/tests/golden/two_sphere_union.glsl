@@ -0,0 +1,8 @@
+float map(vec3 p) {
+    vec3 v0 = p - vec3(-0.5, 0.0, 0.0);
+    float v1 = length(v0) - 0.5;
+    vec3 v2 = p - vec3(0.5, 0.0, 0.0);
+    float v3 = length(v2) - 0.5;
+    float v4 = min(v1, v3);
+    return v4;
+}
@@ -0,0 +1,8 @@
+fn map(p: vec3<f32>) -> f32 {
+    let v0 = p - vec3<f32>(-0.5, 0.0, 0.0);
+    let v1 = length(v0) - 0.5;
+    let v2 = p - vec3<f32>(0.5, 0.0, 0.0);
+    let v3 = length(v2) - 0.5;
+    let v4 = min(v1, v3);
+    return v4;
+}
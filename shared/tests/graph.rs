@@ -0,0 +1,291 @@
+use shared::*;
+
+fn sphere() -> NodeData {
+    NodeData::Sphere(Sphere::default())
+}
+
+fn union() -> NodeData {
+    NodeData::Union(Union::default())
+}
+
+/// A graph with a union at its root, and a sphere under it.
+fn union_of_sphere() -> (Graph, NodeId, NodeId) {
+    let mut graph = Graph::new_authoritative();
+    graph
+        .apply_command(&GraphCommand::CreateNewRoot(union()))
+        .unwrap();
+    let root = graph.root_node_id().unwrap();
+    graph
+        .apply_command(&GraphCommand::AddChild(root, None, sphere()))
+        .unwrap();
+    let child = graph.get(root).unwrap().children[0].unwrap();
+    (graph, root, child)
+}
+
+#[test]
+fn removing_a_child_after_its_last_root_is_rejected() {
+    // Two peers removing the root and its child at the same time
+    let (mut graph, root, child) = union_of_sphere();
+    graph.apply_command(&GraphCommand::Remove(root)).unwrap();
+    assert!(graph.to_components().0.is_empty());
+    assert_eq!(
+        graph.apply_command(&GraphCommand::Remove(child)),
+        Err(GraphCommandError::NodeNotFound(child))
+    );
+    assert_eq!(
+        graph.apply_command(&GraphCommand::Remove(root)),
+        Err(GraphCommandError::NodeNotFound(root))
+    );
+}
+
+#[test]
+fn commands_on_detached_nodes_are_rejected() {
+    let (graph, root, _) = union_of_sphere();
+    let (nodes, _, _, variables) = graph.to_components();
+    let mut graph = Graph::authoritative_from_components((nodes, None, vec![], variables));
+    assert_eq!(
+        graph.apply_command(&GraphCommand::Remove(root)),
+        Err(GraphCommandError::Detached(root))
+    );
+    assert_eq!(
+        graph.apply_command(&GraphCommand::AddNewParent(None, root, union())),
+        Err(GraphCommandError::Detached(root))
+    );
+}
+
+#[test]
+fn radial_repeat_count_is_bounded() {
+    let mut graph = Graph::new_authoritative();
+    let repeat = |count| NodeData::RadialRepeat(RadialRepeat { count });
+    assert_eq!(
+        graph.apply_command(&GraphCommand::CreateNewRoot(repeat(
+            RadialRepeat::MAX_COUNT + 1
+        ))),
+        Err(GraphCommandError::InvalidValue)
+    );
+    graph
+        .apply_command(&GraphCommand::CreateNewRoot(repeat(
+            RadialRepeat::MAX_COUNT,
+        )))
+        .unwrap();
+
+    let root = graph.root_node_id().unwrap();
+    let diff = NodeDiff {
+        data: Some(
+            RadialRepeatDiff {
+                count: Some(RadialRepeat::MAX_COUNT + 1),
+            }
+            .into(),
+        ),
+        ..Default::default()
+    };
+    assert_eq!(
+        graph.apply_command(&GraphCommand::ApplyDiff(root, diff)),
+        Err(GraphCommandError::InvalidValue)
+    );
+}
+
+/// A graph with a union at its root, and a sphere and an instance of the sphere under it.
+fn union_of_sphere_and_instance() -> (Graph, NodeId, NodeId, NodeId) {
+    let (mut graph, root, sphere) = union_of_sphere();
+    let instance = NodeData::Instance(Instance {
+        target: Some(sphere),
+    });
+    graph
+        .apply_command(&GraphCommand::AddChild(root, None, instance))
+        .unwrap();
+    let instance = graph.get(root).unwrap().children[1].unwrap();
+    (graph, root, sphere, instance)
+}
+
+fn instance_target(graph: &Graph, node_id: NodeId) -> Option<NodeId> {
+    match graph.get(node_id).unwrap().data {
+        NodeData::Instance(Instance { target }) => target,
+        _ => panic!("{node_id:?} isn't an instance"),
+    }
+}
+
+#[test]
+fn pasted_instances_point_at_pasted_targets() {
+    let (mut graph, root, sphere, _) = union_of_sphere_and_instance();
+    let subtree = graph.subtree(root).unwrap();
+    graph
+        .apply_command(&GraphCommand::AddSubtree(None, None, subtree))
+        .unwrap();
+
+    let pasted_root = graph.root_node_ids()[1];
+    let pasted = &graph.get(pasted_root).unwrap().children;
+    let (pasted_sphere, pasted_instance) = (pasted[0].unwrap(), pasted[1].unwrap());
+    assert_ne!(pasted_sphere, sphere);
+    assert_eq!(
+        instance_target(&graph, pasted_instance),
+        Some(pasted_sphere)
+    );
+}
+
+#[test]
+fn pasted_instances_of_nodes_outside_the_subtree_lose_their_target() {
+    let (mut graph, root, _, instance) = union_of_sphere_and_instance();
+    let subtree = graph.subtree(instance).unwrap();
+    graph
+        .apply_command(&GraphCommand::AddSubtree(Some(root), None, subtree))
+        .unwrap();
+
+    let pasted_instance = graph.get(root).unwrap().children[2].unwrap();
+    assert_eq!(instance_target(&graph, pasted_instance), None);
+}
+
+#[test]
+fn pasted_instances_that_contain_themselves_are_rejected() {
+    let (mut graph, root, _, instance) = union_of_sphere_and_instance();
+    let mut subtree = graph.subtree(root).unwrap();
+    subtree.nodes.get_mut(&instance).unwrap().data = NodeData::Instance(Instance {
+        target: Some(subtree.root),
+    });
+    assert!(matches!(
+        graph.apply_command(&GraphCommand::AddSubtree(None, None, subtree)),
+        Err(GraphCommandError::Cycle(_))
+    ));
+}
+
+#[test]
+fn primitives_cannot_have_children() {
+    let (mut graph, _, sphere_id) = union_of_sphere();
+    let before = graph.content_hash();
+    assert_eq!(
+        graph.apply_command(&GraphCommand::AddChild(sphere_id, None, sphere())),
+        Err(GraphCommandError::CannotHaveChildren(sphere_id))
+    );
+    assert_eq!(
+        graph.apply_command(&GraphCommand::AddNewParent(None, sphere_id, sphere())),
+        Err(GraphCommandError::TypeCannotHaveChildren(
+            sphere().name().to_string()
+        ))
+    );
+    assert_eq!(graph.content_hash(), before);
+}
+
+#[test]
+fn unparsable_bindings_are_rejected() {
+    let (mut graph, root, _) = union_of_sphere();
+    let before = graph.content_hash();
+    for source in ["(".repeat(100_000), "1 +".to_string()] {
+        assert_eq!(
+            graph.apply_command(&GraphCommand::SetBinding(
+                root,
+                "factor".to_string(),
+                Some(source)
+            )),
+            Err(GraphCommandError::InvalidBinding("factor".to_string()))
+        );
+    }
+    assert_eq!(graph.content_hash(), before);
+
+    // Graphs sent whole are held to the same rules
+    let (mut nodes, root_node_id, additional, variables) = graph.to_components();
+    nodes
+        .get_mut(&root)
+        .unwrap()
+        .bindings
+        .insert("factor".to_string(), "-".repeat(100_000));
+    assert_eq!(
+        graph.apply_command(&GraphCommand::Replace((
+            nodes,
+            root_node_id,
+            additional,
+            variables
+        ))),
+        Err(GraphCommandError::InvalidBinding("factor".to_string()))
+    );
+}
+
+#[test]
+fn bound_values_are_held_to_field_bounds() {
+    let (mut graph, root, _) = union_of_sphere();
+    for command in [
+        GraphCommand::SetVariable("factor".to_string(), Some(-1.0)),
+        GraphCommand::SetBinding(root, "factor".to_string(), Some("factor".to_string())),
+    ] {
+        graph.apply_command(&command).unwrap();
+    }
+    let node = graph.get(root).unwrap();
+    assert_eq!(
+        graph.resolved_data(node),
+        Err(("factor", expression::ExpressionError::OutOfBounds))
+    );
+
+    graph
+        .apply_command(&GraphCommand::SetVariable("factor".to_string(), Some(0.5)))
+        .unwrap();
+    let node = graph.get(root).unwrap();
+    assert_eq!(
+        graph.resolved_data(node).unwrap().into_owned(),
+        NodeData::Union(Union { factor: 0.5 })
+    );
+}
+
+#[test]
+fn out_of_range_factors_are_only_rejected_when_edited() {
+    let (mut graph, root, _) = union_of_sphere();
+    let out_of_range = NodeData::Union(Union { factor: 2.0 });
+    let diff = NodeDiff {
+        data: Some(out_of_range.clone().into()),
+        ..Default::default()
+    };
+    assert_eq!(
+        graph.apply_command(&GraphCommand::ApplyDiff(root, diff)),
+        Err(GraphCommandError::InvalidValue)
+    );
+    assert_eq!(
+        graph.apply_command(&GraphCommand::ChangeType(root, out_of_range.clone())),
+        Err(GraphCommandError::InvalidValue)
+    );
+
+    // Older saves could have any factor, and are still loaded
+    let (mut nodes, root_node_id, additional, variables) = graph.to_components();
+    nodes.get_mut(&root).unwrap().data = out_of_range.clone();
+    graph
+        .apply_command(&GraphCommand::Replace((
+            nodes,
+            root_node_id,
+            additional,
+            variables,
+        )))
+        .unwrap();
+    assert_eq!(graph.get(root).unwrap().data, out_of_range);
+}
+
+fn set_children(node_id: NodeId, children: Vec<Option<NodeId>>) -> GraphCommand {
+    GraphCommand::ApplyDiff(
+        node_id,
+        NodeDiff {
+            children: Some(children),
+            ..Default::default()
+        },
+    )
+}
+
+#[test]
+fn other_roots_cannot_be_adopted() {
+    let (mut graph, root, child) = union_of_sphere();
+    graph
+        .apply_command(&GraphCommand::CreateNewRoot(sphere()))
+        .unwrap();
+    let other_root = graph.root_node_ids()[1];
+    assert_eq!(
+        graph.apply_command(&set_children(root, vec![Some(child), Some(other_root)])),
+        Err(GraphCommandError::IsRoot(other_root))
+    );
+}
+
+#[test]
+fn children_cannot_be_given_twice() {
+    let (mut graph, root, child) = union_of_sphere();
+    assert_eq!(
+        graph.apply_command(&set_children(root, vec![Some(child), Some(child)])),
+        Err(GraphCommandError::DuplicateChild(child))
+    );
+    graph
+        .apply_command(&set_children(root, vec![None, Some(child)]))
+        .unwrap();
+}
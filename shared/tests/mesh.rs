@@ -0,0 +1,202 @@
+use glam::Vec3;
+use shared::{
+    mesh::{CompilationError, CompilationOptions, Mesh},
+    *,
+};
+
+/// A graph with `root` at its root, and a sphere under it for each `(radius, translation)`.
+fn spheres_under(root: NodeData, spheres: &[(f32, Vec3)]) -> Graph {
+    let mut graph = Graph::new_authoritative();
+    graph
+        .apply_command(&GraphCommand::CreateNewRoot(root))
+        .unwrap();
+    let root = graph.root_node_id().unwrap();
+    for (index, (radius, translation)) in spheres.iter().enumerate() {
+        let sphere = NodeData::Sphere(Sphere { radius: *radius });
+        graph
+            .apply_command(&GraphCommand::AddChild(root, None, sphere))
+            .unwrap();
+        let child = graph.get(root).unwrap().children[index].unwrap();
+        let diff = NodeDiff {
+            transform: Some(TransformDiff {
+                translation: Some(*translation),
+                rotation: None,
+                scale: None,
+            }),
+            ..Default::default()
+        };
+        graph
+            .apply_command(&GraphCommand::ApplyDiff(child, diff))
+            .unwrap();
+    }
+    graph
+}
+
+fn mesh_volume(graph: &Graph) -> f32 {
+    mesh::generate_mesh(graph, &CompilationOptions::default())
+        .unwrap()
+        .mesh_volume
+}
+
+fn assert_close(a: f32, b: f32, tolerance: f32) {
+    assert!(
+        (a - b).abs() <= tolerance,
+        "{a} isn't within {tolerance} of {b}"
+    );
+}
+
+#[test]
+fn radial_repeat_has_sixfold_symmetry() {
+    let radius = 0.25;
+    let repeat = NodeData::RadialRepeat(RadialRepeat { count: 6 });
+    let graph = spheres_under(repeat, &[(radius, Vec3::X)]);
+    let output = mesh::generate_mesh(&graph, &CompilationOptions::default()).unwrap();
+
+    // Six separate spheres, spread evenly around the Y axis
+    let sphere_volume = 4.0 / 3.0 * std::f32::consts::PI * radius.powi(3);
+    assert_close(
+        output.mesh_volume,
+        6.0 * sphere_volume,
+        0.1 * 6.0 * sphere_volume,
+    );
+    assert_close(output.center_of_mass.x, 0.0, 0.01);
+    assert_close(output.center_of_mass.z, 0.0, 0.01);
+    // Anything with more than two-fold symmetry about Y turns as easily about X as Z
+    let [ixx, _, izz, ..] = output.inertia;
+    assert_close(ixx, izz, 0.05 * ixx);
+
+    // Each sphere's centre is at a sixth of a turn from the last, so the copy nearest
+    // each of those points has its surface `radius` away
+    for i in 0..6 {
+        let angle = i as f32 / 6.0 * std::f32::consts::TAU;
+        let centre = glam::Quat::from_rotation_y(angle) * Vec3::X;
+        let nearest = output
+            .mesh
+            .positions
+            .iter()
+            .map(|position| Vec3::from(*position).distance(centre))
+            .fold(f32::INFINITY, f32::min);
+        assert_close(nearest, radius, 0.05);
+    }
+}
+
+/// Adding a copy of the last child to a smoothed operation shouldn't change its shape,
+/// as the copy coincides with the original, and the smoothing is only applied once.
+/// `unsmoothed` is the same operation without smoothing, which should differ noticeably,
+/// or the comparison wouldn't show anything.
+fn assert_third_child_changes_nothing(operation: NodeData, unsmoothed: NodeData) {
+    let spheres = [(0.5, Vec3::ZERO), (0.5, Vec3::new(0.4, 0.0, 0.0))];
+    let two = mesh_volume(&spheres_under(operation.clone(), &spheres));
+    let three = mesh_volume(&spheres_under(
+        operation,
+        &[spheres[0], spheres[1], spheres[1]],
+    ));
+    let sharp = mesh_volume(&spheres_under(unsmoothed, &spheres));
+    assert!(two > 0.0);
+    assert!(
+        (two - sharp).abs() > 0.05 * sharp,
+        "{two} is too close to {sharp}"
+    );
+    assert_close(three, two, 0.01 * two);
+}
+
+#[test]
+fn smoothed_intersect_of_three_children_matches_two() {
+    assert_third_child_changes_nothing(
+        NodeData::Intersect(Intersect { factor: 0.5 }),
+        NodeData::Intersect(Intersect { factor: 0.0 }),
+    );
+}
+
+#[test]
+fn smoothed_subtract_of_three_children_matches_two() {
+    assert_third_child_changes_nothing(
+        NodeData::Subtract(Subtract { factor: 0.5 }),
+        NodeData::Subtract(Subtract { factor: 0.0 }),
+    );
+}
+
+#[test]
+fn factors_are_clamped() {
+    assert_eq!(clamp_factor(-1.0), 0.0);
+    assert_eq!(clamp_factor(f32::NAN), 0.0);
+    assert_eq!(clamp_factor(0.25), 0.25);
+    assert_eq!(clamp_factor(2.0), 1.0);
+}
+
+#[test]
+fn negative_factors_mesh_without_nans() {
+    let operations = [
+        NodeData::Union(Union::default()),
+        NodeData::Intersect(Intersect::default()),
+        NodeData::Subtract(Subtract::default()),
+    ];
+    for operation in operations {
+        let spheres = [(0.5, Vec3::ZERO), (0.5, Vec3::new(0.4, 0.0, 0.0))];
+        let graph = spheres_under(operation.clone(), &spheres);
+        // Edits can't set factors out of range, but files aren't checked as they're loaded
+        let (mut nodes, root, additional, variables) = graph.to_components();
+        let data = &mut nodes.get_mut(&root.unwrap()).unwrap().data;
+        data.set_float_field("factor", -1.0);
+        let graph = Graph::authoritative_from_components((nodes, root, additional, variables));
+
+        let output = mesh::generate_mesh(&graph, &CompilationOptions::default())
+            .unwrap_or_else(|err| panic!("{} failed to mesh: {err}", operation.name()));
+        let mesh = &output.mesh;
+        assert!(mesh.positions.iter().flatten().all(|x| x.is_finite()));
+        assert!(mesh.normals.iter().flatten().all(|x| x.is_finite()));
+        assert!(output.volume > 0.0);
+    }
+}
+
+#[test]
+fn non_finite_vertices_are_counted() {
+    let mesh = Mesh {
+        indices: vec![0, 1, 2],
+        positions: vec![[0.0, 0.0, 0.0], [f32::NAN, 0.0, 0.0], [1.0, 1.0, 0.0]],
+        normals: vec![[0.0, 0.0, 1.0], [0.0, 0.0, 1.0], [0.0, f32::INFINITY, 1.0]],
+        colors: vec![[1.0; 3]; 3],
+    };
+    assert_eq!(mesh.non_finite_vertex_count(), 2);
+}
+
+#[test]
+fn pathological_graphs_never_mesh_to_non_finite_vertices() {
+    let coincident = Vec3::ZERO;
+    let roots = [
+        NodeData::Capsule(Capsule {
+            point_1: coincident,
+            point_2: coincident,
+            radius: 0.5,
+        }),
+        NodeData::TaperedCapsule(TaperedCapsule {
+            point_1: coincident,
+            point_2: coincident,
+            radius_1: 0.5,
+            radius_2: 0.25,
+        }),
+        NodeData::Cone(Cone {
+            radius: 0.5,
+            height: 0.0,
+        }),
+        NodeData::BiconvexLens(BiconvexLens {
+            lower_sagitta: 0.0,
+            upper_sagitta: 0.0,
+            chord: 1.0,
+        }),
+        NodeData::Sphere(Sphere { radius: 1e-30 }),
+        NodeData::Sphere(Sphere { radius: 1e30 }),
+    ];
+    for root in roots {
+        let mut graph = Graph::new_authoritative();
+        graph
+            .apply_command(&GraphCommand::CreateNewRoot(root.clone()))
+            .unwrap();
+        // Failing outright is fine, as long as nothing non-finite gets through
+        match mesh::generate_mesh(&graph, &CompilationOptions::default()) {
+            Ok(output) => assert_eq!(output.mesh.non_finite_vertex_count(), 0, "{root:?}"),
+            Err(CompilationError::NonFiniteVertices(count)) => assert!(count > 0),
+            Err(_) => {}
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use glam::{Quat, Vec3};
+use shared::Transform;
+
+fn transform_with_rotation(rotation: [f32; 4]) -> serde_json::Result<Transform> {
+    serde_json::from_value(serde_json::json!({
+        "translation": [1.0, 2.0, 3.0],
+        "rotation": rotation,
+        "scale": 2.0,
+    }))
+}
+
+#[test]
+fn identity_rotation_loads_as_is() {
+    let transform = transform_with_rotation([0.0, 0.0, 0.0, 1.0]).unwrap();
+    assert_eq!(transform.translation, Vec3::new(1.0, 2.0, 3.0));
+    assert_eq!(transform.rotation, Quat::IDENTITY);
+    assert_eq!(transform.scale, 2.0);
+}
+
+#[test]
+fn unnormalised_rotation_is_normalised() {
+    // A quarter turn about Y, at twice the length it should be
+    let half_angle = std::f32::consts::FRAC_PI_4;
+    let rotation = [0.0, 2.0 * half_angle.sin(), 0.0, 2.0 * half_angle.cos()];
+    let transform = transform_with_rotation(rotation).unwrap();
+    assert!(transform.rotation.is_normalized());
+    let expected = Quat::from_rotation_y(std::f32::consts::FRAC_PI_2);
+    assert!(transform.rotation.abs_diff_eq(expected, 1e-6));
+    assert!((transform.rotation * Vec3::X).abs_diff_eq(-Vec3::Z, 1e-6));
+}
+
+#[test]
+fn degenerate_rotation_is_rejected() {
+    assert!(transform_with_rotation([0.0, 0.0, 0.0, 0.0]).is_err());
+    assert!(transform_with_rotation([1e-7, 0.0, 0.0, 0.0]).is_err());
+}
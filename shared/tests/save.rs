@@ -0,0 +1,74 @@
+use shared::{
+    save::{self, Format},
+    *,
+};
+
+#[test]
+fn version_1_files_gain_labels_and_materials() {
+    let contents = include_str!("fixtures/v1.json");
+    let graph = save::from_str(contents, Format::Json).unwrap();
+
+    let (nodes, root_node_id, additional_root_node_ids, variables) = graph.to_components();
+    assert_eq!(nodes.len(), 2);
+    for node in nodes.values() {
+        assert_eq!(node.label, None);
+        assert_eq!(node.material, Material::default());
+    }
+    assert!(additional_root_node_ids.is_empty());
+    assert!(variables.is_empty());
+
+    // Everything that was already there is kept
+    let root = &nodes[&root_node_id.unwrap()];
+    let sphere = &nodes[&root.children[0].unwrap()];
+    assert_eq!(sphere.data, NodeData::Sphere(Sphere { radius: 0.75 }));
+    assert_eq!(sphere.rgb, (1.0, 0.5, 0.0));
+    assert_eq!(sphere.transform.translation, glam::Vec3::Y);
+}
+
+#[test]
+fn insertion_order_does_not_change_the_saved_file() {
+    let mut graph = Graph::new_authoritative();
+    graph
+        .apply_command(&GraphCommand::CreateNewRoot(NodeData::Union(
+            Union::default(),
+        )))
+        .unwrap();
+    let root = graph.root_node_id().unwrap();
+    for _ in 0..8 {
+        graph
+            .apply_command(&GraphCommand::AddChild(
+                root,
+                None,
+                NodeData::Sphere(Sphere::default()),
+            ))
+            .unwrap();
+    }
+    for (index, name) in ["a", "b", "c", "d"].into_iter().enumerate() {
+        graph
+            .apply_command(&GraphCommand::SetVariable(
+                name.to_string(),
+                Some(index as f32),
+            ))
+            .unwrap();
+    }
+
+    // Rebuild the same graph with everything inserted in the opposite order
+    let (nodes, root_node_id, additional_root_node_ids, variables) = graph.to_components();
+    let mut nodes: Vec<_> = nodes.into_iter().collect();
+    nodes.sort_by(|(a, _), (b, _)| b.cmp(a));
+    let mut variables: Vec<_> = variables.into_iter().collect();
+    variables.sort_by(|(a, _), (b, _)| b.cmp(a));
+    let reversed = Graph::authoritative_from_components((
+        nodes.into_iter().collect(),
+        root_node_id,
+        additional_root_node_ids,
+        variables.into_iter().collect(),
+    ));
+
+    for format in [Format::Json, Format::Ron] {
+        assert_eq!(
+            save::to_string(&graph, format).unwrap(),
+            save::to_string(&reversed, format).unwrap()
+        );
+    }
+}
@@ -0,0 +1,30 @@
+use shared::*;
+
+#[test]
+fn schema_lists_every_node_type() {
+    let schema = node_type_schema();
+    let node_types = schema["node_types"].as_array().unwrap();
+    assert_eq!(node_types.len(), NODE_DATA_DEFAULTS.len());
+
+    for data in NODE_DATA_DEFAULTS {
+        let node_type = node_types
+            .iter()
+            .find(|node_type| node_type["name"] == data.name())
+            .unwrap_or_else(|| panic!("{} is missing from the schema", data.name()));
+        let variant = node_type["variant"].as_str().unwrap();
+        let serialized = serde_json::to_value(data).unwrap();
+        assert!(serialized.get(variant).is_some(), "{}", data.name());
+        assert_eq!(node_type["can_have_children"], data.can_have_children());
+
+        let fields = node_type["fields"].as_array().unwrap();
+        assert_eq!(fields.len(), data.fields().len(), "{}", data.name());
+        for field in fields {
+            let name = field["field"].as_str().unwrap();
+            assert!(
+                serialized[variant].get(name).is_some(),
+                "{}.{name}",
+                data.name()
+            );
+        }
+    }
+}